@@ -0,0 +1,115 @@
+//! Rough request throughput of the server, using Rocket's in-process client so
+//! no network is involved. Run with `cargo bench` (sqlite only).
+use device_checkout::*;
+use diesel::connection::SimpleConnection;
+use rocket::futures::future::join_all;
+use rocket::local::asynchronous::Client;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CONCURRENCY: usize = 64;
+const ROUNDS: usize = 20;
+/// How long the "stalled write" scenario keeps the database write lock.
+const LOCK_HELD: Duration = Duration::from_secs(1);
+
+async fn measure(client: &Arc<Client>, uri: &'static str) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let requests = (0..CONCURRENCY).map(|_| {
+            let client = client.clone();
+            rocket::tokio::spawn(async move {
+                let response = client.get(uri).dispatch().await;
+                assert_eq!(response.status(), rocket::http::Status::Ok);
+            })
+        });
+        join_all(requests).await;
+    }
+    (CONCURRENCY * ROUNDS) as f64 / start.elapsed().as_secs_f64()
+}
+
+/// An api token for a new user, as an Authorization header.
+fn api_token(config: &utils::types::Settings) -> rocket::http::Header<'static> {
+    let database = database::establish_connection(config).expect("opening connection");
+    let user = models::UserInsert {
+        username: "bench".into(),
+        password_hash: auth::hash_password(config, "bench password").unwrap(),
+        role: models::UserRole::User,
+        oidc_issuer: None,
+        oidc_subject: None,
+    };
+    database::insert_user(config, &database, &user, &models::ChangeContext::default()).expect("adding user");
+    let user = database::get_user_by_name(config, &database, "bench").unwrap().unwrap();
+    let (token, token_hash) = auth::generate_api_token();
+    let api_token = models::ApiTokenInsert {
+        user_id: user.id,
+        name: "bench".into(),
+        token_hash,
+    };
+    database::insert_api_token(config, &database, &api_token, &models::ChangeContext::default()).expect("adding api token");
+    rocket::http::Header::new("Authorization", format!("Bearer {}", token))
+}
+
+/// Reads while another connection holds the write lock and a couple of writes
+/// are queued behind it, waiting out the busy timeout.
+async fn measure_with_stalled_writes(client: &Arc<Client>, config: &utils::types::Settings) -> f64 {
+    let token = api_token(config);
+    for _ in 0..2 {
+        let response = client
+            .post("/api/reservations")
+            .header(rocket::http::ContentType::JSON)
+            .header(token.clone())
+            .body(r#"{"device_owner":"bench","device":{"pool_id":1}}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), rocket::http::Status::Ok);
+    }
+
+    let locker = database::establish_connection(config).expect("opening connection");
+    locker.batch_execute("BEGIN IMMEDIATE;").expect("taking write lock");
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(LOCK_HELD);
+        locker.batch_execute("COMMIT;").expect("releasing write lock");
+    });
+
+    let writes: Vec<_> = (1..=2)
+        .map(|id| {
+            let client = client.clone();
+            let token = token.clone();
+            rocket::tokio::spawn(async move {
+                client.delete(format!("/api/reservations/{}", id)).header(token).dispatch().await;
+            })
+        })
+        .collect();
+    // give the writes a head start; sleep the thread rather than the task so a
+    // blocked runtime can't delay the start of the measurement
+    std::thread::sleep(Duration::from_millis(50));
+
+    let requests_per_second = measure(client, "/api/devices").await;
+    join_all(writes).await;
+    release.join().unwrap();
+    requests_per_second
+}
+
+#[rocket::main]
+async fn main() {
+    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+    let mut config = utils::types::Settings::new();
+    config.database_url = file.path().to_string_lossy().to_string();
+    database::run_migrations(&config).expect("running migrations");
+
+    let client = Arc::new(
+        Client::tracked(create_new_rocket(config.clone()))
+            .await
+            .expect("valid rocket instance"),
+    );
+
+    for uri in &["/api/devices", "/devices"] {
+        let requests_per_second = measure(&client, uri).await;
+        println!("GET {:<14} {:>8.0} requests/s", uri, requests_per_second);
+    }
+    let requests_per_second = measure_with_stalled_writes(&client, &config).await;
+    println!(
+        "GET {:<14} {:>8.0} requests/s (while writes wait on a lock)",
+        "/api/devices", requests_per_second
+    );
+}
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+-- drop column needs sqlite 3.35 or later
+alter table custom_owners drop column updated_by;
+alter table pools drop column updated_by;
+alter table devices drop column updated_by;
+
+drop table users;
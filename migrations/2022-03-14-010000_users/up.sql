@@ -0,0 +1,19 @@
+-- Your SQL goes here
+create table users (
+	id integer primary key not null,
+	username text unique not null,
+	password_hash text not null,
+	created_at timestamp default current_timestamp not null,
+	updated_at timestamp default current_timestamp not null,
+	check (username <> '')
+);
+
+create trigger users after update on users
+begin
+	update users set updated_at = current_timestamp where id = NEW.id;
+end;
+
+-- who made the last change, null for changes made before accounts existed
+alter table devices add column updated_by text;
+alter table pools add column updated_by text;
+alter table custom_owners add column updated_by text;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table pool_admins;
+
+-- drop column needs sqlite 3.35 or later
+alter table users drop column role;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+alter table users add column role text not null default 'user'
+	check (role in ('viewer', 'user', 'pool_admin', 'admin'));
+
+-- the pools each pool_admin may manage
+create table pool_admins (
+	user_id integer not null references users(id) on delete cascade,
+	pool_id integer not null references pools(id) on delete cascade,
+	primary key (user_id, pool_id)
+);
@@ -0,0 +1,28 @@
+-- Your SQL goes here
+-- every change to devices, pools, custom owners, users and api tokens, with the
+-- entity as json before and after; rows are only ever added
+create table audit_log (
+	id integer primary key not null,
+	created_at timestamp default current_timestamp not null,
+	actor text,
+	action text not null,
+	entity_type text not null,
+	entity_id integer,
+	before text,
+	after text,
+	source text not null,
+	source_ip text
+);
+
+create index audit_log_entity on audit_log (entity_type, entity_id);
+create index audit_log_actor on audit_log (actor);
+
+create trigger audit_log_no_update before update on audit_log
+begin
+	select raise(abort, 'audit_log is append-only');
+end;
+
+create trigger audit_log_no_delete before delete on audit_log
+begin
+	select raise(abort, 'audit_log is append-only');
+end;
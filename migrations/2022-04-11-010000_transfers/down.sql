@@ -0,0 +1,43 @@
+-- This file should undo anything in `up.sql`
+create temporary table audit_log_backup(id, created_at, actor, action, entity_type, entity_id, before, after, source, source_ip);
+
+insert into audit_log_backup select id, created_at, actor, action, entity_type, entity_id, before, after, source, source_ip from audit_log;
+
+drop table audit_log;
+
+-- re-create the audit_log table without the reason, transfers and forced
+-- releases become plain edits
+create table audit_log (
+	id integer primary key not null,
+	created_at timestamp default current_timestamp not null,
+	actor text,
+	action text not null,
+	entity_type text not null,
+	entity_id integer,
+	before text,
+	after text,
+	source text not null,
+	source_ip text
+);
+
+insert into audit_log
+	select id, created_at, actor,
+		case when action in ('transfer', 'force_release') then 'edit' else action end,
+		entity_type, entity_id, before, after, source, source_ip
+	from audit_log_backup;
+
+drop table audit_log_backup;
+
+create index audit_log_entity on audit_log (entity_type, entity_id);
+create index audit_log_actor on audit_log (actor);
+
+-- create the triggers after the restore
+create trigger audit_log_no_update before update on audit_log
+begin
+	select raise(abort, 'audit_log is append-only');
+end;
+
+create trigger audit_log_no_delete before delete on audit_log
+begin
+	select raise(abort, 'audit_log is append-only');
+end;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- why a change was made, for transfers and forced releases
+alter table audit_log add column reason text;
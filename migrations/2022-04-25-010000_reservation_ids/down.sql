@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+-- drop column needs sqlite 3.35 or later
+drop index devices_reservation_id;
+alter table devices drop column reservation_id;
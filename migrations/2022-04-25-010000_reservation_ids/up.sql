@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- devices claimed together share the id of the first of them, which is the
+-- reservation's id; null for devices claimed before reservations had ids,
+-- whose reservation id is their own
+alter table devices add column reservation_id integer;
+
+create index devices_reservation_id on devices (reservation_id);
@@ -0,0 +1,3 @@
+-- drop column needs sqlite 3.35 or later
+alter table custom_owners drop column unlimited_reservations;
+alter table pools drop column max_reservations_per_owner;
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+drop index users_oidc;
+
+-- drop column needs sqlite 3.35 or later
+alter table users drop column oidc_subject;
+alter table users drop column oidc_issuer;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- the identity provider account a user logs in with, so single sign-on never
+-- takes over an account just because the username matches
+alter table users add column oidc_issuer text;
+alter table users add column oidc_subject text;
+create unique index users_oidc on users (oidc_issuer, oidc_subject);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table devices;
+drop function set_updated_at();
+drop type reservation_status;
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+create type reservation_status as enum ('available', 'reserved');
+
+create function set_updated_at() returns trigger as $$
+begin
+	NEW.updated_at = current_timestamp;
+	return NEW;
+end;
+$$ language plpgsql;
+
+create table devices (
+	id serial primary key,
+	device_name text unique not null,
+	device_url text,
+	device_owner text,
+	comments text,
+	reservation_status reservation_status not null default 'available',
+	created_at timestamp default current_timestamp not null,
+	updated_at timestamp default current_timestamp not null,
+	--device_name not empty
+	check (device_name <> ''),
+	--if we're reserved, then we need a not empty device_owner
+	check (reservation_status <> 'reserved' or (device_owner is not null and device_owner <> ''))
+);
+
+create trigger devices before update on devices
+for each row execute procedure set_updated_at();
+
+insert into devices (device_name, device_url) values ('unit1', 'http://unit1');
+insert into devices (device_name, device_url) values ('unit2', 'http://unit2');
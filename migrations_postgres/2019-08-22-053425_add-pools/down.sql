@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table devices drop column pool_id;
+drop table pools;
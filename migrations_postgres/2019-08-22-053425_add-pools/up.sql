@@ -0,0 +1,19 @@
+-- Your SQL goes here
+
+create table pools (
+	id serial primary key,
+	pool_name text not null,
+	created_at timestamp default current_timestamp not null,
+	updated_at timestamp default current_timestamp not null,
+	check (pool_name <> '')
+);
+
+create trigger pools before update on pools
+for each row execute procedure set_updated_at();
+
+insert into pools (pool_name) values ('Default Pool');
+
+-- existing devices all go in the default pool
+alter table devices add column pool_id integer references pools(id);
+update devices set pool_id = (select id from pools where pool_name = 'Default Pool');
+alter table devices alter column pool_id set not null;
@@ -0,0 +1 @@
+drop table custom_owners;
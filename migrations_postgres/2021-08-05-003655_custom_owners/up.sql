@@ -0,0 +1,14 @@
+-- Your SQL goes here
+create table custom_owners (
+	id serial primary key,
+	custom_owner_name text not null,
+	recipient text not null,
+	description text,
+	created_at timestamp default current_timestamp not null,
+	updated_at timestamp default current_timestamp not null,
+	check (custom_owner_name <> ''),
+	check (recipient <> '')
+);
+
+create trigger custom_owners before update on custom_owners
+for each row execute procedure set_updated_at();
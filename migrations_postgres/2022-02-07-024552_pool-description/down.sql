@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table pools drop constraint pools_pool_name_key;
+alter table pools drop column description;
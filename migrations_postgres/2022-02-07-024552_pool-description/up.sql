@@ -0,0 +1,4 @@
+-- Your SQL goes here
+alter table pools add column description text;
+update pools set description = '';
+alter table pools add constraint pools_pool_name_key unique (pool_name);
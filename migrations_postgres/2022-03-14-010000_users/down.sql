@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+alter table custom_owners drop column updated_by;
+alter table pools drop column updated_by;
+alter table devices drop column updated_by;
+
+drop table users;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+create table users (
+	id serial primary key,
+	username text unique not null,
+	password_hash text not null,
+	created_at timestamp default current_timestamp not null,
+	updated_at timestamp default current_timestamp not null,
+	check (username <> '')
+);
+
+create trigger users before update on users
+for each row execute procedure set_updated_at();
+
+-- who made the last change, null for changes made before accounts existed
+alter table devices add column updated_by text;
+alter table pools add column updated_by text;
+alter table custom_owners add column updated_by text;
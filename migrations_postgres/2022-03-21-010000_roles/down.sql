@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table pool_admins;
+
+alter table users drop column role;
+drop type user_role;
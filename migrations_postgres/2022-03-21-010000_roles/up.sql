@@ -0,0 +1,11 @@
+-- Your SQL goes here
+create type user_role as enum ('viewer', 'user', 'pool_admin', 'admin');
+
+alter table users add column role user_role not null default 'user';
+
+-- the pools each pool_admin may manage
+create table pool_admins (
+	user_id integer not null references users(id) on delete cascade,
+	pool_id integer not null references pools(id) on delete cascade,
+	primary key (user_id, pool_id)
+);
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- only a hash of each token is kept, the token itself is shown once on creation
+create table api_tokens (
+	id serial primary key,
+	user_id integer not null references users(id) on delete cascade,
+	name text not null,
+	token_hash text unique not null,
+	created_at timestamp default current_timestamp not null,
+	last_used_at timestamp,
+	check (name <> '')
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+drop table audit_log;
+drop function audit_log_append_only();
+drop type change_source;
+drop type audit_entity;
+drop type audit_action;
@@ -0,0 +1,31 @@
+-- Your SQL goes here
+create type audit_action as enum ('insert', 'edit', 'delete', 'claim', 'return');
+create type audit_entity as enum ('device', 'pool', 'custom_owner', 'user', 'api_token');
+create type change_source as enum ('ui', 'api', 'internal');
+
+-- every change to devices, pools, custom owners, users and api tokens, with the
+-- entity as json before and after; rows are only ever added
+create table audit_log (
+	id serial primary key,
+	created_at timestamp default current_timestamp not null,
+	actor text,
+	action audit_action not null,
+	entity_type audit_entity not null,
+	entity_id integer,
+	before text,
+	after text,
+	source change_source not null,
+	source_ip text
+);
+
+create index audit_log_entity on audit_log (entity_type, entity_id);
+create index audit_log_actor on audit_log (actor);
+
+create function audit_log_append_only() returns trigger as $$
+begin
+	raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_log_append_only before update or delete on audit_log
+	for each row execute procedure audit_log_append_only();
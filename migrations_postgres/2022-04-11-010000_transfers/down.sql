@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+alter table audit_log drop column reason;
+
+-- enum values can't be dropped, so swap the type, transfers and forced
+-- releases become plain edits
+alter type audit_action rename to audit_action_old;
+create type audit_action as enum ('insert', 'edit', 'delete', 'claim', 'return');
+alter table audit_log alter column action type audit_action using (
+	case when action::text in ('transfer', 'force_release') then 'edit' else action::text end
+)::audit_action;
+drop type audit_action_old;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+alter type audit_action add value 'transfer';
+alter type audit_action add value 'force_release';
+
+-- why a change was made, for transfers and forced releases
+alter table audit_log add column reason text;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- everyone sharing a reservation; devices.device_owner keeps them as a
+-- comma separated list for showing
+create table device_owners (
+	device_id integer not null references devices(id) on delete cascade,
+	owner_name text not null,
+	primary key (device_id, owner_name)
+);
+
+create index device_owners_owner_name on device_owners (lower(owner_name));
+
+insert into device_owners (device_id, owner_name)
+	select id, device_owner from devices
+	where reservation_status = 'reserved' and device_owner is not null and device_owner <> '';
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index devices_reservation_id;
+alter table devices drop column reservation_id;
@@ -0,0 +1,2 @@
+alter table custom_owners drop column unlimited_reservations;
+alter table pools drop column max_reservations_per_owner;
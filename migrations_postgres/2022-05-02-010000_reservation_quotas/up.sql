@@ -0,0 +1,2 @@
+alter table pools add column max_reservations_per_owner integer;
+alter table custom_owners add column unlimited_reservations boolean not null default false;
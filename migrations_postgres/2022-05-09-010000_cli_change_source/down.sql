@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+-- enum values can't be dropped, so swap the type, admin subcommand changes
+-- become internal ones
+alter type change_source rename to change_source_old;
+create type change_source as enum ('ui', 'api', 'internal');
+alter table audit_log alter column source type change_source using (
+	case when source::text = 'cli' then 'internal' else source::text end
+)::change_source;
+drop type change_source_old;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- changes made by the admin subcommands
+alter type change_source add value 'cli';
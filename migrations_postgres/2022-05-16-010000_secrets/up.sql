@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- server wide secrets, such as the key cookies are signed with, shared by
+-- every server on the database
+create table secrets (
+	name text primary key,
+	value text not null
+);
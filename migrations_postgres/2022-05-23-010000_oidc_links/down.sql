@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop index users_oidc;
+
+alter table users drop column oidc_subject;
+alter table users drop column oidc_issuer;
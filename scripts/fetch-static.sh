@@ -0,0 +1,32 @@
+#!/bin/sh
+# Downloads the web UI assets into static/, which are built into the binary so
+# the UI works without internet access. Pages load any that are missing from
+# their CDNs instead. Run from the repository root, and when bumping a version
+# update the CDN addresses in src/templates.rs to match.
+set -eu
+
+fetch() {
+    url="$1"
+    file="static/$2"
+    integrity="${3:-}"
+    curl -fsSL -o "$file" "$url"
+    if [ -n "$integrity" ]; then
+        actual="sha384-$(openssl dgst -sha384 -binary "$file" | openssl base64 -A)"
+        if [ "$actual" != "$integrity" ]; then
+            echo "$file: expected $integrity, got $actual" >&2
+            rm -f "$file"
+            exit 1
+        fi
+    fi
+    echo "$file"
+}
+
+fetch https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0/css/bootstrap.min.css bootstrap.min.css \
+    sha384-Gn5384xqQ1aoWXA+058RXPxPg6fy4IWvTNh0E263XmFcJlSAwiGgFAW/dAiS6JXm
+fetch https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0/js/bootstrap.min.js bootstrap.min.js \
+    sha384-JZR6Spejh4U02d8jOt6vLEHfe/JQGiRRSQQxSfFWpi1MquVdAyjUar5+76PVCmYl
+fetch https://cdnjs.cloudflare.com/ajax/libs/popper.js/1.12.9/umd/popper.min.js popper.min.js \
+    sha384-ApNbgh9B+Y1QKtv3Rn7W3mgPxhU9K/ScQsAP7hUibX39j7fakFPskvXusvfa0b4Q
+fetch https://code.jquery.com/jquery-3.6.1.min.js jquery.min.js
+fetch https://unpkg.com/bootstrap-table@1.18.0/dist/bootstrap-table.min.css bootstrap-table.min.css
+fetch https://unpkg.com/bootstrap-table@1.18.0/dist/bootstrap-table.min.js bootstrap-table.min.js
@@ -0,0 +1,394 @@
+//! The admin subcommands, changing the database directly rather than through
+//! a running server, with the same validation as the forms
+
+use crate::auth;
+use crate::database;
+use crate::inventory;
+use crate::models;
+use crate::utils;
+use crate::utils::types::AdminCommand;
+use crate::validation::{find_first_validation_message, ValidateWithContext, ValidationContext};
+use failure;
+use failure::ResultExt;
+use std;
+use validator::{Validate, ValidationErrors};
+
+///Runs an admin subcommand against `--database`, which is migrated first as
+///the server would
+pub fn run(config: &utils::types::Settings, command: &AdminCommand) -> Result<(), failure::Error> {
+    database::run_migrations(config)?;
+    if let AdminCommand::Migrate = command {
+        return Ok(());
+    }
+    let database = database::establish_connection(config)?;
+    let change = models::ChangeContext {
+        actor: std::env::var("USER").ok(),
+        source: models::ChangeSource::Cli,
+        ..Default::default()
+    };
+    let context = ValidationContext::new(config, &database);
+    match command {
+        AdminCommand::DeviceAdd { name, url, pool } => {
+            let device = models::DeviceInsert {
+                device_name: name.clone(),
+                device_url: url.clone(),
+                pool_id: find_pool(config, &database, pool)?.id,
+            };
+            device.validate().map_err(invalid)?;
+            changed(database::insert_device(config, &database, &device, &change), "Failed to add device")?;
+            println!("Added device {}", name);
+        }
+        AdminCommand::DeviceEdit { name, new_name, url } => {
+            let current = find_device(config, &database, name)?;
+            let device = models::DeviceEdit {
+                id: current.id,
+                device_name: new_name.clone().unwrap_or_else(|| current.device_name.clone()),
+                device_url: url.clone().or(current.device_url).unwrap_or_default(),
+                pool_id: current.pool_id,
+            };
+            device.validate().map_err(invalid)?;
+            changed(database::edit_device(config, &database, &device, &change), "Failed to update device")?;
+            println!("Updated device {}", device.device_name);
+        }
+        AdminCommand::DeviceDelete { name } => {
+            let device = models::DeviceDelete {
+                id: find_device(config, &database, name)?.id,
+            };
+            changed(database::delete_device(config, &database, &device, &change), "Failed to delete device")?;
+            println!("Deleted device {}", name);
+        }
+        AdminCommand::DeviceMove { name, pool } => {
+            let current = find_device(config, &database, name)?;
+            let pool = find_pool(config, &database, pool)?;
+            let device = models::DeviceEdit {
+                id: current.id,
+                device_name: current.device_name,
+                device_url: current.device_url.unwrap_or_default(),
+                pool_id: pool.id,
+            };
+            device.validate().map_err(invalid)?;
+            changed(database::edit_device(config, &database, &device, &change), "Failed to move device")?;
+            println!("Moved device {} to {}", name, pool.pool_name);
+        }
+        AdminCommand::PoolAdd {
+            name,
+            description,
+            max_reservations_per_owner,
+        } => {
+            let pool = models::PoolInsert {
+                pool_name: name.clone(),
+                description: description.clone(),
+                max_reservations_per_owner: *max_reservations_per_owner,
+            };
+            pool.validate().map_err(invalid)?;
+            changed(database::insert_pool(config, &database, &pool, &change), "Failed to add pool")?;
+            println!("Added pool {}", name);
+        }
+        AdminCommand::PoolDelete { pool } => {
+            let pool = find_pool(config, &database, pool)?;
+            let pool_delete = models::PoolDelete { id: pool.id };
+            pool_delete.validate_with_context(&context).map_err(invalid)?;
+            changed(database::delete_pool(config, &database, &pool_delete, &change), "Failed to delete pool")?;
+            println!("Deleted pool {}", pool.pool_name);
+        }
+        AdminCommand::OwnerAdd {
+            name,
+            recipient,
+            description,
+            unlimited_reservations,
+        } => {
+            let custom_owner = models::CustomOwnerInsert {
+                custom_owner_name: name.to_lowercase(),
+                recipient: recipient.to_lowercase(),
+                description: description.clone(),
+                unlimited_reservations: *unlimited_reservations,
+            };
+            custom_owner.validate_with_context(&context).map_err(invalid)?;
+            changed(
+                database::insert_custom_owner(config, &database, &custom_owner, &change),
+                "Failed to add custom_owner",
+            )?;
+            println!("Added custom owner {}", custom_owner.custom_owner_name);
+        }
+        AdminCommand::UserAdd { name, role, password } => {
+            let name = name.trim();
+            let password_hash = match password {
+                Some(password) => {
+                    let user_register = models::UserRegister {
+                        username: name.to_string(),
+                        password: password.clone(),
+                    };
+                    user_register.validate().map_err(invalid)?;
+                    auth::hash_password(config, password)?
+                }
+                None if name.is_empty() => return Err(failure::err_msg("Usernames cannot be empty")),
+                None => auth::NO_PASSWORD.to_string(),
+            };
+            if database::get_user_by_name(config, &database, name)?.is_some() {
+                return Err(failure::err_msg("That username is already taken"));
+            }
+            let user_insert = models::UserInsert {
+                username: name.to_string(),
+                password_hash,
+                role: *role,
+                oidc_issuer: None,
+                oidc_subject: None,
+            };
+            changed(database::insert_user(config, &database, &user_insert, &change), "Failed to add user")?;
+            println!("Added {} as {:?}", name, role);
+        }
+        AdminCommand::ReleaseAll { pool, reason } => {
+            let pool = find_pool(config, &database, pool)?;
+            let change = models::ChangeContext {
+                reason: Some(reason.trim().to_string()),
+                ..change
+            };
+            let reserved = database::get_devices_in_pool(config, &database, pool.id)?
+                .into_iter()
+                .filter(|device| device.reservation_status == models::ReservationStatus::Reserved);
+            let mut released = 0;
+            for device in reserved {
+                let release = models::DeviceForceRelease {
+                    id: device.id,
+                    device_owner: device.device_owner.clone().unwrap_or_default(),
+                    reason: reason.trim().to_string(),
+                };
+                release.validate().map_err(invalid)?;
+                if database::force_release_device(config, &database, &release, &change)? == 0 {
+                    warn!("{} was returned or transferred while releasing it", device.device_name);
+                    continue;
+                }
+                released += 1;
+                for owner in device.owners() {
+                    utils::notify::notify_owner(
+                        config,
+                        &database,
+                        &owner,
+                        &format!("{} was released from you: {}", device.device_name, release.reason),
+                    );
+                }
+            }
+            println!("Released {} devices from {}", released, pool.pool_name);
+        }
+        AdminCommand::Check => {
+            let problems = database::check_devices(config, &database)?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(failure::err_msg(format!("Found {} problems", problems.len())));
+            }
+            println!("No problems found");
+        }
+        AdminCommand::Export { format, output } => {
+            let format = match (format, output.as_deref().and_then(inventory::Format::from_path)) {
+                (Some(format), _) => format.parse()?,
+                (None, Some(format)) => format,
+                (None, None) => inventory::Format::Json,
+            };
+            let exported = inventory::export(config, &database)?.to_string(format)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(output, exported).with_context(|_| format!("Failed to write {}", output))?
+                }
+                None => print!("{}", exported),
+            }
+        }
+        AdminCommand::Import { input, format, dry_run } => {
+            let parsed = inventory::read_file(input, format.as_deref())?;
+            let changes = inventory::import(config, &database, &parsed, &change, *dry_run)?;
+            print_changes(&changes, *dry_run);
+        }
+        AdminCommand::Sync {
+            input,
+            format,
+            prune,
+            dry_run,
+        } => {
+            let parsed = inventory::read_file(input, format.as_deref())?;
+            let changes = inventory::sync(config, &database, &parsed, &change, *prune, *dry_run)?;
+            print_changes(&changes, *dry_run);
+        }
+        AdminCommand::Migrate => {}
+    }
+    Ok(())
+}
+
+fn print_changes(changes: &[String], dry_run: bool) {
+    for line in changes {
+        println!("{}", line);
+    }
+    if changes.is_empty() {
+        println!("Nothing to change");
+    } else if dry_run {
+        println!("Nothing was changed, run again without --dry-run to make these changes");
+    }
+}
+
+///A pool by id, or failing that by name
+fn find_pool(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    pool: &str,
+) -> Result<models::Pool, failure::Error> {
+    database::get_pools(config, database)?
+        .into_iter()
+        .find(|candidate| pool.parse::<i32>().ok() == Some(candidate.id) || candidate.pool_name == pool)
+        .ok_or_else(|| failure::err_msg(format!("No pool '{}'", pool)))
+}
+
+fn find_device(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    name: &str,
+) -> Result<models::Device, failure::Error> {
+    database::get_device(config, database, name)?.ok_or_else(|| failure::err_msg(format!("No device '{}'", name)))
+}
+
+fn invalid(errors: ValidationErrors) -> failure::Error {
+    let errors = errors.field_errors();
+    match find_first_validation_message(&errors) {
+        Some(message) => failure::err_msg(message.to_string()),
+        None => failure::err_msg("Invalid arguments"),
+    }
+}
+
+///Turns a change that matched no rows into an error, as the forms would
+fn changed(result: Result<usize, failure::Error>, message: &'static str) -> Result<usize, failure::Error> {
+    match result {
+        Ok(0) => Err(failure::err_msg(message)),
+        Ok(changed) => Ok(changed),
+        Err(e) => Err(e.context(message).into()),
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "sqlite")]
+mod test {
+    use super::*;
+    use diesel;
+    use diesel::prelude::*;
+    use tempfile;
+
+    fn test_settings() -> (tempfile::NamedTempFile, utils::types::Settings) {
+        let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+        let mut config = utils::types::Settings::new();
+        config.database_url = file.path().to_string_lossy().to_string();
+        config.password_hash_cost = (8, 1);
+        (file, config)
+    }
+
+    #[test]
+    fn test_devices_and_pools() {
+        let (_file, config) = test_settings();
+        let add_pool = AdminCommand::PoolAdd {
+            name: "Lab2".into(),
+            description: None,
+            max_reservations_per_owner: Some(1),
+        };
+        run(&config, &add_pool).unwrap();
+        assert!(run(&config, &add_pool).is_err()); // pool names are unique
+        let add_device = AdminCommand::DeviceAdd {
+            name: "unit3".into(),
+            url: "not a url".into(),
+            pool: "Lab2".into(),
+        };
+        assert_eq!(run(&config, &add_device).unwrap_err().to_string(), "URL was invalid");
+        let add_device = AdminCommand::DeviceAdd {
+            name: "unit3".into(),
+            url: "http://unit3".into(),
+            pool: "Lab2".into(),
+        };
+        run(&config, &add_device).unwrap();
+
+        let delete_pool = AdminCommand::PoolDelete { pool: "2".into() };
+        assert!(run(&config, &delete_pool).is_err()); // not while it has devices
+        let move_device = AdminCommand::DeviceMove {
+            name: "unit3".into(),
+            pool: "Default Pool".into(),
+        };
+        run(&config, &move_device).unwrap();
+        let edit_device = AdminCommand::DeviceEdit {
+            name: "unit3".into(),
+            new_name: Some("unit4".into()),
+            url: None,
+        };
+        run(&config, &edit_device).unwrap();
+        run(&config, &delete_pool).unwrap();
+
+        let database = database::establish_connection(&config).unwrap();
+        let device = database::get_device(&config, &database, "unit4").unwrap().unwrap();
+        assert_eq!(device.pool_id, 1);
+        assert_eq!(device.device_url, Some("http://unit3".into()));
+        let audit = database::get_audit_log(&config, &database, &models::AuditFilter::default()).unwrap();
+        assert!(audit.iter().all(|entry| entry.source == models::ChangeSource::Cli));
+
+        run(&config, &AdminCommand::DeviceDelete { name: "unit4".into() }).unwrap();
+        assert!(run(&config, &AdminCommand::DeviceDelete { name: "unit4".into() }).is_err());
+        assert!(run(&config, &AdminCommand::PoolDelete { pool: "Default Pool".into() }).is_err());
+    }
+
+    #[test]
+    fn test_users() {
+        let (_file, config) = test_settings();
+        let add_user = |name: &str, role, password: Option<&str>| AdminCommand::UserAdd {
+            name: name.into(),
+            role,
+            password: password.map(|password| password.into()),
+        };
+        run(&config, &add_user("alice", models::UserRole::Admin, Some("password123"))).unwrap();
+        assert!(run(&config, &add_user("alice", models::UserRole::User, None)).is_err()); // taken
+        assert_eq!(
+            run(&config, &add_user("bob", models::UserRole::User, Some("short"))).unwrap_err().to_string(),
+            "Passwords must be at least 8 characters"
+        );
+        assert!(run(&config, &add_user(" ", models::UserRole::User, None)).is_err());
+        run(&config, &add_user("carol", models::UserRole::Viewer, None)).unwrap();
+
+        let database = database::establish_connection(&config).unwrap();
+        let alice = database::get_user_by_name(&config, &database, "alice").unwrap().unwrap();
+        assert_eq!(alice.role, models::UserRole::Admin);
+        assert!(auth::verify_password("password123", &alice.password_hash));
+        let carol = database::get_user_by_name(&config, &database, "carol").unwrap().unwrap();
+        assert_eq!(carol.password_hash, auth::NO_PASSWORD); // single sign-on only
+        assert!(database::get_user_by_name(&config, &database, "bob").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_release_all_and_check() {
+        let (_file, config) = test_settings();
+        run(&config, &AdminCommand::Check).unwrap();
+        let database = database::establish_connection(&config).unwrap();
+        let device_update = models::DeviceUpdate {
+            id: 1,
+            device_owner: Some("alice, bob".into()),
+            comments: None,
+            reservation_status: models::ReservationStatus::Reserved,
+        };
+        database::update_device(
+            &config,
+            &database,
+            &device_update,
+            models::ReservationStatus::Available,
+            &models::ChangeContext::default(),
+        )
+        .unwrap();
+
+        let release_all = |reason: &str| AdminCommand::ReleaseAll {
+            pool: "1".into(),
+            reason: reason.into(),
+        };
+        assert!(run(&config, &release_all(" ")).is_err()); // a reason is needed, as on the form
+        run(&config, &release_all("lab move")).unwrap();
+        let device = database::get_device_by_id(&config, &database, 1).unwrap().unwrap();
+        assert_eq!(device.reservation_status, models::ReservationStatus::Available);
+        run(&config, &AdminCommand::Check).unwrap();
+
+        diesel::sql_query("insert into device_owners (device_id, owner_name) values (2, 'alice')")
+            .execute(&database)
+            .unwrap();
+        assert!(run(&config, &AdminCommand::Check).is_err());
+        let problems = database::check_devices(&config, &database).unwrap();
+        assert_eq!(problems, vec!["unit2 is available, but still has owners".to_string()]);
+    }
+}
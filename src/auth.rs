@@ -0,0 +1,364 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use crate::database;
+use failure;
+use crate::models;
+use crate::models::UserRole;
+use crate::pool;
+use rocket::config::SecretKey;
+use rocket::outcome::Outcome;
+use rand::RngCore;
+use rocket::request::{self, FromRequest};
+use sha2::{Digest, Sha256};
+use crate::utils;
+
+/// Private (encrypted) cookie holding the id of the logged in user.
+pub const SESSION_COOKIE: &str = "user_id";
+
+/// Private cookie remembering whose devices `/my` shows when nobody is logged in.
+pub const OWNER_COOKIE: &str = "owner";
+
+/// Private cookie holding the state and nonce of a single sign-on in progress.
+pub const OIDC_LOGIN_COOKIE: &str = "oidc_login";
+
+/// Password hash of accounts created by single sign-on, which no password matches.
+pub const NO_PASSWORD: &str = "";
+
+/// Hashes a password with argon2 and a random salt, in PHC string format. The
+/// cost is kept in the hash, so it can be checked whatever it was made with.
+pub fn hash_password(config: &utils::types::Settings, password: &str) -> Result<String, failure::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let (memory_cost, iterations) = config.password_hash_cost;
+    let params = argon2::Params::new(memory_cost, iterations, argon2::Params::DEFAULT_P_COST, None)
+        .map_err(|e| failure::err_msg(format!("Invalid password hash cost: {}", e)))?;
+    Argon2::new(argon2::Algorithm::default(), argon2::Version::default(), params)
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| failure::err_msg(format!("Error hashing password: {}", e)))
+}
+
+/// Checks a password against a hash from `hash_password`.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    if password_hash == NO_PASSWORD {
+        return false;
+    }
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            error!("Stored password hash is invalid: {}", e);
+            false
+        }
+    }
+}
+
+/// Prefix of personal api tokens, so they're easy to spot in logs and configs.
+const API_TOKEN_PREFIX: &str = "dc_";
+
+/// Generates a new personal api token, returning the token and its hash.
+pub fn generate_api_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = format!("{}{}", API_TOKEN_PREFIX, hex::encode(bytes));
+    let token_hash = hash_api_token(&token);
+    (token, token_hash)
+}
+
+/// Api tokens are long and random, so an unsalted SHA-256 is enough to store
+/// them, and unlike a password hash it can be looked up directly.
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Generates a key for signing and encrypting cookies, as base64, the same
+/// format as rocket's own `secret_key` setting.
+pub fn generate_secret_key() -> String {
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode(bytes)
+}
+
+/// Decodes a secret key given as hex or base64, which must be at least 256
+/// bits. Whitespace is ignored, so wrapped `openssl rand -base64 64` output
+/// works as well.
+fn decode_secret_key(key: &str) -> Option<Vec<u8>> {
+    let key: String = key.chars().filter(|c| !c.is_whitespace()).collect();
+    let bytes = hex::decode(&key).or_else(|_| base64::decode(&key)).ok()?;
+    if bytes.len() >= 32 {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+/// The cookie secret key for a configured key, used as is when it's long
+/// enough for Rocket and stretched when it isn't.
+pub fn secret_key(key: &str) -> Option<SecretKey> {
+    let bytes = decode_secret_key(key)?;
+    if bytes.len() >= 64 {
+        Some(SecretKey::from(&bytes))
+    } else {
+        Some(SecretKey::derive_from(&bytes))
+    }
+}
+
+/// The logged in user, from the session cookie. Fails with `Unauthorized` when
+/// nobody is logged in, use `Option<CurrentUser>` where that's fine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub username: String,
+    pub role: UserRole,
+    /// The pools a pool admin manages
+    pub pools: Vec<i32>,
+}
+
+impl CurrentUser {
+    /// Whether the user may add, edit or remove the devices in a pool.
+    pub fn can_manage_pool(&self, pool_id: i32) -> bool {
+        self.role == UserRole::Admin
+            || (self.role == UserRole::PoolAdmin && self.pools.contains(&pool_id))
+    }
+
+    /// Whether the user may reserve in the name of `device_owner`, which has
+    /// to list them among its owners unless they're an admin.
+    pub fn can_reserve_for(&self, device_owner: &str) -> bool {
+        self.role == UserRole::Admin
+            || models::parse_owners(device_owner)
+                .iter()
+                .any(|owner| owner.eq_ignore_ascii_case(&self.username))
+    }
+}
+
+fn load_current_user(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    user: Option<models::User>,
+) -> Result<Option<CurrentUser>, failure::Error> {
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    Ok(Some(CurrentUser {
+        pools: database::get_managed_pools(config, database, user.id)?,
+        id: user.id,
+        username: user.username,
+        role: user.role,
+    }))
+}
+
+async fn lookup_current_user(request: &request::Request<'_>) -> Option<CurrentUser> {
+    let user_id: i32 = request
+        .cookies()
+        .get_private(SESSION_COOKIE)?
+        .value()
+        .parse()
+        .ok()?;
+    let database = match request.guard::<pool::DbConn>().await {
+        Outcome::Success(database) => database,
+        _ => return None,
+    };
+    database
+        .run(move |config, database| {
+            let user = database::get_user_by_id(config, database, user_id)?;
+            load_current_user(config, database, user)
+        })
+        .await
+        .ok()?
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CurrentUser {
+    type Error = ();
+
+    async fn from_request(request: &'r request::Request<'_>) -> request::Outcome<Self, ()> {
+        // cached, several guards on one request may ask for the user
+        let current_user = request
+            .local_cache_async(async { lookup_current_user(request).await })
+            .await;
+        match current_user {
+            Some(current_user) => Outcome::Success(current_user.clone()),
+            None => Outcome::Error((rocket::http::Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The user owning the personal api token in the `Authorization: Bearer`
+/// header. Fails with `Unauthorized` if there's no token or it isn't valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiUser(pub CurrentUser);
+
+impl std::ops::Deref for ApiUser {
+    type Target = CurrentUser;
+
+    fn deref(&self) -> &CurrentUser {
+        &self.0
+    }
+}
+
+async fn lookup_api_user(request: &request::Request<'_>) -> Option<CurrentUser> {
+    let authorization = request.headers().get_one("Authorization")?;
+    let token = authorization.strip_prefix("Bearer ")?.trim();
+    let token_hash = hash_api_token(token);
+    let database = match request.guard::<pool::DbConn>().await {
+        Outcome::Success(database) => database,
+        _ => return None,
+    };
+    database
+        .run(move |config, database| {
+            let user = database::get_user_by_api_token(config, database, &token_hash)?;
+            load_current_user(config, database, user)
+        })
+        .await
+        .ok()?
+}
+
+/// The api user looked up for this request, cached apart from `CurrentUser`.
+struct CachedApiUser(Option<CurrentUser>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiUser {
+    type Error = ();
+
+    async fn from_request(request: &'r request::Request<'_>) -> request::Outcome<Self, ()> {
+        let api_user = request
+            .local_cache_async(async { CachedApiUser(lookup_api_user(request).await) })
+            .await;
+        match api_user.0 {
+            Some(ref user) => Outcome::Success(ApiUser(user.clone())),
+            None => Outcome::Error((rocket::http::Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Who is making the change: the api token's user on `/api/` routes, the
+/// logged in user otherwise, along with the client's address.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for models::ChangeContext {
+    type Error = ();
+
+    async fn from_request(request: &'r request::Request<'_>) -> request::Outcome<Self, ()> {
+        let base_path = request
+            .rocket()
+            .state::<utils::types::Settings>()
+            .map(|config| config.base_path.as_str())
+            .unwrap_or("");
+        let api_path = format!("{}/api/", base_path);
+        let (source, user) = if request.uri().path().as_str().starts_with(&api_path) {
+            (models::ChangeSource::Api, request.guard::<ApiUser>().await.succeeded().map(|user| user.0))
+        } else {
+            (models::ChangeSource::Ui, request.guard::<CurrentUser>().await.succeeded())
+        };
+        Outcome::Success(models::ChangeContext {
+            actor: user.map(|user| user.username),
+            source,
+            source_ip: request.client_ip().map(|ip| ip.to_string()),
+            reason: None,
+        })
+    }
+}
+
+macro_rules! role_guard {
+    ($(#[$attr:meta])* $name:ident, $role:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name(pub CurrentUser);
+
+        impl std::ops::Deref for $name {
+            type Target = CurrentUser;
+
+            fn deref(&self) -> &CurrentUser {
+                &self.0
+            }
+        }
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $name {
+            type Error = ();
+
+            async fn from_request(request: &'r request::Request<'_>) -> request::Outcome<Self, ()> {
+                match request.guard::<CurrentUser>().await {
+                    Outcome::Success(user) if user.role >= $role => Outcome::Success($name(user)),
+                    Outcome::Success(_) => Outcome::Error((rocket::http::Status::Forbidden, ())),
+                    Outcome::Error(error) => Outcome::Error(error),
+                    Outcome::Forward(forward) => Outcome::Forward(forward),
+                }
+            }
+        }
+    };
+}
+
+role_guard!(
+    /// A logged in user allowed to claim and return devices, i.e. not a viewer.
+    Member,
+    UserRole::User
+);
+role_guard!(
+    /// A pool admin or admin. Pool admins still need `can_manage_pool` checking
+    /// against the pools they're touching.
+    PoolAdmin,
+    UserRole::PoolAdmin
+);
+role_guard!(
+    /// An admin, who may do anything.
+    Admin,
+    UserRole::Admin
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_password_hashing() {
+        let hash = hash_password(&utils::types::Settings::new(), "correct horse").unwrap();
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        assert!(!verify_password("", NO_PASSWORD));
+    }
+    #[test]
+    fn test_can_manage_pool() {
+        let mut user = CurrentUser {
+            id: 1,
+            username: "tester".into(),
+            role: UserRole::PoolAdmin,
+            pools: vec![2],
+        };
+        assert!(user.can_manage_pool(2));
+        assert!(!user.can_manage_pool(1));
+        user.role = UserRole::User;
+        assert!(!user.can_manage_pool(2));
+        user.role = UserRole::Admin;
+        assert!(user.can_manage_pool(1));
+    }
+    #[test]
+    fn test_api_tokens() {
+        let (token, token_hash) = generate_api_token();
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(hash_api_token(&token), token_hash);
+        assert_ne!(generate_api_token().0, token);
+    }
+    #[test]
+    fn test_secret_keys() {
+        let key = generate_secret_key();
+        assert_eq!(decode_secret_key(&key).map(|bytes| bytes.len()), Some(64));
+        assert_ne!(generate_secret_key(), key);
+        assert_eq!(decode_secret_key(&"ab".repeat(32)).map(|bytes| bytes.len()), Some(32));
+        assert_eq!(decode_secret_key(&base64::encode([1u8; 16])), None); // too short
+        assert_eq!(decode_secret_key("not a key"), None);
+        // 64 characters of base64 that aren't hex
+        let key = base64::encode([0xffu8; 48]);
+        assert_eq!(key.len(), 64);
+        assert_eq!(decode_secret_key(&key), Some(vec![0xff; 48]));
+        // as `openssl rand -base64 64` wraps it
+        let key = base64::encode([7u8; 64]);
+        let wrapped = format!("{}\n{}\n", &key[..64], &key[64..]);
+        assert_eq!(decode_secret_key(&wrapped), Some(vec![7; 64]));
+        assert!(secret_key(&wrapped).is_some());
+        assert!(secret_key("not a key").is_none());
+    }
+}
@@ -0,0 +1,284 @@
+//! The client subcommands, talking to a running server's json api
+
+use crate::models;
+use crate::utils::types::{ClientCommand, ClientSettings};
+use failure;
+use failure::ResultExt;
+use reqwest;
+use serde;
+use serde_json;
+use std;
+
+///Runs a client subcommand against the server, printing what it got back
+pub fn run(settings: &ClientSettings, command: &ClientCommand) -> Result<(), failure::Error> {
+    let client = ApiClient::new(settings);
+    match command {
+        ClientCommand::List { pool_id } => {
+            let devices: Vec<models::Device> = client.get("devices")?;
+            let devices: Vec<_> = devices
+                .into_iter()
+                .filter(|device| pool_id.is_none_or(|pool_id| device.pool_id == pool_id))
+                .collect();
+            print(settings, &devices, |devices| device_table(devices))
+        }
+        ClientCommand::Claim {
+            pool_id,
+            count,
+            bundle,
+            owner,
+            comments,
+        } => {
+            let request = match bundle {
+                Some(bundle) => serde_json::json!({ "bundle": bundle }),
+                None => serde_json::json!({ "devices": [{ "pool_id": pool_id, "count": count }] }),
+            };
+            match client.claim(request, owner, comments)? {
+                Some(reservation) => print(settings, &reservation, |reservation| {
+                    reservation_table(std::slice::from_ref(reservation))
+                }),
+                None => Err(failure::err_msg("Not enough devices are available")),
+            }
+        }
+        ClientCommand::Return { reservation_id } => {
+            client.delete(&format!("reservations/{}", reservation_id))?;
+            if !settings.json {
+                println!("Returned reservation {}", reservation_id);
+            }
+            Ok(())
+        }
+        ClientCommand::Mine => {
+            let reservations: Vec<models::Reservation> = client.get("reservations")?;
+            print(settings, &reservations, |reservations| reservation_table(reservations))
+        }
+        ClientCommand::Pools => {
+            let pools: Vec<models::Pool> = client.get("pools")?;
+            print(settings, &pools, |pools| pool_table(pools))
+        }
+        ClientCommand::Wait {
+            pool_id,
+            owner,
+            comments,
+            timeout_secs,
+            interval_secs,
+        } => {
+            let started = std::time::Instant::now();
+            let request = serde_json::json!({ "device": { "pool_id": pool_id } });
+            loop {
+                if let Some(reservation) = client.claim(request.clone(), owner, comments)? {
+                    return print(settings, &reservation, |reservation| {
+                        reservation_table(std::slice::from_ref(reservation))
+                    });
+                }
+                if started.elapsed().as_secs() >= *timeout_secs {
+                    return Err(failure::err_msg(format!(
+                        "No device in pool {} became available within {} seconds",
+                        pool_id, timeout_secs
+                    )));
+                }
+                debug!("No device available in pool {}, waiting", pool_id);
+                std::thread::sleep(std::time::Duration::from_secs(*interval_secs));
+            }
+        }
+    }
+}
+
+struct ApiClient<'a> {
+    settings: &'a ClientSettings,
+    http: reqwest::blocking::Client,
+}
+
+impl<'a> ApiClient<'a> {
+    fn new(settings: &'a ClientSettings) -> Self {
+        ApiClient {
+            settings,
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::blocking::RequestBuilder {
+        let request = self
+            .http
+            .request(method, &format!("{}/api/{}", self.settings.url, path));
+        match &self.settings.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn send(&self, request: reqwest::blocking::RequestBuilder) -> Result<reqwest::blocking::Response, failure::Error> {
+        let response = request
+            .send()
+            .with_context(|_| format!("Failed to reach {}", self.settings.url))?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        Err(failure::err_msg(api_error_message(
+            response.status(),
+            response.json::<serde_json::Value>().ok(),
+        )))
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, failure::Error> {
+        Ok(self.send(self.request(reqwest::Method::GET, path))?.json()?)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), failure::Error> {
+        self.send(self.request(reqwest::Method::DELETE, path))?;
+        Ok(())
+    }
+
+    ///Asks for a reservation, with `None` if there weren't enough devices free
+    fn claim(
+        &self,
+        mut request: serde_json::Value,
+        owner: &Option<String>,
+        comments: &Option<String>,
+    ) -> Result<Option<models::Reservation>, failure::Error> {
+        request["device_owner"] = serde_json::json!(owner);
+        request["comments"] = serde_json::json!(comments);
+        let response = self
+            .request(reqwest::Method::POST, "reservations")
+            .json(&request)
+            .send()
+            .with_context(|_| format!("Failed to reach {}", self.settings.url))?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.json()?)),
+            status => Err(failure::err_msg(api_error_message(
+                status,
+                response.json::<serde_json::Value>().ok(),
+            ))),
+        }
+    }
+}
+
+///Says why the api refused, with its message if it gave one
+fn api_error_message(status: reqwest::StatusCode, body: Option<serde_json::Value>) -> String {
+    match body.as_ref().and_then(|body| body["error"]["message"].as_str()) {
+        Some(message) => format!("{}: {}", status, message),
+        None if status == reqwest::StatusCode::UNAUTHORIZED => {
+            format!("{}: check '--token' or DEVICE_CHECKOUT_TOKEN", status)
+        }
+        None => format!("The server said {}", status),
+    }
+}
+
+fn print<T: serde::Serialize + ?Sized>(
+    settings: &ClientSettings,
+    value: &T,
+    table: impl Fn(&T) -> String,
+) -> Result<(), failure::Error> {
+    if settings.json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        print!("{}", table(value));
+    }
+    Ok(())
+}
+
+fn device_table(devices: &[models::Device]) -> String {
+    let rows: Vec<Vec<String>> = devices
+        .iter()
+        .map(|device| {
+            vec![
+                device.id.to_string(),
+                device.device_name.clone(),
+                device.pool_id.to_string(),
+                format!("{:?}", device.reservation_status),
+                device.device_owner.clone().unwrap_or_default(),
+                device.comments.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    table(&["ID", "NAME", "POOL", "STATUS", "OWNER", "COMMENTS"], &rows)
+}
+
+fn reservation_table(reservations: &[models::Reservation]) -> String {
+    let rows: Vec<Vec<String>> = reservations
+        .iter()
+        .flat_map(|reservation| {
+            reservation.devices.iter().map(move |device| {
+                vec![
+                    reservation.id.to_string(),
+                    device.device_name.clone(),
+                    device.device_url.clone().unwrap_or_default(),
+                    device.pool_id.to_string(),
+                    device.device_owner.clone().unwrap_or_default(),
+                    device.comments.clone().unwrap_or_default(),
+                ]
+            })
+        })
+        .collect();
+    table(&["RESERVATION", "NAME", "URL", "POOL", "OWNER", "COMMENTS"], &rows)
+}
+
+fn pool_table(pools: &[models::Pool]) -> String {
+    let rows: Vec<Vec<String>> = pools
+        .iter()
+        .map(|pool| {
+            vec![
+                pool.id.to_string(),
+                pool.pool_name.clone(),
+                pool.description.clone().unwrap_or_default(),
+                pool.max_reservations_per_owner
+                    .map(|max| max.to_string())
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+    table(&["ID", "NAME", "DESCRIPTION", "MAX PER OWNER"], &rows)
+}
+
+///Lines the rows up under their headers
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = std::cmp::max(*width, cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut output = line(headers.to_vec());
+    for row in rows {
+        output.push_str(&line(row.iter().map(|cell| cell.as_str()).collect()));
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let rows = vec![
+            vec!["1".to_string(), "unit1".to_string(), "".to_string()],
+            vec!["10".to_string(), "u2".to_string(), "bob".to_string()],
+        ];
+        assert_eq!(
+            table(&["ID", "NAME", "OWNER"], &rows),
+            "ID  NAME   OWNER\n1   unit1\n10  u2     bob\n"
+        );
+        assert_eq!(table(&["ID"], &[]), "ID\n");
+    }
+
+    #[test]
+    fn test_api_error_message() {
+        let body = serde_json::json!({ "error": { "code": 422, "message": "bob already has 2 devices reserved" } });
+        assert_eq!(
+            api_error_message(reqwest::StatusCode::UNPROCESSABLE_ENTITY, Some(body)),
+            "422 Unprocessable Entity: bob already has 2 devices reserved"
+        );
+        assert_eq!(
+            api_error_message(reqwest::StatusCode::FORBIDDEN, None),
+            "The server said 403 Forbidden"
+        );
+    }
+}
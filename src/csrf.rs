@@ -0,0 +1,157 @@
+use rand::RngCore;
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::form::{Form, FromForm, ValueField};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use crate::templates::Template;
+
+/// Private cookie holding the session's CSRF token.
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// Form field the templates put the CSRF token in.
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// The session's CSRF token, for rendering into forms. Starts a new one if the
+/// session doesn't have one yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        if let Some(cookie) = request.cookies().get_private(CSRF_COOKIE) {
+            return Outcome::Success(CsrfToken(cookie.value().to_string()));
+        }
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        request
+            .cookies()
+            .add_private(rocket::http::Cookie::new(CSRF_COOKIE, token.clone()));
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+/// Renders a template with the CSRF token added to its context as `csrf_token`.
+pub fn render<C: serde::Serialize>(name: &'static str, csrf_token: &CsrfToken, context: &C) -> Template {
+    let mut context = match serde_json::to_value(context) {
+        Ok(serde_json::Value::Object(context)) => context,
+        _ => serde_json::Map::new(),
+    };
+    context.insert(CSRF_FIELD.to_string(), csrf_token.0.clone().into());
+    Template::render(name, context)
+}
+
+/// Set in the request's local cache when a form's CSRF token didn't match, so
+/// the forbidden catcher can say why.
+struct CsrfRejected(bool);
+
+/// Whether the request failed because of its CSRF token.
+pub fn was_rejected(request: &Request<'_>) -> bool {
+    request.local_cache(|| CsrfRejected(false)).0
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reads a urlencoded form body, checking its CSRF token against the session's.
+async fn read_form<'r>(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Vec<(String, String)>, String> {
+    let limit = request.limits().get("form").unwrap_or(Limits::FORM);
+    let body = match data.open(limit).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Outcome::Error((rocket::http::Status::PayloadTooLarge, "Form too large".into())),
+        Err(e) => return Outcome::Error((rocket::http::Status::BadRequest, e.to_string())),
+    };
+    let fields: Vec<(String, String)> = url::form_urlencoded::parse(body.as_bytes())
+        .into_owned()
+        .collect();
+
+    let expected = request.cookies().get_private(CSRF_COOKIE);
+    let submitted = fields.iter().find(|(name, _)| name == CSRF_FIELD);
+    match (expected, submitted) {
+        (Some(expected), Some((_, submitted))) if constant_time_eq(expected.value().as_bytes(), submitted.as_bytes()) => {
+            Outcome::Success(fields)
+        }
+        _ => {
+            warn!("Rejecting {} {} with a missing or wrong CSRF token", request.method(), request.uri());
+            request.local_cache(|| CsrfRejected(true));
+            Outcome::Error((rocket::http::Status::Forbidden, "Missing or wrong CSRF token".into()))
+        }
+    }
+}
+
+/// A form whose CSRF token matched the session's, for every HTML form post.
+/// Otherwise like `Form`, it fails with `Forbidden` if the token is missing or wrong.
+#[derive(Debug)]
+pub struct CsrfForm<T>(T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T> FromData<'r> for CsrfForm<T>
+where
+    T: for<'a> FromForm<'a> + Send + 'static,
+{
+    type Error = String;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self, String> {
+        let fields = match read_form(request, data).await {
+            Outcome::Success(fields) => fields,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+        let fields = fields
+            .iter()
+            .filter(|(name, _)| name != CSRF_FIELD)
+            .map(|(name, value)| ValueField::from((name.as_str(), value.as_str())));
+        match Form::<T>::parse_iter(fields) {
+            Ok(form) => Outcome::Success(CsrfForm(form)),
+            Err(e) => Outcome::Error((rocket::http::Status::UnprocessableEntity, e.to_string())),
+        }
+    }
+}
+
+/// A form post with nothing in it but its CSRF token.
+#[derive(Debug)]
+pub struct CsrfChecked;
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for CsrfChecked {
+    type Error = String;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self, String> {
+        match read_form(request, data).await {
+            Outcome::Success(_) => Outcome::Success(CsrfChecked),
+            Outcome::Error(error) => Outcome::Error(error),
+            Outcome::Forward(forward) => Outcome::Forward(forward),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abcd", b"abcd"));
+        assert!(!constant_time_eq(b"abcd", b"abce"));
+        assert!(!constant_time_eq(b"abcd", b"abc"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+}
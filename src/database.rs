@@ -1,6 +1,7 @@
 use diesel;
 use diesel::sql_types::{Nullable, Text};
 use failure;
+use crate::auth;
 use crate::models;
 use std;
 use crate::utils;
@@ -10,12 +11,29 @@ use self::diesel::prelude::*;
 use failure::ResultExt;
 use crate::schema::devices;
 use crate::schema::devices::dsl::*;
+use crate::schema::device_owners;
 use crate::schema::pools;
 use crate::schema::custom_owners;
+use crate::schema::users;
+use crate::schema::pool_admins;
+use crate::schema::api_tokens;
+use crate::schema::audit_log;
+use crate::schema::secrets;
 
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+compile_error!("features \"sqlite\" and \"postgres\" are mutually exclusive");
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("one of the features \"sqlite\" or \"postgres\" must be enabled");
+
+#[cfg(feature = "sqlite")]
 pub type DbConn = diesel::sqlite::SqliteConnection;
+#[cfg(feature = "postgres")]
+pub type DbConn = diesel::pg::PgConnection;
 
+#[cfg(feature = "sqlite")]
 embed_migrations!();
+#[cfg(feature = "postgres")]
+embed_migrations!("migrations_postgres");
 
 // define SQL function lower to return lowercase of column
 sql_function!(fn lower(x: Nullable<Text>) -> Nullable<Text>);
@@ -26,22 +44,179 @@ pub fn run_migrations(config: &utils::types::Settings) -> Result<(), failure::Er
     Ok(())
 }
 
+///Whether the database url names a PostgreSQL server rather than a sqlite file
+pub fn is_postgres_url(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+///The backend is chosen at build time, so refuse database urls for the other one
+pub fn check_database_url(database_url: &str) -> Result<(), failure::Error> {
+    if is_postgres_url(database_url) && !cfg!(feature = "postgres") {
+        Err(failure::err_msg(
+            "PostgreSQL database urls need device-checkout built with --features postgres",
+        ))
+    } else if !is_postgres_url(database_url) && !cfg!(feature = "sqlite") {
+        Err(failure::err_msg(format!(
+            "{} is not a postgres:// url, and device-checkout was built without sqlite support",
+            database_url
+        )))
+    } else {
+        Ok(())
+    }
+}
+
 pub fn establish_connection(config: &utils::types::Settings) -> Result<DbConn, failure::Error> {
     trace!("establish_connection()");
+    check_database_url(&config.database_url)?;
     Ok(DbConn::establish(&config.database_url)
         .with_context(|_| format!("Error connection to {}", &config.database_url))?)
 }
 
+///Runs a change in a transaction, on sqlite one that takes the write lock up
+///front, so what it reads for the audit log can't change before it writes
+pub(crate) fn write_transaction<T, F>(database: &DbConn, f: F) -> Result<T, failure::Error>
+where
+    F: FnOnce() -> Result<T, failure::Error>,
+{
+    #[cfg(feature = "sqlite")]
+    {
+        use diesel::connection::TransactionManager;
+        if TransactionManager::<DbConn>::get_transaction_depth(database.transaction_manager()) == 0 {
+            return database.immediate_transaction(f);
+        }
+    }
+    database.transaction(f)
+}
+
+///A write transaction for claiming devices, which keeps anyone else from
+///claiming until it's done, so the quotas checked in it still hold when it
+///commits. Sqlite's write lock already does, postgres needs the table locked.
+pub(crate) fn claim_transaction<T, F>(database: &DbConn, f: F) -> Result<T, failure::Error>
+where
+    F: FnOnce() -> Result<T, failure::Error>,
+{
+    write_transaction(database, || {
+        #[cfg(feature = "postgres")]
+        diesel::sql_query("LOCK TABLE devices IN SHARE ROW EXCLUSIVE MODE").execute(database)?;
+        f()
+    })
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+///Appends an entry to the audit log, call within the change's transaction
+fn audit(
+    database: &DbConn,
+    change: &models::ChangeContext,
+    action: models::AuditAction,
+    entity_type: models::AuditEntity,
+    entity_id: Option<i32>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+) -> Result<(), failure::Error> {
+    let entry = models::AuditEntryInsert {
+        actor: change.actor.clone(),
+        action,
+        entity_type,
+        entity_id,
+        before: before.map(|before| before.to_string()),
+        after: after.map(|after| after.to_string()),
+        source: change.source,
+        source_ip: change.source_ip.clone(),
+        reason: change.reason.clone(),
+    };
+    diesel::insert_into(audit_log::table)
+        .values(&entry)
+        .execute(database)
+        .with_context(|_| "Error writing audit log".to_string())?;
+    Ok(())
+}
+
+///How many audit log entries to return when no limit is asked for, and the most
+const AUDIT_LOG_LIMIT: i64 = 100;
+const AUDIT_LOG_MAX_LIMIT: i64 = 1000;
+
+///Get audit log entries, newest first
+pub fn get_audit_log(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    filter: &models::AuditFilter,
+) -> Result<Vec<models::AuditEntry>, failure::Error> {
+    let mut query = audit_log::table.into_boxed();
+    if let Some(entity_type) = filter.entity_type {
+        query = query.filter(audit_log::entity_type.eq(entity_type));
+    }
+    if let Some(entity_id) = filter.entity_id {
+        query = query.filter(audit_log::entity_id.eq(entity_id));
+    }
+    if let Some(ref actor) = filter.actor {
+        query = query.filter(audit_log::actor.eq(actor));
+    }
+    let limit = filter.limit.unwrap_or(AUDIT_LOG_LIMIT).clamp(1, AUDIT_LOG_MAX_LIMIT);
+    Ok(query
+        .order(audit_log::id.desc())
+        .limit(limit)
+        .load::<models::AuditEntry>(database)
+        .with_context(|_| "Error loading audit log".to_string())?)
+}
+
 ///Get all the devices
 pub fn get_devices(
     _config: &utils::types::Settings,
     database: &DbConn,
 ) -> Result<Vec<models::Device>, failure::Error> {
     Ok(devices
+        .order(id)
         .load::<models::Device>(database)
         .with_context(|_| "Error loading devices".to_string())?)
 }
 
+///Looks for devices whose reservation doesn't hang together, describing each
+///problem found
+pub fn check_devices(
+    config: &utils::types::Settings,
+    database: &DbConn,
+) -> Result<Vec<String>, failure::Error> {
+    let pool_ids: Vec<i32> = get_pools(config, database)?.iter().map(|pool| pool.id).collect();
+    let mut problems = vec![];
+    for device in get_devices(config, database)? {
+        if !pool_ids.contains(&device.pool_id) {
+            problems.push(format!("{} is in pool {}, which doesn't exist", device.device_name, device.pool_id));
+        }
+        let mut listed: Vec<String> = device_owners::table
+            .filter(device_owners::device_id.eq(device.id))
+            .select(device_owners::owner_name)
+            .load(database)?;
+        listed.sort();
+        let mut owners = device.owners();
+        owners.sort();
+        match device.reservation_status {
+            models::ReservationStatus::Reserved => {
+                if owners != listed {
+                    problems.push(format!(
+                        "{} is reserved by {:?}, but listed under {:?}",
+                        device.device_name, owners, listed
+                    ));
+                }
+            }
+            models::ReservationStatus::Available => {
+                if !owners.is_empty() || !listed.is_empty() {
+                    problems.push(format!("{} is available, but still has owners", device.device_name));
+                }
+                if let Some(reservation) = device.reservation_id {
+                    problems.push(format!(
+                        "{} is available, but still part of reservation {}",
+                        device.device_name, reservation
+                    ));
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
 ///Get all the devices in a pool
 pub fn get_devices_in_pool(
     _config: &utils::types::Settings,
@@ -50,18 +225,23 @@ pub fn get_devices_in_pool(
 ) -> Result<Vec<models::Device>, failure::Error> {
     Ok(devices
         .filter(pool_id.eq(requested_pool_id))
+        .order(id)
         .load::<models::Device>(database)
         .with_context(|_| "Error loading devices".to_string())?)
 }
 
-///Get devices reserved by owner name
+///Get devices reserved by owner name, alone or shared with others
 pub fn get_devices_by_owner(
     _config: &utils::types::Settings,
     database: &DbConn,
     requested_owner: &str,
 ) -> Result<Vec<models::Device>, failure::Error> {
+    let owned = device_owners::table
+        .filter(lower(device_owners::owner_name.nullable()).eq(requested_owner.to_lowercase()))
+        .select(device_owners::device_id);
     Ok(devices
-        .filter(lower(device_owner).eq(requested_owner.to_lowercase()))
+        .filter(id.eq_any(owned))
+        .order(id)
         .load::<models::Device>(database)
         .with_context(|_| "Error loading devices".to_string())?)
 }
@@ -109,62 +289,430 @@ pub fn get_available_device_from_pool(
         .load::<models::Device>(database)
         .with_context(|_| "Error loading devices".to_string())?
         .choose(&mut rand::thread_rng())
-        .map(|device| device.clone()))
+        .cloned())
+}
+
+///Reserves a randomly chosen available device from a pool. Another connection
+///can claim the device we picked between the select and the update, in which
+///case the update matches no rows and we pick again until the pool runs out.
+pub fn reserve_device_from_pool(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    requested_pool_id: i32,
+    requested_owner: Option<String>,
+    requested_comments: Option<String>,
+    change: &models::ChangeContext,
+) -> Result<Option<models::Device>, failure::Error> {
+    loop {
+        let available_device =
+            match get_available_device_from_pool(config, database, &requested_pool_id)? {
+                Some(device) => device,
+                None => return Ok(None),
+            };
+        let device_update = models::DeviceUpdate {
+            id: available_device.id,
+            device_owner: requested_owner.clone(),
+            comments: requested_comments.clone(),
+            reservation_status: models::ReservationStatus::Reserved,
+        };
+        if update_device(config, database, &device_update, models::ReservationStatus::Available, change)? > 0 {
+            return get_device_by_id(config, database, available_device.id);
+        }
+        debug!("Device {} was claimed by someone else, retrying", &available_device.device_name);
+    }
+}
+
+#[derive(Debug)]
+struct ClaimConflict;
+
+impl std::fmt::Display for ClaimConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "A device was claimed by someone else")
+    }
+}
+
+impl std::error::Error for ClaimConflict {}
+
+///Reserves `count` randomly chosen available devices from each pool as one
+///reservation, or none at all if any pool is short. As with
+///`reserve_device_from_pool` we pick again if someone else claims one of ours.
+///`check`, e.g. of the owners' quotas, runs first in the same transaction, and
+///the `ValidationError` it fails with is the error.
+pub fn reserve_devices_from_pools<F>(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    requested_pools: &[(i32, usize)],
+    requested_owner: Option<String>,
+    requested_comments: Option<String>,
+    change: &models::ChangeContext,
+    check: F,
+) -> Result<Option<Vec<models::Device>>, failure::Error>
+where
+    F: Fn() -> Result<(), validator::ValidationError>,
+{
+    loop {
+        let reserved = claim_transaction(database, || {
+            check()?;
+            let mut chosen: Vec<models::Device> = Vec::new();
+            for (requested_pool_id, count) in requested_pools {
+                let available: Vec<models::Device> = get_devices_in_pool(config, database, *requested_pool_id)?
+                    .into_iter()
+                    .filter(|device| device.reservation_status == models::ReservationStatus::Available)
+                    .filter(|device| chosen.iter().all(|other| other.id != device.id))
+                    .collect();
+                if available.len() < *count {
+                    return Ok(None);
+                }
+                chosen.extend(available.choose_multiple(&mut rand::thread_rng(), *count).cloned());
+            }
+            for device in &chosen {
+                let device_update = models::DeviceUpdate {
+                    id: device.id,
+                    device_owner: requested_owner.clone(),
+                    comments: requested_comments.clone(),
+                    reservation_status: models::ReservationStatus::Reserved,
+                };
+                if update_device(config, database, &device_update, models::ReservationStatus::Available, change)? == 0 {
+                    debug!("Device {} was claimed by someone else, retrying", &device.device_name);
+                    return Err(ClaimConflict.into());
+                }
+            }
+            let ids: Vec<i32> = chosen.iter().map(|device| device.id).collect();
+            diesel::update(devices.filter(id.eq_any(&ids)))
+                .set(reservation_id.eq(ids.first().cloned()))
+                .execute(database)?;
+            Ok(Some(
+                devices
+                    .filter(id.eq_any(&ids))
+                    .order(id)
+                    .load::<models::Device>(database)?,
+            ))
+        });
+        match reserved {
+            Err(ref error) if error.downcast_ref::<ClaimConflict>().is_some() => continue,
+            reserved => return reserved,
+        }
+    }
+}
+
+///Ends a reservation, returning or releasing each device it still holds as
+///`how` says, all together. Devices reserved before reservations were
+///recorded are found by their own id. Gives back the devices and how each
+///went back, or None, changing nothing, if `how` refuses any of them.
+pub fn release_reservation<F>(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    requested_reservation_id: i32,
+    change: &models::ChangeContext,
+    how: F,
+) -> Result<Option<Vec<(models::Device, models::DeviceReturn)>>, failure::Error>
+where
+    F: Fn(&models::Device) -> Option<models::DeviceReturn>,
+{
+    write_transaction(database, || {
+        let reserved = devices
+            .filter(reservation_status.eq(models::ReservationStatus::Reserved))
+            .filter(
+                reservation_id
+                    .eq(requested_reservation_id)
+                    .or(id.eq(requested_reservation_id).and(reservation_id.is_null())),
+            )
+            .load::<models::Device>(database)?;
+        let mut chosen = Vec::new();
+        for device in reserved {
+            match how(&device) {
+                Some(device_return) => chosen.push((device, device_return)),
+                None => return Ok(None),
+            }
+        }
+        give_back_devices(config, database, chosen, change).map(Some)
+    })
+}
+
+///Returns those of the requested devices that `owner` still holds, together,
+///leaving any returned or transferred since they were chosen. `how` says
+///whether each is returned or released, or None when it may be neither, in
+///which case nothing is returned. Gives back what was returned, and how.
+pub fn return_devices<F>(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    owner: &str,
+    requested_ids: &[i32],
+    change: &models::ChangeContext,
+    how: F,
+) -> Result<Option<Vec<(models::Device, models::DeviceReturn)>>, failure::Error>
+where
+    F: Fn(&models::Device) -> Option<models::DeviceReturn>,
+{
+    write_transaction(database, || {
+        let mut chosen = Vec::new();
+        for device in get_devices_by_owner(config, database, owner)? {
+            if !requested_ids.contains(&device.id) || device.reservation_status != models::ReservationStatus::Reserved {
+                continue;
+            }
+            match how(&device) {
+                Some(device_return) => chosen.push((device, device_return)),
+                None => return Ok(None),
+            }
+        }
+        give_back_devices(config, database, chosen, change).map(Some)
+    })
+}
+
+///Returns or releases each chosen device as it says, skipping any that have
+///changed hands since
+fn give_back_devices(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    chosen: Vec<(models::Device, models::DeviceReturn)>,
+    change: &models::ChangeContext,
+) -> Result<Vec<(models::Device, models::DeviceReturn)>, failure::Error> {
+    let mut returned = Vec::new();
+    for (device, device_return) in chosen {
+        let updated = match &device_return {
+            models::DeviceReturn::Owner => {
+                let device_update = models::DeviceUpdate {
+                    id: device.id,
+                    device_owner: None,
+                    comments: None,
+                    reservation_status: models::ReservationStatus::Available,
+                };
+                update_device(config, database, &device_update, models::ReservationStatus::Reserved, change)?
+            }
+            models::DeviceReturn::Release(release) => {
+                let change = models::ChangeContext {
+                    reason: Some(release.reason.clone()),
+                    ..change.clone()
+                };
+                force_release_device(config, database, release, &change)?
+            }
+        };
+        if updated > 0 {
+            returned.push((device, device_return));
+        }
+    }
+    Ok(returned)
 }
 
 ///Updates a device, designed for the common case on the main http form
 pub fn update_device(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
+    database: &DbConn,
+    device_update: &models::DeviceUpdate,
+    expected_status: models::ReservationStatus,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    let action = match (expected_status, device_update.reservation_status) {
+        (models::ReservationStatus::Available, models::ReservationStatus::Reserved) => models::AuditAction::Claim,
+        (models::ReservationStatus::Reserved, models::ReservationStatus::Available) => models::AuditAction::Return,
+        _ => models::AuditAction::Edit,
+    };
+    update_device_from(config, database, device_update, expected_status, None, action, change)
+}
+
+///Hands a reserved device straight from its owner to a new one, without it
+///becoming available in between
+pub fn transfer_device(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    device_transfer: &models::DeviceTransfer,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    let device_update = models::DeviceUpdate {
+        id: device_transfer.id,
+        device_owner: Some(device_transfer.new_owner.trim().to_string()),
+        comments: device_transfer.comments.clone(),
+        reservation_status: models::ReservationStatus::Reserved,
+    };
+    update_device_from(
+        config,
+        database,
+        &device_update,
+        models::ReservationStatus::Reserved,
+        Some(&device_transfer.device_owner),
+        models::AuditAction::Transfer,
+        change,
+    )
+}
+
+///Returns a device on its owner's behalf
+pub fn force_release_device(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    device_release: &models::DeviceForceRelease,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    let device_update = models::DeviceUpdate {
+        id: device_release.id,
+        device_owner: None,
+        comments: None,
+        reservation_status: models::ReservationStatus::Available,
+    };
+    update_device_from(
+        config,
+        database,
+        &device_update,
+        models::ReservationStatus::Reserved,
+        Some(&device_release.device_owner),
+        models::AuditAction::ForceRelease,
+        change,
+    )
+}
+
+fn set_device_owners(database: &DbConn, device_id: i32, owners: &[String]) -> Result<(), failure::Error> {
+    diesel::delete(device_owners::table.filter(device_owners::device_id.eq(device_id))).execute(database)?;
+    let rows: Vec<_> = owners
+        .iter()
+        .map(|owner| (device_owners::device_id.eq(device_id), device_owners::owner_name.eq(owner)))
+        .collect();
+    if !rows.is_empty() {
+        diesel::insert_into(device_owners::table).values(&rows).execute(database)?;
+    }
+    Ok(())
+}
+
+///Updates a device if it's still in the status, and with the owner, the change
+///was asked for against, auditing it as `action`
+fn update_device_from(
+    config: &utils::types::Settings,
     database: &DbConn,
     device_update: &models::DeviceUpdate,
     expected_status: models::ReservationStatus,
+    expected_owner: Option<&str>,
+    action: models::AuditAction,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
     let selector = devices.filter(
         id.eq(&device_update.id)
             .and(reservation_status.eq(expected_status)),
     );
-    Ok(diesel::update(selector)
-        .set((
-            device_owner.eq(&device_update.device_owner),
-            comments.eq(&device_update.comments),
-            reservation_status.eq(&device_update.reservation_status),
-        ))
-        .execute(database)?)
+    // the shared owners are kept as a list on the device and a row each
+    let owners = device_update.device_owner.as_deref().map(models::parse_owners).unwrap_or_default();
+    let values = (
+        device_owner.eq(models::format_owners(&owners)),
+        comments.eq(&device_update.comments),
+        reservation_status.eq(&device_update.reservation_status),
+        updated_by.eq(&change.actor),
+    );
+    write_transaction(database, || {
+        let before = get_device_by_id(config, database, device_update.id)?;
+        let updated = match expected_owner {
+            Some(expected_owner) => diesel::update(selector.filter(device_owner.eq(expected_owner)))
+                .set(values)
+                .execute(database)?,
+            None => diesel::update(selector).set(values).execute(database)?,
+        };
+        if updated > 0 {
+            set_device_owners(database, device_update.id, &owners)?;
+            // a fresh claim starts a reservation of its own, see `reserve_devices_from_pools`
+            let reservation = match (expected_status, device_update.reservation_status) {
+                (models::ReservationStatus::Available, models::ReservationStatus::Reserved) => Some(Some(device_update.id)),
+                (_, models::ReservationStatus::Available) => Some(None),
+                _ => None,
+            };
+            if let Some(reservation) = reservation {
+                diesel::update(devices.filter(id.eq(&device_update.id)))
+                    .set(reservation_id.eq(reservation))
+                    .execute(database)?;
+            }
+            let after = get_device_by_id(config, database, device_update.id)?;
+            audit(
+                database,
+                change,
+                action,
+                models::AuditEntity::Device,
+                Some(device_update.id),
+                before.as_ref().and_then(to_json),
+                after.as_ref().and_then(to_json),
+            )?;
+        }
+        Ok(updated)
+    })
 }
 
 ///Edits the details specific to the device, i.e the name and url
 pub fn edit_device(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     device_edit: &models::DeviceEdit,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::update(devices.filter(id.eq(&device_edit.id)))
-        .set((
-            device_name.eq(&device_edit.device_name),
-            device_url.eq(&device_edit.device_url),
-            pool_id.eq(&device_edit.pool_id),
-        ))
-        .execute(database)?)
+    write_transaction(database, || {
+        let before = get_device_by_id(config, database, device_edit.id)?;
+        let updated = diesel::update(devices.filter(id.eq(&device_edit.id)))
+            .set((
+                device_name.eq(&device_edit.device_name),
+                device_url.eq(&device_edit.device_url),
+                pool_id.eq(&device_edit.pool_id),
+                updated_by.eq(&change.actor),
+            ))
+            .execute(database)?;
+        if updated > 0 {
+            let after = get_device_by_id(config, database, device_edit.id)?;
+            audit(
+                database,
+                change,
+                models::AuditAction::Edit,
+                models::AuditEntity::Device,
+                Some(device_edit.id),
+                before.as_ref().and_then(to_json),
+                after.as_ref().and_then(to_json),
+            )?;
+        }
+        Ok(updated)
+    })
 }
 
-///Edits the details specific to the device, i.e the name and url
+///Removes a device
 pub fn delete_device(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     device_delete: &models::DeviceDelete,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::delete(devices.filter(id.eq(&device_delete.id))).execute(database)?)
+    info!("device {} deleted by {:?}", &device_delete.id, &change.actor);
+    write_transaction(database, || {
+        let before = get_device_by_id(config, database, device_delete.id)?;
+        set_device_owners(database, device_delete.id, &[])?;
+        let deleted = diesel::delete(devices.filter(id.eq(&device_delete.id))).execute(database)?;
+        if deleted > 0 {
+            audit(
+                database,
+                change,
+                models::AuditAction::Delete,
+                models::AuditEntity::Device,
+                Some(device_delete.id),
+                before.as_ref().and_then(to_json),
+                None,
+            )?;
+        }
+        Ok(deleted)
+    })
 }
 
 ///Inserts a new device
 pub fn insert_device(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     device_insert: &models::DeviceInsert,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::insert_into(devices::table)
-        .values(device_insert)
-        .execute(database)?)
+    write_transaction(database, || {
+        let inserted = diesel::insert_into(devices::table)
+            .values((device_insert, updated_by.eq(&change.actor)))
+            .execute(database)?;
+        let after = get_device(config, database, &device_insert.device_name)?;
+        audit(
+            database,
+            change,
+            models::AuditAction::Insert,
+            models::AuditEntity::Device,
+            after.as_ref().map(|device| device.id),
+            None,
+            after.as_ref().and_then(to_json),
+        )?;
+        Ok(inserted)
+    })
 }
 
 ///Get all the pools
@@ -173,6 +721,7 @@ pub fn get_pools(
     database: &DbConn,
 ) -> Result<Vec<models::Pool>, failure::Error> {
     Ok(pools::dsl::pools
+        .order(pools::id)
         .load::<models::Pool>(database)
         .with_context(|_| "Error loading pools".to_string())?)
 }
@@ -191,28 +740,64 @@ pub fn get_pool_by_id(
 
 ///Edits the details specific to the pool
 pub fn edit_pool(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     pool_edit: &models::PoolModify,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::update(pools::table
-        .filter(pools::id.eq(&pool_edit.id)))
-        .set((
-            pools::pool_name.eq(&pool_edit.pool_name),
-            pools::description.eq(&pool_edit.description),
-        ))
-        .execute(database)?)
+    write_transaction(database, || {
+        let before = get_pool_by_id(config, database, pool_edit.id).ok();
+        let updated = diesel::update(pools::table
+            .filter(pools::id.eq(&pool_edit.id)))
+            .set((
+                pools::pool_name.eq(&pool_edit.pool_name),
+                pools::description.eq(&pool_edit.description),
+                pools::max_reservations_per_owner.eq(&pool_edit.max_reservations_per_owner),
+                pools::updated_by.eq(&change.actor),
+            ))
+            .execute(database)?;
+        if updated > 0 {
+            let after = get_pool_by_id(config, database, pool_edit.id).ok();
+            audit(
+                database,
+                change,
+                models::AuditAction::Edit,
+                models::AuditEntity::Pool,
+                Some(pool_edit.id),
+                before.as_ref().and_then(to_json),
+                after.as_ref().and_then(to_json),
+            )?;
+        }
+        Ok(updated)
+    })
 }
 
 ///Remove the pool from the database
 pub fn delete_pool(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     pool_delete: &models::PoolDelete,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::delete(pools::table
-        .filter(pools::id.eq(&pool_delete.id)))
-        .execute(database)?)
+    info!("pool {} deleted by {:?}", &pool_delete.id, &change.actor);
+    write_transaction(database, || {
+        let before = get_pool_by_id(config, database, pool_delete.id).ok();
+        let deleted = diesel::delete(pools::table
+            .filter(pools::id.eq(&pool_delete.id)))
+            .execute(database)?;
+        if deleted > 0 {
+            audit(
+                database,
+                change,
+                models::AuditAction::Delete,
+                models::AuditEntity::Pool,
+                Some(pool_delete.id),
+                before.as_ref().and_then(to_json),
+                None,
+            )?;
+        }
+        Ok(deleted)
+    })
 }
 
 ///Inserts a new pool
@@ -220,10 +805,27 @@ pub fn insert_pool(
     _config: &utils::types::Settings,
     database: &DbConn,
     pool_insert: &models::PoolInsert,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::insert_into(pools::table)
-        .values(pool_insert)
-        .execute(database)?)
+    write_transaction(database, || {
+        let inserted = diesel::insert_into(pools::table)
+            .values((pool_insert, pools::updated_by.eq(&change.actor)))
+            .execute(database)?;
+        let after = pools::table
+            .filter(pools::pool_name.eq(&pool_insert.pool_name))
+            .first::<models::Pool>(database)
+            .optional()?;
+        audit(
+            database,
+            change,
+            models::AuditAction::Insert,
+            models::AuditEntity::Pool,
+            after.as_ref().map(|pool| pool.id),
+            None,
+            after.as_ref().and_then(to_json),
+        )?;
+        Ok(inserted)
+    })
 }
 
 // custom owners (exceptions)
@@ -233,6 +835,7 @@ pub fn get_custom_owners(
     database: &DbConn,
 ) -> Result<Vec<models::CustomOwner>, failure::Error> {
     Ok(custom_owners::dsl::custom_owners
+        .order(custom_owners::id)
         .load::<models::CustomOwner>(database)
         .with_context(|_| "Error loading custom_owners".to_string())?)
 }
@@ -267,29 +870,65 @@ pub fn get_custom_owner_by_id(
 
 ///Edits the details specific to the custom_owner, e.g. the name and channel
 pub fn edit_custom_owner(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     custom_owner_edit: &models::CustomOwnerModify,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::update(custom_owners::table
-        .filter(custom_owners::id.eq(&custom_owner_edit.id)))
-        .set((
-            custom_owners::custom_owner_name.eq(&custom_owner_edit.custom_owner_name),
-            custom_owners::recipient.eq(&custom_owner_edit.recipient),
-            custom_owners::description.eq(&custom_owner_edit.description),
-        ))
-        .execute(database)?)
+    write_transaction(database, || {
+        let before = get_custom_owner_by_id(config, database, custom_owner_edit.id)?;
+        let updated = diesel::update(custom_owners::table
+            .filter(custom_owners::id.eq(&custom_owner_edit.id)))
+            .set((
+                custom_owners::custom_owner_name.eq(&custom_owner_edit.custom_owner_name),
+                custom_owners::recipient.eq(&custom_owner_edit.recipient),
+                custom_owners::description.eq(&custom_owner_edit.description),
+                custom_owners::unlimited_reservations.eq(&custom_owner_edit.unlimited_reservations),
+                custom_owners::updated_by.eq(&change.actor),
+            ))
+            .execute(database)?;
+        if updated > 0 {
+            let after = get_custom_owner_by_id(config, database, custom_owner_edit.id)?;
+            audit(
+                database,
+                change,
+                models::AuditAction::Edit,
+                models::AuditEntity::CustomOwner,
+                Some(custom_owner_edit.id),
+                before.as_ref().and_then(to_json),
+                after.as_ref().and_then(to_json),
+            )?;
+        }
+        Ok(updated)
+    })
 }
 
 ///Remove the custom_owner from the database
 pub fn delete_custom_owner(
-    _config: &utils::types::Settings,
+    config: &utils::types::Settings,
     database: &DbConn,
     custom_owner_delete: &models::CustomOwnerDelete,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::delete(custom_owners::table
-        .filter(custom_owners::id.eq(&custom_owner_delete.id)))
-        .execute(database)?)
+    info!("custom_owner {} deleted by {:?}", &custom_owner_delete.id, &change.actor);
+    write_transaction(database, || {
+        let before = get_custom_owner_by_id(config, database, custom_owner_delete.id)?;
+        let deleted = diesel::delete(custom_owners::table
+            .filter(custom_owners::id.eq(&custom_owner_delete.id)))
+            .execute(database)?;
+        if deleted > 0 {
+            audit(
+                database,
+                change,
+                models::AuditAction::Delete,
+                models::AuditEntity::CustomOwner,
+                Some(custom_owner_delete.id),
+                before.as_ref().and_then(to_json),
+                None,
+            )?;
+        }
+        Ok(deleted)
+    })
 }
 
 ///Inserts a new custom_owner - must have valid slack channel associated.
@@ -297,8 +936,380 @@ pub fn insert_custom_owner(
     _config: &utils::types::Settings,
     database: &DbConn,
     custom_owner_insert: &models::CustomOwnerInsert,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    write_transaction(database, || {
+        let inserted = diesel::insert_into(custom_owners::table)
+            .values((custom_owner_insert, custom_owners::updated_by.eq(&change.actor)))
+            .execute(database)?;
+        let after = custom_owners::table
+            .filter(custom_owners::custom_owner_name.eq(&custom_owner_insert.custom_owner_name))
+            .order(custom_owners::id.desc())
+            .first::<models::CustomOwner>(database)
+            .optional()?;
+        audit(
+            database,
+            change,
+            models::AuditAction::Insert,
+            models::AuditEntity::CustomOwner,
+            after.as_ref().map(|custom_owner| custom_owner.id),
+            None,
+            after.as_ref().and_then(to_json),
+        )?;
+        Ok(inserted)
+    })
+}
+
+// users
+///Lookup a single user by username
+pub fn get_user_by_name(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    requested_name: &str,
+) -> Result<Option<models::User>, failure::Error> {
+    Ok(users::table
+        .filter(users::username.eq(requested_name))
+        .load::<models::User>(database)
+        .with_context(|_| "Error loading users".to_string())?
+        .into_iter()
+        .next())
+}
+
+///Lookup the user linked to an identity provider account
+pub fn get_user_by_oidc_subject(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<models::User>, failure::Error> {
+    Ok(users::table
+        .filter(users::oidc_issuer.eq(issuer))
+        .filter(users::oidc_subject.eq(subject))
+        .load::<models::User>(database)
+        .with_context(|_| "Error loading users".to_string())?
+        .into_iter()
+        .next())
+}
+
+///Links a user to an identity provider account, unless it has a password or
+///is already linked to one
+pub fn link_oidc_user(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    user_id: i32,
+    issuer: &str,
+    subject: &str,
+    change: &models::ChangeContext,
 ) -> Result<usize, failure::Error> {
-    Ok(diesel::insert_into(custom_owners::table)
-        .values(custom_owner_insert)
-        .execute(database)?)
+    write_transaction(database, || {
+        let before = get_user_summary(config, database, user_id)?;
+        let updated = diesel::update(
+            users::table
+                .filter(users::id.eq(user_id))
+                .filter(users::password_hash.eq(auth::NO_PASSWORD))
+                .filter(users::oidc_subject.is_null()),
+        )
+        .set((users::oidc_issuer.eq(issuer), users::oidc_subject.eq(subject)))
+        .execute(database)?;
+        audit_user_edit(config, database, change, user_id, before, updated)?;
+        Ok(updated)
+    })
+}
+
+///Lookup a single user by id
+pub fn get_user_by_id(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    requested_id: i32,
+) -> Result<Option<models::User>, failure::Error> {
+    Ok(users::table
+        .filter(users::id.eq(requested_id))
+        .load::<models::User>(database)
+        .with_context(|_| "Error loading users".to_string())?
+        .into_iter()
+        .next())
+}
+
+///Inserts a new user, the password must already be hashed
+pub fn insert_user(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    user_insert: &models::UserInsert,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    write_transaction(database, || {
+        let inserted = diesel::insert_into(users::table)
+            .values(user_insert)
+            .execute(database)?;
+        let after = match get_user_by_name(config, database, &user_insert.username)? {
+            Some(user) => get_user_summary(config, database, user.id)?,
+            None => None,
+        };
+        audit(
+            database,
+            change,
+            models::AuditAction::Insert,
+            models::AuditEntity::User,
+            after.as_ref().map(|user| user.id),
+            None,
+            after.as_ref().and_then(to_json),
+        )?;
+        Ok(inserted)
+    })
+}
+
+///Set a user's role, leaving the pools they administer alone
+pub fn set_user_role(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    user_id: i32,
+    role: models::UserRole,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    info!("user {} set to {:?} by {:?}", &user_id, &role, &change.actor);
+    write_transaction(database, || {
+        let before = get_user_summary(config, database, user_id)?;
+        let updated = diesel::update(users::table.filter(users::id.eq(user_id)))
+            .set(users::role.eq(role))
+            .execute(database)?;
+        audit_user_edit(config, database, change, user_id, before, updated)?;
+        Ok(updated)
+    })
+}
+
+fn audit_user_edit(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    change: &models::ChangeContext,
+    user_id: i32,
+    before: Option<models::UserSummary>,
+    updated: usize,
+) -> Result<(), failure::Error> {
+    if updated == 0 {
+        return Ok(());
+    }
+    let after = get_user_summary(config, database, user_id)?;
+    audit(
+        database,
+        change,
+        models::AuditAction::Edit,
+        models::AuditEntity::User,
+        Some(user_id),
+        before.as_ref().and_then(to_json),
+        after.as_ref().and_then(to_json),
+    )
+}
+
+///Get all the users
+pub fn get_users(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+) -> Result<Vec<models::User>, failure::Error> {
+    Ok(users::table
+        .order(users::id)
+        .load::<models::User>(database)
+        .with_context(|_| "Error loading users".to_string())?)
+}
+
+///Get the ids of the pools a user administers
+pub fn get_managed_pools(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    requested_user_id: i32,
+) -> Result<Vec<i32>, failure::Error> {
+    Ok(pool_admins::table
+        .filter(pool_admins::user_id.eq(requested_user_id))
+        .select(pool_admins::pool_id)
+        .order(pool_admins::pool_id)
+        .load::<i32>(database)
+        .with_context(|_| "Error loading pool admins".to_string())?)
+}
+
+///Get a user along with the pools they administer
+pub fn get_user_summary(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    requested_id: i32,
+) -> Result<Option<models::UserSummary>, failure::Error> {
+    match get_user_by_id(config, database, requested_id)? {
+        Some(user) => Ok(Some(models::UserSummary {
+            pool_ids: get_managed_pools(config, database, user.id)?,
+            id: user.id,
+            username: user.username,
+            role: user.role,
+        })),
+        None => Ok(None),
+    }
+}
+
+///Get all the users along with the pools they administer
+pub fn get_user_summaries(
+    config: &utils::types::Settings,
+    database: &DbConn,
+) -> Result<Vec<models::UserSummary>, failure::Error> {
+    get_users(config, database)?
+        .into_iter()
+        .map(|user| {
+            Ok(models::UserSummary {
+                pool_ids: get_managed_pools(config, database, user.id)?,
+                id: user.id,
+                username: user.username,
+                role: user.role,
+            })
+        })
+        .collect()
+}
+
+///Sets a user's role, and for pool admins, the pools they administer
+pub fn edit_user_role(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    user_modify: &models::UserModify,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    info!("user {} set to {:?} by {:?}", &user_modify.id, &user_modify.role, &change.actor);
+    write_transaction(database, || {
+        let before = get_user_summary(config, database, user_modify.id)?;
+        let updated = diesel::update(users::table.filter(users::id.eq(&user_modify.id)))
+            .set(users::role.eq(&user_modify.role))
+            .execute(database)?;
+        diesel::delete(pool_admins::table.filter(pool_admins::user_id.eq(&user_modify.id)))
+            .execute(database)?;
+        if user_modify.role == models::UserRole::PoolAdmin && !user_modify.pool_ids.is_empty() {
+            let rows: Vec<_> = user_modify
+                .pool_ids
+                .iter()
+                .map(|requested_pool_id| {
+                    (
+                        pool_admins::user_id.eq(user_modify.id),
+                        pool_admins::pool_id.eq(*requested_pool_id),
+                    )
+                })
+                .collect();
+            diesel::insert_into(pool_admins::table)
+                .values(&rows)
+                .execute(database)?;
+        }
+        audit_user_edit(config, database, change, user_modify.id, before, updated)?;
+        Ok(updated)
+    })
+}
+
+// api tokens
+///Get a user's api tokens
+pub fn get_api_tokens(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    requested_user_id: i32,
+) -> Result<Vec<models::ApiToken>, failure::Error> {
+    Ok(api_tokens::table
+        .filter(api_tokens::user_id.eq(requested_user_id))
+        .order(api_tokens::id)
+        .load::<models::ApiToken>(database)
+        .with_context(|_| "Error loading api tokens".to_string())?)
+}
+
+///Inserts a new api token, the token must already be hashed
+pub fn insert_api_token(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    api_token_insert: &models::ApiTokenInsert,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    write_transaction(database, || {
+        let inserted = diesel::insert_into(api_tokens::table)
+            .values(api_token_insert)
+            .execute(database)?;
+        let after = api_tokens::table
+            .filter(api_tokens::token_hash.eq(&api_token_insert.token_hash))
+            .first::<models::ApiToken>(database)
+            .optional()?;
+        audit(
+            database,
+            change,
+            models::AuditAction::Insert,
+            models::AuditEntity::ApiToken,
+            after.as_ref().map(|api_token| api_token.id),
+            None,
+            after.as_ref().and_then(to_json),
+        )?;
+        Ok(inserted)
+    })
+}
+
+///Revokes one of a user's api tokens
+pub fn delete_api_token(
+    _config: &utils::types::Settings,
+    database: &DbConn,
+    requested_user_id: i32,
+    api_token_delete: &models::ApiTokenDelete,
+    change: &models::ChangeContext,
+) -> Result<usize, failure::Error> {
+    let selector = api_tokens::table.filter(
+        api_tokens::id
+            .eq(&api_token_delete.id)
+            .and(api_tokens::user_id.eq(requested_user_id)),
+    );
+    write_transaction(database, || {
+        let before = selector.first::<models::ApiToken>(database).optional()?;
+        let deleted = diesel::delete(selector).execute(database)?;
+        if deleted > 0 {
+            audit(
+                database,
+                change,
+                models::AuditAction::Delete,
+                models::AuditEntity::ApiToken,
+                Some(api_token_delete.id),
+                before.as_ref().and_then(to_json),
+                None,
+            )?;
+        }
+        Ok(deleted)
+    })
+}
+
+///Lookup the user an api token belongs to, noting that the token was used
+pub fn get_user_by_api_token(
+    config: &utils::types::Settings,
+    database: &DbConn,
+    requested_token_hash: &str,
+) -> Result<Option<models::User>, failure::Error> {
+    let api_token = api_tokens::table
+        .filter(api_tokens::token_hash.eq(requested_token_hash))
+        .load::<models::ApiToken>(database)
+        .with_context(|_| "Error loading api tokens".to_string())?
+        .into_iter()
+        .next();
+    match api_token {
+        Some(api_token) => {
+            diesel::update(api_tokens::table.find(api_token.id))
+                .set(api_tokens::last_used_at.eq(diesel::dsl::now.nullable()))
+                .execute(database)?;
+            get_user_by_id(config, database, api_token.user_id)
+        }
+        None => Ok(None),
+    }
+}
+
+const SECRET_KEY: &str = "secret_key";
+
+///The key cookies are signed with, generated and stored the first time, so
+///sessions survive restarts and are shared by every server on the database
+pub fn get_or_create_secret_key(_config: &utils::types::Settings, database: &DbConn) -> Result<String, failure::Error> {
+    let selector = secrets::table.find(SECRET_KEY).select(secrets::value);
+    let created = write_transaction(database, || {
+        if let Some(key) = selector.first::<String>(database).optional()? {
+            return Ok(key);
+        }
+        let key = auth::generate_secret_key();
+        diesel::insert_into(secrets::table)
+            .values((secrets::name.eq(SECRET_KEY), secrets::value.eq(&key)))
+            .execute(database)?;
+        Ok(key)
+    });
+    match created {
+        Ok(key) => Ok(key),
+        // another server may have stored one first
+        Err(e) => selector.first::<String>(database).optional()?.ok_or(e),
+    }
 }
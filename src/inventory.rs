@@ -0,0 +1,767 @@
+//! Importing and exporting the whole inventory, i.e. the pools, custom owners
+//! and devices, as JSON, CSV, TOML or YAML. Everything is matched up by name, so
+//! an export can be edited and imported again, here or on another server, or
+//! kept under version control and synced.
+
+use crate::database;
+use crate::models;
+use crate::utils;
+use crate::validation::{find_first_validation_message, ValidateWithContext, ValidationContext};
+use csv;
+use failure;
+use failure::ResultExt;
+use serde_json;
+use serde_yaml;
+use std;
+use toml;
+use validator::{Validate, ValidationErrors};
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct Inventory {
+    #[serde(default)]
+    pub pools: Vec<InventoryPool>,
+    #[serde(default)]
+    pub custom_owners: Vec<InventoryCustomOwner>,
+    #[serde(default)]
+    pub devices: Vec<InventoryDevice>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct InventoryPool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub max_reservations_per_owner: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct InventoryCustomOwner {
+    pub name: String,
+    pub recipient: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub unlimited_reservations: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct InventoryDevice {
+    pub name: String,
+    pub url: String,
+    ///The pool's name, rather than its id, which differs between servers
+    pub pool: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    Json,
+    Csv,
+    Toml,
+    Yaml,
+}
+
+impl std::str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(failure::err_msg(format!(
+                "Unknown format '{}', expected csv, json, toml or yaml",
+                s
+            ))),
+        }
+    }
+}
+
+impl Format {
+    ///Guesses the format from a file's extension
+    pub fn from_path(path: &str) -> Option<Format> {
+        std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.parse().ok())
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Toml => "application/toml",
+            Format::Yaml => "application/yaml",
+        }
+    }
+}
+
+///One line of the CSV form, which puts everything in a single file with a
+///`type` column saying what each line is
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+struct CsvRow {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    url: Option<String>,
+    pool: Option<String>,
+    recipient: Option<String>,
+    description: Option<String>,
+    max_reservations_per_owner: Option<i32>,
+    unlimited_reservations: Option<bool>,
+}
+
+impl Inventory {
+    pub fn parse(data: &str, format: Format) -> Result<Inventory, failure::Error> {
+        match format {
+            Format::Json => Ok(serde_json::from_str(data)?),
+            Format::Toml => Ok(toml::from_str(data)?),
+            Format::Yaml => Ok(serde_yaml::from_str(data)?),
+            Format::Csv => {
+                let mut inventory = Inventory::default();
+                let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data.as_bytes());
+                for (line, row) in reader.deserialize::<CsvRow>().enumerate() {
+                    // line 1 is the header
+                    let row = row.map_err(|e| failure::err_msg(format!("line {}: {}", line + 2, e)))?;
+                    let missing = |field: &str| failure::err_msg(format!("line {}: {} needs a {}", line + 2, row.kind, field));
+                    match row.kind.as_str() {
+                        "pool" => inventory.pools.push(InventoryPool {
+                            name: row.name.clone(),
+                            description: row.description.clone(),
+                            max_reservations_per_owner: row.max_reservations_per_owner,
+                        }),
+                        "custom_owner" => inventory.custom_owners.push(InventoryCustomOwner {
+                            name: row.name.clone(),
+                            recipient: row.recipient.clone().ok_or_else(|| missing("recipient"))?,
+                            description: row.description.clone(),
+                            unlimited_reservations: row.unlimited_reservations.unwrap_or(false),
+                        }),
+                        "device" => inventory.devices.push(InventoryDevice {
+                            name: row.name.clone(),
+                            url: row.url.clone().ok_or_else(|| missing("url"))?,
+                            pool: row.pool.clone().ok_or_else(|| missing("pool"))?,
+                        }),
+                        kind => {
+                            return Err(failure::err_msg(format!(
+                                "line {}: unknown type '{}', expected pool, custom_owner or device",
+                                line + 2,
+                                kind
+                            )))
+                        }
+                    }
+                }
+                Ok(inventory)
+            }
+        }
+    }
+
+    pub fn to_string(&self, format: Format) -> Result<String, failure::Error> {
+        match format {
+            Format::Json => Ok(serde_json::to_string_pretty(self)?),
+            // via a Value, which puts the empty arrays ahead of the tables as toml needs
+            Format::Toml => Ok(toml::to_string(&toml::Value::try_from(self)?)?),
+            Format::Yaml => Ok(serde_yaml::to_string(self)?),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(vec![]);
+                for pool in &self.pools {
+                    writer.serialize(CsvRow {
+                        kind: "pool".into(),
+                        name: pool.name.clone(),
+                        description: pool.description.clone(),
+                        max_reservations_per_owner: pool.max_reservations_per_owner,
+                        ..Default::default()
+                    })?;
+                }
+                for custom_owner in &self.custom_owners {
+                    writer.serialize(CsvRow {
+                        kind: "custom_owner".into(),
+                        name: custom_owner.name.clone(),
+                        recipient: Some(custom_owner.recipient.clone()),
+                        description: custom_owner.description.clone(),
+                        unlimited_reservations: Some(custom_owner.unlimited_reservations),
+                        ..Default::default()
+                    })?;
+                }
+                for device in &self.devices {
+                    writer.serialize(CsvRow {
+                        kind: "device".into(),
+                        name: device.name.clone(),
+                        url: Some(device.url.clone()),
+                        pool: Some(device.pool.clone()),
+                        ..Default::default()
+                    })?;
+                }
+                Ok(String::from_utf8(writer.into_inner()?)?)
+            }
+        }
+    }
+}
+
+///Reads an inventory file, in `format` or failing that the one its extension says
+pub fn read_file(path: &str, format: Option<&str>) -> Result<Inventory, failure::Error> {
+    let format = match (format, Format::from_path(path)) {
+        (Some(format), _) => format.parse()?,
+        (None, Some(format)) => format,
+        (None, None) => return Err(failure::err_msg("Can't tell the format from the file name, pass '--format'")),
+    };
+    let data = std::fs::read_to_string(path).with_context(|_| format!("Failed to read {}", path))?;
+    Inventory::parse(&data, format)
+}
+
+///Syncs `--inventory` into the database as the server starts
+pub fn sync_at_startup(config: &utils::types::Settings) -> Result<(), failure::Error> {
+    let path = match &config.inventory_file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let inventory = read_file(path, None)?;
+    let database = database::establish_connection(config)?;
+    let change = models::ChangeContext {
+        source: models::ChangeSource::Internal,
+        reason: Some(format!("synced from {}", path)),
+        ..Default::default()
+    };
+    for line in sync(config, &database, &inventory, &change, config.prune_inventory, false)? {
+        info!("{}: {}", path, line);
+    }
+    Ok(())
+}
+
+///Reads everything out of the database
+pub fn export(config: &utils::types::Settings, database: &database::DbConn) -> Result<Inventory, failure::Error> {
+    let pools = database::get_pools(config, database)?;
+    let pool_name = |pool_id| {
+        pools
+            .iter()
+            .find(|pool| pool.id == pool_id)
+            .map(|pool| pool.pool_name.clone())
+            .unwrap_or_default()
+    };
+    Ok(Inventory {
+        pools: pools
+            .iter()
+            .map(|pool| InventoryPool {
+                name: pool.pool_name.clone(),
+                description: non_empty(&pool.description),
+                max_reservations_per_owner: pool.max_reservations_per_owner,
+            })
+            .collect(),
+        custom_owners: database::get_custom_owners(config, database)?
+            .into_iter()
+            .map(|custom_owner| InventoryCustomOwner {
+                name: custom_owner.custom_owner_name,
+                recipient: custom_owner.recipient,
+                description: non_empty(&custom_owner.description),
+                unlimited_reservations: custom_owner.unlimited_reservations,
+            })
+            .collect(),
+        devices: database::get_devices(config, database)?
+            .into_iter()
+            .map(|device| InventoryDevice {
+                url: device.device_url.unwrap_or_default(),
+                pool: pool_name(device.pool_id),
+                name: device.device_name,
+            })
+            .collect(),
+    })
+}
+
+#[derive(Debug)]
+struct DryRun;
+
+impl std::fmt::Display for DryRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Rolling back a dry run")
+    }
+}
+
+impl std::error::Error for DryRun {}
+
+///Adds whatever isn't in the database yet and updates whatever differs,
+///matching by name, and returns what changed. Nothing is removed. It all
+///happens in one transaction, so if any row fails validation nothing is
+///changed, and with `dry_run` it's rolled back regardless, to preview the
+///changes.
+pub fn import(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    inventory: &Inventory,
+    change: &models::ChangeContext,
+    dry_run: bool,
+) -> Result<Vec<String>, failure::Error> {
+    sync(config, database, inventory, change, false, dry_run)
+}
+
+///As `import`, but with `prune` the devices and pools that aren't in the
+///inventory are removed too, so the database matches it. Reservations are
+///left alone: reserved devices are updated in place, and never pruned.
+pub fn sync(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    inventory: &Inventory,
+    change: &models::ChangeContext,
+    prune: bool,
+    dry_run: bool,
+) -> Result<Vec<String>, failure::Error> {
+    let mut changes = vec![];
+    let result = database::write_transaction(database, || {
+        import_pools(config, database, &inventory.pools, change, &mut changes)?;
+        import_custom_owners(config, database, &inventory.custom_owners, change, &mut changes)?;
+        import_devices(config, database, &inventory.devices, change, &mut changes)?;
+        if prune {
+            prune_devices(config, database, &inventory.devices, change, &mut changes)?;
+            prune_pools(config, database, &inventory.pools, change, &mut changes)?;
+        }
+        if dry_run {
+            return Err(DryRun.into());
+        }
+        Ok(())
+    });
+    match result {
+        Err(ref error) if error.downcast_ref::<DryRun>().is_some() => Ok(changes),
+        Err(error) => Err(error),
+        Ok(()) => Ok(changes),
+    }
+}
+
+fn import_pools(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    pools: &[InventoryPool],
+    change: &models::ChangeContext,
+    changes: &mut Vec<String>,
+) -> Result<(), failure::Error> {
+    for pool in pools {
+        let existing = database::get_pools(config, database)?
+            .into_iter()
+            .find(|existing| existing.pool_name == pool.name);
+        match existing {
+            None => {
+                let pool_insert = models::PoolInsert {
+                    pool_name: pool.name.clone(),
+                    description: pool.description.clone(),
+                    max_reservations_per_owner: pool.max_reservations_per_owner,
+                };
+                pool_insert.validate().map_err(|e| invalid("pool", &pool.name, e))?;
+                database::insert_pool(config, database, &pool_insert, change)?;
+                changes.push(format!("add pool {}", pool.name));
+            }
+            Some(existing) => {
+                let mut differences = vec![];
+                difference(
+                    &mut differences,
+                    "description",
+                    &non_empty(&existing.description),
+                    &non_empty(&pool.description),
+                );
+                difference(
+                    &mut differences,
+                    "max_reservations_per_owner",
+                    &existing.max_reservations_per_owner,
+                    &pool.max_reservations_per_owner,
+                );
+                if differences.is_empty() {
+                    continue;
+                }
+                let pool_modify = models::PoolModify {
+                    id: existing.id,
+                    pool_name: pool.name.clone(),
+                    description: pool.description.clone(),
+                    max_reservations_per_owner: pool.max_reservations_per_owner,
+                };
+                pool_modify.validate().map_err(|e| invalid("pool", &pool.name, e))?;
+                database::edit_pool(config, database, &pool_modify, change)?;
+                changes.push(format!("update pool {}: {}", pool.name, differences.join(", ")));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn import_custom_owners(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    custom_owners: &[InventoryCustomOwner],
+    change: &models::ChangeContext,
+    changes: &mut Vec<String>,
+) -> Result<(), failure::Error> {
+    let context = ValidationContext::new(config, database);
+    for custom_owner in custom_owners {
+        let name = custom_owner.name.to_lowercase();
+        let recipient = custom_owner.recipient.to_lowercase();
+        match database::get_custom_owner(config, database, &name)? {
+            None => {
+                let custom_owner_insert = models::CustomOwnerInsert {
+                    custom_owner_name: name.clone(),
+                    recipient,
+                    description: custom_owner.description.clone(),
+                    unlimited_reservations: custom_owner.unlimited_reservations,
+                };
+                custom_owner_insert
+                    .validate_with_context(&context)
+                    .map_err(|e| invalid("custom owner", &name, e))?;
+                database::insert_custom_owner(config, database, &custom_owner_insert, change)?;
+                changes.push(format!("add custom owner {}", name));
+            }
+            Some(existing) => {
+                let mut differences = vec![];
+                difference(&mut differences, "recipient", &existing.recipient, &recipient);
+                difference(
+                    &mut differences,
+                    "description",
+                    &non_empty(&existing.description),
+                    &non_empty(&custom_owner.description),
+                );
+                difference(
+                    &mut differences,
+                    "unlimited_reservations",
+                    &existing.unlimited_reservations,
+                    &custom_owner.unlimited_reservations,
+                );
+                if differences.is_empty() {
+                    continue;
+                }
+                let custom_owner_modify = models::CustomOwnerModify {
+                    id: existing.id,
+                    custom_owner_name: name.clone(),
+                    recipient,
+                    description: custom_owner.description.clone(),
+                    unlimited_reservations: custom_owner.unlimited_reservations,
+                };
+                custom_owner_modify
+                    .validate_with_context(&context)
+                    .map_err(|e| invalid("custom owner", &name, e))?;
+                database::edit_custom_owner(config, database, &custom_owner_modify, change)?;
+                changes.push(format!("update custom owner {}: {}", name, differences.join(", ")));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn import_devices(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    devices: &[InventoryDevice],
+    change: &models::ChangeContext,
+    changes: &mut Vec<String>,
+) -> Result<(), failure::Error> {
+    let pools = database::get_pools(config, database)?;
+    for device in devices {
+        let pool = pools
+            .iter()
+            .find(|pool| pool.pool_name == device.pool)
+            .ok_or_else(|| failure::err_msg(format!("device {}: no pool '{}'", device.name, device.pool)))?;
+        match database::get_device(config, database, &device.name)? {
+            None => {
+                let device_insert = models::DeviceInsert {
+                    device_name: device.name.clone(),
+                    device_url: device.url.clone(),
+                    pool_id: pool.id,
+                };
+                device_insert.validate().map_err(|e| invalid("device", &device.name, e))?;
+                database::insert_device(config, database, &device_insert, change)?;
+                changes.push(format!("add device {} to {}", device.name, pool.pool_name));
+            }
+            Some(existing) => {
+                let existing_pool = pools
+                    .iter()
+                    .find(|pool| pool.id == existing.pool_id)
+                    .map(|pool| pool.pool_name.clone());
+                let mut differences = vec![];
+                difference(&mut differences, "url", &existing.device_url, &Some(device.url.clone()));
+                difference(&mut differences, "pool", &existing_pool, &Some(pool.pool_name.clone()));
+                if differences.is_empty() {
+                    continue;
+                }
+                let device_edit = models::DeviceEdit {
+                    id: existing.id,
+                    device_name: device.name.clone(),
+                    device_url: device.url.clone(),
+                    pool_id: pool.id,
+                };
+                device_edit.validate().map_err(|e| invalid("device", &device.name, e))?;
+                database::edit_device(config, database, &device_edit, change)?;
+                changes.push(format!("update device {}: {}", device.name, differences.join(", ")));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn prune_devices(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    devices: &[InventoryDevice],
+    change: &models::ChangeContext,
+    changes: &mut Vec<String>,
+) -> Result<(), failure::Error> {
+    for existing in database::get_devices(config, database)? {
+        if devices.iter().any(|device| device.name == existing.device_name) {
+            continue;
+        }
+        if existing.reservation_status == models::ReservationStatus::Reserved {
+            changes.push(format!(
+                "keep device {}: reserved by {}",
+                existing.device_name,
+                existing.device_owner.unwrap_or_default()
+            ));
+            continue;
+        }
+        database::delete_device(config, database, &models::DeviceDelete { id: existing.id }, change)?;
+        changes.push(format!("remove device {}", existing.device_name));
+    }
+    Ok(())
+}
+
+fn prune_pools(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    pools: &[InventoryPool],
+    change: &models::ChangeContext,
+    changes: &mut Vec<String>,
+) -> Result<(), failure::Error> {
+    let context = ValidationContext::new(config, database);
+    for existing in database::get_pools(config, database)? {
+        if pools.iter().any(|pool| pool.name == existing.pool_name) {
+            continue;
+        }
+        let pool_delete = models::PoolDelete { id: existing.id };
+        // the default pool stays, as do any still holding reserved devices
+        if let Err(errors) = pool_delete.validate_with_context(&context) {
+            let errors = errors.field_errors();
+            let message = find_first_validation_message(&errors).map(|message| message.to_string());
+            changes.push(format!(
+                "keep pool {}: {}",
+                existing.pool_name,
+                message.unwrap_or_else(|| "can't be removed".to_string())
+            ));
+            continue;
+        }
+        database::delete_pool(config, database, &pool_delete, change)?;
+        changes.push(format!("remove pool {}", existing.pool_name));
+    }
+    Ok(())
+}
+
+///Descriptions left empty on the forms are stored as "", which CSV can't
+///tell apart from none at all
+fn non_empty(description: &Option<String>) -> Option<String> {
+    description.clone().filter(|description| !description.is_empty())
+}
+
+///Notes a field that's changing, as `field: old -> new`
+fn difference<T: std::fmt::Debug + PartialEq>(differences: &mut Vec<String>, field: &str, old: &T, new: &T) {
+    if old != new {
+        differences.push(format!("{} {:?} -> {:?}", field, old, new));
+    }
+}
+
+fn invalid(kind: &str, name: &str, errors: ValidationErrors) -> failure::Error {
+    let errors = errors.field_errors();
+    let message = find_first_validation_message(&errors)
+        .map(|message| message.to_string())
+        .unwrap_or_else(|| "invalid".to_string());
+    failure::err_msg(format!("{} {}: {}", kind, name, message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_csv_round_trip() {
+        let inventory = Inventory {
+            pools: vec![InventoryPool {
+                name: "Phones".into(),
+                description: Some("on the rack, by the door".into()),
+                max_reservations_per_owner: Some(2),
+            }],
+            custom_owners: vec![InventoryCustomOwner {
+                name: "ci".into(),
+                recipient: "none".into(),
+                description: None,
+                unlimited_reservations: true,
+            }],
+            devices: vec![InventoryDevice {
+                name: "phone1".into(),
+                url: "http://phone1".into(),
+                pool: "Phones".into(),
+            }],
+        };
+        let csv = inventory.to_string(Format::Csv).unwrap();
+        assert_eq!(
+            csv.lines().next(),
+            Some("type,name,url,pool,recipient,description,max_reservations_per_owner,unlimited_reservations")
+        );
+        assert_eq!(Inventory::parse(&csv, Format::Csv).unwrap(), inventory);
+        let json = inventory.to_string(Format::Json).unwrap();
+        assert_eq!(Inventory::parse(&json, Format::Json).unwrap(), inventory);
+    }
+
+    #[test]
+    fn test_csv_errors() {
+        let header = "type,name,url,pool,recipient,description,max_reservations_per_owner,unlimited_reservations\n";
+        let parse = |rows: &str| Inventory::parse(&format!("{}{}", header, rows), Format::Csv);
+        let inventory = parse("device,unit3,http://unit3,Default Pool,,,,\n").unwrap();
+        assert_eq!(inventory.devices.len(), 1);
+        assert_eq!(
+            parse("device,unit3,,Default Pool,,,,\n").unwrap_err().to_string(),
+            "line 2: device needs a url"
+        );
+        assert_eq!(
+            parse("pool,Phones,,,,,,\nrack,A,,,,,,\n").unwrap_err().to_string(),
+            "line 3: unknown type 'rack', expected pool, custom_owner or device"
+        );
+        assert!(parse("pool,Phones,,,,,two,\n").is_err());
+        assert_eq!(Format::from_path("rack.CSV"), Some(Format::Csv));
+        assert_eq!(Format::from_path("rack"), None);
+    }
+
+    #[test]
+    fn test_toml_and_yaml() {
+        let toml = r#"
+[[pools]]
+name = "Phones"
+max_reservations_per_owner = 2
+
+[[devices]]
+name = "phone1"
+url = "http://phone1"
+pool = "Phones"
+"#;
+        let inventory = Inventory::parse(toml, Format::Toml).unwrap();
+        assert_eq!(inventory.pools[0].max_reservations_per_owner, Some(2));
+        assert_eq!(inventory.devices[0].pool, "Phones");
+        assert!(inventory.custom_owners.is_empty());
+        let yaml = "pools:\n  - name: Phones\n    max_reservations_per_owner: 2\ndevices:\n  - name: phone1\n    url: http://phone1\n    pool: Phones\n";
+        assert_eq!(Inventory::parse(yaml, Format::Yaml).unwrap(), inventory);
+        for format in &[Format::Toml, Format::Yaml] {
+            let written = inventory.to_string(*format).unwrap();
+            assert_eq!(Inventory::parse(&written, *format).unwrap(), inventory);
+        }
+        assert_eq!(Format::from_path("inventory.yml"), Some(Format::Yaml));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sync_prune() {
+        let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+        let mut config = utils::types::Settings::new();
+        config.database_url = file.path().to_string_lossy().to_string();
+        database::run_migrations(&config).unwrap();
+        let database = database::establish_connection(&config).unwrap();
+        let change = models::ChangeContext::default();
+        let device_update = models::DeviceUpdate {
+            id: 1,
+            device_owner: Some("alice".into()),
+            comments: Some("soak test".into()),
+            reservation_status: models::ReservationStatus::Reserved,
+        };
+        database::update_device(&config, &database, &device_update, models::ReservationStatus::Available, &change)
+            .unwrap();
+
+        let mut inventory = export(&config, &database).unwrap();
+        inventory.pools.push(InventoryPool {
+            name: "Phones".into(),
+            description: None,
+            max_reservations_per_owner: None,
+        });
+        inventory.devices.push(InventoryDevice {
+            name: "phone1".into(),
+            url: "http://phone1".into(),
+            pool: "Phones".into(),
+        });
+        sync(&config, &database, &inventory, &change, true, false).unwrap();
+
+        // unit1 is reserved, so it moves pool but stays reserved, and when it's
+        // dropped from the file it's kept
+        let inventory = Inventory {
+            pools: vec![inventory.pools[1].clone()],
+            custom_owners: vec![],
+            devices: vec![InventoryDevice {
+                name: "unit1".into(),
+                url: "http://unit1".into(),
+                pool: "Phones".into(),
+            }],
+        };
+        let changes = sync(&config, &database, &inventory, &change, true, false).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "update device unit1: pool Some(\"Default Pool\") -> Some(\"Phones\")".to_string(),
+                "remove device unit2".to_string(),
+                "remove device phone1".to_string(),
+                "keep pool Default Pool: Default pool cannot be deleted".to_string(),
+            ]
+        );
+        let device = database::get_device(&config, &database, "unit1").unwrap().unwrap();
+        assert_eq!(device.reservation_status, models::ReservationStatus::Reserved);
+        assert_eq!(device.device_owner, Some("alice".into()));
+        assert_eq!(device.comments, Some("soak test".into()));
+
+        let changes = sync(&config, &database, &Inventory::default(), &change, true, false).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "keep device unit1: reserved by alice".to_string(),
+                "keep pool Default Pool: Default pool cannot be deleted".to_string(),
+                "keep pool Phones: Cannot delete non-empty pool".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_import() {
+        let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+        let mut config = utils::types::Settings::new();
+        config.database_url = file.path().to_string_lossy().to_string();
+        database::run_migrations(&config).unwrap();
+        let database = database::establish_connection(&config).unwrap();
+        let change = models::ChangeContext::default();
+
+        let inventory = export(&config, &database).unwrap();
+        assert_eq!(inventory.devices.len(), 2);
+        let csv = inventory.to_string(Format::Csv).unwrap();
+        let inventory = Inventory::parse(&csv, Format::Csv).unwrap();
+        assert!(import(&config, &database, &inventory, &change, false).unwrap().is_empty());
+        let mut inventory = inventory;
+
+        inventory.pools.push(InventoryPool {
+            name: "Phones".into(),
+            description: None,
+            max_reservations_per_owner: None,
+        });
+        inventory.devices[1].pool = "Phones".into();
+        inventory.devices.push(InventoryDevice {
+            name: "phone2".into(),
+            url: "http://phone2".into(),
+            pool: "Phones".into(),
+        });
+        let expected = vec![
+            "add pool Phones".to_string(),
+            "update device unit2: pool Some(\"Default Pool\") -> Some(\"Phones\")".to_string(),
+            "add device phone2 to Phones".to_string(),
+        ];
+        assert_eq!(import(&config, &database, &inventory, &change, true).unwrap(), expected);
+        assert_eq!(database::get_pools(&config, &database).unwrap().len(), 1);
+
+        // one bad row and none of it happens
+        let mut broken = inventory.clone();
+        broken.devices.push(InventoryDevice {
+            name: "phone3".into(),
+            url: "not a url".into(),
+            pool: "Phones".into(),
+        });
+        assert_eq!(
+            import(&config, &database, &broken, &change, false).unwrap_err().to_string(),
+            "device phone3: URL was invalid"
+        );
+        assert_eq!(database::get_devices(&config, &database).unwrap().len(), 2);
+
+        assert_eq!(import(&config, &database, &inventory, &change, false).unwrap(), expected);
+        assert_eq!(export(&config, &database).unwrap(), inventory);
+    }
+}
@@ -1,13 +1,12 @@
 //#[macro_use] //this crate has macros, currently unused
 pub use failure;
-#[macro_use]
 pub extern crate log;
 pub use stderrlog;
 #[macro_use]
 pub extern crate clap;
 #[macro_use]
 pub extern crate rocket;
-pub use rocket_dyn_templates;
+pub use handlebars;
 #[macro_use]
 pub extern crate serde_derive;
 pub use serde;
@@ -19,37 +18,58 @@ pub extern crate diesel_migrations;
 #[macro_use]
 pub extern crate diesel_derive_enum;
 pub use chrono;
-use dotenv::dotenv;
 pub use exitfailure;
-use failure::ResultExt;
-use rocket::{Build, Config, Request, response, Rocket};
+use rocket::{Build, Config, Rocket};
 use rocket::config::SecretKey;
-use rocket::response::Responder;
 
 #[macro_use]
 extern crate validator_derive;
-use validator;
-use crate::routes::{api_routes, html_routes};
+use crate::routes::{api_catchers, api_routes, html_catchers, html_routes};
 
+pub mod admin;
+pub mod auth;
+pub mod client;
+pub mod csrf;
+//diesel 1.x's derives and macros put their impls inside consts
+#[allow(non_local_definitions)]
 pub mod database;
+pub mod inventory;
+#[allow(non_local_definitions)]
 pub mod models;
 pub mod pool;
 pub mod routes;
+#[allow(non_local_definitions)]
 pub mod schema;
+pub mod templates;
 pub mod utils;
+pub mod validation;
 
 pub fn create_new_rocket(config: utils::types::Settings) -> Rocket<Build> {
     // Create the rocket config
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(rocket::config::TlsConfig::from_paths(cert, key)),
+        _ => None,
+    };
     let rocket_config = Config {
+        address: config.address,
         port: config.port,
-        secret_key: SecretKey::generate().unwrap(),
+        tls,
+        secret_key: match config.secret_key.as_deref().and_then(auth::secret_key) {
+            Some(key) => key,
+            None => SecretKey::generate().unwrap(),
+        },
         ..Config::release_default()
     };
 
+    let api_path = format!("{}/api/", config.base_path);
     rocket::custom(&rocket_config)
         .manage(pool::init_pool(&config))
+        .attach(templates::fairing())
+        .mount(format!("{}/", config.base_path), html_routes())
+        .register(format!("{}/", config.base_path), html_catchers())
+        .register(api_path.clone(), api_catchers())
+        .mount(api_path, api_routes())
+        // shared with the database work, which runs on the blocking threads
+        .manage(std::sync::Arc::new(config.clone()))
         .manage(config)
-        .attach(rocket_dyn_templates::Template::fairing())
-        .mount("/", html_routes())
-        .mount("/api/", api_routes())
-}
\ No newline at end of file
+}
@@ -1,19 +1,53 @@
 use dotenv::dotenv;
 use failure::ResultExt;
-use rocket::{Build, Config, Rocket};
-use device_checkout::{create_new_rocket, database, pool, utils};
-use device_checkout::routes::{api_routes, html_routes};
-use rocket::launch;
+use device_checkout::{admin, client, create_new_rocket, database, inventory, utils};
 
-#[launch]
-fn rocket() -> Rocket<Build> {
+fn main() {
     dotenv().ok();
     let mut config = utils::cmdline::parse_cmdline();
     config.module_path = Some(module_path!().into());
     utils::logging::configure_logger(&config);
-    database::run_migrations(&config).context("Failed to migrate database").unwrap();
 
-    create_new_rocket(config)
+    match config.command.clone() {
+        utils::types::Command::Client(settings, command) => {
+            if let Err(e) = client::run(&settings, &command) {
+                exit_with(e);
+            }
+        }
+        utils::types::Command::Admin(command) => {
+            if let Err(e) = admin::run(&config, &command) {
+                exit_with(e);
+            }
+        }
+        utils::types::Command::Serve => {
+            database::run_migrations(&config).context("Failed to migrate database").unwrap();
+            if let Err(e) = inventory::sync_at_startup(&config) {
+                exit_with(e.context("Failed to sync inventory").into());
+            }
+            if config.secret_key.is_none() {
+                let stored = database::establish_connection(&config)
+                    .and_then(|database| database::get_or_create_secret_key(&config, &database));
+                match stored {
+                    Ok(key) => config.secret_key = Some(key),
+                    // a key that changes on every restart would log everyone out
+                    Err(e) => exit_with(
+                        e.context("No stable secret key, set secret_key in the config file or DEVICE_CHECKOUT_SECRET_KEY")
+                            .into(),
+                    ),
+                }
+            }
+            if let Err(e) = rocket::execute(create_new_rocket(config).launch()) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
 
-    // Rocket ignition happens automatically by the macro
-}
\ No newline at end of file
+fn exit_with(e: failure::Error) -> ! {
+    eprintln!("error: {}", e);
+    for cause in e.iter_causes() {
+        eprintln!("  caused by: {}", cause);
+    }
+    std::process::exit(1);
+}
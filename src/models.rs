@@ -2,25 +2,20 @@ use chrono;
 use rocket;
 use crate::database;
 use crate::schema::*;
-use crate::utils;
-use crate::utils::slack;
+use crate::utils::directory;
+use crate::validation::{ValidateWithContext, ValidationContext};
 use std;
 use validator::{Validate, ValidationError};
 
 #[derive(
-    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize, DbEnum, FromFormField
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default, Serialize, Deserialize, DbEnum, FromFormField
 )]
 pub enum ReservationStatus {
+    #[default]
     Available,
     Reserved,
 }
 
-impl Default for ReservationStatus {
-    fn default() -> Self {
-        ReservationStatus::Available
-    }
-}
-
 impl std::ops::Not for ReservationStatus {
     type Output = ReservationStatus;
 
@@ -32,6 +27,19 @@ impl std::ops::Not for ReservationStatus {
     }
 }
 
+/// What a user may do, each role can do everything the ones before it can.
+/// Pool admins manage the devices in the pools they've been given.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default, Serialize, Deserialize, DbEnum, FromFormField
+)]
+pub enum UserRole {
+    Viewer,
+    #[default]
+    User,
+    PoolAdmin,
+    Admin,
+}
+
 //deliberately not making this Copy
 #[derive(
     Debug,
@@ -64,12 +72,67 @@ pub struct Device {
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
     pub pool_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    /// Shared by devices reserved together, see `Reservation`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub reservation_id: Option<i32>,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+impl Device {
+    /// Everyone sharing the reservation, the one who claimed it first
+    pub fn owners(&self) -> Vec<String> {
+        self.device_owner.as_deref().map(parse_owners).unwrap_or_default()
+    }
+
+    /// Whether `owner` is one of its owners, ignoring case as
+    /// `database::get_devices_by_owner` does
+    pub fn is_owned_by(&self, owner: &str) -> bool {
+        self.owners().iter().any(|device_owner| device_owner.eq_ignore_ascii_case(owner))
+    }
+
+    /// How long since it last changed, i.e. how long it's been held, as its
+    /// two largest units, e.g. "2 days 3 hours"
+    pub fn held_for(&self, now: chrono::NaiveDateTime) -> String {
+        let minutes = (now - self.updated_at).num_minutes();
+        if minutes < 1 {
+            return "under a minute".to_string();
+        }
+        let units = [(minutes / (24 * 60), "day"), (minutes / 60 % 24, "hour"), (minutes % 60, "minute")];
+        units
+            .iter()
+            .skip_while(|(count, _)| *count == 0)
+            .take(2)
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, unit)| format!("{} {}{}", count, unit, if *count == 1 { "" } else { "s" }))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Splits a comma separated list of owners, dropping blanks and repeats.
+pub fn parse_owners(owners: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = Vec::new();
+    for owner in owners.split(',').map(str::trim).filter(|owner| !owner.is_empty()) {
+        if !parsed.iter().any(|seen| seen.eq_ignore_ascii_case(owner)) {
+            parsed.push(owner.to_string());
+        }
+    }
+    parsed
+}
+
+/// Joins owners back into the list shown in `device_owner`.
+pub fn format_owners(owners: &[String]) -> Option<String> {
+    if owners.is_empty() {
+        None
+    } else {
+        Some(owners.join(", "))
+    }
+}
+
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -84,7 +147,6 @@ pub struct Device {
     FromForm,
     Validate,
 )]
-#[validate(schema(function = "validate_device_checkout"))]
 pub struct DeviceUpdate {
     pub id: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -96,41 +158,44 @@ pub struct DeviceUpdate {
     pub reservation_status: ReservationStatus,
 }
 
-fn validate_device_checkout(device: &DeviceUpdate) -> Result<(), ValidationError> {
+impl ValidateWithContext for DeviceUpdate {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_device_checkout(self, context)
+    }
+}
+
+fn is_valid_owner(owner: &str, context: &ValidationContext<'_>) -> bool {
+    let directory_user_exists = directory::owner_exists(context.config, owner);
+
+    let is_custom_owner = match database::get_custom_owner(context.config, context.database, owner) {
+        Ok(Some(custom_owner)) => {
+            trace!("User in custom owners: {:?}", custom_owner);
+            debug!("Matched owner '{}' to custom owner '{}'", owner, &custom_owner.custom_owner_name);
+            true
+        },
+        _ => {
+            debug!("Owner '{}' is not in custom owners", owner);
+            false
+        }
+    };
+    let is_local_user = match database::get_user_by_name(context.config, context.database, owner) {
+        Ok(Some(_)) => {
+            debug!("Owner '{}' is a local user", owner);
+            true
+        },
+        _ => false,
+    };
+    directory_user_exists || is_custom_owner || is_local_user
+}
+
+fn validate_device_checkout(device: &DeviceUpdate, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
     if device.reservation_status == ReservationStatus::Reserved {
-        debug!("Validate device (id: {}) reserved - owner is valid", &device.id);
-        match device.device_owner {
-            Some(ref owner) if !owner.trim().is_empty() => {
-                let slack_client = slack::slack_client_init();
-                let slack_user_exists = slack::slack_user_exists(&owner.trim(), &slack_client);
-
-                let mut config = utils::cmdline::parse_cmdline();
-                config.module_path = Some(module_path!().into());
-                let database = database::establish_connection(&config).unwrap();
-                let is_custom_owner = match database::get_custom_owner(&config, &database, &owner.trim()) {
-                    Ok(Some(custom_owner)) => {
-                        trace!("User in custom owners: {:?}", custom_owner);
-                        debug!("Matched owner '{}' to custom owner '{}'", &owner.trim(), &custom_owner.custom_owner_name);
-                        true
-                    },
-                    _ => {
-                        debug!("Owner '{}' is not in custom owners", &owner.trim());
-                        false
-                    }
-                };
-                if slack_user_exists || is_custom_owner {
-                    Ok(())
-                } else {
-                    let mut e = ValidationError::new("reservation");
-                    e.message = Some("Please enter a valid slack username or custom owner when reserving a device.".into());
-                    Err(e)
-                }
-            },
-            _ => {
-                let mut e = ValidationError::new("reservation");
-                e.message = Some("Please supply a username when reserving a device".into());
-                Err(e)
-            }
+        debug!("Validate device (id: {}) reserved - owners are valid", &device.id);
+        let owners = device.device_owner.as_deref().map(parse_owners).unwrap_or_default();
+        validate_owners(&owners, context)?;
+        match database::get_device_by_id(context.config, context.database, device.id) {
+            Ok(Some(current)) => validate_reservation_quota(&owners, &[(current.pool_id, 1)], Some(device.id), context),
+            _ => Ok(()),
         }
     } else {
         debug!("Validate device (id: {}) available", &device.id);
@@ -138,10 +203,148 @@ fn validate_device_checkout(device: &DeviceUpdate) -> Result<(), ValidationError
     }
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+/// Checks there's at least one owner and each is a user, custom owner or known
+/// to the directory.
+pub fn validate_owners(owners: &[String], context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+    if owners.is_empty() {
+        let mut e = ValidationError::new("reservation");
+        e.message = Some("Please supply a username when reserving a device".into());
+        return Err(e);
+    }
+    for owner in owners {
+        if !is_valid_owner(owner, context) {
+            let mut e = ValidationError::new("reservation");
+            e.message = Some(format!("Please enter a valid username or custom owner for '{}' when reserving a device.", owner).into());
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Checks none of the owners go over their quotas by taking the requested
+/// number of devices from each pool. Whatever they already have counts against
+/// them, except `device_id` when it's the device being changed. Custom owners
+/// an admin has marked as unlimited, such as CI accounts, are let through.
+pub fn validate_reservation_quota(
+    owners: &[String],
+    requested: &[(i32, usize)],
+    device_id: Option<i32>,
+    context: &ValidationContext<'_>,
+) -> Result<(), ValidationError> {
+    let quota_error = |message: String| {
+        let mut e = ValidationError::new("reservation");
+        e.message = Some(message.into());
+        e
+    };
+    let mut requested_by_pool = std::collections::BTreeMap::new();
+    for (pool_id, count) in requested {
+        *requested_by_pool.entry(*pool_id).or_insert(0) += count;
+    }
+    let requested_total: usize = requested_by_pool.values().sum();
+    let pools = database::get_pools(context.config, context.database).map_err(|_| ValidationError::new("reservation"))?;
+    for owner in owners {
+        debug!("Validate reservation quota for {}", owner);
+        if let Ok(Some(custom_owner)) = database::get_custom_owner(context.config, context.database, &owner.to_lowercase()) {
+            if custom_owner.unlimited_reservations {
+                continue;
+            }
+        }
+        let held: Vec<Device> = database::get_devices_by_owner(context.config, context.database, owner)
+            .map_err(|_| ValidationError::new("reservation"))?
+            .into_iter()
+            .filter(|held_device| Some(held_device.id) != device_id)
+            .collect();
+        if let Some(max) = context.config.max_reservations_per_owner {
+            if held.len() + requested_total > max {
+                return Err(quota_error(format!(
+                    "{} already has {} devices reserved, and no one can have more than {} at once.",
+                    owner,
+                    held.len(),
+                    max
+                )));
+            }
+        }
+        for (pool_id, count) in &requested_by_pool {
+            let pool = match pools.iter().find(|pool| pool.id == *pool_id) {
+                Some(pool) => pool,
+                None => continue,
+            };
+            if let Some(max) = pool.max_reservations_per_owner {
+                let held_in_pool = held.iter().filter(|held_device| held_device.pool_id == *pool_id).count();
+                if held_in_pool + count > max.max(0) as usize {
+                    return Err(quota_error(format!(
+                        "{} already has {} devices reserved from {}, and no one can have more than {} from it at once.",
+                        owner, held_in_pool, pool.pool_name, max
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands a reserved device from `device_owner` straight to `new_owner`, either
+/// may be a list of co-owners.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, Serialize, Deserialize, FromForm, Validate)]
+pub struct DeviceTransfer {
+    pub id: i32,
+    /// Who had it when the page was loaded, the transfer fails if that changed
+    pub device_owner: String,
+    pub new_owner: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub comments: Option<String>,
+}
+
+impl ValidateWithContext for DeviceTransfer {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        let device_update = DeviceUpdate {
+            id: self.id,
+            device_owner: Some(self.new_owner.clone()),
+            comments: self.comments.clone(),
+            reservation_status: ReservationStatus::Reserved,
+        };
+        validate_device_checkout(&device_update, context)
+    }
+}
+
+/// Returns a device on `device_owner`'s behalf.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, Serialize, Deserialize, FromForm, Validate)]
+pub struct DeviceForceRelease {
+    pub id: i32,
+    /// Who had it when the page was loaded, the release fails if that changed
+    pub device_owner: String,
+    #[validate(length(min = "1", message = "Please give a reason for releasing someone else's device"))]
+    pub reason: String,
+}
+
+/// Devices to give back together from `/my`, only those `device_owner` still
+/// holds are returned.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, Serialize, Deserialize, FromForm)]
+pub struct DevicesReturn {
+    pub device_owner: String,
+    pub ids: Vec<i32>,
+}
+
+/// How a device is given back: by one of its owners, or released from them by
+/// a pool admin.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum DeviceReturn {
+    Owner,
+    Release(DeviceForceRelease),
+}
+
+/// An inventory upload from `/editDevices`, previewed before it's imported.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, Serialize, Deserialize, FromForm)]
+pub struct InventoryImport {
+    pub data: String,
+    /// csv or json
+    pub format: String,
+    /// "preview" to only show what would change
+    pub action: String,
+}
+
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -165,10 +368,7 @@ pub struct DeviceEdit {
     pub pool_id: i32,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Default, Clone, Hash, Serialize, Deserialize, FromForm,
 )]
@@ -176,10 +376,7 @@ pub struct DeviceDelete {
     pub id: i32,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -204,19 +401,177 @@ pub struct DeviceInsert {
     pub pool_id: i32,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Queryable, Serialize, Deserialize)]
+/// Where a change came from.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default, Serialize, Deserialize, DbEnum, FromFormField
+)]
+pub enum ChangeSource {
+    Ui,
+    Api,
+    /// Not from a request, e.g. startup or tests
+    #[default]
+    Internal,
+    /// The admin subcommands
+    Cli,
+}
+
+/// Who is making a change and from where, recorded alongside it and in the
+/// audit log. `actor` is None for anonymous changes.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash)]
+pub struct ChangeContext {
+    pub actor: Option<String>,
+    pub source: ChangeSource,
+    pub source_ip: Option<String>,
+    /// Why, when the change needs explaining
+    pub reason: Option<String>,
+}
+
+impl ChangeContext {
+    pub fn new(actor: Option<String>) -> Self {
+        ChangeContext {
+            actor,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize, DbEnum, FromFormField
+)]
+pub enum AuditAction {
+    Insert,
+    Edit,
+    Delete,
+    /// A device being reserved
+    Claim,
+    /// A device being made available again
+    Return,
+    /// A reserved device handed straight to a new owner
+    Transfer,
+    /// A device returned on its owner's behalf by an admin
+    ForceRelease,
+}
+
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize, DbEnum, FromFormField
+)]
+pub enum AuditEntity {
+    Device,
+    Pool,
+    CustomOwner,
+    User,
+    ApiToken,
+}
+
+/// One change in the audit log, `before` and `after` are the entity as json.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Queryable, Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub entity_type: AuditEntity,
+    pub entity_id: Option<i32>,
+    #[serde(serialize_with = "serialize_json_text")]
+    pub before: Option<String>,
+    #[serde(serialize_with = "serialize_json_text")]
+    pub after: Option<String>,
+    pub source: ChangeSource,
+    pub source_ip: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Serializes json stored as text as the json itself rather than a string.
+fn serialize_json_text<S: serde::Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+    value
+        .as_ref()
+        .and_then(|value| serde_json::from_str::<serde_json::Value>(value).ok())
+        .serialize(serializer)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Insertable)]
+#[table_name = "audit_log"]
+pub struct AuditEntryInsert {
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub entity_type: AuditEntity,
+    pub entity_id: Option<i32>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub source: ChangeSource,
+    pub source_ip: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Which audit log entries to show, newest first.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm, Serialize)]
+pub struct AuditFilter {
+    pub entity_type: Option<AuditEntity>,
+    pub entity_id: Option<i32>,
+    pub actor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
 pub struct Reservation {
     pub id: i32,
     pub device_owner: String,
     pub comments: Option<String>,
+    /// The first of `devices`, for clients that only ever ask for one
     pub device: Device,
+    pub devices: Vec<Device>,
+}
+
+impl Reservation {
+    /// Gathers devices back into the reservations they were made in
+    pub fn from_devices(devices: Vec<Device>) -> Vec<Reservation> {
+        let mut reservations: std::collections::BTreeMap<i32, Vec<Device>> = std::collections::BTreeMap::new();
+        for device in devices {
+            reservations
+                .entry(device.reservation_id.unwrap_or(device.id))
+                .or_default()
+                .push(device);
+        }
+        reservations
+            .into_iter()
+            .map(|(id, devices)| Reservation {
+                id,
+                device_owner: devices[0].device_owner.clone().unwrap_or_default(),
+                comments: devices[0].comments.clone(),
+                device: devices[0].clone(),
+                devices,
+            })
+            .collect()
+    }
 }
 
+/// Asks for a single `device`, a number of `devices` from some pools, or a
+/// `bundle` defined in the settings
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
 pub struct ReservationRequest {
     pub device_owner: Option<String>,
     pub comments: Option<String>,
-    pub device: ReservationRequestDevice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub device: Option<ReservationRequestDevice>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub devices: Vec<ReservationRequestPool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub bundle: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
+pub struct ReservationRequestPool {
+    pub pool_id: i32,
+    #[serde(default = "default_reservation_count")]
+    pub count: usize,
+}
+
+fn default_reservation_count() -> usize {
+    1
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Serialize, Deserialize)]
@@ -256,12 +611,16 @@ pub struct Pool {
     pub description: Option<String>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    /// How many of the pool's devices one owner may have at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max_reservations_per_owner: Option<i32>,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -281,12 +640,10 @@ pub struct PoolModify {
     #[validate(length(min = "1", message = "pool_name cannot be empty"))]
     pub pool_name: String,
     pub description: Option<String>,
+    pub max_reservations_per_owner: Option<i32>,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -308,23 +665,26 @@ pub struct PoolInsert {
     #[validate(length(min = "1", message = "pool_name cannot be empty"))]
     pub pool_name: String,
     pub description: Option<String>,
+    pub max_reservations_per_owner: Option<i32>,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Default, Clone, Hash, Serialize, Deserialize, FromForm, Validate
 )]
-#[validate(schema(function = "validate_pool_delete"))]
 pub struct PoolDelete {
     pub id: i32,
 }
 
-fn validate_pool_delete(pool: &PoolDelete) -> Result<(), ValidationError> {
+impl ValidateWithContext for PoolDelete {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_pool_delete(self, context)
+    }
+}
+
+fn validate_pool_delete(pool: &PoolDelete, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
     debug!("Validate pool (id: {}) delete - not default pool", &pool.id);
-    let ref pool_id = pool.id;
+    let pool_id = &pool.id;
     if *pool_id == 1 {
         let mut e = ValidationError::new("pool");
         e.message = Some("Default pool cannot be deleted".into());
@@ -332,13 +692,10 @@ fn validate_pool_delete(pool: &PoolDelete) -> Result<(), ValidationError> {
     }
 
     // delete allowed only if pool is empty
-    let mut config = utils::cmdline::parse_cmdline();
-    config.module_path = Some(module_path!().into());
-    let database = database::establish_connection(&config).unwrap();
     debug!("Validate pool (id: {}) delete - pool is empty", &pool.id);
-    let pool_devices = database::get_devices_in_pool(&config, &database, *pool_id)
-        .expect("Failed to get pool devices");
-    if pool_devices.iter().count() > 0 {
+    let pool_devices = database::get_devices_in_pool(context.config, context.database, *pool_id)
+        .map_err(|_| ValidationError::new("pool"))?;
+    if !pool_devices.is_empty() {
         let mut e = ValidationError::new("pool");
         e.message = Some("Cannot delete non-empty pool".into());
         return Err(e);
@@ -369,12 +726,15 @@ pub struct CustomOwner {
     pub description: Option<String>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub updated_by: Option<String>,
+    /// Set by admins for accounts like CI that aren't held to the quotas
+    #[serde(default)]
+    pub unlimited_reservations: bool,
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -389,7 +749,6 @@ pub struct CustomOwner {
     FromForm,
     Validate,
 )]
-#[validate(schema(function = "validate_custom_owner_modify"))]
 pub struct CustomOwnerModify {
     pub id: i32,
     #[validate(length(min = "1", message = "custom_owner_name cannot be empty"))]
@@ -397,21 +756,25 @@ pub struct CustomOwnerModify {
     #[validate(length(min = "1", message = "recipient cannot be empty"))]
     pub recipient: String,
     pub description: Option<String>,
+    pub unlimited_reservations: bool,
+}
+
+impl ValidateWithContext for CustomOwnerModify {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_custom_owner_modify(self, context)
+    }
 }
 
-fn validate_custom_owner_modify(custom_owner: &CustomOwnerModify) -> Result<(), ValidationError> {
-    let mut config = utils::cmdline::parse_cmdline();
-    config.module_path = Some(module_path!().into());
-    let database = database::establish_connection(&config).unwrap();
+fn validate_custom_owner_modify(custom_owner: &CustomOwnerModify, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
     // modify name allowed only if no devices reserved
     debug!("Validate custom_owner (id: {}) modify name - not in reserved devices", &custom_owner.id);
-    let custom_owner_rec = database::get_custom_owner_by_id(&config, &database, custom_owner.id);
+    let custom_owner_rec = database::get_custom_owner_by_id(context.config, context.database, custom_owner.id);
     match custom_owner_rec {
         Ok(Some(custom_owner_rec)) => {
             if custom_owner_rec.custom_owner_name.ne(&custom_owner.custom_owner_name) {
-                let custom_owner_devices = database::get_devices_by_owner(&config, &database, &custom_owner_rec.custom_owner_name)
-                    .expect("Failed to get custom_owner devices");
-                if custom_owner_devices.iter().count() > 0 {
+                let custom_owner_devices = database::get_devices_by_owner(context.config, context.database, &custom_owner_rec.custom_owner_name)
+                    .map_err(|_| ValidationError::new("custom_owner"))?;
+                if !custom_owner_devices.is_empty() {
                     let mut e = ValidationError::new("custom_owner");
                     e.message = Some("Cannot modify name of custom_owner with devices reserved".into());
                     return Err(e);
@@ -419,35 +782,30 @@ fn validate_custom_owner_modify(custom_owner: &CustomOwnerModify) -> Result<(),
             }
         },
         Ok(None) => {
-            #[cfg(test)]
-            warn!("custom_owner_rec not found. This is acceptable in unit tests.");
+            let mut e = ValidationError::new("custom_owner");
+            e.message = Some("custom_owner does not exist".into());
+            return Err(e);
         },
         Err(error) => error!("Error occured while retrieving custom_owner_rec: {:?}", error),
     }
     // validate recipient
     debug!("Validate custom_owner (id: {}) modify - recipient: {}", &custom_owner.id, &custom_owner.recipient);
-    let ref recipient = custom_owner.recipient;
-    if recipient.eq_ignore_ascii_case("none".into()) {
+    let recipient = &custom_owner.recipient;
+    if recipient.eq_ignore_ascii_case("none") {
         debug!("Recipient '{}' matches \"none\"", &custom_owner.recipient);
         Ok(())
     } else {
-        let slack_client = slack::slack_client_init();
-        let slack_user_exists = slack::slack_user_exists(&recipient.trim(), &slack_client);
-        let slack_channel_exists = slack::slack_channel_exists(&recipient.trim(), &slack_client);
-        if slack_user_exists || slack_channel_exists {
+        if directory::recipient_exists(context.config, recipient.trim()) {
             Ok(())
         } else {
             let mut e = ValidationError::new("custom_owner");
-            e.message = Some("Recipient must be valid Slack user, Slack channel or \"None\".".into());
+            e.message = Some("Recipient must be valid Slack user, Slack channel, directory user or \"None\".".into());
             Err(e)
         }
     }
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug,
     PartialEq,
@@ -463,7 +821,6 @@ fn validate_custom_owner_modify(custom_owner: &CustomOwnerModify) -> Result<(),
     FromForm,
     Validate,
 )]
-#[validate(schema(function = "validate_custom_owner_insert"))]
 #[table_name = "custom_owners"]
 // We have a separate struct for insert because rocket expects the form to match exactly
 pub struct CustomOwnerInsert {
@@ -472,52 +829,61 @@ pub struct CustomOwnerInsert {
     #[validate(length(min = "1", message = "recipient cannot be empty"))]
     pub recipient: String,
     pub description: Option<String>,
+    pub unlimited_reservations: bool,
+}
+
+impl ValidateWithContext for CustomOwnerInsert {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_custom_owner_insert(self, context)
+    }
 }
 
-fn validate_custom_owner_insert(custom_owner: &CustomOwnerInsert) -> Result<(), ValidationError> {
+fn validate_custom_owner_insert(custom_owner: &CustomOwnerInsert, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
     debug!("Validate custom_owner insert - recipient: {}", &custom_owner.recipient);
-    let ref recipient = custom_owner.recipient;
-    if recipient.eq_ignore_ascii_case("none".into()) {
+    let recipient = &custom_owner.recipient;
+    if recipient.eq_ignore_ascii_case("none") {
         debug!("Recipient '{}' matches \"none\"", &custom_owner.recipient);
         Ok(())
     } else {
-        let slack_client = slack::slack_client_init();
-        let slack_user_exists = slack::slack_user_exists(&recipient.trim(), &slack_client);
-        let slack_channel_exists = slack::slack_channel_exists(&recipient.trim(), &slack_client);
-        if slack_user_exists || slack_channel_exists {
+        if directory::recipient_exists(context.config, recipient.trim()) {
             Ok(())
         } else {
             let mut e = ValidationError::new("custom_owner");
-            e.message = Some("Recipient must be valid Slack user, Slack channel or \"none\".".into());
+            e.message = Some("Recipient must be valid Slack user, Slack channel, directory user or \"none\".".into());
             Err(e)
         }
     }
 }
 
-#[cfg_attr(
-    feature = "cargo-clippy",
-    allow(print_literal, suspicious_else_formatting)
-)]
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Default, Clone, Hash, Serialize, Deserialize, FromForm, Validate
 )]
-#[validate(schema(function = "validate_custom_owner_delete"))]
 pub struct CustomOwnerDelete {
     pub id: i32,
 }
 
-fn validate_custom_owner_delete(custom_owner: &CustomOwnerDelete) -> Result<(), ValidationError> {
-    let mut config = utils::cmdline::parse_cmdline();
-    config.module_path = Some(module_path!().into());
-    let database = database::establish_connection(&config).unwrap();
+impl ValidateWithContext for CustomOwnerDelete {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_custom_owner_delete(self, context)
+    }
+}
+
+fn validate_custom_owner_delete(custom_owner: &CustomOwnerDelete, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
     // delete allowed only if no devices reserved
     debug!("Validate custom_owner (id: {}) delete - not in reserved devices", &custom_owner.id);
-    let ref custom_owner_id = custom_owner.id;
-    let custom_owner_rec = database::get_custom_owner_by_id(&config, &database, *custom_owner_id)
-        .expect("Failed to get custom owner by id").unwrap();
-    let custom_owner_devices = database::get_devices_by_owner(&config, &database, &custom_owner_rec.custom_owner_name)
-        .expect("Failed to get custom_owner devices");
-    if custom_owner_devices.iter().count() > 0 {
+    let custom_owner_id = &custom_owner.id;
+    let custom_owner_rec = match database::get_custom_owner_by_id(context.config, context.database, *custom_owner_id) {
+        Ok(Some(custom_owner_rec)) => custom_owner_rec,
+        _ => {
+            let mut e = ValidationError::new("custom_owner");
+            e.message = Some("custom_owner does not exist".into());
+            return Err(e);
+        }
+    };
+    let custom_owner_devices = database::get_devices_by_owner(context.config, context.database, &custom_owner_rec.custom_owner_name)
+        .map_err(|_| ValidationError::new("custom_owner"))?;
+    if !custom_owner_devices.is_empty() {
         let mut e = ValidationError::new("custom_owner");
         e.message = Some("Cannot delete custom_owner with devices reserved".into());
         return Err(e);
@@ -525,28 +891,297 @@ fn validate_custom_owner_delete(custom_owner: &CustomOwnerDelete) -> Result<(),
     Ok(())
 }
 
+// users
+
+//never serialized, so the password hash can't end up in a page or the api
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Identifiable, Queryable)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+    pub role: UserRole,
+    /// The identity provider account they log in with, if any
+    pub oidc_issuer: Option<String>,
+    pub oidc_subject: Option<String>,
+}
+
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm, Validate)]
+pub struct UserRegister {
+    #[validate(length(min = "1", message = "Usernames cannot be empty"))]
+    pub username: String,
+    #[validate(length(min = "8", message = "Passwords must be at least 8 characters"))]
+    pub password: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm)]
+pub struct UserLogin {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Insertable)]
+#[table_name = "users"]
+pub struct UserInsert {
+    pub username: String,
+    pub password_hash: String,
+    pub role: UserRole,
+    pub oidc_issuer: Option<String>,
+    pub oidc_subject: Option<String>,
+}
+
+/// A user as shown on the user admin page
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub role: UserRole,
+    pub pool_ids: Vec<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm, Validate)]
+pub struct UserModify {
+    pub id: i32,
+    pub role: UserRole,
+    /// Only kept for pool admins
+    pub pool_ids: Vec<i32>,
+}
+
+impl ValidateWithContext for UserModify {
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+        validate_user_modify(self, context)
+    }
+}
+
+fn validate_user_modify(user: &UserModify, context: &ValidationContext<'_>) -> Result<(), ValidationError> {
+    debug!("Validate user (id: {}) modify - not the last admin", &user.id);
+    let users = database::get_users(context.config, context.database)
+        .map_err(|_| ValidationError::new("user"))?;
+    let user_rec = match users.iter().find(|u| u.id == user.id) {
+        Some(user_rec) => user_rec,
+        None => {
+            let mut e = ValidationError::new("user");
+            e.message = Some("user does not exist".into());
+            return Err(e);
+        }
+    };
+    let admins = users.iter().filter(|u| u.role == UserRole::Admin).count();
+    if user_rec.role == UserRole::Admin && user.role != UserRole::Admin && admins == 1 {
+        let mut e = ValidationError::new("user");
+        e.message = Some("There must always be at least one admin".into());
+        return Err(e);
+    }
+    Ok(())
+}
+
+// api tokens
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Identifiable, Queryable, Serialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Insertable)]
+#[table_name = "api_tokens"]
+pub struct ApiTokenInsert {
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+}
+
+#[allow(clippy::print_literal, clippy::suspicious_else_formatting)]
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm, Validate)]
+pub struct ApiTokenCreate {
+    #[validate(length(min = "1", message = "Token names cannot be empty"))]
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Default, Clone, Hash, FromForm)]
+pub struct ApiTokenDelete {
+    pub id: i32,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(feature = "sqlite")]
+    use crate::utils;
+
+    #[cfg(feature = "sqlite")]
+    fn test_database() -> (tempfile::NamedTempFile, utils::types::Settings, database::DbConn) {
+        let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+        let mut config = utils::types::Settings::new();
+        config.database_url = file.path().to_string_lossy().to_string();
+        database::run_migrations(&config).expect("running migrations");
+        let database = database::establish_connection(&config).expect("connecting to database");
+        (file, config, database)
+    }
 
     #[test]
+    #[cfg(feature = "sqlite")]
     fn test_device_update_validation() {
+        let (_file, config, database) = test_database();
+        let context = ValidationContext::new(&config, &database);
         let mut device = DeviceUpdate {
             id: 3,
             device_owner: None,
             comments: None,
             reservation_status: ReservationStatus::Available,
         };
-        assert!(device.validate().is_ok()); // empty fields valid if device being returned
+        assert!(device.validate_with_context(&context).is_ok()); // empty fields valid if device being returned
         device.reservation_status = ReservationStatus::Reserved;
-        assert!(device.validate().is_err()); // empty device_owner not ok
+        assert!(device.validate_with_context(&context).is_err()); // empty device_owner not ok
         device.device_owner = Some("fake_user".into());
-        assert!(device.validate().is_err()); // invalid slack user not ok
+        assert!(device.validate_with_context(&context).is_err()); // invalid slack user not ok
         device.device_owner = Some("slack_user".into());
-        assert!(device.validate().is_ok()); // slack user valid
-        // TODO custom_owner db entry required
-        // device.device_owner = Some("custom1".into());
-        // assert!(device.validate().is_ok()); // custom owner valid
+        assert!(device.validate_with_context(&context).is_ok()); // slack user valid
+        device.device_owner = Some("custom1".into());
+        assert!(device.validate_with_context(&context).is_err()); // unknown custom owner not ok
+        let custom_owner = CustomOwnerInsert {
+            custom_owner_name: "custom1".into(),
+            recipient: "none".into(),
+            description: None,
+            unlimited_reservations: false,
+        };
+        database::insert_custom_owner(&config, &database, &custom_owner, &ChangeContext::default()).unwrap();
+        assert!(device.validate_with_context(&context).is_ok()); // custom owner valid
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_pool_delete_validation() {
+        let (_file, config, database) = test_database();
+        let context = ValidationContext::new(&config, &database);
+        let mut pool = PoolDelete { id: 1 };
+        assert!(pool.validate_with_context(&context).is_err()); // default pool not ok
+        let pool_insert = PoolInsert {
+            pool_name: "custom1".into(),
+            description: None,
+            max_reservations_per_owner: None,
+        };
+        database::insert_pool(&config, &database, &pool_insert, &ChangeContext::default()).unwrap();
+        pool.id = 2;
+        assert!(pool.validate_with_context(&context).is_ok()); // empty pool ok
+        let device = DeviceInsert {
+            device_name: "unit3".into(),
+            device_url: "http://unit3".into(),
+            pool_id: 2,
+        };
+        database::insert_device(&config, &database, &device, &ChangeContext::default()).unwrap();
+        assert!(pool.validate_with_context(&context).is_err()); // non-empty pool not ok
+    }
+
+    #[test]
+    fn test_parse_owners() {
+        assert_eq!(parse_owners("alice"), vec!["alice"]);
+        assert_eq!(parse_owners(" alice, bob ,,Alice"), vec!["alice", "bob"]);
+        assert!(parse_owners(" , ").is_empty());
+        assert_eq!(format_owners(&parse_owners("alice,bob")), Some("alice, bob".to_string()));
+        assert_eq!(format_owners(&[]), None);
+    }
+
+    #[test]
+    fn test_held_for() {
+        let claimed = chrono::NaiveDate::from_ymd_opt(2022, 5, 16).unwrap().and_hms_opt(9, 30, 0).unwrap();
+        let device = Device {
+            id: 1,
+            device_name: "unit1".into(),
+            device_url: None,
+            device_owner: Some("alice".into()),
+            comments: None,
+            reservation_status: ReservationStatus::Reserved,
+            created_at: claimed,
+            updated_at: claimed,
+            pool_id: 1,
+            updated_by: None,
+            reservation_id: None,
+        };
+        let after = |minutes| claimed + chrono::Duration::minutes(minutes);
+        assert_eq!(device.held_for(after(0)), "under a minute");
+        assert_eq!(device.held_for(after(1)), "1 minute");
+        assert_eq!(device.held_for(after(65)), "1 hour 5 minutes");
+        assert_eq!(device.held_for(after(2 * 24 * 60 + 3 * 60 + 59)), "2 days 3 hours");
+        assert_eq!(device.held_for(after(24 * 60 + 5)), "1 day");
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_shared_reservations() {
+        let (_file, config, database) = test_database();
+        let mut device = DeviceUpdate {
+            id: 1,
+            device_owner: Some("alice,Bob".into()),
+            comments: None,
+            reservation_status: ReservationStatus::Reserved,
+        };
+        let change = ChangeContext::default();
+        database::update_device(&config, &database, &device, ReservationStatus::Available, &change).unwrap();
+        let reserved = database::get_device_by_id(&config, &database, 1).unwrap().unwrap();
+        assert_eq!(reserved.device_owner, Some("alice, Bob".into()));
+        assert!(reserved.is_owned_by("Bob"));
+        assert!(reserved.is_owned_by("bob"));
+        assert!(!reserved.is_owned_by("carol"));
+        assert_eq!(database::get_devices_by_owner(&config, &database, "bob").unwrap().len(), 1);
+        assert_eq!(database::get_devices_by_owner(&config, &database, "alice").unwrap().len(), 1);
+        assert!(database::get_devices_by_owner(&config, &database, "alice, Bob").unwrap().is_empty());
+
+        device.device_owner = None;
+        device.reservation_status = ReservationStatus::Available;
+        database::update_device(&config, &database, &device, ReservationStatus::Reserved, &change).unwrap();
+        assert!(database::get_devices_by_owner(&config, &database, "bob").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_reservation_quota() {
+        let (_file, mut config, database) = test_database();
+        let change = ChangeContext::default();
+        let reserve = |id, owner: &str| DeviceUpdate {
+            id,
+            device_owner: Some(owner.into()),
+            comments: None,
+            reservation_status: ReservationStatus::Reserved,
+        };
+        let custom_owner = CustomOwnerInsert {
+            custom_owner_name: "ci".into(),
+            recipient: "none".into(),
+            description: None,
+            unlimited_reservations: true,
+        };
+        database::insert_custom_owner(&config, &database, &custom_owner, &change).unwrap();
+        database::update_device(&config, &database, &reserve(1, "slack_user"), ReservationStatus::Available, &change).unwrap();
+        let owners = vec!["slack_user".to_string()];
+        let context = ValidationContext::new(&config, &database);
+        assert!(validate_reservation_quota(&owners, &[(1, 5)], None, &context).is_ok()); // no limits by default
+
+        config.max_reservations_per_owner = Some(1);
+        let context = ValidationContext::new(&config, &database);
+        assert!(validate_reservation_quota(&owners, &[(1, 1)], None, &context).is_err());
+        assert!(validate_reservation_quota(&owners, &[(1, 1)], Some(1), &context).is_ok()); // the device being changed
+        assert!(reserve(2, "ci, slack_user").validate_with_context(&context).is_err());
+        assert!(reserve(1, "ci, slack_user").validate_with_context(&context).is_ok()); // adding a co-owner
+        assert!(validate_reservation_quota(&["CI".to_string()], &[(1, 3)], None, &context).is_ok()); // unlimited
+
+        config.max_reservations_per_owner = None;
+        let pool = PoolModify {
+            id: 1,
+            pool_name: "Default Pool".into(),
+            description: None,
+            max_reservations_per_owner: Some(1),
+        };
+        database::edit_pool(&config, &database, &pool, &change).unwrap();
+        let context = ValidationContext::new(&config, &database);
+        let error = reserve(2, "slack_user").validate_context(&context).unwrap_err();
+        assert!(error.message.unwrap().contains("from Default Pool"));
+        assert!(validate_reservation_quota(&owners, &[(2, 1)], None, &context).is_ok()); // other pools aren't limited
     }
 
     #[test]
@@ -585,6 +1220,7 @@ mod test {
         let mut pool = PoolInsert {
             pool_name: "custom1".into(),
             description: Some("test description".into()),
+            max_reservations_per_owner: None,
         };
         assert!(pool.validate().is_ok()); // normal case ok
         pool.description = Some("".into());
@@ -599,6 +1235,7 @@ mod test {
             id: 0,
             pool_name: "custom1".into(),
             description: Some("test description".into()),
+            max_reservations_per_owner: None,
         };
         assert!(pool.validate().is_ok()); // normal case is ok
         pool.description = Some("".into());
@@ -608,55 +1245,72 @@ mod test {
     }
 
     #[test]
+    #[cfg(feature = "sqlite")]
     fn test_custom_owner_insert_validation() {
+        let (_file, config, database) = test_database();
+        let context = ValidationContext::new(&config, &database);
         let mut custom_owner = CustomOwnerInsert {
             custom_owner_name: "custom1".into(),
             recipient: "slack_channel".into(),
             description: Some("description".into()),
+            unlimited_reservations: false,
         };
-        assert!(custom_owner.validate().is_ok()); // slack channel recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // slack channel recipient is ok
         custom_owner.recipient = "slack_user".into();
-        assert!(custom_owner.validate().is_ok()); // slack user recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // slack user recipient is ok
         custom_owner.recipient = "none".into();
-        assert!(custom_owner.validate().is_ok()); // "none" recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // "none" recipient is ok
         custom_owner.custom_owner_name = "".into();
-        assert!(custom_owner.validate().is_err()); // empty name not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // empty name not ok
         custom_owner.custom_owner_name = "test".into();
         custom_owner.recipient = "".into();
-        assert!(custom_owner.validate().is_err()); // empty recipient not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // empty recipient not ok
         custom_owner.recipient = "not_real".into();
-        assert!(custom_owner.validate().is_err()); // invalid recipient not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // invalid recipient not ok
         custom_owner.recipient = "slack_channel".into();
         custom_owner.description = Some("".into());
-        assert!(custom_owner.validate().is_ok()); // empty description is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // empty description is ok
         custom_owner.description = None;
-        assert!(custom_owner.validate().is_ok()); // None description is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // None description is ok
     }
 
     #[test]
+    #[cfg(feature = "sqlite")]
     fn test_custom_owner_edit_validation() {
+        let (_file, config, database) = test_database();
+        let context = ValidationContext::new(&config, &database);
+        let custom_owner_insert = CustomOwnerInsert {
+            custom_owner_name: "custom1".into(),
+            recipient: "none".into(),
+            description: None,
+            unlimited_reservations: false,
+        };
+        database::insert_custom_owner(&config, &database, &custom_owner_insert, &ChangeContext::default()).unwrap();
         let mut custom_owner = CustomOwnerModify {
-            id: 0,
+            id: 1,
             custom_owner_name: "custom1".into(),
             recipient: "slack_channel".into(),
             description: Some("description".into()),
+            unlimited_reservations: false,
         };
-        assert!(custom_owner.validate().is_ok()); // slack channel recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // slack channel recipient is ok
         custom_owner.recipient = "slack_user".into();
-        assert!(custom_owner.validate().is_ok()); // slack user recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // slack user recipient is ok
         custom_owner.recipient = "none".into();
-        assert!(custom_owner.validate().is_ok()); // "none" recipient is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // "none" recipient is ok
         custom_owner.custom_owner_name = "".into();
-        assert!(custom_owner.validate().is_err()); // empty name not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // empty name not ok
         custom_owner.custom_owner_name = "test".into();
         custom_owner.recipient = "".into();
-        assert!(custom_owner.validate().is_err()); // empty recipient not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // empty recipient not ok
         custom_owner.recipient = "not_real".into();
-        assert!(custom_owner.validate().is_err()); // invalid recipient not ok
+        assert!(custom_owner.validate_with_context(&context).is_err()); // invalid recipient not ok
         custom_owner.recipient = "slack_channel".into();
         custom_owner.description = Some("".into());
-        assert!(custom_owner.validate().is_ok()); // empty description is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // empty description is ok
         custom_owner.description = None;
-        assert!(custom_owner.validate().is_ok()); // None description is ok
+        assert!(custom_owner.validate_with_context(&context).is_ok()); // None description is ok
+        custom_owner.id = 9000;
+        assert!(custom_owner.validate_with_context(&context).is_err()); // unknown custom owner not ok
     }
 }
@@ -1,31 +1,77 @@
 use diesel;
 use rocket;
+use crate::database;
 use crate::utils;
 
+#[cfg(feature = "sqlite")]
+use diesel::connection::SimpleConnection;
 use rocket::request::FromRequest;
-use std::ops::Deref;
 use rocket::outcome::Outcome;
 use rocket::request;
+use std::sync::Arc;
+
+pub type DbPool = diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<database::DbConn>>;
+
+/// Sets up every pooled connection so concurrent requests can share the database:
+/// WAL lets readers carry on while a write is in progress, the busy timeout makes
+/// writers wait for each other rather than failing with SQLITE_BUSY, and sqlite
+/// only enforces foreign keys when asked to.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct ConnectionCustomizer {
+    busy_timeout_ms: u32,
+}
+
+#[cfg(feature = "sqlite")]
+impl diesel::r2d2::CustomizeConnection<diesel::sqlite::SqliteConnection, diesel::r2d2::Error>
+    for ConnectionCustomizer
+{
+    fn on_acquire(
+        &self,
+        conn: &mut diesel::sqlite::SqliteConnection,
+    ) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;",
+            self.busy_timeout_ms
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
 
 /// Initializes a database pool.
-pub fn init_pool(
-    config: &utils::types::Settings,
-) -> diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::sqlite::SqliteConnection>> {
+pub fn init_pool(config: &utils::types::Settings) -> DbPool {
     let database_url = config.database_url.clone();
-    let manager =
-        diesel::r2d2::ConnectionManager::<diesel::sqlite::SqliteConnection>::new(database_url);
-    diesel::r2d2::Pool::builder()
-        .max_size(1)
-        .build(manager)
-        .expect("Database pool")
+    let manager = diesel::r2d2::ConnectionManager::<database::DbConn>::new(database_url);
+    let builder = diesel::r2d2::Pool::builder().max_size(config.pool_size);
+    #[cfg(feature = "sqlite")]
+    let builder = builder.connection_customizer(Box::new(ConnectionCustomizer {
+        busy_timeout_ms: config.busy_timeout_ms,
+    }));
+    builder.build(manager).expect("Database pool")
 }
 
-// Connection request guard type: a wrapper around an r2d2 pooled connection.
-pub struct DbConn(
-    pub  diesel::r2d2::PooledConnection<
-        diesel::r2d2::ConnectionManager<diesel::sqlite::SqliteConnection>,
-    >,
-);
+/// Connection request guard type: a pooled connection that can only be used
+/// through `run`, which does the work on tokio's blocking thread pool so slow
+/// queries (and Slack or LDAP lookups during validation) never stall Rocket's
+/// async workers.
+pub struct DbConn {
+    connection: diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<database::DbConn>>,
+    config: Arc<utils::types::Settings>,
+}
+
+impl DbConn {
+    /// Runs `f` with the settings and the connection on a blocking thread and
+    /// waits for the result. The connection goes back to the pool when it's done.
+    pub async fn run<F, R>(self, f: F) -> R
+    where
+        F: FnOnce(&utils::types::Settings, &database::DbConn) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        rocket::tokio::task::spawn_blocking(move || f(&self.config, &self.connection))
+            .await
+            .expect("database task panicked")
+    }
+}
 
 /// Attempts to retrieve a single connection from the managed database pool. If
 /// no pool is currently managed, fails with an `InternalServerError` status. If
@@ -37,23 +83,20 @@ impl<'r> FromRequest<'r> for DbConn {
     async fn from_request(
         request: &'r request::Request<'_>,
     ) -> request::Outcome<Self, ()> {
-        let pool = request.guard::<&'r rocket::State<diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::sqlite::SqliteConnection>>>>().await;
+        let pool = match request.guard::<&'r rocket::State<DbPool>>().await {
+            Outcome::Success(pool) => pool.inner().clone(),
+            _ => return Outcome::Error((rocket::http::Status::InternalServerError, ())),
+        };
+        // shared rather than cloned, the work may outlive the request
+        let config = match request.rocket().state::<Arc<utils::types::Settings>>() {
+            Some(config) => config.clone(),
+            None => return Outcome::Error((rocket::http::Status::InternalServerError, ())),
+        };
 
-        if let Outcome::Success(pool) = pool {
-            if let Ok(conn) = pool.get() {
-                return Outcome::Success(DbConn(conn));
-            }
+        // waiting for a free connection blocks, so do that off the async workers too
+        match rocket::tokio::task::spawn_blocking(move || pool.get()).await {
+            Ok(Ok(connection)) => Outcome::Success(DbConn { connection, config }),
+            _ => Outcome::Error((rocket::http::Status::ServiceUnavailable, ())),
         }
-
-        return Outcome::Failure((rocket::http::Status::ServiceUnavailable, ()));
-    }
-}
-
-// For the convenience of using an &DbConn as an &SqliteConnection.
-impl Deref for DbConn {
-    type Target = diesel::sqlite::SqliteConnection;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
     }
 }
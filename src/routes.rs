@@ -1,30 +1,42 @@
-#![cfg_attr(feature = "cargo-clippy", allow(print_literal))]
+#![allow(clippy::print_literal)]
+//Handlers answer with a flash redirect on error, which is large but cheap next to a request
+#![allow(clippy::result_large_err)]
 
 use chrono;
 use chrono::Offset;
+use crate::auth;
+use crate::csrf;
 use crate::database;
+use crate::inventory;
 use failure;
 use crate::models;
 use crate::pool;
-use rocket_dyn_templates;
+use crate::templates;
 use std;
-use failure::{Error, Fail};
-use rocket::form::Form;
 use rocket::serde::json;
 use crate::utils;
+use crate::validation::{find_first_validation_message, ValidateWithContext, ValidationContext};
 use validator;
 use validator::Validate;
-use crate::models::{CustomOwner, Device, Pool};
+use crate::models::Device;
 
 pub fn html_routes() -> Vec<rocket::Route> {
     routes![
         self::index,
         self::get_devices,
         self::post_devices,
+        self::post_update_reservations,
+        self::post_transfer_devices,
+        self::post_release_devices,
+        self::get_my_devices,
+        self::get_owner_devices,
+        self::post_return_devices,
         self::get_edit_devices,
         self::post_edit_devices,
         self::post_add_devices,
         self::post_delete_devices,
+        self::get_export_inventory,
+        self::post_import_inventory,
         self::get_edit_pools,
         self::post_edit_pools,
         self::post_add_pools,
@@ -33,9 +45,31 @@ pub fn html_routes() -> Vec<rocket::Route> {
         self::post_edit_custom_owners,
         self::post_add_custom_owners,
         self::post_delete_custom_owners,
+        self::get_login,
+        self::post_login,
+        self::get_login_oidc,
+        self::get_login_oidc_callback,
+        self::post_logout,
+        self::get_register,
+        self::post_register,
+        self::get_edit_users,
+        self::post_edit_users,
+        self::get_settings,
+        self::post_add_api_tokens,
+        self::post_delete_api_tokens,
+        self::get_audit,
+        self::get_static,
     ]
 }
 
+pub fn api_catchers() -> Vec<rocket::Catcher> {
+    catchers![self::api_error]
+}
+
+pub fn html_catchers() -> Vec<rocket::Catcher> {
+    catchers![self::unauthorized, self::forbidden]
+}
+
 pub fn api_routes() -> Vec<rocket::Route> {
     routes![
         self::api_get_device,
@@ -43,130 +77,245 @@ pub fn api_routes() -> Vec<rocket::Route> {
         self::api_get_pools,
         self::api_get_custom_owner,
         self::api_get_custom_owners,
+        self::api_get_reservations,
         self::api_post_reservations,
         self::api_delete_reservation,
+        self::api_get_audit,
     ]
 }
 
+///A redirect to one of our own pages, under `--base-path`
+fn redirect(config: &utils::types::Settings, path: &str) -> rocket::response::Redirect {
+    rocket::response::Redirect::to(format!("{}{}", config.base_path, path))
+}
+
+#[get("/static/<path..>")]
+pub fn get_static(
+    config: &rocket::State<utils::types::Settings>,
+    path: std::path::PathBuf,
+) -> Option<(rocket::http::ContentType, Vec<u8>)> {
+    trace!("get_static()");
+    templates::static_file(config, &path)
+}
+
 #[get("/")]
-pub fn index() -> rocket::response::Redirect {
+pub fn index(config: &rocket::State<utils::types::Settings>) -> rocket::response::Redirect {
     trace!("index()");
-    rocket::response::Redirect::to("/devices")
+    redirect(config, "/devices")
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/devices/<name>")]
-pub fn api_get_device(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_get_device(
     database: pool::DbConn,
     name: String,
 ) -> Result<json::Json<models::Device>, rocket::response::status::Custom<String>> {
     trace!("api_get_device()");
-    database::get_device(&*config, &*database, &name)
-        .map_err(|_| {
-            rocket::response::status::Custom(
-                rocket::http::Status::InternalServerError,
-                "500 Internal Server Error".to_string(),
-            )
-        })
-        .and_then(|devices| {
-            devices.ok_or_else(|| {
-                rocket::response::status::Custom(
-                    rocket::http::Status::NotFound,
-                    "404 Not Found".to_string(),
-                )
-            })
+    database
+        .run(move |config, database| {
+            database::get_device(config, database, &name)
+                .map_err(|_| {
+                    rocket::response::status::Custom(
+                        rocket::http::Status::InternalServerError,
+                        "500 Internal Server Error".to_string(),
+                    )
+                })
+                .and_then(|devices| {
+                    devices.ok_or_else(|| {
+                        rocket::response::status::Custom(
+                            rocket::http::Status::NotFound,
+                            "404 Not Found".to_string(),
+                        )
+                    })
+                })
+                .map(json::Json)
         })
-        .map(json::Json)
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/devices")]
-pub fn api_get_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_get_devices(
     database: pool::DbConn,
 ) -> Result<json::Json<Vec<Device>>, String> {
     trace!("api_get_devices()");
-    return match database::get_devices(&*config, &*database) {
-        Ok(devices) => {
-            Ok(json::Json(devices))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
-    };
+    database
+        .run(move |config, database| {
+            match database::get_devices(config, database) {
+                Ok(devices) => {
+                    Ok(json::Json(devices))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+/// The reservations the token's user holds, alone or shared
+#[get("/reservations")]
+pub async fn api_get_reservations(
+    database: pool::DbConn,
+    user: auth::ApiUser,
+) -> Result<json::Json<Vec<models::Reservation>>, rocket::http::Status> {
+    trace!("api_get_reservations()");
+    database
+        .run(move |config, database| {
+            database::get_devices_by_owner(config, database, &user.username)
+                .map(|devices| json::Json(models::Reservation::from_devices(devices)))
+                .map_err(|_| rocket::http::Status::InternalServerError)
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
 #[post("/reservations", format = "application/json", data = "<reservation>")]
-pub fn api_post_reservations(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_post_reservations(
     database: pool::DbConn,
+    user: auth::ApiUser,
+    change: models::ChangeContext,
     reservation: json::Json<models::ReservationRequest>,
-) -> Result<json::Json<models::Reservation>, rocket::http::Status> {
+) -> Result<json::Json<models::Reservation>, (rocket::http::Status, json::Json<json::Value>)> {
     trace!("api_post_reservations");
-    // Find an available device from the pool specified
-    let available_device =
-        database::get_available_device_from_pool(&*config, &*database, &reservation.device.pool_id)
-            .map_err(|_| rocket::http::Status::InternalServerError)
-            .and_then(|devices| devices.ok_or_else(|| rocket::http::Status::NotFound))?;
-    // Set the device as reserved
-    let update_reserved_device = models::DeviceUpdate {
-        id: available_device.id,
-        device_owner: reservation.device_owner.clone(),
-        comments: reservation.comments.clone(),
-        reservation_status: models::ReservationStatus::Reserved,
-    };
-    let updated_device = match database::update_device(
-        &*config,
-        &*database,
-        &update_reserved_device,
-        models::ReservationStatus::Available,
-    ) {
-        Ok(0) | Err(_) => Err(rocket::http::Status::InternalServerError),
-        _ => database::get_device(&*config, &*database, &available_device.device_name)
-            .map_err(|_| rocket::http::Status::InternalServerError)
-            .and_then(|devices| devices.ok_or_else(|| rocket::http::Status::NotFound)),
-    }?;
-    // Return a reservation response with the reserved device
-    let reservation_response = models::Reservation {
-        id: updated_device.id.clone(),
-        device: updated_device,
-        device_owner: reservation.device_owner.clone().unwrap(),
-        comments: reservation.comments.clone(),
-    };
-    Ok(json::Json(reservation_response))
+    if user.role < models::UserRole::User {
+        return Err(api_error_response(rocket::http::Status::Forbidden, None));
+    }
+    // Reserve for the token's owner unless told otherwise, and only admins can
+    // leave them off the owners, so every reservation is someone's
+    let device_owner = reservation
+        .device_owner
+        .clone()
+        .unwrap_or_else(|| user.username.clone());
+    if !user.can_reserve_for(&device_owner) {
+        return Err(api_error_response(
+            rocket::http::Status::Forbidden,
+            Some("Only admins can reserve devices for someone else"),
+        ));
+    }
+    database
+        .run(move |config, database| {
+            let status_error = |status| api_error_response(status, None);
+            // Work out how many devices are wanted from which pools
+            let requested_pools = match (&reservation.device, reservation.devices.is_empty(), &reservation.bundle) {
+                (Some(device), true, None) => vec![(device.pool_id, 1)],
+                (None, false, None) => reservation
+                    .devices
+                    .iter()
+                    .map(|pool| (pool.pool_id, pool.count))
+                    .collect(),
+                (None, true, Some(bundle)) => {
+                    let bundle = config
+                        .bundles
+                        .iter()
+                        .find(|definition| &definition.name == bundle)
+                        .ok_or_else(|| status_error(rocket::http::Status::NotFound))?;
+                    let pools = database::get_pools(config, database)
+                        .map_err(|_| status_error(rocket::http::Status::InternalServerError))?;
+                    bundle
+                        .pools
+                        .iter()
+                        .map(|(pool_name, count)| match pools.iter().find(|pool| &pool.pool_name == pool_name) {
+                            Some(pool) => Ok((pool.id, *count)),
+                            None => {
+                                error!("Bundle {} names a pool that doesn't exist: {}", bundle.name, pool_name);
+                                Err(status_error(rocket::http::Status::InternalServerError))
+                            }
+                        })
+                        .collect::<Result<Vec<_>, _>>()?
+                }
+                _ => return Err(status_error(rocket::http::Status::BadRequest)),
+            };
+            if requested_pools.iter().any(|(_, count)| *count == 0) {
+                return Err(status_error(rocket::http::Status::BadRequest));
+            }
+            let owners = models::parse_owners(&device_owner);
+            let context = ValidationContext::new(config, database);
+            let validation_error = |e: &validator::ValidationError| {
+                api_error_response(rocket::http::Status::UnprocessableEntity, e.message.as_deref())
+            };
+            models::validate_owners(&owners, &context).map_err(|e| validation_error(&e))?;
+            // Reserve them all, or nothing if any pool is short, checking the
+            // quotas as we claim so two requests can't both squeeze under them
+            let reserved_devices = database::reserve_devices_from_pools(
+                config,
+                database,
+                &requested_pools,
+                Some(device_owner.clone()),
+                reservation.comments.clone(),
+                &change,
+                || models::validate_reservation_quota(&owners, &requested_pools, None, &context),
+            )
+            .map_err(|error| match error.downcast_ref::<validator::ValidationError>() {
+                Some(e) => validation_error(e),
+                None => status_error(rocket::http::Status::InternalServerError),
+            })
+            .and_then(|devices| devices.ok_or_else(|| status_error(rocket::http::Status::NotFound)))?;
+            // Return a reservation response with the reserved devices
+            let first_device = reserved_devices[0].clone();
+            let reservation_response = models::Reservation {
+                id: first_device.reservation_id.unwrap_or(first_device.id),
+                device: first_device,
+                devices: reserved_devices,
+                device_owner,
+                comments: reservation.comments.clone(),
+            };
+            Ok(json::Json(reservation_response))
+        })
+        .await
 }
 
 #[delete("/reservations/<id>")]
-pub fn api_delete_reservation(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_delete_reservation(
     database: pool::DbConn,
+    user: auth::ApiUser,
+    change: models::ChangeContext,
     id: i32,
 ) -> rocket::http::Status {
     trace!("api_delete_reservation()");
-    /* For now the id of a reservation is the id of the device. */
-    if let Ok(None) = database::get_device_by_id(&*config, &*database, id) {
-        return rocket::http::Status::NotFound;
+    if user.role < models::UserRole::User {
+        return rocket::http::Status::Forbidden;
     }
+    database
+        .run(move |config, database| {
+            /* A reservation is known by the id of the first device in it. */
+            if let Ok(None) = database::get_device_by_id(config, database, id) {
+                return rocket::http::Status::NotFound;
+            }
+            let update_result = database::release_reservation(config, database, id, &change, |device| {
+                device_return(&user, device, "released through the api")
+            });
+
+            match update_result {
+                Ok(None) => rocket::http::Status::Forbidden,
+                Ok(Some(returned)) if returned.is_empty() => rocket::http::Status::BadRequest,
+                Err(_) => rocket::http::Status::InternalServerError,
+                Ok(Some(returned)) => {
+                    for (device, device_return) in &returned {
+                        if let models::DeviceReturn::Release(release) = device_return {
+                            notify_released(config, database, &user, device, release);
+                        }
+                    }
+                    rocket::http::Status::NoContent
+                }
+            }
+        })
+        .await
+}
 
-    let device_update = models::DeviceUpdate {
-        id: id,
-        reservation_status: models::ReservationStatus::Available,
-        comments: None,
-        device_owner: None,
-    };
-    let update_result = database::update_device(
-        &*config,
-        &*database,
-        &device_update,
-        models::ReservationStatus::Reserved,
-    );
+/// An owned copy of the request's flash message, so it can be moved onto the
+/// blocking thread that builds the page context.
+struct StatusMessage {
+    kind: String,
+    message: String,
+}
 
-    match update_result {
-        Ok(0) => rocket::http::Status::BadRequest,
-        Err(_) => rocket::http::Status::InternalServerError,
-        _ => rocket::http::Status::NoContent,
+impl<'a> From<rocket::request::FlashMessage<'a>> for StatusMessage {
+    fn from(status_message: rocket::request::FlashMessage<'a>) -> Self {
+        StatusMessage {
+            kind: status_message.kind().to_string(),
+            message: status_message.message().to_string(),
+        }
     }
 }
 
@@ -175,51 +324,78 @@ struct PerDeviceContext {
     device: models::Device,
     is_reserved: bool,
     updated_at_local: String,
+    can_update: bool,
+    can_release: bool,
 }
 
 #[derive(Serialize, Default)]
-struct DevicesContext<'a> {
+struct DevicesContext {
     devices: Vec<PerDeviceContext>,
     pools: Vec<models::Pool>,
     current_pool: Option<models::Pool>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    error_message: Option<&'a str>,
+    current_user: Option<String>,
+    can_reserve: bool,
+    can_edit_devices: bool,
+    is_admin: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    success_message: Option<&'a str>,
+    error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    import_preview: Option<InventoryImportPreview>,
 }
 
-fn format_device(device: models::Device) -> PerDeviceContext {
+/// What an uploaded inventory would change, with the upload to import it for real
+#[derive(Serialize)]
+struct InventoryImportPreview {
+    changes: Vec<String>,
+    data: String,
+    format: String,
+}
+
+///A time from the database, which is in UTC, in the server's time zone
+fn format_local_time(time: chrono::NaiveDateTime) -> String {
+    let local = chrono::DateTime::<chrono::Local>::from_naive_utc_and_offset(time, chrono::Local::now().offset().fix());
+    format!("{}", local.format("%F %r"))
+}
+
+fn format_device(device: models::Device, current_user: Option<&auth::CurrentUser>) -> PerDeviceContext {
     let is_reserved = device.reservation_status == models::ReservationStatus::Reserved;
-    let updated_at_local = chrono::DateTime::<chrono::Local>::from_utc(
-        device.updated_at,
-        chrono::Local::now().offset().fix(),
-    );
     trace!("format_device");
 
-    let updated_at_local = format!("{}", updated_at_local.format("%F %r"));
+    let updated_at_local = format_local_time(device.updated_at);
+    // owners can hand their devices on, pool admins can move or release anyone's
+    let manages_pool = current_user.is_some_and(|user| user.can_manage_pool(device.pool_id));
+    let is_owner = current_user.is_some_and(|user| {
+        user.role >= models::UserRole::User && device.is_owned_by(&user.username)
+    });
     PerDeviceContext {
+        can_update: is_reserved && (is_owner || manages_pool),
+        can_release: is_reserved && manages_pool,
         device,
         is_reserved,
         updated_at_local,
     }
 }
 
-fn gen_device_context<'a>(
+fn gen_device_context(
     config: &utils::types::Settings,
     database: &database::DbConn,
-    status_message: &'a Option<rocket::request::FlashMessage<'_>>,
+    status_message: Option<StatusMessage>,
     requested_pool_id: Option<i32>,
-) -> Result<DevicesContext<'a>, failure::Error> {
+    current_user: Option<auth::CurrentUser>,
+) -> Result<DevicesContext, failure::Error> {
     trace!("gen_device_context");
 
     let mut success_message = None;
     let mut error_message = None;
 
-    if let Some(ref status_message) = *status_message {
-        if status_message.kind() == "success" {
-            success_message = Some(status_message.message());
+    if let Some(status_message) = status_message {
+        if status_message.kind == "success" {
+            success_message = Some(status_message.message);
         } else {
-            error_message = Some(status_message.message());
+            error_message = Some(status_message.message);
         }
     }
 
@@ -234,218 +410,921 @@ fn gen_device_context<'a>(
         current_pool = None;
     };
     let devices = unformatted_devices.into_iter()
-        .map(format_device)
+        .map(|device| format_device(device, current_user.as_ref()))
         .collect();
 
     Ok(DevicesContext {
         devices,
         pools,
         current_pool,
+        can_reserve: current_user.as_ref().is_some_and(|user| user.role >= models::UserRole::User),
+        can_edit_devices: current_user.as_ref().is_some_and(|user| user.role >= models::UserRole::PoolAdmin),
+        is_admin: current_user.as_ref().is_some_and(|user| user.role == models::UserRole::Admin),
+        current_user: current_user.map(|user| user.username),
         error_message,
         success_message,
+        import_preview: None,
     })
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/devices?<pool_id>")]
-pub fn get_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn get_devices(
     database: pool::DbConn,
+    user: Option<auth::CurrentUser>,
     status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
     pool_id: Option<i32>,
-) -> Result<rocket_dyn_templates::Template, String> {
+) -> Result<templates::Template, String> {
     trace!("get_devices()");
-
-    return match gen_device_context(&*config, &*database, &status_message, pool_id) {
-        Ok(context) => {
-            Ok(rocket_dyn_templates::Template::render(
-            "devices", &context,
-            ))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
-    };
+    let status_message = status_message.map(StatusMessage::from);
+    database
+        .run(move |config, database| {
+            match gen_device_context(config, database, status_message, pool_id, user) {
+                Ok(context) => {
+                    Ok(csrf::render("devices", &csrf_token, &context))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/editDevices")]
-pub fn get_edit_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn get_edit_devices(
     database: pool::DbConn,
+    user: auth::PoolAdmin,
     status_message: Option<rocket::request::FlashMessage<'_>>,
-) -> Result<rocket_dyn_templates::Template, String> {
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, String> {
     trace!("get_edit_devices()");
+    let status_message = status_message.map(StatusMessage::from);
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            match gen_device_context(config, database, status_message, None, Some(user.clone())) {
+                Ok(mut context) => {
+                    //pool admins only get to see the pools they manage
+                    context.devices.retain(|device| user.can_manage_pool(device.device.pool_id));
+                    context.pools.retain(|pool| user.can_manage_pool(pool.id));
+                    Ok(csrf::render("edit_devices", &csrf_token, &context))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
+}
 
-    match gen_device_context(&*config, &*database, &status_message, None) {
-        Ok(context) => {
-            Ok(rocket_dyn_templates::Template::render(
-                "edit_devices",
-                &context,
-            ))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
+/// Whether the user manages the pool a device is currently in
+fn can_manage_device(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    user: &auth::CurrentUser,
+    device_id: i32,
+) -> bool {
+    match database::get_device_by_id(config, database, device_id) {
+        Ok(Some(device)) => user.can_manage_pool(device.pool_id),
+        _ => false,
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/addDevices", data = "<device_add>")]
-pub fn post_add_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_add_devices(
     database: pool::DbConn,
-    device_add: Form<models::DeviceInsert>,
+    user: auth::PoolAdmin,
+    change: models::ChangeContext,
+    device_add: csrf::CsrfForm<models::DeviceInsert>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_add_devices()");
-
-    let device = device_add.into_inner();
-    if let Err(errors) = device.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editDevices"),
-            msg,
-        );
-    }
-    let add_result = database::insert_device(&*config, &*database, &device);
-
-    match add_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editDevices"),
-            "Failed to add device",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editDevices"),
-            "Successfully added device",
-        ),
-    }
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let device = device_add.into_inner();
+            if let Err(errors) = device.validate() {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    msg,
+                );
+            }
+            if !user.can_manage_pool(device.pool_id) {
+                return rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "You can only manage the devices in your own pools",
+                );
+            }
+            let add_result = database::insert_device(config, database, &device, &change);
+
+            match add_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "Failed to add device",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editDevices"),
+                    "Successfully added device",
+                ),
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/deleteDevices", data = "<device_edit>")]
-pub fn post_delete_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_delete_devices(
     database: pool::DbConn,
-    device_edit: Form<models::DeviceDelete>,
+    user: auth::PoolAdmin,
+    change: models::ChangeContext,
+    device_edit: csrf::CsrfForm<models::DeviceDelete>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_delete_devices()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let device = device_edit.into_inner();
+            if !can_manage_device(config, database, &user, device.id) {
+                return rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "You can only manage the devices in your own pools",
+                );
+            }
+            let update_result = database::delete_device(config, database, &device, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "Failed to delete device",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editDevices"),
+                    "Successfully deleted device",
+                ),
+            }
+        })
+        .await
+}
 
-    let device = device_edit.into_inner();
-    let update_result = database::delete_device(&*config, &*database, &device);
+#[allow(clippy::needless_pass_by_value)]
+#[get("/exportInventory?<format>")]
+pub async fn get_export_inventory(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    format: Option<String>,
+) -> Result<(rocket::http::ContentType, String), rocket::http::Status> {
+    trace!("get_export_inventory()");
+    let format: inventory::Format = format
+        .as_deref()
+        .unwrap_or("json")
+        .parse()
+        .map_err(|_| rocket::http::Status::BadRequest)?;
+    database
+        .run(move |config, database| {
+            let exported = inventory::export(config, database).and_then(|inventory| inventory.to_string(format));
+            match exported {
+                Ok(exported) => {
+                    let content_type = rocket::http::ContentType::parse_flexible(format.content_type())
+                        .unwrap_or(rocket::http::ContentType::Plain);
+                    Ok((content_type, exported))
+                }
+                Err(e) => {
+                    error!("Failed to export inventory: {}", e);
+                    Err(rocket::http::Status::InternalServerError)
+                }
+            }
+        })
+        .await
+}
 
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editDevices"),
-            "Failed to delete device",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editDevices"),
-            "Successfully deleted device",
-        ),
-    }
+#[allow(clippy::needless_pass_by_value)]
+#[post("/importInventory", data = "<inventory_import>")]
+pub async fn post_import_inventory(
+    database: pool::DbConn,
+    user: auth::Admin,
+    change: models::ChangeContext,
+    csrf_token: csrf::CsrfToken,
+    inventory_import: csrf::CsrfForm<models::InventoryImport>,
+) -> Result<templates::Template, rocket::response::Flash<rocket::response::Redirect>> {
+    trace!("post_import_inventory()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let upload = inventory_import.into_inner();
+            let dry_run = upload.action == "preview";
+            let changes = upload
+                .format
+                .parse()
+                .and_then(|format| inventory::Inventory::parse(&upload.data, format))
+                .and_then(|parsed| inventory::import(config, database, &parsed, &change, dry_run));
+            let changes = match changes {
+                Ok(changes) => changes,
+                Err(e) => {
+                    return Err(rocket::response::Flash::error(
+                        redirect(config, "/editDevices"),
+                        format!("Nothing was imported, {}", e),
+                    ))
+                }
+            };
+            if !dry_run {
+                return Err(rocket::response::Flash::success(
+                    redirect(config, "/editDevices"),
+                    format!("Successfully imported {} changes", changes.len()),
+                ));
+            }
+            match gen_device_context(config, database, None, None, Some(user)) {
+                Ok(mut context) => {
+                    context.import_preview = Some(InventoryImportPreview {
+                        changes,
+                        data: upload.data,
+                        format: upload.format,
+                    });
+                    Ok(csrf::render("edit_devices", &csrf_token, &context))
+                }
+                Err(e) => Err(rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    e.to_string(),
+                )),
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/editDevices", data = "<device_edit>")]
-pub fn post_edit_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_edit_devices(
     database: pool::DbConn,
-    device_edit: Form<models::DeviceEdit>,
+    user: auth::PoolAdmin,
+    change: models::ChangeContext,
+    device_edit: csrf::CsrfForm<models::DeviceEdit>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_edit_devices()");
-
-    let device = device_edit.into_inner();
-    if let Err(errors) = device.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editDevices"),
-            msg,
-        );
-    }
-    let update_result = database::edit_device(&*config, &*database, &device);
-
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editDevices"),
-            "Failed to update device",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editDevices"),
-            "Successfully updated device",
-        ),
-    }
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let device = device_edit.into_inner();
+            if let Err(errors) = device.validate() {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    msg,
+                );
+            }
+            if !can_manage_device(config, database, &user, device.id) || !user.can_manage_pool(device.pool_id) {
+                return rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "You can only manage the devices in your own pools",
+                );
+            }
+            let update_result = database::edit_device(config, database, &device, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editDevices"),
+                    "Failed to update device",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editDevices"),
+                    "Successfully updated device",
+                ),
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/devices", data = "<device_update>")]
-pub fn post_devices(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_devices(
     database: pool::DbConn,
-    device_update: Form<models::DeviceUpdate>
+    user: auth::Member,
+    change: models::ChangeContext,
+    device_update: csrf::CsrfForm<models::DeviceUpdate>
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_devices()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let mut device = device_update.into_inner();
+
+            //save the old reservation status around for the sql query
+            let current_reservation_status = device.reservation_status;
+
+            //toggle the reservation status
+            device.reservation_status = !device.reservation_status;
+
+            //blank out the owner and comments if we're returning it
+            if device.reservation_status == models::ReservationStatus::Available {
+                device.device_owner = None;
+                device.comments = None;
+
+                // only its owners return it, a pool admin returning someone
+                // else's releases it from them
+                let current = match database::get_device_by_id(config, database, device.id) {
+                    Ok(Some(current)) => current,
+                    _ => return rocket::response::Flash::error(redirect(config, "/devices"), "Failed to update device"),
+                };
+                if current.reservation_status == models::ReservationStatus::Reserved
+                    && !current.is_owned_by(&user.username)
+                {
+                    if !user.can_manage_pool(current.pool_id) {
+                        return rocket::response::Flash::error(
+                            redirect(config, "/devices"),
+                            "You can only return devices you share",
+                        );
+                    }
+                    let release = models::DeviceForceRelease {
+                        id: current.id,
+                        device_owner: current.device_owner.clone().unwrap_or_default(),
+                        reason: "returned from the device list".to_string(),
+                    };
+                    let change = models::ChangeContext {
+                        reason: Some(release.reason.clone()),
+                        ..change
+                    };
+                    return match database::force_release_device(config, database, &release, &change) {
+                        Ok(0) | Err(_) => rocket::response::Flash::error(
+                            redirect(config, "/devices"),
+                            "Failed to update device",
+                        ),
+                        _ => {
+                            notify_released(config, database, &user, &current, &release);
+                            rocket::response::Flash::success(
+                                redirect(config, "/devices"),
+                                format!("Released {} from {}", current.device_name, release.device_owner),
+                            )
+                        }
+                    };
+                }
+            }
 
-    let mut device = device_update.into_inner();
+            // as with the api, only admins can reserve without being an owner
+            if device.reservation_status == models::ReservationStatus::Reserved
+                && !user.can_reserve_for(device.device_owner.as_deref().unwrap_or_default())
+            {
+                return rocket::response::Flash::error(
+                    redirect(config, "/devices"),
+                    "Only admins can reserve devices for someone else",
+                );
+            }
 
-    //save the old reservation status around for the sql query
-    let current_reservation_status = device.reservation_status;
+            let update_result = update_device_validated(config, database, &device, current_reservation_status, &change);
+
+            match update_result {
+                Ok(Err(errors)) => {
+                    let errors = errors.field_errors();
+                    let msg = match find_first_validation_message(&errors) {
+                        Some(m) => m,
+                        None => "Failed to parse form data",
+                    };
+                    rocket::response::Flash::error(redirect(config, "/devices"), msg)
+                }
+                Ok(Ok(0)) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/devices"),
+                    "Failed to update device",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/devices"),
+                    "Successfully updated device",
+                ),
+            }
+        })
+        .await
+}
 
-    //toggle the reservation status
-    device.reservation_status = !device.reservation_status;
+///Validates a change to a device's reservation and makes it in the same claim
+///transaction, so the owners' quotas it's checked against still hold
+fn update_device_validated(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    device: &models::DeviceUpdate,
+    expected_status: models::ReservationStatus,
+    change: &models::ChangeContext,
+) -> Result<Result<usize, validator::ValidationErrors>, failure::Error> {
+    database::claim_transaction(database, || {
+        match device.validate_with_context(&ValidationContext::new(config, database)) {
+            Ok(()) => database::update_device(config, database, device, expected_status, change).map(Ok),
+            Err(errors) => Ok(Err(errors)),
+        }
+    })
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/updateReservations", data = "<device_update>")]
+pub async fn post_update_reservations(
+    database: pool::DbConn,
+    user: auth::Member,
+    change: models::ChangeContext,
+    device_update: csrf::CsrfForm<models::DeviceUpdate>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_update_reservations()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            // changes who shares it, or its comments, keeping it reserved
+            let mut device = device_update.into_inner();
+            device.reservation_status = models::ReservationStatus::Reserved;
+            let current = match database::get_device_by_id(config, database, device.id) {
+                Ok(Some(current)) if current.is_owned_by(&user.username) || user.can_manage_pool(current.pool_id) => current,
+                _ => {
+                    return rocket::response::Flash::error(
+                        redirect(config, "/devices"),
+                        "You can only update reservations you share",
+                    )
+                }
+            };
+            let current_owners = current.owners();
+            let new_owners = models::parse_owners(device.device_owner.as_deref().unwrap_or(""));
+            let first_owner = current_owners.first().cloned().unwrap_or_default();
+            let same_owners = |owners: &[String]| {
+                owners.len() == new_owners.len()
+                    && owners.iter().zip(&new_owners).all(|(owner, new_owner)| owner.eq_ignore_ascii_case(new_owner))
+            };
+            // whoever claimed it decides who shares it, the others can only leave
+            if !first_owner.eq_ignore_ascii_case(&user.username) && !user.can_manage_pool(current.pool_id) {
+                let leaving: Vec<String> = current_owners
+                    .iter()
+                    .filter(|owner| !owner.eq_ignore_ascii_case(&user.username))
+                    .cloned()
+                    .collect();
+                if !same_owners(&current_owners) && !same_owners(&leaving) {
+                    return rocket::response::Flash::error(
+                        redirect(config, "/devices"),
+                        format!("Only {} can change who shares {}, you can only leave it", first_owner, current.device_name),
+                    );
+                }
+            }
+            // taking it from whoever claimed it is handing it on
+            if !new_owners.is_empty() && !new_owners.iter().any(|owner| owner.eq_ignore_ascii_case(&first_owner)) {
+                let transfer = models::DeviceTransfer {
+                    id: current.id,
+                    device_owner: current.device_owner.clone().unwrap_or_default(),
+                    new_owner: device.device_owner.clone().unwrap_or_default().trim().to_string(),
+                    comments: device.comments.clone(),
+                };
+                return transfer_device_notifying(config, database, &user, &current, &transfer, &change);
+            }
+            match update_device_validated(config, database, &device, models::ReservationStatus::Reserved, &change) {
+                Ok(Err(errors)) => {
+                    let errors = errors.field_errors();
+                    let msg = match find_first_validation_message(&errors) {
+                        Some(m) => m,
+                        None => "Failed to parse form data",
+                    };
+                    rocket::response::Flash::error(redirect(config, "/devices"), msg)
+                }
+                Ok(Ok(0)) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/devices"),
+                    "Failed to update reservation, it may have been returned already",
+                ),
+                _ => {
+                    // let whoever joined or left know, unless it's them
+                    for owner in &current_owners {
+                        if !owner.eq_ignore_ascii_case(&user.username)
+                            && !new_owners.iter().any(|new_owner| new_owner.eq_ignore_ascii_case(owner))
+                        {
+                            utils::notify::notify_owner(
+                                config,
+                                database,
+                                owner,
+                                &format!("{} took you off {}", user.username, current.device_name),
+                            );
+                        }
+                    }
+                    for owner in &new_owners {
+                        if !owner.eq_ignore_ascii_case(&user.username) && !current.is_owned_by(owner) {
+                            utils::notify::notify_owner(
+                                config,
+                                database,
+                                owner,
+                                &format!("{} shared {} with you", user.username, current.device_name),
+                            );
+                        }
+                    }
+                    rocket::response::Flash::success(
+                        redirect(config, "/devices"),
+                        "Successfully updated reservation",
+                    )
+                }
+            }
+        })
+        .await
+}
 
-    //blank out the owner and comments if we're returning it
-    if device.reservation_status == models::ReservationStatus::Available {
-        device.device_owner = None;
-        device.comments = None;
+#[allow(clippy::needless_pass_by_value)]
+#[post("/transferDevices", data = "<device_transfer>")]
+pub async fn post_transfer_devices(
+    database: pool::DbConn,
+    user: auth::Member,
+    change: models::ChangeContext,
+    device_transfer: csrf::CsrfForm<models::DeviceTransfer>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_transfer_devices()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let mut transfer = device_transfer.into_inner();
+            transfer.new_owner = transfer.new_owner.trim().to_string();
+            let device = match database::get_device_by_id(config, database, transfer.id) {
+                Ok(Some(device)) => device,
+                _ => return rocket::response::Flash::error(redirect(config, "/devices"), "Failed to transfer device"),
+            };
+            // its owners can pass it on, pool admins can move anyone's
+            if !device.is_owned_by(&user.username) && !user.can_manage_pool(device.pool_id) {
+                return rocket::response::Flash::error(
+                    redirect(config, "/devices"),
+                    "You can only transfer devices you have claimed",
+                );
+            }
+            transfer_device_notifying(config, database, &user, &device, &transfer, &change)
+        })
+        .await
+}
+
+///Hands `device` on as `transfer` says, in a claim transaction so the new
+///owners' quotas hold, and tells its old and new owners
+fn transfer_device_notifying(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    user: &auth::CurrentUser,
+    device: &models::Device,
+    transfer: &models::DeviceTransfer,
+    change: &models::ChangeContext,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    let transferred = database::claim_transaction(database, || {
+        match transfer.validate_with_context(&ValidationContext::new(config, database)) {
+            Ok(()) => database::transfer_device(config, database, transfer, change).map(Ok),
+            Err(errors) => Ok(Err(errors)),
+        }
+    });
+    match transferred {
+        Ok(Err(errors)) => {
+            let errors = errors.field_errors();
+            let msg = match find_first_validation_message(&errors) {
+                Some(m) => m,
+                None => "Failed to parse form data",
+            };
+            rocket::response::Flash::error(redirect(config, "/devices"), msg)
+        }
+        Ok(Ok(0)) | Err(_) => rocket::response::Flash::error(
+            redirect(config, "/devices"),
+            "Failed to transfer device, it may have been returned or transferred already",
+        ),
+        _ => {
+            for owner in models::parse_owners(&transfer.device_owner) {
+                utils::notify::notify_owner(
+                    config,
+                    database,
+                    &owner,
+                    &format!("{} transferred {} from you to {}", user.username, device.device_name, transfer.new_owner),
+                );
+            }
+            for owner in models::parse_owners(&transfer.new_owner) {
+                utils::notify::notify_owner(
+                    config,
+                    database,
+                    &owner,
+                    &format!("{} transferred {} to you from {}", user.username, device.device_name, transfer.device_owner),
+                );
+            }
+            rocket::response::Flash::success(
+                redirect(config, "/devices"),
+                format!("Transferred {} to {}", device.device_name, transfer.new_owner),
+            )
+        }
     }
+}
 
-    if let Err(errors) = device.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(rocket::response::Redirect::to("/devices"), msg);
+///Tells a device's owners someone else gave it back for them
+fn notify_released(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    user: &auth::CurrentUser,
+    device: &models::Device,
+    release: &models::DeviceForceRelease,
+) {
+    for owner in models::parse_owners(&release.device_owner) {
+        utils::notify::notify_owner(
+            config,
+            database,
+            &owner,
+            &format!("{} released {} from you: {}", user.username, device.device_name, release.reason),
+        );
     }
+}
 
-    let update_result = database::update_device(&*config, &*database, &device, current_reservation_status);
+#[allow(clippy::needless_pass_by_value)]
+#[post("/releaseDevices", data = "<device_release>")]
+pub async fn post_release_devices(
+    database: pool::DbConn,
+    user: auth::PoolAdmin,
+    change: models::ChangeContext,
+    device_release: csrf::CsrfForm<models::DeviceForceRelease>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_release_devices()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let mut release = device_release.into_inner();
+            release.reason = release.reason.trim().to_string();
+            if let Err(errors) = release.validate() {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(redirect(config, "/devices"), msg);
+            }
+            let device = match database::get_device_by_id(config, database, release.id) {
+                Ok(Some(device)) if user.can_manage_pool(device.pool_id) => device,
+                _ => {
+                    return rocket::response::Flash::error(
+                        redirect(config, "/devices"),
+                        "You can only manage the devices in your own pools",
+                    )
+                }
+            };
+
+            let change = models::ChangeContext {
+                reason: Some(release.reason.clone()),
+                ..change
+            };
+            match database::force_release_device(config, database, &release, &change) {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/devices"),
+                    "Failed to release device, it may have been returned or transferred already",
+                ),
+                _ => {
+                    notify_released(config, database, &user, &device, &release);
+                    rocket::response::Flash::success(
+                        redirect(config, "/devices"),
+                        format!("Released {} from {}", device.device_name, release.device_owner),
+                    )
+                }
+            }
+        })
+        .await
+}
 
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/devices"),
-            "Failed to update device",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/devices"),
-            "Successfully updated device",
-        ),
+#[derive(Serialize)]
+struct HeldDeviceContext {
+    device: models::Device,
+    pool_name: String,
+    updated_at_local: String,
+    held_for: String,
+    can_return: bool,
+}
+
+#[derive(Serialize)]
+struct HeldReservationContext {
+    id: i32,
+    owners: Vec<String>,
+    devices: Vec<HeldDeviceContext>,
+}
+
+#[derive(Serialize, Default)]
+struct OwnerContext {
+    ///Whose devices these are, unset on `/my` until we know who's asking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    ///`/my`, rather than someone else's `/owners/<name>`
+    is_me: bool,
+    reservations: Vec<HeldReservationContext>,
+    device_count: usize,
+    can_return: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_user: Option<String>,
+    #[serde(flatten)]
+    status_message: MessageContext,
+}
+
+fn gen_owner_context(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    owner: Option<String>,
+    is_me: bool,
+    current_user: Option<auth::CurrentUser>,
+    status_message: MessageContext,
+) -> Result<OwnerContext, failure::Error> {
+    trace!("gen_owner_context");
+    let can_return = |device: &models::Device| {
+        current_user.as_ref().is_some_and(|user| device_return(user, device, "").is_some())
+    };
+    let mut context = OwnerContext {
+        is_me,
+        current_user: current_user.as_ref().map(|user| user.username.clone()),
+        status_message,
+        ..Default::default()
+    };
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(context),
+    };
+    let pools = database::get_pools(config, database)?;
+    let devices = database::get_devices_by_owner(config, database, &owner)?;
+    let now = chrono::Utc::now().naive_utc();
+    context.device_count = devices.len();
+    context.reservations = models::Reservation::from_devices(devices)
+        .into_iter()
+        .map(|reservation| HeldReservationContext {
+            id: reservation.id,
+            owners: models::parse_owners(&reservation.device_owner),
+            devices: reservation
+                .devices
+                .into_iter()
+                .map(|device| HeldDeviceContext {
+                    pool_name: pools
+                        .iter()
+                        .find(|pool| pool.id == device.pool_id)
+                        .map(|pool| pool.pool_name.clone())
+                        .unwrap_or_default(),
+                    updated_at_local: format_local_time(device.updated_at),
+                    held_for: device.held_for(now),
+                    can_return: can_return(&device),
+                    device,
+                })
+                .collect(),
+        })
+        .collect();
+    context.can_return = context
+        .reservations
+        .iter()
+        .any(|reservation| reservation.devices.iter().any(|held| held.can_return));
+    context.owner = Some(owner);
+    Ok(context)
+}
+
+///The devices held by whoever is logged in, or otherwise by the owner this
+///browser last asked for
+#[allow(clippy::needless_pass_by_value)]
+#[get("/my?<owner>")]
+pub async fn get_my_devices(
+    database: pool::DbConn,
+    user: Option<auth::CurrentUser>,
+    cookies: &rocket::http::CookieJar<'_>,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+    owner: Option<String>,
+) -> Result<templates::Template, String> {
+    trace!("get_my_devices()");
+    let status_message = gen_message_context(status_message);
+    let asked_for = owner.map(|owner| owner.trim().to_string()).filter(|owner| !owner.is_empty());
+    let owner = match (&user, asked_for) {
+        (Some(user), _) => Some(user.username.clone()),
+        (None, Some(owner)) => {
+            cookies.add_private(rocket::http::Cookie::new(auth::OWNER_COOKIE, owner.clone()));
+            Some(owner)
+        }
+        (None, None) => cookies
+            .get_private(auth::OWNER_COOKIE)
+            .map(|cookie| cookie.value().to_string()),
+    };
+    database
+        .run(move |config, database| {
+            gen_owner_context(config, database, owner, true, user, status_message)
+                .map(|context| csrf::render("owner", &csrf_token, &context))
+                .map_err(|e| e.to_string())
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[get("/owners/<name>")]
+pub async fn get_owner_devices(
+    database: pool::DbConn,
+    user: Option<auth::CurrentUser>,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+    name: String,
+) -> Result<templates::Template, String> {
+    trace!("get_owner_devices()");
+    let status_message = gen_message_context(status_message);
+    let is_me = user.iter().any(|user| user.username == name);
+    database
+        .run(move |config, database| {
+            gen_owner_context(config, database, Some(name), is_me, user, status_message)
+                .map(|context| csrf::render("owner", &csrf_token, &context))
+                .map_err(|e| e.to_string())
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/my/return", data = "<devices_return>")]
+pub async fn post_return_devices(
+    database: pool::DbConn,
+    user: auth::Member,
+    change: models::ChangeContext,
+    devices_return: csrf::CsrfForm<models::DevicesReturn>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_return_devices()");
+    let user = user.0;
+    database
+        .run(move |config, database| {
+            let devices_return = devices_return.into_inner();
+            let owner = devices_return.device_owner;
+            let page = if owner == user.username {
+                "/my".to_string()
+            } else {
+                uri!(get_owner_devices(owner.as_str())).to_string()
+            };
+            if devices_return.ids.is_empty() {
+                return rocket::response::Flash::error(redirect(config, &page), "Please choose the devices to return");
+            }
+            let reason = format!("returned from {}'s devices", owner);
+            let how = |device: &models::Device| device_return(&user, device, &reason);
+            let requested = devices_return.ids.len();
+            let returned = match database::return_devices(config, database, &owner, &devices_return.ids, &change, how) {
+                Ok(Some(returned)) if !returned.is_empty() => returned,
+                Ok(None) => {
+                    return rocket::response::Flash::error(
+                        redirect(config, &page),
+                        "You can only return your own devices, or those in pools you manage",
+                    )
+                }
+                Ok(Some(_)) | Err(_) => {
+                    return rocket::response::Flash::error(
+                        redirect(config, &page),
+                        "Failed to return devices, they may have been returned already",
+                    )
+                }
+            };
+            for (device, how) in &returned {
+                if let models::DeviceReturn::Release(release) = how {
+                    notify_released(config, database, &user, device, release);
+                }
+            }
+            if returned.len() < requested {
+                rocket::response::Flash::success(
+                    redirect(config, &page),
+                    format!(
+                        "Returned {} of {} devices, the rest had been returned or transferred already",
+                        returned.len(),
+                        requested
+                    ),
+                )
+            } else {
+                rocket::response::Flash::success(
+                    redirect(config, &page),
+                    format!(
+                        "Successfully returned {} {}",
+                        returned.len(),
+                        if returned.len() == 1 { "device" } else { "devices" }
+                    ),
+                )
+            }
+        })
+        .await
+}
+
+///How `user` may give a device back, if at all: as one of its owners, or
+///releasing it from them in a pool they manage
+fn device_return(user: &auth::CurrentUser, device: &models::Device, reason: &str) -> Option<models::DeviceReturn> {
+    if user.role < models::UserRole::User {
+        None
+    } else if device.is_owned_by(&user.username) {
+        Some(models::DeviceReturn::Owner)
+    } else if user.can_manage_pool(device.pool_id) {
+        Some(models::DeviceReturn::Release(models::DeviceForceRelease {
+            id: device.id,
+            device_owner: device.device_owner.clone().unwrap_or_default(),
+            reason: reason.to_string(),
+        }))
+    } else {
+        None
     }
 }
 
 // pools
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/pools")]
-pub fn api_get_pools(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_get_pools(
     database: pool::DbConn,
 ) -> Result<json::Json<Vec<models::Pool>>, String> {
     trace!("api_get_pools()");
-    return match database::get_pools(&*config, &*database) {
-        Ok(pools) => {
-            Ok(json::Json(pools))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
-    };
+    database
+        .run(move |config, database| {
+            match database::get_pools(config, database) {
+                Ok(pools) => {
+                    Ok(json::Json(pools))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
 }
 
 #[derive(Serialize)]
@@ -455,16 +1334,16 @@ struct PerPoolContext {
 }
 
 #[derive(Serialize, Default)]
-struct PoolContext<'a> {
+struct PoolContext {
     pools: Vec<PerPoolContext>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    error_message: Option<&'a str>,
+    error_message: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    success_message: Option<&'a str>,
+    success_message: Option<String>,
 }
 
 fn format_pool(pool: models::Pool) -> PerPoolContext {
-    let updated_at_local = chrono::DateTime::<chrono::Local>::from_utc(
+    let updated_at_local = chrono::DateTime::<chrono::Local>::from_naive_utc_and_offset(
         pool.updated_at,
         chrono::Local::now().offset().fix(),
     );
@@ -477,21 +1356,21 @@ fn format_pool(pool: models::Pool) -> PerPoolContext {
     }
 }
 
-fn gen_pool_context<'a>(
+fn gen_pool_context(
     config: &utils::types::Settings,
     database: &database::DbConn,
-    status_message: &'a Option<rocket::request::FlashMessage<'_>>,
-) -> Result<PoolContext<'a>, failure::Error> {
+    status_message: Option<StatusMessage>,
+) -> Result<PoolContext, failure::Error> {
     trace!("gen_pool_context");
 
     let mut success_message = None;
     let mut error_message = None;
 
-    if let Some(ref status_message) = *status_message {
-        if status_message.kind() == "success" {
-            success_message = Some(status_message.message());
+    if let Some(status_message) = status_message {
+        if status_message.kind == "success" {
+            success_message = Some(status_message.message);
         } else {
-            error_message = Some(status_message.message());
+            error_message = Some(status_message.message);
         }
     }
 
@@ -508,133 +1387,147 @@ fn gen_pool_context<'a>(
     })
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/editPools")]
-pub fn get_edit_pools(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn get_edit_pools(
     database: pool::DbConn,
+    _user: auth::Admin,
     status_message: Option<rocket::request::FlashMessage<'_>>,
-) -> Result<rocket_dyn_templates::Template, String> {
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, String> {
     trace!("get_edit_pools()");
-
-    return match gen_pool_context(&*config, &*database, &status_message) {
-        Ok(context) => {
-            Ok(rocket_dyn_templates::Template::render(
-                "edit_pools",
-                &context,
-            ))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
-    };
+    let status_message = status_message.map(StatusMessage::from);
+    database
+        .run(move |config, database| {
+            match gen_pool_context(config, database, status_message) {
+                Ok(context) => {
+                    Ok(csrf::render("edit_pools", &csrf_token, &context))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/addPools", data = "<pool_add>")]
-pub fn post_add_pools(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_add_pools(
     database: pool::DbConn,
-    pool_add: Form<models::PoolInsert>,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    pool_add: csrf::CsrfForm<models::PoolInsert>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_add_pools()");
-
-    let pool = pool_add.into_inner();
-    debug!("pool: {:?}", pool);
-    if let Err(errors) = pool.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            msg,
-        );
-    }
-    let add_result = database::insert_pool(&*config, &*database, &pool);
-
-    match add_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            "Failed to add pool",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editPools"),
-            "Successfully added pool",
-        ),
-    }
+    database
+        .run(move |config, database| {
+            let pool = pool_add.into_inner();
+            debug!("pool: {:?}", pool);
+            if let Err(errors) = pool.validate() {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    msg,
+                );
+            }
+            let add_result = database::insert_pool(config, database, &pool, &change);
+
+            match add_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    "Failed to add pool",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editPools"),
+                    "Successfully added pool",
+                ),
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/deletePools", data = "<pool_edit>")]
-pub fn post_delete_pools(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_delete_pools(
     database: pool::DbConn,
-    pool_edit: Form<models::PoolDelete>,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    pool_edit: csrf::CsrfForm<models::PoolDelete>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_delete_pools()");
-
-    let pool = pool_edit.into_inner();
-    debug!("pool: {:?}", pool);
-    if let Err(errors) = pool.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            msg,
-        );
-    }
-    let update_result = database::delete_pool(&*config, &*database, &pool);
-
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            "Failed to delete pool",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editPools"),
-            "Successfully deleted pool",
-        ),
-    }
+    database
+        .run(move |config, database| {
+            let pool = pool_edit.into_inner();
+            debug!("pool: {:?}", pool);
+            if let Err(errors) = pool.validate_with_context(&ValidationContext::new(config, database)) {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    msg,
+                );
+            }
+            let update_result = database::delete_pool(config, database, &pool, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    "Failed to delete pool",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editPools"),
+                    "Successfully deleted pool",
+                ),
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[post("/editPools", data = "<pool_edit>")]
-pub fn post_edit_pools(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn post_edit_pools(
     database: pool::DbConn,
-    pool_edit: Form<models::PoolModify>,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    pool_edit: csrf::CsrfForm<models::PoolModify>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
     trace!("post_edit_pools()");
-
-    let pool = pool_edit.into_inner();
-    if let Err(errors) = pool.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            msg,
-        );
-    }
-    let update_result = database::edit_pool(&*config, &*database, &pool);
-
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editPools"),
-            "Failed to update pool",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editPools"),
-            "Successfully updated pool",
-        ),
-    }
+    database
+        .run(move |config, database| {
+            let pool = pool_edit.into_inner();
+            if let Err(errors) = pool.validate() {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    msg,
+                );
+            }
+            let update_result = database::edit_pool(config, database, &pool, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editPools"),
+                    "Failed to update pool",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editPools"),
+                    "Successfully updated pool",
+                ),
+            }
+        })
+        .await
 }
 
 // customOwners (Exceptions)
@@ -646,16 +1539,16 @@ struct PerCustomOwnerContext {
 }
 
 #[derive(Serialize, Default)]
-struct CustomOwnerContext<'a> {
+struct CustomOwnerContext {
     custom_owners: Vec<PerCustomOwnerContext>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    error_message: Option<&'a str>,
+    error_message: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    success_message: Option<&'a str>,
+    success_message: Option<String>,
 }
 
 fn format_custom_owner(custom_owner: models::CustomOwner) -> PerCustomOwnerContext {
-    let updated_at_local = chrono::DateTime::<chrono::Local>::from_utc(
+    let updated_at_local = chrono::DateTime::<chrono::Local>::from_naive_utc_and_offset(
         custom_owner.updated_at,
         chrono::Local::now().offset().fix(),
     );
@@ -668,21 +1561,21 @@ fn format_custom_owner(custom_owner: models::CustomOwner) -> PerCustomOwnerConte
     }
 }
 
-fn gen_custom_owner_context<'a>(
+fn gen_custom_owner_context(
     config: &utils::types::Settings,
     database: &database::DbConn,
-    status_message: &'a Option<rocket::request::FlashMessage<'_>>,
-) -> Result<CustomOwnerContext<'a>, failure::Error> {
+    status_message: Option<StatusMessage>,
+) -> Result<CustomOwnerContext, failure::Error> {
     trace!("gen_custom_owner_context");
 
     let mut success_message = None;
     let mut error_message = None;
 
-    if let Some(ref status_message) = *status_message {
-        if status_message.kind() == "success" {
-            success_message = Some(status_message.message());
+    if let Some(status_message) = status_message {
+        if status_message.kind == "success" {
+            success_message = Some(status_message.message);
         } else {
-            error_message = Some(status_message.message());
+            error_message = Some(status_message.message);
         }
     }
 
@@ -700,194 +1593,888 @@ fn gen_custom_owner_context<'a>(
 }
 
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/custom_owners/<name>")]
-pub fn api_get_custom_owner(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_get_custom_owner(
     database: pool::DbConn,
     name: String,
 ) -> Result<json::Json<models::CustomOwner>, rocket::response::status::Custom<String>> {
     trace!("api_get_custom_owner()");
-    database::get_custom_owner(&*config, &*database, &name)
-        .map_err(|_| {
-            rocket::response::status::Custom(
-                rocket::http::Status::InternalServerError,
-                "500 Internal Server Error".to_string(),
-            )
+    database
+        .run(move |config, database| {
+            database::get_custom_owner(config, database, &name)
+                .map_err(|_| {
+                    rocket::response::status::Custom(
+                        rocket::http::Status::InternalServerError,
+                        "500 Internal Server Error".to_string(),
+                    )
+                })
+                .and_then(|custom_owners| {
+                    custom_owners.ok_or_else(|| {
+                        rocket::response::status::Custom(
+                            rocket::http::Status::NotFound,
+                            "404 Not Found".to_string(),
+                        )
+                    })
+                })
+                .map(json::Json)
         })
-        .and_then(|custom_owners| {
-            custom_owners.ok_or_else(|| {
-                rocket::response::status::Custom(
-                    rocket::http::Status::NotFound,
-                    "404 Not Found".to_string(),
-                )
-            })
-        })
-        .map(json::Json)
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/custom_owners")]
-pub fn api_get_custom_owners(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn api_get_custom_owners(
     database: pool::DbConn,
 ) -> Result<json::Json<Vec<models::CustomOwner>>, String> {
     trace!("api_get_custom_owners()");
-    return match database::get_custom_owners(&*config, &*database) {
-        Ok(custom_owners) => {
-            Ok(json::Json(custom_owners))
-        }
-        Err(e) => {
-            Err(e.to_string())
-        }
-    };
+    database
+        .run(move |config, database| {
+            match database::get_custom_owners(config, database) {
+                Ok(custom_owners) => {
+                    Ok(json::Json(custom_owners))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#[allow(clippy::needless_pass_by_value)]
 #[get("/editCustomOwners")]
-pub fn get_edit_custom_owners(
-    config: &rocket::State<utils::types::Settings>,
+pub async fn get_edit_custom_owners(
     database: pool::DbConn,
+    _user: auth::Admin,
     status_message: Option<rocket::request::FlashMessage<'_>>,
-) -> Result<rocket_dyn_templates::Template, String> {
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, String> {
     trace!("get_edit_custom_owners()");
+    let status_message = status_message.map(StatusMessage::from);
+    database
+        .run(move |config, database| {
+            match gen_custom_owner_context(config, database, status_message) {
+                Ok(context) => {
+                    Ok(csrf::render("edit_custom_owners", &csrf_token, &context))
+                }
+                Err(e) => {
+                    Err(e.to_string())
+                }
+            }
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/addCustomOwners", data = "<custom_owner_add>")]
+pub async fn post_add_custom_owners(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    custom_owner_add: csrf::CsrfForm<models::CustomOwnerInsert>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_add_custom_owners()");
+    database
+        .run(move |config, database| {
+            let mut custom_owner = custom_owner_add.into_inner();
+            custom_owner.custom_owner_name = custom_owner.custom_owner_name.to_lowercase();
+            custom_owner.recipient = custom_owner.recipient.to_lowercase();
+            debug!("custom_owner: {:?}", custom_owner);
+            if let Err(errors) = custom_owner.validate_with_context(&ValidationContext::new(config, database)) {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    msg,
+                );
+            }
+
+            let add_result = database::insert_custom_owner(config, database, &custom_owner, &change);
+
+            match add_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    "Failed to add custom_owner",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editCustomOwners"),
+                    "Successfully added custom_owner",
+                ),
+            }
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/deleteCustomOwners", data = "<custom_owner_edit>")]
+pub async fn post_delete_custom_owners(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    custom_owner_edit: csrf::CsrfForm<models::CustomOwnerDelete>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_delete_custom_owners()");
+    database
+        .run(move |config, database| {
+            let custom_owner = custom_owner_edit.into_inner();
+            if let Err(errors) = custom_owner.validate_with_context(&ValidationContext::new(config, database)) {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    msg,
+                );
+            }
+            let update_result = database::delete_custom_owner(config, database, &custom_owner, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    "Failed to delete custom_owner",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editCustomOwners"),
+                    "Successfully deleted custom_owner",
+                ),
+            }
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/editCustomOwners", data = "<custom_owner_edit>")]
+pub async fn post_edit_custom_owners(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    custom_owner_edit: csrf::CsrfForm<models::CustomOwnerModify>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_edit_custom_owners()");
+    database
+        .run(move |config, database| {
+            let mut custom_owner = custom_owner_edit.into_inner();
+            custom_owner.custom_owner_name = custom_owner.custom_owner_name.to_lowercase();
+            custom_owner.recipient = custom_owner.recipient.to_lowercase();
+            if let Err(errors) = custom_owner.validate_with_context(&ValidationContext::new(config, database)) {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    msg,
+                );
+            }
+            let update_result = database::edit_custom_owner(config, database, &custom_owner, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editCustomOwners"),
+                    "Failed to update custom_owner",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editCustomOwners"),
+                    "Successfully updated custom_owner",
+                ),
+            }
+        })
+        .await
+}
+
+// users
+
+/// Errors under /api/ stay machine readable, rather than redirecting to a page
+#[catch(default)]
+pub fn api_error(
+    status: rocket::http::Status,
+    _request: &rocket::Request<'_>,
+) -> (rocket::http::Status, json::Json<json::Value>) {
+    api_error_response(status, None)
+}
+
+/// The body `api_error` sends, for handlers with a message saying what to fix
+fn api_error_response(
+    status: rocket::http::Status,
+    message: Option<&str>,
+) -> (rocket::http::Status, json::Json<json::Value>) {
+    let mut error = json::json!({
+        "code": status.code,
+        "reason": status.reason().unwrap_or(""),
+    });
+    if let Some(message) = message {
+        error["message"] = json::Value::from(message);
+    }
+    (status, json::Json(json::json!({ "error": error })))
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &rocket::Request<'_>) -> rocket::response::Flash<rocket::response::Redirect> {
+    rocket::response::Flash::error(redirect(catcher_config(request), "/login"), "Please log in first")
+}
+
+#[catch(403)]
+pub fn forbidden(request: &rocket::Request<'_>) -> rocket::response::Flash<rocket::response::Redirect> {
+    let message = if csrf::was_rejected(request) {
+        "That form had expired, please try again"
+    } else {
+        "You do not have permission to do that"
+    };
+    rocket::response::Flash::error(redirect(catcher_config(request), "/devices"), message)
+}
+
+fn catcher_config<'r>(request: &'r rocket::Request<'_>) -> &'r utils::types::Settings {
+    request
+        .rocket()
+        .state::<utils::types::Settings>()
+        .expect("settings are managed")
+}
+
+#[derive(Serialize, Default)]
+struct MessageContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success_message: Option<String>,
+}
+
+fn gen_message_context(status_message: Option<rocket::request::FlashMessage<'_>>) -> MessageContext {
+    let mut context = MessageContext::default();
+    if let Some(status_message) = status_message.map(StatusMessage::from) {
+        if status_message.kind == "success" {
+            context.success_message = Some(status_message.message);
+        } else {
+            context.error_message = Some(status_message.message);
+        }
+    }
+    context
+}
+
+#[derive(Serialize)]
+struct LoginContext {
+    #[serde(flatten)]
+    status_message: MessageContext,
+    single_sign_on: bool,
+    registration: bool,
+}
+
+#[get("/login")]
+pub fn get_login(
+    config: &rocket::State<utils::types::Settings>,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+) -> templates::Template {
+    trace!("get_login()");
+    let context = LoginContext {
+        status_message: gen_message_context(status_message),
+        single_sign_on: config.oidc_issuer.is_some(),
+        registration: !config.disable_registration,
+    };
+    csrf::render("login", &csrf_token, &context)
+}
 
-    match gen_custom_owner_context(&*config, &*database, &status_message) {
-        Ok(context) => {
-            Ok(rocket_dyn_templates::Template::render(
-                "edit_custom_owners",
-                &context,
-            ))
+#[allow(clippy::needless_pass_by_value)]
+#[post("/login", data = "<user_login>")]
+pub async fn post_login(
+    config: &rocket::State<utils::types::Settings>,
+    database: pool::DbConn,
+    cookies: &rocket::http::CookieJar<'_>,
+    user_login: csrf::CsrfForm<models::UserLogin>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_login()");
+    let user_login = user_login.into_inner();
+    let user = database
+        .run(move |config, database| {
+            match database::get_user_by_name(config, database, &user_login.username) {
+                Ok(Some(user)) if auth::verify_password(&user_login.password, &user.password_hash) => Some(user),
+                _ => None,
+            }
+        })
+        .await;
+
+    match user {
+        Some(user) => {
+            cookies.add_private(rocket::http::Cookie::new(auth::SESSION_COOKIE, user.id.to_string()));
+            rocket::response::Flash::success(
+                redirect(config, "/devices"),
+                format!("Logged in as {}", user.username),
+            )
+        }
+        None => rocket::response::Flash::error(
+            redirect(config, "/login"),
+            "Incorrect username or password",
+        ),
+    }
+}
+
+/// A private cookie for single sign-on. These are lax rather than strict, so
+/// they're sent on the redirects back from the identity provider.
+fn lax_private_cookie(name: &'static str, value: String) -> rocket::http::Cookie<'static> {
+    let mut cookie = rocket::http::Cookie::new(name, value);
+    cookie.set_same_site(rocket::http::SameSite::Lax);
+    cookie
+}
+
+fn single_sign_on_failed(config: &utils::types::Settings) -> rocket::response::Flash<rocket::response::Redirect> {
+    rocket::response::Flash::error(
+        redirect(config, "/login"),
+        "Single sign-on failed, please try again",
+    )
+}
+
+#[get("/login/oidc")]
+pub async fn get_login_oidc(
+    config: &rocket::State<utils::types::Settings>,
+    cookies: &rocket::http::CookieJar<'_>,
+) -> Result<rocket::response::Redirect, rocket::response::Flash<rocket::response::Redirect>> {
+    trace!("get_login_oidc()");
+    let oidc_config = config.inner().clone();
+    let result = rocket::tokio::task::spawn_blocking(move || {
+        let oidc_client = utils::oidc::oidc_client_init(&oidc_config)
+            .ok_or_else(|| failure::err_msg("Single sign-on isn't configured"))?;
+        let metadata = oidc_client.discover()?;
+        let state = utils::oidc::random_value();
+        let nonce = utils::oidc::random_value();
+        let url = oidc_client.authorization_url(&metadata, &state, &nonce)?;
+        Ok::<_, failure::Error>((url, state, nonce))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((url, state, nonce))) => {
+            cookies.add_private(lax_private_cookie(auth::OIDC_LOGIN_COOKIE, format!("{} {}", state, nonce)));
+            Ok(rocket::response::Redirect::to(url))
+        }
+        Ok(Err(e)) => {
+            warn!("Error starting single sign-on: {}", e);
+            Err(single_sign_on_failed(config))
         }
         Err(e) => {
-            Err(e.to_string())
+            error!("Single sign-on panicked: {}", e);
+            Err(single_sign_on_failed(config))
         }
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
-#[post("/addCustomOwners", data = "<custom_owner_add>")]
-pub fn post_add_custom_owners(
+#[allow(clippy::needless_pass_by_value)]
+#[get("/login/oidc/callback?<code>&<state>&<error>")]
+pub async fn get_login_oidc_callback(
     config: &rocket::State<utils::types::Settings>,
     database: pool::DbConn,
-    custom_owner_add: Form<models::CustomOwnerInsert>,
+    cookies: &rocket::http::CookieJar<'_>,
+    change: models::ChangeContext,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
-    trace!("post_add_custom_owners()");
+    trace!("get_login_oidc_callback()");
+    let login = cookies.get_private(auth::OIDC_LOGIN_COOKIE);
+    cookies.remove_private(auth::OIDC_LOGIN_COOKIE);
+    if let Some(error) = error {
+        warn!("Identity provider returned an error: {}", error);
+        return single_sign_on_failed(config);
+    }
+    let (code, nonce) = match (login, code, state) {
+        (Some(login), Some(code), Some(state)) => match login.value().split_once(' ') {
+            Some((expected_state, nonce)) if expected_state == state => (code, nonce.to_string()),
+            _ => {
+                warn!("Single sign-on state doesn't match");
+                return single_sign_on_failed(config);
+            }
+        },
+        _ => return single_sign_on_failed(config),
+    };
 
-    let mut custom_owner = custom_owner_add.into_inner();
-    custom_owner.custom_owner_name = custom_owner.custom_owner_name.to_lowercase();
-    custom_owner.recipient = custom_owner.recipient.to_lowercase();
-    debug!("custom_owner: {:?}", custom_owner);
-    if let Err(errors) = custom_owner.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            msg,
-        );
+    let oidc_config = config.inner().clone();
+    let oidc_user = rocket::tokio::task::spawn_blocking(move || {
+        let oidc_client = utils::oidc::oidc_client_init(&oidc_config)
+            .ok_or_else(|| failure::err_msg("Single sign-on isn't configured"))?;
+        let metadata = oidc_client.discover()?;
+        oidc_client.exchange_code(&metadata, &code, &nonce)
+    })
+    .await;
+    let oidc_user = match oidc_user {
+        Ok(Ok(oidc_user)) => oidc_user,
+        Ok(Err(e)) => {
+            warn!("Error completing single sign-on: {}", e);
+            return single_sign_on_failed(config);
+        }
+        Err(e) => {
+            error!("Single sign-on panicked: {}", e);
+            return single_sign_on_failed(config);
+        }
+    };
+
+    let user = database
+        .run(move |config, database| provision_oidc_user(config, database, &oidc_user, change))
+        .await;
+    match user {
+        Ok(user) => {
+            cookies.add_private(lax_private_cookie(auth::SESSION_COOKIE, user.id.to_string()));
+            rocket::response::Flash::success(
+                redirect(config, "/devices"),
+                format!("Logged in as {}", user.username),
+            )
+        }
+        Err(e) => {
+            error!("Error saving single sign-on user: {}", e);
+            single_sign_on_failed(config)
+        }
     }
+}
 
-    let add_result = database::insert_custom_owner(&*config, &*database, &custom_owner);
+/// Finds or creates the account for someone who logged in through the identity
+/// provider, and syncs their role from their groups. Accounts are matched on
+/// the provider's subject; one with the same username is only taken over if
+/// it has no password and isn't linked to anyone else, so registering a name
+/// first doesn't capture someone's single sign-on.
+fn provision_oidc_user(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    oidc_user: &utils::oidc::OidcUser,
+    change: models::ChangeContext,
+) -> Result<models::User, failure::Error> {
+    // recorded as done by the person logging in
+    let change = models::ChangeContext {
+        actor: Some(oidc_user.username.clone()),
+        ..change
+    };
+    let linked = database::get_user_by_oidc_subject(config, database, &oidc_user.issuer, &oidc_user.subject)?;
+    let user = match linked {
+        Some(user) => user,
+        None => match database::get_user_by_name(config, database, &oidc_user.username)? {
+            Some(user) => {
+                if database::link_oidc_user(config, database, user.id, &oidc_user.issuer, &oidc_user.subject, &change)? == 0 {
+                    return Err(failure::err_msg(format!(
+                        "{} is a local account, not linked to single sign-on",
+                        user.username
+                    )));
+                }
+                user
+            }
+            None => {
+                let user_insert = models::UserInsert {
+                    username: oidc_user.username.clone(),
+                    password_hash: auth::NO_PASSWORD.to_string(),
+                    role: oidc_user.role.unwrap_or(models::UserRole::User),
+                    oidc_issuer: Some(oidc_user.issuer.clone()),
+                    oidc_subject: Some(oidc_user.subject.clone()),
+                };
+                database::insert_user(config, database, &user_insert, &change)?;
+                return database::get_user_by_name(config, database, &user_insert.username)?
+                    .ok_or_else(|| failure::err_msg("Failed to add user"));
+            }
+        },
+    };
+    match oidc_user.role {
+        Some(role) if role != user.role => {
+            database::set_user_role(config, database, user.id, role, &change)?;
+            Ok(models::User { role, ..user })
+        }
+        _ => Ok(user),
+    }
+}
 
-    match add_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Failed to add custom_owner",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Successfully added custom_owner",
-        ),
+#[post("/logout", data = "<_csrf>")]
+pub fn post_logout(
+    config: &rocket::State<utils::types::Settings>,
+    cookies: &rocket::http::CookieJar<'_>,
+    _csrf: csrf::CsrfChecked,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_logout()");
+    cookies.remove_private(auth::SESSION_COOKIE);
+    rocket::response::Flash::success(
+        redirect(config, "/devices"),
+        "Logged out",
+    )
+}
+
+#[get("/register")]
+pub fn get_register(
+    config: &rocket::State<utils::types::Settings>,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, rocket::response::Flash<rocket::response::Redirect>> {
+    trace!("get_register()");
+    if config.disable_registration {
+        return Err(registration_disabled(config));
     }
+    Ok(csrf::render("register", &csrf_token, &gen_message_context(status_message)))
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
-#[post("/deleteCustomOwners", data = "<custom_owner_edit>")]
-pub fn post_delete_custom_owners(
+fn registration_disabled(config: &utils::types::Settings) -> rocket::response::Flash<rocket::response::Redirect> {
+    rocket::response::Flash::error(
+        redirect(config, "/login"),
+        "Registration is disabled, ask an admin for an account",
+    )
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/register", data = "<user_register>")]
+pub async fn post_register(
     config: &rocket::State<utils::types::Settings>,
     database: pool::DbConn,
-    custom_owner_edit: Form<models::CustomOwnerDelete>,
+    cookies: &rocket::http::CookieJar<'_>,
+    change: models::ChangeContext,
+    user_register: csrf::CsrfForm<models::UserRegister>,
 ) -> rocket::response::Flash<rocket::response::Redirect> {
-    trace!("post_delete_custom_owners()");
-
-    let custom_owner = custom_owner_edit.into_inner();
-    if let Err(errors) = custom_owner.validate() {
+    trace!("post_register()");
+    if config.disable_registration {
+        return registration_disabled(config);
+    }
+    let mut user_register = user_register.into_inner();
+    user_register.username = user_register.username.trim().to_string();
+    if let Err(errors) = user_register.validate() {
         let errors = errors.field_errors();
         let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
+            Some(m) => m.to_string(),
+            None => "Failed to parse form data".to_string(),
         };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            msg,
-        );
+        return rocket::response::Flash::error(redirect(config, "/register"), msg);
     }
-    let update_result = database::delete_custom_owner(&*config, &*database, &custom_owner);
 
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Failed to delete custom_owner",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Successfully deleted custom_owner",
-        ),
+    let result = database
+        .run(move |config, database| -> Result<models::User, String> {
+            if let Ok(Some(_)) = database::get_user_by_name(config, database, &user_register.username) {
+                return Err("That username is already taken".to_string());
+            }
+            let user_insert = models::UserInsert {
+                username: user_register.username.clone(),
+                password_hash: auth::hash_password(config, &user_register.password).map_err(|e| e.to_string())?,
+                role: models::UserRole::User,
+                oidc_issuer: None,
+                oidc_subject: None,
+            };
+            let change = models::ChangeContext {
+                actor: Some(user_insert.username.clone()),
+                ..change
+            };
+            match database::insert_user(config, database, &user_insert, &change) {
+                Ok(1) => {}
+                _ => return Err("Failed to register user".to_string()),
+            }
+            database::get_user_by_name(config, database, &user_insert.username)
+                .ok()
+                .and_then(|user| user)
+                .ok_or_else(|| "Failed to register user".to_string())
+        })
+        .await;
+
+    match result {
+        Ok(user) => {
+            cookies.add_private(rocket::http::Cookie::new(auth::SESSION_COOKIE, user.id.to_string()));
+            rocket::response::Flash::success(
+                redirect(config, "/devices"),
+                format!("Registered and logged in as {}", user.username),
+            )
+        }
+        Err(msg) => rocket::response::Flash::error(redirect(config, "/register"), msg),
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
-#[post("/editCustomOwners", data = "<custom_owner_edit>")]
-pub fn post_edit_custom_owners(
-    config: &rocket::State<utils::types::Settings>,
+#[derive(Serialize)]
+struct PerUserPoolContext {
+    id: i32,
+    pool_name: String,
+    managed: bool,
+}
+
+#[derive(Serialize)]
+struct PerUserContext {
+    user: models::UserSummary,
+    pools: Vec<PerUserPoolContext>,
+}
+
+#[derive(Serialize, Default)]
+struct UsersContext {
+    users: Vec<PerUserContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success_message: Option<String>,
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[get("/audit?<filter..>")]
+pub async fn api_get_audit(
     database: pool::DbConn,
-    custom_owner_edit: Form<models::CustomOwnerModify>,
-) -> rocket::response::Flash<rocket::response::Redirect> {
-    trace!("post_edit_custom_owners()");
+    user: auth::ApiUser,
+    filter: models::AuditFilter,
+) -> Result<json::Json<Vec<models::AuditEntry>>, rocket::http::Status> {
+    trace!("api_get_audit()");
+    if user.role < models::UserRole::Admin {
+        return Err(rocket::http::Status::Forbidden);
+    }
+    database
+        .run(move |config, database| {
+            database::get_audit_log(config, database, &filter)
+                .map(json::Json)
+                .map_err(|_| rocket::http::Status::InternalServerError)
+        })
+        .await
+}
 
-    let mut custom_owner = custom_owner_edit.into_inner();
-    custom_owner.custom_owner_name = custom_owner.custom_owner_name.to_lowercase();
-    custom_owner.recipient = custom_owner.recipient.to_lowercase();
-    if let Err(errors) = custom_owner.validate() {
-        let errors = errors.field_errors();
-        let msg = match find_first_validation_message(&errors) {
-            Some(m) => m,
-            None => "Failed to parse form data",
-        };
-        return rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            msg,
-        );
+#[derive(Serialize)]
+struct PerAuditEntryContext {
+    #[serde(flatten)]
+    entry: models::AuditEntry,
+    created_at_local: String,
+    before_text: Option<String>,
+    after_text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditContext {
+    entries: Vec<PerAuditEntryContext>,
+    filter: models::AuditFilter,
+}
+
+/// Pretty prints json stored as text for showing in a page.
+fn pretty_json(value: &Option<String>) -> Option<String> {
+    let value = value.as_ref()?;
+    match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(json) => serde_json::to_string_pretty(&json).ok(),
+        Err(_) => Some(value.clone()),
     }
-    let update_result = database::edit_custom_owner(&*config, &*database, &custom_owner);
+}
 
-    match update_result {
-        Ok(0) | Err(_) => rocket::response::Flash::error(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Failed to update custom_owner",
-        ),
-        _ => rocket::response::Flash::success(
-            rocket::response::Redirect::to("/editCustomOwners"),
-            "Successfully updated custom_owner",
-        ),
+fn format_audit_entry(entry: models::AuditEntry) -> PerAuditEntryContext {
+    let created_at_local = chrono::DateTime::<chrono::Local>::from_naive_utc_and_offset(
+        entry.created_at,
+        chrono::Local::now().offset().fix(),
+    );
+    PerAuditEntryContext {
+        created_at_local: format!("{}", created_at_local.format("%F %r")),
+        before_text: pretty_json(&entry.before),
+        after_text: pretty_json(&entry.after),
+        entry,
     }
 }
 
-type ValidationErrorsInner =
-    std::collections::HashMap<&'static str, Vec<validator::ValidationError>>;
+#[allow(clippy::needless_pass_by_value)]
+#[get("/audit?<filter..>")]
+pub async fn get_audit(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    filter: models::AuditFilter,
+) -> Result<templates::Template, String> {
+    trace!("get_audit()");
+    database
+        .run(move |config, database| {
+            let entries = database::get_audit_log(config, database, &filter)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(format_audit_entry)
+                .collect();
+            let context = AuditContext { entries, filter };
+            Ok(templates::Template::render("audit", &context))
+        })
+        .await
+}
 
-fn find_first_validation_message<'a>(
-    errors: &'a ValidationErrorsInner,
-) -> Option<&'a std::borrow::Cow<'static, str>> {
-    for es in errors {
-        for e in es.1 {
-            if let Some(ref e) = e.message {
-                return Some(e);
+#[allow(clippy::needless_pass_by_value)]
+#[get("/editUsers")]
+pub async fn get_edit_users(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, String> {
+    trace!("get_edit_users()");
+    let message_context = gen_message_context(status_message);
+    database
+        .run(move |config, database| {
+            let pools = database::get_pools(config, database).map_err(|e| e.to_string())?;
+            let users = database::get_user_summaries(config, database)
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|user| PerUserContext {
+                    pools: pools
+                        .iter()
+                        .map(|pool| PerUserPoolContext {
+                            id: pool.id,
+                            pool_name: pool.pool_name.clone(),
+                            managed: user.pool_ids.contains(&pool.id),
+                        })
+                        .collect(),
+                    user,
+                })
+                .collect();
+            let context = UsersContext {
+                users,
+                error_message: message_context.error_message,
+                success_message: message_context.success_message,
+            };
+            Ok(csrf::render("edit_users", &csrf_token, &context))
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/editUsers", data = "<user_edit>")]
+pub async fn post_edit_users(
+    database: pool::DbConn,
+    _user: auth::Admin,
+    change: models::ChangeContext,
+    user_edit: csrf::CsrfForm<models::UserModify>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_edit_users()");
+    database
+        .run(move |config, database| {
+            let user_edit = user_edit.into_inner();
+            if let Err(errors) = user_edit.validate_with_context(&ValidationContext::new(config, database)) {
+                let errors = errors.field_errors();
+                let msg = match find_first_validation_message(&errors) {
+                    Some(m) => m,
+                    None => "Failed to parse form data",
+                };
+                return rocket::response::Flash::error(
+                    redirect(config, "/editUsers"),
+                    msg,
+                );
             }
-        }
-    }
-    None
+            let update_result = database::edit_user_role(config, database, &user_edit, &change);
+
+            match update_result {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/editUsers"),
+                    "Failed to update user",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/editUsers"),
+                    "Successfully updated user",
+                ),
+            }
+        })
+        .await
+}
+
+// settings
+
+#[derive(Serialize, Default)]
+struct SettingsContext {
+    username: String,
+    role: models::UserRole,
+    api_tokens: Vec<models::ApiToken>,
+    /// A token just created, only ever shown this once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_api_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success_message: Option<String>,
+}
+
+fn gen_settings_context(
+    config: &utils::types::Settings,
+    database: &database::DbConn,
+    user: &auth::CurrentUser,
+    status_message: MessageContext,
+) -> Result<SettingsContext, failure::Error> {
+    Ok(SettingsContext {
+        username: user.username.clone(),
+        role: user.role,
+        api_tokens: database::get_api_tokens(config, database, user.id)?,
+        new_api_token: None,
+        error_message: status_message.error_message,
+        success_message: status_message.success_message,
+    })
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[get("/settings")]
+pub async fn get_settings(
+    database: pool::DbConn,
+    user: auth::CurrentUser,
+    status_message: Option<rocket::request::FlashMessage<'_>>,
+    csrf_token: csrf::CsrfToken,
+) -> Result<templates::Template, String> {
+    trace!("get_settings()");
+    let status_message = gen_message_context(status_message);
+    database
+        .run(move |config, database| {
+            gen_settings_context(config, database, &user, status_message)
+                .map(|context| csrf::render("settings", &csrf_token, &context))
+                .map_err(|e| e.to_string())
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/settings/tokens", data = "<api_token_add>")]
+pub async fn post_add_api_tokens(
+    database: pool::DbConn,
+    user: auth::CurrentUser,
+    change: models::ChangeContext,
+    csrf_token: csrf::CsrfToken,
+    api_token_add: csrf::CsrfForm<models::ApiTokenCreate>,
+) -> Result<templates::Template, String> {
+    trace!("post_add_api_tokens()");
+    database
+        .run(move |config, database| {
+            let api_token = api_token_add.into_inner();
+            let mut status_message = MessageContext::default();
+            let mut new_api_token = None;
+            if let Err(errors) = api_token.validate() {
+                let errors = errors.field_errors();
+                status_message.error_message = Some(match find_first_validation_message(&errors) {
+                    Some(m) => m.to_string(),
+                    None => "Failed to parse form data".to_string(),
+                });
+            } else {
+                let (token, token_hash) = auth::generate_api_token();
+                let api_token_insert = models::ApiTokenInsert {
+                    user_id: user.id,
+                    name: api_token.name,
+                    token_hash,
+                };
+                match database::insert_api_token(config, database, &api_token_insert, &change) {
+                    Ok(1) => {
+                        status_message.success_message = Some("Created api token, copy it now as it won't be shown again".into());
+                        new_api_token = Some(token);
+                    }
+                    _ => status_message.error_message = Some("Failed to create api token".into()),
+                }
+            }
+
+            //rendered rather than redirected, so the new token never goes in a cookie
+            let mut context = gen_settings_context(config, database, &user, status_message)
+                .map_err(|e| e.to_string())?;
+            context.new_api_token = new_api_token;
+            Ok(csrf::render("settings", &csrf_token, &context))
+        })
+        .await
+}
+
+#[allow(clippy::needless_pass_by_value)]
+#[post("/settings/tokens/delete", data = "<api_token_delete>")]
+pub async fn post_delete_api_tokens(
+    database: pool::DbConn,
+    user: auth::CurrentUser,
+    change: models::ChangeContext,
+    api_token_delete: csrf::CsrfForm<models::ApiTokenDelete>,
+) -> rocket::response::Flash<rocket::response::Redirect> {
+    trace!("post_delete_api_tokens()");
+    database
+        .run(move |config, database| {
+            let api_token = api_token_delete.into_inner();
+            match database::delete_api_token(config, database, user.id, &api_token, &change) {
+                Ok(0) | Err(_) => rocket::response::Flash::error(
+                    redirect(config, "/settings"),
+                    "Failed to revoke api token",
+                ),
+                _ => rocket::response::Flash::success(
+                    redirect(config, "/settings"),
+                    "Successfully revoked api token",
+                ),
+            }
+        })
+        .await
 }
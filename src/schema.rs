@@ -9,6 +9,8 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         pool_id -> Integer,
+        updated_by -> Nullable<Text>,
+        reservation_id -> Nullable<Integer>,
     }
 }
 
@@ -19,12 +21,22 @@ table! {
         description -> Nullable<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        updated_by -> Nullable<Text>,
+        max_reservations_per_owner -> Nullable<Integer>,
+    }
+}
+
+table! {
+    device_owners (device_id, owner_name) {
+        device_id -> Integer,
+        owner_name -> Text,
     }
 }
 
 joinable!(devices -> pools (pool_id));
+joinable!(device_owners -> devices (device_id));
 
-allow_tables_to_appear_in_same_query!(devices, pools,);
+allow_tables_to_appear_in_same_query!(api_tokens, audit_log, device_owners, devices, pools, pool_admins, users,);
 
 table! {
     custom_owners (id) {
@@ -34,5 +46,67 @@ table! {
         description -> Nullable<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        updated_by -> Nullable<Text>,
+        unlimited_reservations -> Bool,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Integer,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        role -> crate::models::UserRoleMapping,
+        oidc_issuer -> Nullable<Text>,
+        oidc_subject -> Nullable<Text>,
+    }
+}
+
+table! {
+    pool_admins (user_id, pool_id) {
+        user_id -> Integer,
+        pool_id -> Integer,
+    }
+}
+
+joinable!(pool_admins -> pools (pool_id));
+joinable!(pool_admins -> users (user_id));
+
+
+table! {
+    api_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+joinable!(api_tokens -> users (user_id));
+
+table! {
+    audit_log (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        actor -> Nullable<Text>,
+        action -> crate::models::AuditActionMapping,
+        entity_type -> crate::models::AuditEntityMapping,
+        entity_id -> Nullable<Integer>,
+        before -> Nullable<Text>,
+        after -> Nullable<Text>,
+        source -> crate::models::ChangeSourceMapping,
+        source_ip -> Nullable<Text>,
+        reason -> Nullable<Text>,
+    }
+}
+
+table! {
+    secrets (name) {
+        name -> Text,
+        value -> Text,
     }
 }
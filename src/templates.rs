@@ -0,0 +1,354 @@
+//! The web UI's handlebars templates and static assets, built into the binary
+//! so it runs without them on disk. Files in `--templates` replace the built in
+//! ones of the same name, templates at its top and assets under `static/`.
+//! Templates are also partials, so `header.html.hbs` there changes the header
+//! of every page.
+
+use crate::utils;
+use failure;
+use failure::ResultExt;
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError};
+use include_dir::{include_dir, Dir};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use serde;
+use serde_json;
+use std;
+use std::path::{Path, PathBuf};
+
+static TEMPLATES: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/templates");
+static STATIC_FILES: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/static");
+
+const TEMPLATE_EXTENSION: &str = ".html.hbs";
+
+///Where pages load the css and javascript from when it isn't in `static/`,
+///with the hash to check it against. Only jQuery is committed there, the rest
+///is left to `scripts/fetch-static.sh`.
+const CDN_ASSETS: &[(&str, &str, Option<&str>)] = &[
+    (
+        "bootstrap.min.css",
+        "https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0/css/bootstrap.min.css",
+        Some("sha384-Gn5384xqQ1aoWXA+058RXPxPg6fy4IWvTNh0E263XmFcJlSAwiGgFAW/dAiS6JXm"),
+    ),
+    (
+        "bootstrap.min.js",
+        "https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0/js/bootstrap.min.js",
+        Some("sha384-JZR6Spejh4U02d8jOt6vLEHfe/JQGiRRSQQxSfFWpi1MquVdAyjUar5+76PVCmYl"),
+    ),
+    (
+        "popper.min.js",
+        "https://cdnjs.cloudflare.com/ajax/libs/popper.js/1.12.9/umd/popper.min.js",
+        Some("sha384-ApNbgh9B+Y1QKtv3Rn7W3mgPxhU9K/ScQsAP7hUibX39j7fakFPskvXusvfa0b4Q"),
+    ),
+    ("jquery.min.js", "https://code.jquery.com/jquery-3.6.1.min.js", None),
+    (
+        "bootstrap-table.min.css",
+        "https://unpkg.com/bootstrap-table@1.18.0/dist/bootstrap-table.min.css",
+        None,
+    ),
+    (
+        "bootstrap-table.min.js",
+        "https://unpkg.com/bootstrap-table@1.18.0/dist/bootstrap-table.min.js",
+        None,
+    ),
+];
+
+///The registered templates, managed by rocket
+pub struct Templates {
+    handlebars: Handlebars<'static>,
+}
+
+impl Templates {
+    pub fn new(config: &utils::types::Settings) -> Result<Self, failure::Error> {
+        let mut handlebars = Handlebars::new();
+        for file in TEMPLATES.files() {
+            if let (Some(name), Some(source)) = (template_name(file.path()), file.contents_utf8()) {
+                handlebars
+                    .register_template_string(&name, source)
+                    .with_context(|_| format!("Failed to parse built in template {}", name))?;
+            }
+        }
+        if let Some(template_dir) = &config.template_dir {
+            let entries =
+                std::fs::read_dir(template_dir).with_context(|_| format!("Failed to read {}", template_dir))?;
+            for entry in entries {
+                let path = entry?.path();
+                if let Some(name) = template_name(&path) {
+                    debug!("Using template {} from {}", name, path.display());
+                    handlebars
+                        .register_template_file(&name, &path)
+                        .with_context(|_| format!("Failed to parse template {}", path.display()))?;
+                }
+            }
+        }
+        handlebars.register_helper("base_path", Box::new(BasePathHelper(config.base_path.clone())));
+        for kind in &[AssetKind::Stylesheet, AssetKind::Script] {
+            let helper = AssetHelper {
+                kind: *kind,
+                base_path: config.base_path.clone(),
+                template_dir: config.template_dir.clone(),
+            };
+            handlebars.register_helper(kind.helper_name(), Box::new(helper));
+        }
+        Ok(Templates { handlebars })
+    }
+
+    pub fn render(&self, name: &str, context: &serde_json::Value) -> Result<String, failure::Error> {
+        Ok(self
+            .handlebars
+            .render(name, context)
+            .with_context(|_| format!("Failed to render template {}", name))?)
+    }
+}
+
+///`devices` for `devices.html.hbs`
+fn template_name(path: &Path) -> Option<String> {
+    let file_name = path.file_name()?.to_str()?;
+    file_name.strip_suffix(TEMPLATE_EXTENSION).map(|name| name.to_string())
+}
+
+///Loads the templates as rocket starts, refusing to start if one is broken
+pub fn fairing() -> impl Fairing {
+    AdHoc::try_on_ignite("Templates", |rocket| async {
+        let templates = match rocket.state::<utils::types::Settings>() {
+            Some(config) => Templates::new(config),
+            None => Err(failure::err_msg("No settings to load templates with")),
+        };
+        match templates {
+            Ok(templates) => Ok(rocket.manage(templates)),
+            Err(e) => {
+                error!("{}", e);
+                for cause in e.iter_causes() {
+                    error!("  caused by: {}", cause);
+                }
+                Err(rocket)
+            }
+        }
+    })
+}
+
+///`{{base_path}}`, which the templates put in front of every link and form
+struct BasePathHelper(String);
+
+impl HelperDef for BasePathHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        out.write(&self.0)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AssetKind {
+    Stylesheet,
+    Script,
+}
+
+impl AssetKind {
+    fn helper_name(self) -> &'static str {
+        match self {
+            AssetKind::Stylesheet => "stylesheet",
+            AssetKind::Script => "script",
+        }
+    }
+}
+
+///`{{stylesheet "bootstrap.min.css"}}` and `{{script "jquery.min.js"}}`, the
+///tag loading a file from `static/` if we have it, otherwise from its CDN
+struct AssetHelper {
+    kind: AssetKind,
+    base_path: String,
+    template_dir: Option<String>,
+}
+
+impl AssetHelper {
+    fn is_local(&self, name: &str) -> bool {
+        let on_disk = self
+            .template_dir
+            .as_ref()
+            .is_some_and(|template_dir| PathBuf::from(template_dir).join("static").join(name).is_file());
+        on_disk || STATIC_FILES.get_file(name).is_some()
+    }
+
+    fn tag(&self, name: &str) -> String {
+        let (url, integrity) = match CDN_ASSETS.iter().find(|(file, _, _)| *file == name) {
+            Some((_, url, integrity)) if !self.is_local(name) => (url.to_string(), *integrity),
+            _ => (format!("{}/static/{}", self.base_path, name), None),
+        };
+        let integrity = integrity
+            .map(|integrity| format!(r#" integrity="{}" crossorigin="anonymous""#, integrity))
+            .unwrap_or_default();
+        match self.kind {
+            AssetKind::Stylesheet => format!(r#"<link rel="stylesheet" href="{}"{}/>"#, url, integrity),
+            AssetKind::Script => format!(r#"<script src="{}"{}></script>"#, url, integrity),
+        }
+    }
+}
+
+impl HelperDef for AssetHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let name = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or_else(|| RenderError::new(format!("{} needs a file name", self.kind.helper_name())))?;
+        out.write(&self.tag(name))?;
+        Ok(())
+    }
+}
+
+///A page rendered from a template and its context
+#[derive(Debug)]
+pub struct Template {
+    name: &'static str,
+    context: serde_json::Value,
+}
+
+impl Template {
+    pub fn render<C: serde::Serialize>(name: &'static str, context: C) -> Template {
+        Template {
+            name,
+            context: serde_json::to_value(context).unwrap_or(serde_json::Value::Null),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Template {
+    fn respond_to(mut self, request: &'r Request<'_>) -> response::Result<'static> {
+        let templates = request.rocket().state::<Templates>().ok_or(Status::InternalServerError)?;
+        let config = request
+            .rocket()
+            .state::<utils::types::Settings>()
+            .ok_or(Status::InternalServerError)?;
+        if let serde_json::Value::Object(context) = &mut self.context {
+            context.insert("theme".to_string(), serde_json::json!(config.theme));
+        }
+        match templates.render(self.name, &self.context) {
+            Ok(html) => (ContentType::HTML, html).respond_to(request),
+            Err(e) => {
+                error!("{}", e);
+                for cause in e.iter_causes() {
+                    error!("  caused by: {}", cause);
+                }
+                Err(Status::InternalServerError)
+            }
+        }
+    }
+}
+
+///A static asset, from `static/` in `--templates` if it's there, otherwise
+///built in
+pub fn static_file(config: &utils::types::Settings, path: &Path) -> Option<(ContentType, Vec<u8>)> {
+    let content_type = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary);
+    let on_disk = config
+        .template_dir
+        .as_ref()
+        .map(|template_dir| PathBuf::from(template_dir).join("static").join(path))
+        .and_then(|path| std::fs::read(path).ok());
+    let contents = on_disk.or_else(|| STATIC_FILES.get_file(path).map(|file| file.contents().to_vec()))?;
+    Some((content_type, contents))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_built_in_templates() {
+        let mut config = utils::types::Settings::new();
+        config.base_path = "/checkout".to_string();
+        let templates = Templates::new(&config).unwrap();
+        let html = templates.render("login", &serde_json::json!({})).unwrap();
+        assert!(html.contains(r#"action="/checkout/login""#));
+        assert!(html.contains(r#"<link rel="stylesheet" href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0/css/bootstrap.min.css" integrity="sha384-"#));
+        let html = templates.render("devices", &serde_json::json!({})).unwrap();
+        assert!(html.contains(r#"<script src="/checkout/static/jquery.min.js"></script>"#));
+        assert!(templates.render("nonexistent", &serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn test_template_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("login.html.hbs"), "custom login").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a template").unwrap();
+        std::fs::create_dir(dir.path().join("static")).unwrap();
+        std::fs::write(dir.path().join("static").join("logo.svg"), "<svg/>").unwrap();
+        std::fs::write(dir.path().join("static").join("bootstrap.min.css"), "").unwrap();
+        let mut config = utils::types::Settings::new();
+        config.template_dir = Some(dir.path().to_string_lossy().to_string());
+
+        let templates = Templates::new(&config).unwrap();
+        assert_eq!(templates.render("login", &serde_json::json!({})).unwrap(), "custom login");
+        let html = templates.render("devices", &serde_json::json!({})).unwrap();
+        assert!(html.contains(r#"<link rel="stylesheet" href="/static/bootstrap.min.css"/>"#));
+        assert!(html.contains(r#"<link rel="stylesheet" href="https://unpkg.com/bootstrap-table@1.18.0/dist/bootstrap-table.min.css"/>"#));
+
+        let (content_type, contents) = static_file(&config, Path::new("logo.svg")).unwrap();
+        assert_eq!(content_type, ContentType::SVG);
+        assert_eq!(contents, b"<svg/>");
+        let (content_type, _) = static_file(&config, Path::new("jquery.min.js")).unwrap();
+        assert_eq!(content_type, ContentType::JavaScript);
+        assert!(static_file(&config, Path::new("missing.css")).is_none());
+
+        std::fs::write(dir.path().join("devices.html.hbs"), "{{#if}}").unwrap();
+        assert!(Templates::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_theme() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = utils::types::Settings::new();
+        config.template_dir = Some(dir.path().to_string_lossy().to_string());
+        let theme = utils::types::Theme {
+            site_title: "Lab 4".into(),
+            logo_url: Some("/static/logo.svg".into()),
+            accent_color: Some("#336699".into()),
+            footer_links: vec![utils::types::FooterLink {
+                label: "Wiki".into(),
+                url: "https://wiki.example".into(),
+            }],
+            banner: Some("Closed <Friday>".into()),
+        };
+        let context = serde_json::json!({ "theme": theme });
+
+        let html = Templates::new(&config).unwrap().render("login", &context).unwrap();
+        assert!(html.contains("<title>Log in - Lab 4</title>"));
+        assert!(html.contains(r#"<img src="/static/logo.svg""#));
+        assert!(html.contains("background-color: #336699;"));
+        assert!(html.contains(r#"<a href="https://wiki.example" class="mr-3">Wiki</a>"#));
+        assert!(html.contains("Closed &lt;Friday&gt;"));
+
+        let html = Templates::new(&config)
+            .unwrap()
+            .render("login", &serde_json::json!({ "theme": utils::types::Theme::default() }))
+            .unwrap();
+        assert!(!html.contains("<style>"));
+        assert!(!html.contains("site_footer"));
+        assert!(!html.contains(r#"id="banner""#));
+
+        // a partial replaced on disk changes every page
+        std::fs::write(dir.path().join("footer.html.hbs"), "<footer>{{theme.site_title}} footer</footer>").unwrap();
+        let templates = Templates::new(&config).unwrap();
+        for page in &["login", "register", "devices", "audit"] {
+            assert!(templates.render(page, &context).unwrap().contains("<footer>Lab 4 footer</footer>"));
+        }
+    }
+}
@@ -1,16 +1,34 @@
 use clap;
+use crate::auth;
+use crate::models::UserRole;
+use crate::utils::config;
 use crate::utils::types;
 
 pub fn parse_cmdline() -> types::Settings {
     let matches = matcher().get_matches();
-    match parse(&matches) {
+    let settings = config::Layers::from_process(&matches).and_then(|layers| {
+        let settings = parse_layers(&layers)?;
+        if matches.is_present("print_config") {
+            print!("{}", layers.describe()?);
+            std::process::exit(0);
+        }
+        Ok(settings)
+    });
+    match settings {
         Ok(s) => s,
         Err(e) => e.exit(),
     }
 }
 
+///Parses the command line alone, without the environment or a config file
+#[cfg(test)]
 fn parse(matches: &clap::ArgMatches<'_>) -> Result<types::Settings, clap::Error> {
-    let verbosity = matches.occurrences_of("verbosity") as usize;
+    parse_layers(&config::Layers::new(matches, Default::default(), Default::default())?)
+}
+
+fn parse_layers(layers: &config::Layers<'_, '_>) -> Result<types::Settings, clap::Error> {
+    layers.check()?;
+    let verbosity = layers.occurrences_of("verbosity") as usize;
     if verbosity > 4 {
         Err(clap::Error {
             message: "invalid number of 'v' flags".into(),
@@ -18,8 +36,8 @@ fn parse(matches: &clap::ArgMatches<'_>) -> Result<types::Settings, clap::Error>
             info: None,
         })?
     }
-    let quiet = matches.is_present("quiet");
-    let timestamp = match matches.value_of("timestamp") {
+    let quiet = layers.is_present("quiet");
+    let timestamp = match layers.value_of("timestamp").as_deref() {
         Some("ns") => types::Timestamp::Nanosecond,
         Some("ms") => types::Timestamp::Microsecond,
         Some("sec") => types::Timestamp::Second,
@@ -31,26 +49,621 @@ fn parse(matches: &clap::ArgMatches<'_>) -> Result<types::Settings, clap::Error>
         })?,
     };
 
-    let template_dir = matches.value_of("templates").map(|x| x.to_string());
+    let template_dir = layers.value_of("templates");
 
-    let port = value_t!(matches.value_of("port"), u16)?;
-    let database = matches.value_of("database").ok_or_else(|| clap::Error {
+    let address = value_t!(layers.value_of("address"), std::net::IpAddr)?;
+    let port = value_t!(layers.value_of("port"), u16)?;
+    let tls_cert = layers.value_of("tls_cert");
+    let tls_key = layers.value_of("tls_key");
+    if tls_cert.is_some() != tls_key.is_some() {
+        Err(clap::Error {
+            message: "'--tls-cert' and '--tls-key' must be given together".into(),
+            kind: clap::ErrorKind::MissingRequiredArgument,
+            info: None,
+        })?
+    }
+    let base_path = parse_base_path(&layers.value_of("base_path").unwrap_or_default())?;
+    let secret_key = layers.value_of("secret_key");
+    if secret_key.iter().any(|key| auth::secret_key(key).is_none()) {
+        Err(clap::Error {
+            message: "invalid value for 'secret_key': expected at least 256 bits as base64 or hex".into(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        })?
+    }
+    let pool_size = value_t!(layers.value_of("pool_size"), u32)?;
+    if pool_size == 0 {
+        Err(clap::Error {
+            message: "invalid value for 'pool-size'".into(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        })?
+    }
+    let busy_timeout_ms = value_t!(layers.value_of("busy_timeout"), u32)?;
+    let max_reservations_per_owner = match layers.value_of("max_reservations") {
+        Some(_) => Some(value_t!(layers.value_of("max_reservations"), usize)?),
+        None => None,
+    };
+    let database = layers.value_of("database").ok_or_else(|| clap::Error {
         message: "invalid value for 'database'".into(),
         kind: clap::ErrorKind::InvalidValue,
         info: None,
     })?;
 
+    let directory_backends = match layers.values_of("directory") {
+        Some(values) => values
+            .iter()
+            .map(|v| match v.as_str() {
+                "ldap" => Ok(types::DirectoryBackend::Ldap),
+                "slack" => Ok(types::DirectoryBackend::Slack),
+                _ => Err(clap::Error {
+                    message: format!("invalid value '{}' for 'directory', expected slack or ldap", v),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![types::DirectoryBackend::Slack],
+    };
+    let ldap_url = layers.value_of("ldap_url");
+    if directory_backends.contains(&types::DirectoryBackend::Ldap) && ldap_url.is_none() {
+        Err(clap::Error {
+            message: "'--directory ldap' requires '--ldap-url'".into(),
+            kind: clap::ErrorKind::MissingRequiredArgument,
+            info: None,
+        })?
+    }
+    let ldap_search_filter = layers
+        .value_of("ldap_filter")
+        .unwrap_or_else(|| "(uid={username})".to_string());
+    let ldap_timeout_secs = value_t!(layers.value_of("ldap_timeout"), u64)?;
+    if ldap_timeout_secs == 0 {
+        Err(clap::Error {
+            message: "invalid value for 'ldap-timeout'".into(),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        })?
+    }
+    let ldap_accept_unreachable = match layers.value_of("ldap_unreachable").as_deref() {
+        None | Some("accept") => true,
+        Some("reject") => false,
+        Some(v) => Err(clap::Error {
+            message: format!("invalid value '{}' for 'ldap-unreachable', expected accept or reject", v),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        })?,
+    };
+
+    let oidc_issuer = layers.value_of("oidc_issuer");
+    let oidc_client_id = layers.value_of("oidc_client_id");
+    let oidc_redirect_url = layers.value_of("oidc_redirect_url");
+    if oidc_issuer.is_some() && (oidc_client_id.is_none() || oidc_redirect_url.is_none()) {
+        Err(clap::Error {
+            message: "'--oidc-issuer' requires '--oidc-client-id' and '--oidc-redirect-url'".into(),
+            kind: clap::ErrorKind::MissingRequiredArgument,
+            info: None,
+        })?
+    }
+    let oidc_group_roles = match layers.values_of("oidc_group_role") {
+        Some(values) => values.iter().map(|v| parse_group_role(v)).collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+    let command = match layers.matches.subcommand() {
+        ("admin", Some(subcommand)) => types::Command::Admin(parse_admin_command(subcommand)?),
+        ("sync", Some(subcommand)) => types::Command::Admin(types::AdminCommand::Sync {
+            input: subcommand.value_of("input").unwrap_or("").to_string(),
+            format: subcommand.value_of("format").map(|x| x.to_string()),
+            prune: subcommand.is_present("prune"),
+            dry_run: subcommand.is_present("dry_run"),
+        }),
+        (name, Some(subcommand)) => parse_client_command(name, subcommand)?,
+        _ => types::Command::Serve,
+    };
+    let theme = parse_theme(layers, &base_path)?;
+    let bundles = match layers.values_of("bundle") {
+        Some(values) => values.iter().map(|v| parse_bundle(v)).collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+
     Ok(types::Settings {
         verbosity,
         quiet,
         timestamp,
+        address,
         port,
+        tls_cert,
+        tls_key,
+        base_path,
+        secret_key,
+        pool_size,
+        busy_timeout_ms,
         template_dir,
-        database_url: database.to_string(),
+        theme,
+        database_url: database,
+        directory_backends,
+        ldap_url,
+        slack_api_token: layers.value_of("slack_api_token"),
+        ldap_bind_dn: layers.value_of("ldap_bind_dn"),
+        ldap_bind_password: layers.value_of("ldap_bind_password"),
+        ldap_base_dn: layers.value_of("ldap_base_dn"),
+        ldap_search_filter,
+        ldap_timeout_secs,
+        ldap_accept_unreachable,
+        disable_registration: layers.is_present("disable_registration"),
+        oidc_issuer,
+        oidc_client_id,
+        oidc_client_secret: layers.value_of("oidc_client_secret"),
+        oidc_redirect_url,
+        oidc_scopes: layers
+            .value_of("oidc_scopes")
+            .unwrap_or_else(|| "openid profile email".to_string()),
+        oidc_username_claim: layers
+            .value_of("oidc_username_claim")
+            .unwrap_or_else(|| "preferred_username".to_string()),
+        oidc_groups_claim: layers.value_of("oidc_groups_claim").unwrap_or_else(|| "groups".to_string()),
+        oidc_group_roles,
+        bundles,
+        max_reservations_per_owner,
+        inventory_file: layers.value_of("inventory"),
+        prune_inventory: layers.is_present("prune_inventory"),
+        command,
         ..Default::default()
     })
 }
 
+fn parse_theme(layers: &config::Layers<'_, '_>, base_path: &str) -> Result<types::Theme, clap::Error> {
+    let invalid = |name: &str, value: &str, expected: &str| clap::Error {
+        message: format!("invalid value '{}' for '{}', expected {}", value, name, expected),
+        kind: clap::ErrorKind::InvalidValue,
+        info: None,
+    };
+    let accent_color = layers.value_of("accent_color");
+    if let Some(color) = &accent_color {
+        // it goes into a style sheet, so only hex colours and names
+        let hex = color.strip_prefix('#').filter(|hex| hex.len() == 3 || hex.len() == 6);
+        let valid = match hex {
+            Some(hex) => hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
+        };
+        if !valid {
+            Err(invalid("accent-color", color, "#rgb, #rrggbb or a colour name"))?
+        }
+    }
+    let footer_links = layers
+        .values_of("footer_link")
+        .unwrap_or_default()
+        .iter()
+        .map(|value| match value.split_once('=') {
+            Some((label, url)) if !label.trim().is_empty() && !url.trim().is_empty() => Ok(types::FooterLink {
+                label: label.trim().to_string(),
+                url: url.trim().to_string(),
+            }),
+            _ => Err(invalid("footer-link", value, "<label>=<url>")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // a bare file name is one of the static files
+    let logo_url = layers.value_of("logo").map(|logo| {
+        if logo.starts_with('/') || logo.contains("://") {
+            logo
+        } else {
+            format!("{}/static/{}", base_path, logo)
+        }
+    });
+    let defaults = types::Theme::default();
+    Ok(types::Theme {
+        site_title: layers.value_of("site_title").unwrap_or(defaults.site_title),
+        logo_url,
+        accent_color,
+        footer_links,
+        banner: layers.value_of("banner").filter(|banner| !banner.trim().is_empty()),
+    })
+}
+
+///Normalizes a `--base-path` to start with `/` and not end with one, so
+///`/checkout/` and `checkout` are both `/checkout`, and `/` is the root
+fn parse_base_path(value: &str) -> Result<String, clap::Error> {
+    let segments: Vec<&str> = value.split('/').filter(|segment| !segment.is_empty()).collect();
+    let valid = segments
+        .iter()
+        .all(|segment| segment.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c)));
+    if !valid {
+        Err(clap::Error {
+            message: format!("invalid value for 'base-path': {}", value),
+            kind: clap::ErrorKind::InvalidValue,
+            info: None,
+        })?
+    }
+    Ok(segments.iter().map(|segment| format!("/{}", segment)).collect())
+}
+
+///Parses a `<group>=<role>` mapping for '--oidc-group-role'
+fn parse_group_role(value: &str) -> Result<(String, UserRole), clap::Error> {
+    let invalid = || clap::Error {
+        message: format!("invalid value '{}' for 'oidc-group-role', expected <group>=<role>", value),
+        kind: clap::ErrorKind::InvalidValue,
+        info: None,
+    };
+    let separator = value.rfind('=').ok_or_else(invalid)?;
+    let role = parse_role(&value[separator + 1..]).ok_or_else(invalid)?;
+    Ok((value[..separator].to_string(), role))
+}
+
+///A role as it's written on the command line
+fn parse_role(value: &str) -> Option<UserRole> {
+    match value {
+        "viewer" => Some(UserRole::Viewer),
+        "user" => Some(UserRole::User),
+        "pool_admin" => Some(UserRole::PoolAdmin),
+        "admin" => Some(UserRole::Admin),
+        _ => None,
+    }
+}
+
+///Parses a `<name>=<pool>[:<count>],...` definition for '--bundle'
+fn parse_bundle(value: &str) -> Result<types::BundleDefinition, clap::Error> {
+    let invalid = || clap::Error {
+        message: format!(
+            "invalid value '{}' for 'bundle', expected <name>=<pool>[:<count>],...",
+            value
+        ),
+        kind: clap::ErrorKind::InvalidValue,
+        info: None,
+    };
+    let separator = value.find('=').ok_or_else(invalid)?;
+    let name = value[..separator].trim();
+    if name.is_empty() {
+        Err(invalid())?
+    }
+    let mut pools = vec![];
+    for pool in value[separator + 1..].split(',') {
+        let (pool_name, count) = match pool.rfind(':') {
+            Some(colon) => (&pool[..colon], pool[colon + 1..].trim().parse().map_err(|_| invalid())?),
+            None => (pool, 1),
+        };
+        if pool_name.trim().is_empty() || count == 0 {
+            Err(invalid())?
+        }
+        pools.push((pool_name.trim().to_string(), count));
+    }
+    Ok(types::BundleDefinition {
+        name: name.to_string(),
+        pools,
+    })
+}
+
+///Parses one of the client subcommands, which all share '--url', '--token'
+///and '--json'
+fn parse_client_command(name: &str, matches: &clap::ArgMatches<'_>) -> Result<types::Command, clap::Error> {
+    let settings = types::ClientSettings {
+        url: matches
+            .value_of("url")
+            .unwrap_or("http://localhost:8000")
+            .trim_end_matches('/')
+            .to_string(),
+        token: matches.value_of("token").map(|x| x.to_string()),
+        json: matches.is_present("json"),
+    };
+    let owner = matches.value_of("owner").map(|x| x.to_string());
+    let comments = matches.value_of("comments").map(|x| x.to_string());
+    let command = match name {
+        "list" => types::ClientCommand::List {
+            pool_id: match matches.value_of("pool") {
+                Some(_) => Some(value_t!(matches, "pool", i32)?),
+                None => None,
+            },
+        },
+        "claim" => {
+            let count = value_t!(matches, "count", usize)?;
+            if count == 0 {
+                Err(clap::Error {
+                    message: "invalid value for 'count'".into(),
+                    kind: clap::ErrorKind::InvalidValue,
+                    info: None,
+                })?
+            }
+            types::ClientCommand::Claim {
+                pool_id: value_t!(matches, "pool", i32)?,
+                count,
+                bundle: matches.value_of("bundle").map(|x| x.to_string()),
+                owner,
+                comments,
+            }
+        }
+        "return" => types::ClientCommand::Return {
+            reservation_id: value_t!(matches, "reservation", i32)?,
+        },
+        "mine" => types::ClientCommand::Mine,
+        "pools" => types::ClientCommand::Pools,
+        "wait" => types::ClientCommand::Wait {
+            pool_id: value_t!(matches, "pool", i32)?,
+            owner,
+            comments,
+            timeout_secs: value_t!(matches, "timeout", u64)?,
+            interval_secs: value_t!(matches, "interval", u64)?,
+        },
+        _ => Err(clap::Error {
+            message: format!("unknown subcommand '{}'", name),
+            kind: clap::ErrorKind::UnrecognizedSubcommand,
+            info: None,
+        })?,
+    };
+    Ok(types::Command::Client(settings, command))
+}
+
+///Parses 'admin' and its subcommands
+fn parse_admin_command(matches: &clap::ArgMatches<'_>) -> Result<types::AdminCommand, clap::Error> {
+    let value = |matches: &clap::ArgMatches<'_>, name: &str| matches.value_of(name).unwrap_or("").to_string();
+    let optional = |matches: &clap::ArgMatches<'_>, name: &str| matches.value_of(name).map(|x| x.to_string());
+    Ok(match matches.subcommand() {
+        ("device", Some(device)) => match device.subcommand() {
+            ("add", Some(m)) => types::AdminCommand::DeviceAdd {
+                name: value(m, "name"),
+                url: value(m, "url"),
+                pool: value(m, "pool"),
+            },
+            ("edit", Some(m)) => types::AdminCommand::DeviceEdit {
+                name: value(m, "name"),
+                new_name: optional(m, "new_name"),
+                url: optional(m, "url"),
+            },
+            ("delete", Some(m)) => types::AdminCommand::DeviceDelete { name: value(m, "name") },
+            ("move", Some(m)) => types::AdminCommand::DeviceMove {
+                name: value(m, "name"),
+                pool: value(m, "pool"),
+            },
+            _ => Err(clap::Error::with_description("missing device subcommand", clap::ErrorKind::MissingSubcommand))?,
+        },
+        ("pool", Some(pool)) => match pool.subcommand() {
+            ("add", Some(m)) => types::AdminCommand::PoolAdd {
+                name: value(m, "name"),
+                description: optional(m, "description"),
+                max_reservations_per_owner: match m.value_of("max_per_owner") {
+                    Some(_) => Some(value_t!(m, "max_per_owner", i32)?),
+                    None => None,
+                },
+            },
+            ("delete", Some(m)) => types::AdminCommand::PoolDelete { pool: value(m, "pool") },
+            _ => Err(clap::Error::with_description("missing pool subcommand", clap::ErrorKind::MissingSubcommand))?,
+        },
+        ("owner", Some(owner)) => match owner.subcommand() {
+            ("add", Some(m)) => types::AdminCommand::OwnerAdd {
+                name: value(m, "name"),
+                recipient: value(m, "recipient"),
+                description: optional(m, "description"),
+                unlimited_reservations: m.is_present("unlimited"),
+            },
+            _ => Err(clap::Error::with_description("missing owner subcommand", clap::ErrorKind::MissingSubcommand))?,
+        },
+        ("user", Some(user)) => match user.subcommand() {
+            ("add", Some(m)) => types::AdminCommand::UserAdd {
+                name: value(m, "name"),
+                role: parse_role(&value(m, "role")).unwrap_or(UserRole::User),
+                // like the other secrets, kept out of the process list
+                password: std::env::var(format!("{}PASSWORD", config::ENV_PREFIX)).ok(),
+            },
+            _ => Err(clap::Error::with_description("missing user subcommand", clap::ErrorKind::MissingSubcommand))?,
+        },
+        ("release-all", Some(m)) => types::AdminCommand::ReleaseAll {
+            pool: value(m, "pool"),
+            reason: value(m, "reason"),
+        },
+        ("export", Some(m)) => types::AdminCommand::Export {
+            format: optional(m, "format"),
+            output: optional(m, "output"),
+        },
+        ("import", Some(m)) => types::AdminCommand::Import {
+            input: value(m, "input"),
+            format: optional(m, "format"),
+            dry_run: m.is_present("dry_run"),
+        },
+        ("migrate", _) => types::AdminCommand::Migrate,
+        ("check", _) => types::AdminCommand::Check,
+        _ => Err(clap::Error::with_description("missing admin subcommand", clap::ErrorKind::MissingSubcommand))?,
+    })
+}
+
+///'--format' for inventory files
+fn inventory_format<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("format")
+        .long("format")
+        .help("by default from the file's extension")
+        .possible_values(&["csv", "json", "toml", "yaml"])
+        .takes_value(true)
+}
+
+///The 'admin' subcommands, working on '--database' directly
+fn admin_subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    let subcommand = |name, about| {
+        clap::SubCommand::with_name(name)
+            .about(about)
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+    };
+    let name = |help| clap::Arg::with_name("name").help(help).required(true);
+    let pool = |help| clap::Arg::with_name("pool").long("pool").help(help).takes_value(true);
+    subcommand("admin", "Changes '--database' directly, without a server")
+        .subcommand(
+            subcommand("device", "Adds, edits, deletes or moves devices")
+                .subcommand(
+                    clap::SubCommand::with_name("add")
+                        .arg(name("device name"))
+                        .arg(
+                            clap::Arg::with_name("url")
+                                .long("url")
+                                .help("device url")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(pool("pool id or name").default_value("1")),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("edit")
+                        .arg(name("device name"))
+                        .arg(
+                            clap::Arg::with_name("new_name")
+                                .long("name")
+                                .help("new device name")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("url")
+                                .long("url")
+                                .help("new device url")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(clap::SubCommand::with_name("delete").arg(name("device name")))
+                .subcommand(
+                    clap::SubCommand::with_name("move")
+                        .arg(name("device name"))
+                        .arg(pool("pool id or name to move it to").required(true)),
+                ),
+        )
+        .subcommand(
+            subcommand("pool", "Adds or deletes pools")
+                .subcommand(
+                    clap::SubCommand::with_name("add")
+                        .arg(name("pool name"))
+                        .arg(
+                            clap::Arg::with_name("description")
+                                .long("description")
+                                .help("pool description")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("max_per_owner")
+                                .long("max-per-owner")
+                                .help("most of its devices one owner may have at once")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("delete")
+                        .arg(clap::Arg::with_name("pool").help("pool id or name").required(true)),
+                ),
+        )
+        .subcommand(
+            subcommand("owner", "Adds custom owners").subcommand(
+                clap::SubCommand::with_name("add")
+                    .arg(name("custom owner name"))
+                    .arg(
+                        clap::Arg::with_name("recipient")
+                            .long("recipient")
+                            .help("who is told about their devices, or none")
+                            .default_value("none")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("description")
+                            .long("description")
+                            .help("custom owner description")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::with_name("unlimited")
+                            .long("unlimited")
+                            .help("not held to the reservation quotas, e.g. for CI"),
+                    ),
+            ),
+        )
+        .subcommand(
+            subcommand("user", "Adds accounts, such as the first admin").subcommand(
+                clap::SubCommand::with_name("add")
+                    .about("Adds an account, with the password in DEVICE_CHECKOUT_PASSWORD, or none for single sign-on")
+                    .arg(name("username"))
+                    .arg(
+                        clap::Arg::with_name("role")
+                            .long("role")
+                            .help("what they may do")
+                            .possible_values(&["viewer", "user", "pool_admin", "admin"])
+                            .default_value("user")
+                            .takes_value(true),
+                    ),
+            ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("release-all")
+                .about("Force releases every reserved device in a pool")
+                .arg(pool("pool id or name").required(true))
+                .arg(
+                    clap::Arg::with_name("reason")
+                        .long("reason")
+                        .help("why, as told to the owners")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Writes out every pool, custom owner and device")
+                .arg(inventory_format())
+                .arg(
+                    clap::Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .help("file to write, instead of stdout")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Adds or updates pools, custom owners and devices by name, all or nothing")
+                .arg(clap::Arg::with_name("input").help("file to read").required(true))
+                .arg(inventory_format())
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("only print what would change"),
+                ),
+        )
+        .subcommand(clap::SubCommand::with_name("migrate").about("Brings the database up to date"))
+        .subcommand(
+            clap::SubCommand::with_name("check").about("Looks for devices whose reservations don't hang together"),
+        )
+}
+
+///A subcommand talking to a running server
+fn client_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            clap::Arg::with_name("url")
+                .long("url")
+                .env("DEVICE_CHECKOUT_URL")
+                .help("the server to talk to")
+                .default_value("http://localhost:8000")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("token")
+                .long("token")
+                .env("DEVICE_CHECKOUT_TOKEN")
+                .hide_env_values(true)
+                .help("api token, from your /settings page")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("json")
+                .long("json")
+                .help("print the server's json instead of a table"),
+        )
+}
+
+///The owner and comments for the subcommands claiming devices
+fn reservation_args<'a, 'b>(subcommand: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    subcommand
+        .arg(
+            clap::Arg::with_name("owner")
+                .long("owner")
+                .help("who to reserve for, defaults to the token's user")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("comments")
+                .long("comments")
+                .help("comments on the reservation")
+                .takes_value(true),
+        )
+}
+
 fn matcher<'a, 'b>() -> clap::App<'a, 'b> {
     clap::App::new(crate_name!())
         .version(crate_version!())
@@ -75,6 +688,25 @@ fn matcher<'a, 'b>() -> clap::App<'a, 'b> {
                 .takes_value(true)
                 .possible_values(&["none", "sec", "ms", "ns"]),
         )
+        .arg(
+            clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("TOML file of settings, overridden by DEVICE_CHECKOUT_* environment variables and then flags")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("print_config")
+                .long("print-config")
+                .help("print the settings in effect and where each came from, then exit"),
+        )
+        .arg(
+            clap::Arg::with_name("address")
+                .long("address")
+                .help("ip address to listen on")
+                .default_value("127.0.0.1")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("port")
                 .short("p")
@@ -83,6 +715,56 @@ fn matcher<'a, 'b>() -> clap::App<'a, 'b> {
                 .default_value("8000")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("site_title")
+                .long("site-title")
+                .help("name shown in the header and page titles")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("logo")
+                .long("logo")
+                .help("logo shown beside the site title, a url or a file under static/ in --templates")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("accent_color")
+                .long("accent-color")
+                .help("colour of buttons, links and the header, as #rrggbb or a css colour name")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("footer_link")
+                .long("footer-link")
+                .help("<label>=<url> link shown at the bottom of every page, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("banner")
+                .long("banner")
+                .help("announcement shown at the top of every page")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .help("PEM certificate chain to serve HTTPS with, needs --tls-key")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("tls_key")
+                .long("tls-key")
+                .help("PEM private key for --tls-cert")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("base_path")
+                .long("base-path")
+                .help("path the site is served under behind a reverse proxy, e.g. /checkout")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("templates")
                 .long("templates")
@@ -93,10 +775,232 @@ fn matcher<'a, 'b>() -> clap::App<'a, 'b> {
             clap::Arg::with_name("database")
                 .short("d")
                 .long("database")
-                .help("path to database file")
+                .help("path to sqlite database file, or postgres:// url")
                 .default_value("devices.db")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("pool_size")
+                .long("pool-size")
+                .help("maximum number of database connections")
+                .default_value("8")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("busy_timeout")
+                .long("busy-timeout")
+                .help("milliseconds to wait for a locked database")
+                .default_value("5000")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("max_reservations")
+                .long("max-reservations-per-owner")
+                .help("most devices one owner may have reserved at once")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("inventory")
+                .long("inventory")
+                .help("toml, yaml, json or csv file of pools and devices to sync into the database at startup")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("prune_inventory")
+                .long("prune-inventory")
+                .help("remove the pools and devices that aren't in '--inventory', except reserved devices")
+                .requires("inventory"),
+        )
+        .arg(
+            clap::Arg::with_name("directory")
+                .long("directory")
+                .help("directory used to validate owners, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["slack", "ldap"]),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_url")
+                .long("ldap-url")
+                .help("LDAP server url, e.g. ldap://ldap.example.com:389")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_bind_dn")
+                .long("ldap-bind-dn")
+                .help("DN to bind as, the password is read from DEVICE_CHECKOUT_LDAP_BIND_PASSWORD")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_base_dn")
+                .long("ldap-base-dn")
+                .help("base DN to search for owners under")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_filter")
+                .long("ldap-filter")
+                .help("LDAP search filter, {username} is replaced with the owner")
+                .default_value("(uid={username})")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_timeout")
+                .long("ldap-timeout")
+                .help("seconds to wait for the LDAP server to connect, and then to answer")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ldap_unreachable")
+                .long("ldap-unreachable")
+                .help("whether owners are accepted or rejected when the LDAP server can't be reached")
+                .possible_values(&["accept", "reject"])
+                .default_value("accept")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("disable_registration")
+                .long("disable-registration")
+                .help("only admins add accounts, with 'admin user add', or single sign-on makes them"),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_issuer")
+                .long("oidc-issuer")
+                .help("OpenID Connect issuer url for single sign-on, the client secret is read from DEVICE_CHECKOUT_OIDC_CLIENT_SECRET")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_client_id")
+                .long("oidc-client-id")
+                .help("client id registered with the OpenID Connect provider")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_redirect_url")
+                .long("oidc-redirect-url")
+                .help("public url of /login/oidc/callback, as registered with the provider")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_scopes")
+                .long("oidc-scopes")
+                .help("space separated scopes to request")
+                .default_value("openid profile email")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_username_claim")
+                .long("oidc-username-claim")
+                .help("claim holding the username")
+                .default_value("preferred_username")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_groups_claim")
+                .long("oidc-groups-claim")
+                .help("claim holding the user's groups")
+                .default_value("groups")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("oidc_group_role")
+                .long("oidc-group-role")
+                .help("<group>=<role> role given to members of a group, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("bundle")
+                .long("bundle")
+                .help("<name>=<pool>[:<count>],... devices reserved together by name, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .subcommand(admin_subcommand())
+        .subcommand(
+            clap::SubCommand::with_name("sync")
+                .about("Makes '--database' match an inventory file, without a server")
+                .arg(clap::Arg::with_name("input").help("inventory file").required(true))
+                .arg(inventory_format())
+                .arg(
+                    clap::Arg::with_name("prune")
+                        .long("prune")
+                        .help("remove the pools and devices that aren't in the file, except reserved devices"),
+                )
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("only print what would change"),
+                ),
+        )
+        .subcommand(
+            client_subcommand("list", "Lists the devices").arg(
+                clap::Arg::with_name("pool")
+                    .long("pool")
+                    .help("only the devices in this pool id")
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(reservation_args(
+            client_subcommand("claim", "Reserves available devices from a pool, or a bundle")
+                .arg(
+                    clap::Arg::with_name("pool")
+                        .long("pool")
+                        .help("pool id to reserve from")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("count")
+                        .long("count")
+                        .help("how many devices to reserve")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("bundle")
+                        .long("bundle")
+                        .help("reserve a bundle the server defines instead")
+                        .takes_value(true),
+                ),
+        ))
+        .subcommand(
+            client_subcommand("return", "Returns the devices of a reservation").arg(
+                clap::Arg::with_name("reservation")
+                    .help("reservation id, as printed by claim or mine")
+                    .required(true),
+            ),
+        )
+        .subcommand(client_subcommand("mine", "Lists the token's user's reservations"))
+        .subcommand(client_subcommand("pools", "Lists the pools"))
+        .subcommand(reservation_args(
+            client_subcommand("wait", "Waits for a device in a pool to be free, and reserves it")
+                .arg(
+                    clap::Arg::with_name("pool")
+                        .long("pool")
+                        .help("pool id to reserve from")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("timeout")
+                        .long("timeout")
+                        .help("seconds to wait before giving up")
+                        .default_value("3600")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("interval")
+                        .long("interval")
+                        .help("seconds between tries")
+                        .default_value("10")
+                        .takes_value(true),
+                ),
+        ))
 }
 
 #[cfg(test)]
@@ -123,6 +1027,26 @@ mod tests {
         assert_eq!(s.database_url, "somefile.txt".to_string());
     }
 
+    #[test]
+    fn test_pool_size() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.pool_size, 8);
+        assert_eq!(s.busy_timeout_ms, 5000);
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--pool-size", "16", "--busy-timeout", "100"])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.pool_size, 16);
+        assert_eq!(s.busy_timeout_ms, 100);
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--pool-size", "0"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
     #[test]
     fn test_template_dir() {
         let m = matcher()
@@ -141,6 +1065,290 @@ mod tests {
         assert_eq!(s.template_dir, None);
     }
 
+    #[test]
+    fn test_directory() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.directory_backends, vec![types::DirectoryBackend::Slack]);
+
+        let m = matcher()
+            .get_matches_from_safe(vec![
+                "",
+                "--directory",
+                "ldap",
+                "--directory",
+                "slack",
+                "--ldap-url",
+                "ldap://localhost",
+                "--ldap-base-dn",
+                "ou=people,dc=example,dc=com",
+            ])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(
+            s.directory_backends,
+            vec![types::DirectoryBackend::Ldap, types::DirectoryBackend::Slack]
+        );
+        assert_eq!(s.ldap_url, Some("ldap://localhost".to_string()));
+        assert_eq!(s.ldap_base_dn, Some("ou=people,dc=example,dc=com".to_string()));
+        assert_eq!(s.ldap_search_filter, "(uid={username})".to_string());
+        assert_eq!(s.ldap_timeout_secs, 5);
+        assert!(s.ldap_accept_unreachable);
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--ldap-timeout", "2", "--ldap-unreachable", "reject"])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.ldap_timeout_secs, 2);
+        assert!(!s.ldap_accept_unreachable);
+        let m = matcher().get_matches_from_safe(vec!["", "--ldap-timeout", "0"]).unwrap();
+        assert!(parse(&m).is_err());
+        assert!(matcher().get_matches_from_safe(vec!["", "--ldap-unreachable", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn test_ldap_directory_without_url() {
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--directory", "ldap"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_oidc() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.oidc_issuer, None);
+        assert_eq!(s.oidc_username_claim, "preferred_username".to_string());
+        assert!(!s.disable_registration);
+
+        let m = matcher()
+            .get_matches_from_safe(vec![
+                "",
+                "--oidc-issuer",
+                "https://idp.example.com",
+                "--oidc-client-id",
+                "device-checkout",
+                "--oidc-redirect-url",
+                "https://checkout.example.com/login/oidc/callback",
+                "--oidc-username-claim",
+                "email",
+                "--oidc-group-role",
+                "checkout-admins=admin",
+                "--oidc-group-role",
+                "team=lab=pool_admin",
+                "--disable-registration",
+            ])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert!(s.disable_registration);
+        assert_eq!(s.oidc_issuer, Some("https://idp.example.com".to_string()));
+        assert_eq!(s.oidc_username_claim, "email".to_string());
+        assert_eq!(
+            s.oidc_group_roles,
+            vec![
+                ("checkout-admins".to_string(), UserRole::Admin),
+                ("team=lab".to_string(), UserRole::PoolAdmin),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_oidc() {
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--oidc-issuer", "https://idp.example.com"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--oidc-group-role", "admins=superuser"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--oidc-group-role", "admins"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_client_subcommands() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        assert_eq!(parse(&m).unwrap().command, types::Command::Serve);
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "list", "--url", "http://checkout/", "--pool", "2", "--json"])
+            .unwrap();
+        assert_eq!(
+            parse(&m).unwrap().command,
+            types::Command::Client(
+                types::ClientSettings {
+                    url: "http://checkout".to_string(),
+                    token: None,
+                    json: true,
+                },
+                types::ClientCommand::List { pool_id: Some(2) }
+            )
+        );
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "claim", "--token", "dc_x", "--count", "2", "--owner", "ci"])
+            .unwrap();
+        match parse(&m).unwrap().command {
+            types::Command::Client(settings, command) => {
+                assert_eq!(settings.token, Some("dc_x".to_string()));
+                assert_eq!(
+                    command,
+                    types::ClientCommand::Claim {
+                        pool_id: 1,
+                        count: 2,
+                        bundle: None,
+                        owner: Some("ci".to_string()),
+                        comments: None,
+                    }
+                );
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+
+        let m = matcher().get_matches_from_safe(vec!["", "return", "7"]).unwrap();
+        match parse(&m).unwrap().command {
+            types::Command::Client(_, command) => {
+                assert_eq!(command, types::ClientCommand::Return { reservation_id: 7 })
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+
+        assert!(matcher().get_matches_from_safe(vec!["", "return"]).is_err());
+        for bad in &[vec!["", "claim", "--count", "0"], vec!["", "return", "seven"], vec!["", "wait", "--timeout", "soon"]] {
+            let m = matcher().get_matches_from_safe(bad.clone()).unwrap();
+            assert!(parse(&m).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_admin_subcommands() {
+        let admin = |args: Vec<&str>| {
+            let m = matcher().get_matches_from_safe(args).unwrap();
+            match parse(&m).unwrap().command {
+                types::Command::Admin(command) => command,
+                command => panic!("unexpected {:?}", command),
+            }
+        };
+        assert_eq!(
+            admin(vec!["", "--database", "lab.db", "admin", "device", "add", "unit3", "--url", "http://unit3"]),
+            types::AdminCommand::DeviceAdd {
+                name: "unit3".to_string(),
+                url: "http://unit3".to_string(),
+                pool: "1".to_string(),
+            }
+        );
+        assert_eq!(
+            admin(vec!["", "admin", "device", "move", "unit3", "--pool", "Lab2"]),
+            types::AdminCommand::DeviceMove {
+                name: "unit3".to_string(),
+                pool: "Lab2".to_string(),
+            }
+        );
+        assert_eq!(
+            admin(vec!["", "admin", "pool", "add", "Lab2", "--max-per-owner", "2"]),
+            types::AdminCommand::PoolAdd {
+                name: "Lab2".to_string(),
+                description: None,
+                max_reservations_per_owner: Some(2),
+            }
+        );
+        assert_eq!(
+            admin(vec!["", "admin", "owner", "add", "ci", "--unlimited"]),
+            types::AdminCommand::OwnerAdd {
+                name: "ci".to_string(),
+                recipient: "none".to_string(),
+                description: None,
+                unlimited_reservations: true,
+            }
+        );
+        match admin(vec!["", "admin", "user", "add", "alice", "--role", "admin"]) {
+            types::AdminCommand::UserAdd { name, role, .. } => assert_eq!((name.as_str(), role), ("alice", UserRole::Admin)),
+            command => panic!("unexpected {:?}", command),
+        }
+        assert!(matcher().get_matches_from_safe(vec!["", "admin", "user", "add", "alice", "--role", "root"]).is_err());
+        assert_eq!(
+            admin(vec!["", "admin", "release-all", "--pool", "2", "--reason", "lab move"]),
+            types::AdminCommand::ReleaseAll {
+                pool: "2".to_string(),
+                reason: "lab move".to_string(),
+            }
+        );
+        assert_eq!(admin(vec!["", "admin", "check"]), types::AdminCommand::Check);
+        assert_eq!(
+            admin(vec!["", "admin", "import", "rack.csv", "--dry-run"]),
+            types::AdminCommand::Import {
+                input: "rack.csv".to_string(),
+                format: None,
+                dry_run: true,
+            }
+        );
+        assert_eq!(
+            admin(vec!["", "admin", "export", "--format", "json"]),
+            types::AdminCommand::Export {
+                format: Some("json".to_string()),
+                output: None,
+            }
+        );
+        assert!(matcher().get_matches_from_safe(vec!["", "admin", "export", "--format", "xml"]).is_err());
+        assert_eq!(
+            admin(vec!["", "sync", "inventory.toml", "--prune"]),
+            types::AdminCommand::Sync {
+                input: "inventory.toml".to_string(),
+                format: None,
+                prune: true,
+                dry_run: false,
+            }
+        );
+
+        assert!(matcher().get_matches_from_safe(vec!["", "admin", "device", "move", "unit3"]).is_err());
+        assert!(matcher().get_matches_from_safe(vec!["", "admin", "device"]).is_err());
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "admin", "pool", "add", "Lab2", "--max-per-owner", "two"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_max_reservations() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        assert_eq!(parse(&m).unwrap().max_reservations_per_owner, None);
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--max-reservations-per-owner", "3"])
+            .unwrap();
+        assert_eq!(parse(&m).unwrap().max_reservations_per_owner, Some(3));
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--max-reservations-per-owner", "lots"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_bundle() {
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--bundle", "rig=phones:2,Default Pool"])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(
+            s.bundles,
+            vec![types::BundleDefinition {
+                name: "rig".to_string(),
+                pools: vec![("phones".to_string(), 2), ("Default Pool".to_string(), 1)],
+            }]
+        );
+
+        for bad in &["rig", "=phones", "rig=phones:0", "rig=phones:lots", "rig=a,,b"] {
+            let m = matcher().get_matches_from_safe(vec!["", "--bundle", bad]).unwrap();
+            assert!(parse(&m).is_err(), "{}", bad);
+        }
+    }
+
     #[test]
     fn test_too_much_verbosity() {
         let m = matcher().get_matches_from_safe(vec!["", "-vvvvv"]).unwrap();
@@ -174,4 +1382,208 @@ mod tests {
             .get_matches_from_safe(vec!["", "-t", "bogus"])
             .is_err());
     }
+
+    #[test]
+    fn test_address() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.address.to_string(), "127.0.0.1");
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--address", "::"])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.address.to_string(), "::");
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--address", "localhost"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_tls_and_base_path() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.tls_cert, None);
+        assert_eq!(s.base_path, "");
+
+        let m = matcher()
+            .get_matches_from_safe(vec![
+                "",
+                "--tls-cert",
+                "cert.pem",
+                "--tls-key",
+                "key.pem",
+                "--base-path",
+                "/lab/checkout/",
+            ])
+            .unwrap();
+        let s = parse(&m).unwrap();
+        assert_eq!(s.tls_cert, Some("cert.pem".to_string()));
+        assert_eq!(s.tls_key, Some("key.pem".to_string()));
+        assert_eq!(s.base_path, "/lab/checkout");
+
+        for base_path in &["/", "checkout", "/check out", "/checkout?x"] {
+            let m = matcher()
+                .get_matches_from_safe(vec!["", "--base-path", base_path])
+                .unwrap();
+            match *base_path {
+                "/" => assert_eq!(parse(&m).unwrap().base_path, ""),
+                "checkout" => assert_eq!(parse(&m).unwrap().base_path, "/checkout"),
+                _ => assert!(parse(&m).is_err()),
+            }
+        }
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--tls-cert", "cert.pem"])
+            .unwrap();
+        assert!(parse(&m).is_err());
+    }
+
+    #[test]
+    fn test_theme() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        assert_eq!(parse(&m).unwrap().theme, types::Theme::default());
+
+        let m = matcher()
+            .get_matches_from_safe(vec![
+                "",
+                "--base-path",
+                "/checkout",
+                "--site-title",
+                "Lab 4",
+                "--logo",
+                "logo.svg",
+                "--accent-color",
+                "#336699",
+                "--footer-link",
+                "Wiki=https://wiki.example/?a=b",
+                "--footer-link",
+                "Help = mailto:lab@example.com",
+                "--banner",
+                "Closed Friday",
+            ])
+            .unwrap();
+        let theme = parse(&m).unwrap().theme;
+        assert_eq!(theme.site_title, "Lab 4");
+        assert_eq!(theme.logo_url, Some("/checkout/static/logo.svg".to_string()));
+        assert_eq!(theme.accent_color, Some("#336699".to_string()));
+        assert_eq!(
+            theme.footer_links,
+            vec![
+                types::FooterLink {
+                    label: "Wiki".into(),
+                    url: "https://wiki.example/?a=b".into(),
+                },
+                types::FooterLink {
+                    label: "Help".into(),
+                    url: "mailto:lab@example.com".into(),
+                },
+            ]
+        );
+        assert_eq!(theme.banner, Some("Closed Friday".to_string()));
+
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--logo", "https://example.com/logo.png", "--accent-color", "teal"])
+            .unwrap();
+        let theme = parse(&m).unwrap().theme;
+        assert_eq!(theme.logo_url, Some("https://example.com/logo.png".to_string()));
+        assert_eq!(theme.accent_color, Some("teal".to_string()));
+
+        for (arg, value) in &[
+            ("--accent-color", "red; background: url(x)"),
+            ("--accent-color", "#12345"),
+            ("--footer-link", "Wiki"),
+            ("--footer-link", "=https://wiki.example"),
+        ] {
+            let m = matcher().get_matches_from_safe(vec!["", arg, value]).unwrap();
+            assert!(parse(&m).is_err(), "{} {}", arg, value);
+        }
+    }
+
+    #[test]
+    fn test_secret_key() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        assert_eq!(parse(&m).unwrap().secret_key, None);
+
+        let key = auth::generate_secret_key();
+        let env = vec![("ROCKET_SECRET_KEY".to_string(), key.clone())].into_iter().collect();
+        let layers = config::Layers::new(&m, env, Default::default()).unwrap();
+        assert_eq!(parse_layers(&layers).unwrap().secret_key, Some(key.clone()));
+        assert!(!layers.describe().unwrap().contains(&key));
+
+        let file = config::test_file("secret_key = 'too short'");
+        let layers = config::Layers::new(&m, Default::default(), file).unwrap();
+        assert!(parse_layers(&layers).is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let file = config::test_file(
+            r#"
+            port = 9000
+            database = "file.db"
+            templates = "file-templates"
+            directory = ["slack"]
+            slack_api_token = "from-file"
+            "#,
+        );
+        let env = vec![
+            ("DEVICE_CHECKOUT_PORT", "8500"),
+            ("DEVICE_CHECKOUT_DATABASE", "env.db"),
+            ("DEVICE_CHECKOUT_TIMESTAMP", "sec"),
+            ("DEVICE_CHECKOUT_DIRECTORY", "slack; ldap"),
+            ("DEVICE_CHECKOUT_LDAP_URL", "ldap://ldap"),
+            ("SLACK_API_TOKEN", "legacy"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let m = matcher()
+            .get_matches_from_safe(vec!["", "--port", "1234"])
+            .unwrap();
+        let layers = config::Layers::new(&m, env, file).unwrap();
+        let s = parse_layers(&layers).unwrap();
+
+        assert_eq!(s.port, 1234);
+        assert_eq!(layers.source_of("port"), Some(config::Source::Cli));
+        assert_eq!(s.database_url, "env.db");
+        assert_eq!(layers.source_of("database"), Some(config::Source::Env));
+        assert_eq!(s.template_dir, Some("file-templates".to_string()));
+        assert_eq!(layers.source_of("templates"), Some(config::Source::File));
+        assert_eq!(s.pool_size, 8);
+        assert_eq!(layers.source_of("pool_size"), Some(config::Source::Default));
+        assert_eq!(
+            s.directory_backends,
+            vec![types::DirectoryBackend::Slack, types::DirectoryBackend::Ldap]
+        );
+        assert!(matches!(s.timestamp, types::Timestamp::Second));
+        // the older variable still counts as the environment, above the file
+        assert_eq!(s.slack_api_token, Some("legacy".to_string()));
+
+        let description = layers.describe().unwrap();
+        assert!(description.contains("port = 1234 # command line\n"));
+        assert!(description.contains("directory = [\"slack\", \"ldap\"] # environment\n"));
+        assert!(description.contains("slack_api_token = \"********\" # environment\n"));
+        assert!(description.contains("# oidc_issuer is unset\n"));
+        assert!(!description.contains("legacy"));
+    }
+
+    #[test]
+    fn test_bad_config_file() {
+        let m = matcher().get_matches_from_safe(vec![""]).unwrap();
+        let file = config::test_file("prot = 9000");
+        assert!(config::Layers::new(&m, Default::default(), file).is_err());
+
+        let file = config::test_file("port = [9000]");
+        let layers = config::Layers::new(&m, Default::default(), file).unwrap();
+        assert!(parse_layers(&layers).is_err());
+
+        let env = vec![("DEVICE_CHECKOUT_TIMESTAMP".to_string(), "bogus".to_string())]
+            .into_iter()
+            .collect();
+        let layers = config::Layers::new(&m, env, Default::default()).unwrap();
+        assert!(parse_layers(&layers).is_err());
+    }
 }
@@ -0,0 +1,298 @@
+//! Settings come from, in increasing priority, their defaults, the `--config`
+//! TOML file, `DEVICE_CHECKOUT_*` environment variables and the command line.
+
+use clap;
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use toml;
+
+pub const ENV_PREFIX: &str = "DEVICE_CHECKOUT_";
+
+///Where a setting's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "config file"),
+            Source::Env => write!(f, "environment"),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Value,
+    ///Repeatable on the command line, an array in the file and `;` separated
+    ///in the environment
+    Values,
+    Flag,
+    ///Counted `-v` flags
+    Count,
+    ///Never on the command line, where it would show in the process list, and
+    ///also read from its older environment variable
+    Secret(&'static str),
+}
+
+///Every layered setting, as its clap arg name, its key in the config file and
+///in the environment (upper cased, after `DEVICE_CHECKOUT_`), and its kind
+const SETTINGS: &[(&str, &str, Kind)] = &[
+    ("verbosity", "verbosity", Kind::Count),
+    ("quiet", "quiet", Kind::Flag),
+    ("timestamp", "timestamp", Kind::Value),
+    ("address", "address", Kind::Value),
+    ("port", "port", Kind::Value),
+    ("tls_cert", "tls_cert", Kind::Value),
+    ("tls_key", "tls_key", Kind::Value),
+    ("base_path", "base_path", Kind::Value),
+    ("secret_key", "secret_key", Kind::Secret("ROCKET_SECRET_KEY")),
+    ("templates", "templates", Kind::Value),
+    ("site_title", "site_title", Kind::Value),
+    ("logo", "logo", Kind::Value),
+    ("accent_color", "accent_color", Kind::Value),
+    ("footer_link", "footer_links", Kind::Values),
+    ("banner", "banner", Kind::Value),
+    ("database", "database", Kind::Value),
+    ("pool_size", "pool_size", Kind::Value),
+    ("busy_timeout", "busy_timeout", Kind::Value),
+    ("max_reservations", "max_reservations_per_owner", Kind::Value),
+    ("inventory", "inventory", Kind::Value),
+    ("prune_inventory", "prune_inventory", Kind::Flag),
+    ("directory", "directory", Kind::Values),
+    ("slack_api_token", "slack_api_token", Kind::Secret("SLACK_API_TOKEN")),
+    ("ldap_url", "ldap_url", Kind::Value),
+    ("ldap_bind_dn", "ldap_bind_dn", Kind::Value),
+    ("ldap_bind_password", "ldap_bind_password", Kind::Secret("LDAP_BIND_PASSWORD")),
+    ("ldap_base_dn", "ldap_base_dn", Kind::Value),
+    ("ldap_filter", "ldap_filter", Kind::Value),
+    ("ldap_timeout", "ldap_timeout", Kind::Value),
+    ("ldap_unreachable", "ldap_unreachable", Kind::Value),
+    ("disable_registration", "disable_registration", Kind::Flag),
+    ("oidc_issuer", "oidc_issuer", Kind::Value),
+    ("oidc_client_id", "oidc_client_id", Kind::Value),
+    ("oidc_client_secret", "oidc_client_secret", Kind::Secret("OIDC_CLIENT_SECRET")),
+    ("oidc_redirect_url", "oidc_redirect_url", Kind::Value),
+    ("oidc_scopes", "oidc_scopes", Kind::Value),
+    ("oidc_username_claim", "oidc_username_claim", Kind::Value),
+    ("oidc_groups_claim", "oidc_groups_claim", Kind::Value),
+    ("oidc_group_role", "oidc_group_roles", Kind::Values),
+    ("bundle", "bundles", Kind::Values),
+];
+
+///The command line, environment and config file, to look settings up in
+pub struct Layers<'a, 'b> {
+    pub matches: &'a clap::ArgMatches<'b>,
+    env: HashMap<String, String>,
+    file: toml::value::Table,
+    pub file_path: Option<String>,
+    ///What was looked up, and where it was found, for `--print-config`
+    used: RefCell<HashMap<&'static str, Source>>,
+}
+
+impl<'a, 'b> Layers<'a, 'b> {
+    ///Layers over the process's environment and the `--config` file, if any
+    pub fn from_process(matches: &'a clap::ArgMatches<'b>) -> Result<Self, clap::Error> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+        let file_path = matches
+            .value_of("config")
+            .map(|x| x.to_string())
+            .or_else(|| env.get(&format!("{}CONFIG", ENV_PREFIX)).cloned());
+        let file = match &file_path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| invalid(format!("failed to read config file {}: {}", path, e)))?;
+                parse_file(&contents).map_err(|e| invalid(format!("{}: {}", path, e.message)))?
+            }
+            None => toml::value::Table::new(),
+        };
+        let mut layers = Layers::new(matches, env, file)?;
+        layers.file_path = file_path;
+        Ok(layers)
+    }
+
+    pub fn new(
+        matches: &'a clap::ArgMatches<'b>,
+        env: HashMap<String, String>,
+        file: toml::value::Table,
+    ) -> Result<Self, clap::Error> {
+        if let Some(key) = file.keys().find(|key| !SETTINGS.iter().any(|(_, name, _)| name == key)) {
+            Err(invalid(format!("unknown setting '{}' in the config file", key)))?
+        }
+        Ok(Layers {
+            matches,
+            env,
+            file,
+            file_path: None,
+            used: RefCell::new(HashMap::new()),
+        })
+    }
+
+    ///The values of a setting and where they came from, or None if it's unset
+    fn lookup(&self, arg: &str) -> Result<Option<(Vec<String>, Source)>, clap::Error> {
+        let (arg, key, kind) = match SETTINGS.iter().find(|(name, _, _)| *name == arg) {
+            Some(setting) => *setting,
+            None => return Ok(self.cli(arg, Kind::Value).map(|values| (values, Source::Cli))),
+        };
+        let found = if self.matches.occurrences_of(arg) > 0 {
+            self.cli(arg, kind).map(|values| (values, Source::Cli))
+        } else if let Some(value) = self.env_value(key, kind) {
+            Some((env_values(&value, kind), Source::Env))
+        } else if let Some(value) = self.file.get(key) {
+            Some((file_values(key, value)?, Source::File))
+        } else {
+            // the default, if clap has one
+            self.cli(arg, kind).map(|values| (values, Source::Default))
+        };
+        if let Some((_, source)) = &found {
+            self.used.borrow_mut().insert(key, *source);
+        }
+        Ok(found)
+    }
+
+    fn cli(&self, arg: &str, kind: Kind) -> Option<Vec<String>> {
+        match kind {
+            Kind::Flag if self.matches.is_present(arg) => Some(vec!["true".into()]),
+            Kind::Count if self.matches.occurrences_of(arg) > 0 => {
+                Some(vec![self.matches.occurrences_of(arg).to_string()])
+            }
+            Kind::Flag | Kind::Count | Kind::Secret(_) => None,
+            Kind::Value | Kind::Values => self
+                .matches
+                .values_of(arg)
+                .map(|values| values.map(|x| x.to_string()).collect()),
+        }
+    }
+
+    fn env_value(&self, key: &str, kind: Kind) -> Option<String> {
+        let value = self.env.get(&format!("{}{}", ENV_PREFIX, key.to_uppercase()));
+        match kind {
+            Kind::Secret(older) => value.or_else(|| self.env.get(older)).cloned(),
+            _ => value.cloned(),
+        }
+    }
+
+    pub fn value_of(&self, arg: &str) -> Option<String> {
+        self.values_of(arg).and_then(|values| values.into_iter().next())
+    }
+
+    pub fn values_of(&self, arg: &str) -> Option<Vec<String>> {
+        // bad values in the file are caught by `check` up front
+        self.lookup(arg).ok().and_then(|found| found.map(|(values, _)| values))
+    }
+
+    pub fn is_present(&self, arg: &str) -> bool {
+        match self.value_of(arg) {
+            Some(value) => parse_bool(&value),
+            None => false,
+        }
+    }
+
+    pub fn occurrences_of(&self, arg: &str) -> u64 {
+        self.value_of(arg).and_then(|value| value.parse().ok()).unwrap_or(0)
+    }
+
+    ///Checks every setting in the file can be read
+    pub fn check(&self) -> Result<(), clap::Error> {
+        for (key, value) in &self.file {
+            file_values(key, value)?;
+        }
+        Ok(())
+    }
+
+    ///Every setting with its effective value, as a config file with comments
+    ///saying where each came from. Secrets are masked.
+    pub fn describe(&self) -> Result<String, clap::Error> {
+        let mut description = match &self.file_path {
+            Some(path) => format!("# config file: {}\n", path),
+            None => "# no config file\n".to_string(),
+        };
+        for (arg, key, kind) in SETTINGS {
+            match self.lookup(arg)? {
+                None => description.push_str(&format!("# {} is unset\n", key)),
+                Some((values, source)) => {
+                    let value = match kind {
+                        Kind::Secret(_) => toml::Value::String("********".into()),
+                        Kind::Values => toml::Value::Array(values.into_iter().map(toml::Value::String).collect()),
+                        Kind::Flag => toml::Value::Boolean(values.iter().any(|value| parse_bool(value))),
+                        Kind::Count => toml::Value::Integer(values[0].parse().unwrap_or(0)),
+                        Kind::Value => match values[0].parse::<i64>() {
+                            Ok(number) => toml::Value::Integer(number),
+                            Err(_) => toml::Value::String(values[0].clone()),
+                        },
+                    };
+                    description.push_str(&format!("{} = {} # {}\n", key, value, source));
+                }
+            }
+        }
+        Ok(description)
+    }
+
+    #[cfg(test)]
+    pub fn source_of(&self, key: &str) -> Option<Source> {
+        self.used.borrow().get(key).cloned()
+    }
+}
+
+fn invalid(message: String) -> clap::Error {
+    clap::Error {
+        message,
+        kind: clap::ErrorKind::InvalidValue,
+        info: None,
+    }
+}
+
+fn parse_file(contents: &str) -> Result<toml::value::Table, clap::Error> {
+    match contents.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => Ok(table),
+        Ok(_) => Err(invalid("expected a table of settings".into())),
+        Err(e) => Err(invalid(e.to_string())),
+    }
+}
+
+fn parse_bool(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "true" | "1" | "yes" | "on")
+}
+
+fn env_values(value: &str, kind: Kind) -> Vec<String> {
+    match kind {
+        Kind::Values => value
+            .split(';')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect(),
+        _ => vec![value.to_string()],
+    }
+}
+
+fn file_values(key: &str, value: &toml::Value) -> Result<Vec<String>, clap::Error> {
+    match value {
+        toml::Value::String(value) => Ok(vec![value.clone()]),
+        toml::Value::Integer(value) => Ok(vec![value.to_string()]),
+        toml::Value::Boolean(value) => Ok(vec![value.to_string()]),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::String(value) => Ok(value.clone()),
+                _ => Err(invalid(format!("'{}' in the config file should be a list of strings", key))),
+            })
+            .collect(),
+        _ => Err(invalid(format!(
+            "'{}' in the config file should be a string, number or true/false",
+            key
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub fn test_file(contents: &str) -> toml::value::Table {
+    parse_file(contents).unwrap()
+}
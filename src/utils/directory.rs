@@ -0,0 +1,86 @@
+use crate::utils::ldap;
+use crate::utils::slack;
+use crate::utils::types;
+
+///Checks whether a device owner is known to any of the configured directories
+pub fn owner_exists(config: &types::Settings, test_name: &str) -> bool {
+    config.directory_backends.iter().any(|backend| match backend {
+        types::DirectoryBackend::Slack => {
+            let slack_client = slack::slack_client_init(config);
+            slack::slack_user_exists(test_name, &slack_client)
+        }
+        types::DirectoryBackend::Ldap => match ldap::ldap_client_init(config) {
+            Some(ldap_client) => ldap::ldap_user_exists(test_name, &ldap_client),
+            None => {
+                warn!("LDAP directory enabled but no LDAP server configured");
+                false
+            }
+        },
+    })
+}
+
+///Checks whether a custom owner's recipient is known to any of the configured
+///directories. Slack recipients may also be channels.
+pub fn recipient_exists(config: &types::Settings, test_name: &str) -> bool {
+    config.directory_backends.iter().any(|backend| match backend {
+        types::DirectoryBackend::Slack => {
+            let slack_client = slack::slack_client_init(config);
+            slack::slack_user_exists(test_name, &slack_client)
+                || slack::slack_channel_exists(test_name, &slack_client)
+        }
+        types::DirectoryBackend::Ldap => match ldap::ldap_client_init(config) {
+            Some(ldap_client) => ldap::ldap_user_exists(test_name, &ldap_client),
+            None => {
+                warn!("LDAP directory enabled but no LDAP server configured");
+                false
+            }
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    //an LDAP server that's down, so whether anyone's in it is the fallback
+    fn unreachable_ldap(config: &mut types::Settings, accept_unreachable: bool) {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").expect("listening");
+        config.ldap_url = Some(format!("ldap://{}", closed.local_addr().unwrap()));
+        config.ldap_accept_unreachable = accept_unreachable;
+    }
+
+    #[test]
+    fn test_owner_exists() {
+        let mut config = types::Settings::new();
+        assert!(owner_exists(&config, "slack_user"));
+        assert!(!owner_exists(&config, "ldap_user"));
+
+        //no server configured, then one that's down, failing open or closed
+        config.directory_backends = vec![types::DirectoryBackend::Ldap];
+        assert!(!owner_exists(&config, "ldap_user"));
+        unreachable_ldap(&mut config, false);
+        assert!(!owner_exists(&config, "slack_user"));
+        unreachable_ldap(&mut config, true);
+        assert!(owner_exists(&config, "ldap_user"));
+
+        config.directory_backends = vec![types::DirectoryBackend::Slack, types::DirectoryBackend::Ldap];
+        unreachable_ldap(&mut config, false);
+        assert!(owner_exists(&config, "slack_user"));
+        assert!(!owner_exists(&config, "fake_user"));
+        unreachable_ldap(&mut config, true);
+        assert!(owner_exists(&config, "fake_user"));
+    }
+
+    #[test]
+    fn test_recipient_exists() {
+        let mut config = types::Settings::new();
+        assert!(recipient_exists(&config, "slack_channel"));
+        assert!(!recipient_exists(&config, "ldap_user"));
+
+        config.directory_backends = vec![types::DirectoryBackend::Ldap];
+        unreachable_ldap(&mut config, false);
+        assert!(!recipient_exists(&config, "slack_channel"));
+        unreachable_ldap(&mut config, true);
+        assert!(recipient_exists(&config, "ldap_user"));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::utils::types;
+
+pub struct LdapClient {
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: String,
+    base_dn: String,
+    search_filter: String,
+    timeout: std::time::Duration,
+    accept_unreachable: bool,
+}
+
+/// Builds an LDAP client from the settings, or None if no LDAP server is configured.
+/// The bind password is never taken from the command line, so it stays out of the process list.
+pub fn ldap_client_init(config: &types::Settings) -> Option<LdapClient> {
+    let url = config.ldap_url.clone()?;
+    Some(LdapClient {
+        url,
+        bind_dn: config.ldap_bind_dn.clone(),
+        bind_password: match &config.ldap_bind_password {
+            Some(password) => password.clone(),
+            None => {
+                if config.ldap_bind_dn.is_some() {
+                    error!("No LDAP bind password, set DEVICE_CHECKOUT_LDAP_BIND_PASSWORD");
+                }
+                "".to_string()
+            }
+        },
+        base_dn: config.ldap_base_dn.clone().unwrap_or_default(),
+        search_filter: config.ldap_search_filter.clone(),
+        timeout: std::time::Duration::from_secs(config.ldap_timeout_secs),
+        accept_unreachable: config.ldap_accept_unreachable,
+    })
+}
+
+/// Expands the configured search filter for a single username, escaping the
+/// username so it can't alter the structure of the filter.
+pub fn ldap_search_filter(search_filter: &str, test_name: &str) -> String {
+    search_filter.replace("{username}", &ldap3::ldap_escape(test_name))
+}
+
+fn ldap_search(test_name: &str, ldap_client: &LdapClient) -> Result<usize, ldap3::LdapError> {
+    let settings = ldap3::LdapConnSettings::new().set_conn_timeout(ldap_client.timeout);
+    let mut ldap = ldap3::LdapConn::with_settings(settings, &ldap_client.url)?;
+    if let Some(ref bind_dn) = ldap_client.bind_dn {
+        ldap.with_timeout(ldap_client.timeout)
+            .simple_bind(bind_dn, &ldap_client.bind_password)?
+            .success()?;
+    }
+    let filter = ldap_search_filter(&ldap_client.search_filter, test_name);
+    trace!("ldap search - base: {}, filter: {}", &ldap_client.base_dn, &filter);
+    let (entries, _) = ldap
+        .with_timeout(ldap_client.timeout)
+        .search(&ldap_client.base_dn, ldap3::Scope::Subtree, &filter, vec!["1.1"])?
+        .success()?;
+    let _ = ldap.unbind();
+    Ok(entries.len())
+}
+
+pub fn ldap_user_exists(test_name: &str, ldap_client: &LdapClient) -> bool {
+    debug!("ldap_user_exists(test_name: {})", &test_name);
+    // ldap3's synchronous client drives its own tokio runtime, which can't be
+    // started from a thread owned by Rocket's runtime (workers or the blocking
+    // pool database work runs on), so do the lookup on our own.
+    let result = std::thread::scope(|scope| {
+        scope
+            .spawn(|| ldap_search(test_name, ldap_client))
+            .join()
+            .expect("LDAP lookup thread panicked")
+    });
+    match result {
+        Ok(0) => {
+            debug!("Unable to match input '{}' with any LDAP entry.", &test_name);
+            false
+        }
+        Ok(_) => {
+            debug!("Input '{}' matched an LDAP entry", &test_name);
+            true
+        }
+        Err(error) => {
+            warn!("Error occured while searching LDAP directory: {:?}", error);
+            warn!(
+                "ldap_user_exists() returns {} if cannot reach LDAP server, see --ldap-unreachable.",
+                ldap_client.accept_unreachable
+            );
+            ldap_client.accept_unreachable
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ldap_client_init() {
+        let mut config = types::Settings::new();
+        assert!(ldap_client_init(&config).is_none());
+        config.ldap_url = Some("ldap://localhost:389".into());
+        assert!(ldap_client_init(&config).is_some());
+    }
+
+    #[test]
+    fn test_ldap_search_filter() {
+        assert_eq!(ldap_search_filter("(uid={username})", "jsmith"), "(uid=jsmith)");
+        assert_eq!(
+            ldap_search_filter("(|(uid={username})(mail={username}))", "a*b"),
+            "(|(uid=a\\2ab)(mail=a\\2ab))"
+        );
+    }
+
+    #[test]
+    fn test_ldap_unreachable() {
+        //a server that takes the connection but never answers, and none at all
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").expect("listening");
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").expect("listening");
+        let urls = [
+            format!("ldap://{}", silent.local_addr().unwrap()),
+            format!("ldap://{}", closed.local_addr().unwrap()),
+        ];
+        drop(closed);
+        std::thread::spawn(move || {
+            //hold on to every connection without ever answering
+            let _connections: Vec<_> = silent.incoming().collect();
+        });
+
+        let mut config = types::Settings::new();
+        config.ldap_timeout_secs = 1;
+        for url in &urls {
+            config.ldap_url = Some(url.clone());
+            for accept in [true, false] {
+                config.ldap_accept_unreachable = accept;
+                let client = ldap_client_init(&config).unwrap();
+                let started = std::time::Instant::now();
+                assert_eq!(ldap_user_exists("alice", &client), accept, "{}", url);
+                assert!(started.elapsed() < std::time::Duration::from_secs(5), "{} didn't time out", url);
+            }
+        }
+    }
+}
@@ -1,4 +1,9 @@
 pub mod cmdline;
+pub mod config;
+pub mod directory;
+pub mod ldap;
 pub mod logging;
+pub mod notify;
+pub mod oidc;
 pub mod slack;
 pub mod types;
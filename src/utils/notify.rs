@@ -0,0 +1,67 @@
+use crate::database;
+use crate::utils::slack;
+use crate::utils::types;
+
+///Who to message for a device owner, a custom owner's recipient or the owner themselves
+pub fn owner_recipient(config: &types::Settings, database: &database::DbConn, owner: &str) -> String {
+    match database::get_custom_owner(config, database, owner) {
+        Ok(Some(custom_owner)) => custom_owner.recipient,
+        _ => owner.to_string(),
+    }
+}
+
+///Tells a device owner about something done to their device, through whichever
+///of the configured directories can send messages. Failing to isn't an error.
+pub fn notify_owner(config: &types::Settings, database: &database::DbConn, owner: &str, message: &str) {
+    let recipient = owner_recipient(config, database, owner);
+    for backend in &config.directory_backends {
+        match backend {
+            types::DirectoryBackend::Slack => {
+                let slack_client = slack::slack_client_init(config);
+                if !slack::slack_send_message(&recipient, message, &slack_client) {
+                    warn!("Failed to notify '{}' over Slack", &recipient);
+                }
+            }
+            types::DirectoryBackend::Ldap => {
+                debug!("LDAP can't send messages, not notifying '{}' through it", &recipient);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "sqlite")]
+mod test {
+    use super::*;
+    use crate::models;
+
+    #[test]
+    fn test_notify_owner() {
+        let file = tempfile::NamedTempFile::new().expect("creating tempfile");
+        let mut config = types::Settings::new();
+        config.database_url = file.path().to_string_lossy().to_string();
+        database::run_migrations(&config).expect("running migrations");
+        let database = database::establish_connection(&config).expect("connecting to database");
+        let custom_owner = models::CustomOwnerInsert {
+            custom_owner_name: "lab".into(),
+            recipient: "slack_channel".into(),
+            description: None,
+            unlimited_reservations: false,
+        };
+        database::insert_custom_owner(&config, &database, &custom_owner, &models::ChangeContext::default()).unwrap();
+
+        notify_owner(&config, &database, "slack_user", "to a user");
+        notify_owner(&config, &database, "lab", "to a custom owner");
+        config.directory_backends = vec![types::DirectoryBackend::Ldap];
+        notify_owner(&config, &database, "slack_user", "not sent");
+
+        let sent = slack::SENT_MESSAGES.with(|sent| sent.borrow().clone());
+        assert_eq!(
+            sent,
+            vec![
+                ("slack_user".to_string(), "to a user".to_string()),
+                ("slack_channel".to_string(), "to a custom owner".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use crate::models::UserRole;
+use crate::utils::types;
+use failure::ResultExt;
+use rand::RngCore;
+
+/// Claims from an id token or the userinfo endpoint.
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+/// The parts of the provider's discovery document that we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scopes: String,
+    username_claim: String,
+    groups_claim: String,
+    group_roles: Vec<(String, UserRole)>,
+    http: reqwest::blocking::Client,
+}
+
+/// Someone who logged in through the provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OidcUser {
+    /// The provider and its id for them, which unlike the username never changes
+    pub issuer: String,
+    pub subject: String,
+    pub username: String,
+    /// None if no group to role mappings are configured, roles are then managed locally
+    pub role: Option<UserRole>,
+}
+
+/// Builds an OIDC client from the settings, or None if single sign-on isn't configured.
+/// The client secret is never taken from the command line, so it stays out of the process list.
+/// Makes blocking requests, so keep it off the async workers.
+pub fn oidc_client_init(config: &types::Settings) -> Option<OidcClient> {
+    let issuer = config.oidc_issuer.clone()?;
+    Some(OidcClient {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id: config.oidc_client_id.clone().unwrap_or_default(),
+        client_secret: match &config.oidc_client_secret {
+            Some(secret) => secret.clone(),
+            None => {
+                error!("No OpenID Connect client secret, set DEVICE_CHECKOUT_OIDC_CLIENT_SECRET");
+                "".to_string()
+            }
+        },
+        redirect_url: config.oidc_redirect_url.clone().unwrap_or_default(),
+        scopes: config.oidc_scopes.clone(),
+        username_claim: config.oidc_username_claim.clone(),
+        groups_claim: config.oidc_groups_claim.clone(),
+        group_roles: config.oidc_group_roles.clone(),
+        http: reqwest::blocking::Client::new(),
+    })
+}
+
+/// A random value for the state and nonce parameters.
+pub fn random_value() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl OidcClient {
+    /// Fetches the provider's endpoints from its discovery document.
+    pub fn discover(&self) -> Result<ProviderMetadata, failure::Error> {
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        debug!("oidc discovery: {}", &url);
+        let metadata: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .with_context(|_| format!("Error fetching {}", &url))?;
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            return Err(failure::err_msg(format!(
+                "Provider issuer {} doesn't match {}",
+                &metadata.issuer, &self.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+
+    /// Where to send the browser to log in.
+    pub fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        state: &str,
+        nonce: &str,
+    ) -> Result<String, failure::Error> {
+        let url = url::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", &self.scopes),
+                ("state", state),
+                ("nonce", nonce),
+            ],
+        )
+        .with_context(|_| format!("Invalid authorization endpoint {}", &metadata.authorization_endpoint))?;
+        Ok(url.into())
+    }
+
+    /// Swaps the code from the provider's redirect for the user's claims.
+    pub fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        nonce: &str,
+    ) -> Result<OidcUser, failure::Error> {
+        let token: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+            ])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .with_context(|_| "Error exchanging authorization code".to_string())?;
+
+        // the id token comes straight from the token endpoint rather than through
+        // the browser, so as OIDC core 3.1.3.7 allows, TLS vouches for it and we
+        // don't check its signature
+        let mut claims = decode_id_token(&token.id_token)?;
+        validate_id_token(
+            &claims,
+            &metadata.issuer,
+            &self.client_id,
+            nonce,
+            chrono::Utc::now().timestamp(),
+        )?;
+
+        if let Some(ref userinfo_endpoint) = metadata.userinfo_endpoint {
+            let userinfo: Claims = self
+                .http
+                .get(userinfo_endpoint)
+                .bearer_auth(&token.access_token)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .and_then(|response| response.json())
+                .with_context(|_| "Error fetching userinfo".to_string())?;
+            merge_userinfo(&mut claims, userinfo);
+        }
+
+        self.user_from_claims(&claims)
+    }
+
+    fn user_from_claims(&self, claims: &Claims) -> Result<OidcUser, failure::Error> {
+        let username = claims
+            .get(&self.username_claim)
+            .and_then(|username| username.as_str())
+            .map(|username| username.trim())
+            .filter(|username| !username.is_empty())
+            .ok_or_else(|| failure::err_msg(format!("No {} claim", &self.username_claim)))?;
+        let subject = claims
+            .get("sub")
+            .and_then(|subject| subject.as_str())
+            .filter(|subject| !subject.is_empty())
+            .ok_or_else(|| failure::err_msg("No sub claim"))?;
+        Ok(OidcUser {
+            issuer: self.issuer.clone(),
+            subject: subject.to_string(),
+            username: username.to_string(),
+            role: self.role_for_groups(&groups(claims, &self.groups_claim)),
+        })
+    }
+
+    /// The highest role any of the groups maps to, or viewer if none do.
+    fn role_for_groups(&self, groups: &[String]) -> Option<UserRole> {
+        if self.group_roles.is_empty() {
+            return None;
+        }
+        let role = self
+            .group_roles
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(_, role)| *role)
+            .max()
+            .unwrap_or(UserRole::Viewer);
+        Some(role)
+    }
+}
+
+/// Decodes the claims of an id token, without checking its signature.
+pub fn decode_id_token(id_token: &str) -> Result<Claims, failure::Error> {
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(failure::err_msg("Malformed id token"));
+    }
+    let payload = base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD)
+        .with_context(|_| "Malformed id token payload".to_string())?;
+    Ok(serde_json::from_slice(&payload).with_context(|_| "Malformed id token claims".to_string())?)
+}
+
+/// Checks the id token was issued by our provider, for us, for this login.
+pub fn validate_id_token(
+    claims: &Claims,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<(), failure::Error> {
+    if claims.get("iss").and_then(|iss| iss.as_str()) != Some(issuer) {
+        return Err(failure::err_msg("Id token has the wrong issuer"));
+    }
+    let audience: Vec<&str> = match claims.get("aud") {
+        Some(serde_json::Value::String(aud)) => vec![aud.as_str()],
+        Some(serde_json::Value::Array(aud)) => aud.iter().filter_map(|aud| aud.as_str()).collect(),
+        _ => vec![],
+    };
+    if !audience.contains(&client_id)
+        || (audience.len() > 1 && claims.get("azp").and_then(|azp| azp.as_str()) != Some(client_id))
+    {
+        return Err(failure::err_msg("Id token is for someone else"));
+    }
+    match claims.get("exp").and_then(|exp| exp.as_i64()) {
+        Some(exp) if exp > now => {}
+        _ => return Err(failure::err_msg("Id token has expired")),
+    }
+    if claims.get("nonce").and_then(|n| n.as_str()) != Some(nonce) {
+        return Err(failure::err_msg("Id token nonce doesn't match"));
+    }
+    Ok(())
+}
+
+/// Adds claims from the userinfo endpoint that the id token didn't have,
+/// ignoring them if they're about someone else.
+fn merge_userinfo(claims: &mut Claims, userinfo: Claims) {
+    if userinfo.get("sub") != claims.get("sub") {
+        warn!("Ignoring userinfo for a different subject");
+        return;
+    }
+    for (claim, value) in userinfo {
+        claims.entry(claim).or_insert(value);
+    }
+}
+
+/// The groups claim, as a list or a single group.
+fn groups(claims: &Claims, groups_claim: &str) -> Vec<String> {
+    match claims.get(groups_claim) {
+        Some(serde_json::Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|group| group.as_str())
+            .map(|group| group.to_string())
+            .collect(),
+        Some(serde_json::Value::String(group)) => vec![group.to_string()],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn claims(json: serde_json::Value) -> Claims {
+        json.as_object().unwrap().clone()
+    }
+
+    fn test_client(group_roles: Vec<(String, UserRole)>) -> OidcClient {
+        let mut config = types::Settings::new();
+        config.oidc_issuer = Some("https://idp.example.com/".into());
+        config.oidc_client_id = Some("device-checkout".into());
+        config.oidc_group_roles = group_roles;
+        oidc_client_init(&config).unwrap()
+    }
+
+    #[test]
+    fn test_oidc_client_init() {
+        let mut config = types::Settings::new();
+        assert!(oidc_client_init(&config).is_none());
+        config.oidc_issuer = Some("https://idp.example.com/".into());
+        assert_eq!(oidc_client_init(&config).unwrap().issuer, "https://idp.example.com");
+    }
+
+    #[test]
+    fn test_decode_id_token() {
+        let payload = base64::encode_config(r#"{"sub":"1234","nonce":"n"}"#, base64::URL_SAFE_NO_PAD);
+        let claims = decode_id_token(&format!("eyJhbGciOiJub25lIn0.{}.", payload)).unwrap();
+        assert_eq!(claims["sub"], "1234");
+        assert!(decode_id_token("not a token").is_err());
+        assert!(decode_id_token("a.!!!.c").is_err());
+    }
+
+    #[test]
+    fn test_validate_id_token() {
+        let valid = serde_json::json!({
+            "iss": "https://idp", "aud": "client", "exp": 200, "nonce": "n",
+        });
+        assert!(validate_id_token(&claims(valid.clone()), "https://idp", "client", "n", 100).is_ok());
+        assert!(validate_id_token(&claims(valid.clone()), "https://other", "client", "n", 100).is_err());
+        assert!(validate_id_token(&claims(valid.clone()), "https://idp", "other", "n", 100).is_err());
+        assert!(validate_id_token(&claims(valid.clone()), "https://idp", "client", "m", 100).is_err());
+        assert!(validate_id_token(&claims(valid), "https://idp", "client", "n", 300).is_err());
+
+        let shared = serde_json::json!({
+            "iss": "https://idp", "aud": ["client", "other"], "exp": 200, "nonce": "n",
+        });
+        assert!(validate_id_token(&claims(shared.clone()), "https://idp", "client", "n", 100).is_err());
+        let mut shared = claims(shared);
+        shared.insert("azp".into(), "client".into());
+        assert!(validate_id_token(&shared, "https://idp", "client", "n", 100).is_ok());
+    }
+
+    #[test]
+    fn test_merge_userinfo() {
+        let mut id_token = claims(serde_json::json!({"sub": "1", "preferred_username": "alice"}));
+        merge_userinfo(&mut id_token, claims(serde_json::json!({"sub": "2", "groups": ["admins"]})));
+        assert!(id_token.get("groups").is_none());
+        merge_userinfo(
+            &mut id_token,
+            claims(serde_json::json!({"sub": "1", "preferred_username": "mallory", "groups": ["admins"]})),
+        );
+        assert_eq!(id_token["preferred_username"], "alice");
+        assert_eq!(groups(&id_token, "groups"), vec!["admins".to_string()]);
+    }
+
+    #[test]
+    fn test_user_from_claims() {
+        let oidc_client = test_client(vec![]);
+        let user = oidc_client
+            .user_from_claims(&claims(serde_json::json!({"sub": "1", "preferred_username": " alice ", "groups": "admins"})))
+            .unwrap();
+        assert_eq!(
+            user,
+            OidcUser {
+                issuer: oidc_client.issuer.clone(),
+                subject: "1".into(),
+                username: "alice".into(),
+                role: None
+            }
+        );
+        assert!(oidc_client.user_from_claims(&claims(serde_json::json!({"sub": "1"}))).is_err());
+        assert!(oidc_client.user_from_claims(&claims(serde_json::json!({"preferred_username": "alice"}))).is_err());
+
+        let oidc_client = test_client(vec![
+            ("staff".into(), UserRole::User),
+            ("admins".into(), UserRole::Admin),
+        ]);
+        assert_eq!(oidc_client.role_for_groups(&["staff".into(), "admins".into()]), Some(UserRole::Admin));
+        assert_eq!(oidc_client.role_for_groups(&["staff".into()]), Some(UserRole::User));
+        assert_eq!(oidc_client.role_for_groups(&[]), Some(UserRole::Viewer));
+    }
+
+    #[test]
+    fn test_authorization_url() {
+        let oidc_client = test_client(vec![]);
+        let metadata = ProviderMetadata {
+            issuer: "https://idp.example.com".into(),
+            authorization_endpoint: "https://idp.example.com/authorize?prompt=login".into(),
+            token_endpoint: "https://idp.example.com/token".into(),
+            userinfo_endpoint: None,
+        };
+        let url = oidc_client.authorization_url(&metadata, "state", "nonce").unwrap();
+        assert!(url.starts_with("https://idp.example.com/authorize?prompt=login&response_type=code"));
+        assert!(url.contains("&client_id=device-checkout&"));
+        assert!(url.contains("&scope=openid+profile+email&state=state&nonce=nonce"));
+    }
+}
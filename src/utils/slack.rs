@@ -1,24 +1,26 @@
+use crate::utils::types;
 use slack::api as slack_api;
-use std::env;
 
+//the test build's stand-ins never talk to Slack
+#[cfg_attr(test, allow(dead_code))]
 pub struct SlackAPIClient {
     token: String,
     client: reqwest::blocking::Client,
 }
 
 #[cfg(not(test))]
-pub fn slack_client_init() -> SlackAPIClient {
+pub fn slack_client_init(config: &types::Settings) -> SlackAPIClient {
     let slack_client = SlackAPIClient {
-        token: match env::var("SLACK_API_TOKEN") {
-            Ok(token) => token,
-            Err(_) => {
-                error!("Failed to retrieve env var SLACK_API_TOKEN");
+        token: match &config.slack_api_token {
+            Some(token) => token.clone(),
+            None => {
+                error!("No Slack API token, set DEVICE_CHECKOUT_SLACK_API_TOKEN");
                 "".to_string()
             }
         },
         client: slack_api::default_client().unwrap(),
     };
-    return slack_client
+    slack_client
 }
 
 #[cfg(not(test))]
@@ -47,7 +49,7 @@ pub fn slack_channel_exists(test_name: &str, slack_client: &SlackAPIClient) -> b
                     if *is_archived {
                         continue
                     }
-                    if name.eq_ignore_ascii_case(&test_name) {
+                    if name.eq_ignore_ascii_case(test_name) {
                         debug!("Input '{}' matched channel name '{}'", &test_name, &name);
                         return true;
                     }
@@ -61,7 +63,7 @@ pub fn slack_channel_exists(test_name: &str, slack_client: &SlackAPIClient) -> b
             return true
         },
     }
-    return false;
+    false
 }
 
 #[cfg(not(test))]
@@ -88,11 +90,11 @@ pub fn slack_user_exists(test_name: &str, slack_client: &SlackAPIClient) -> bool
                     if *is_bot || *deleted {
                         continue
                     }
-                    if name.eq_ignore_ascii_case(&test_name) {
+                    if name.eq_ignore_ascii_case(test_name) {
                         debug!("Input '{}' matched name: {}", &test_name, &name);
                         return true;
                     }
-                    if display_name.eq_ignore_ascii_case(&test_name) {
+                    if display_name.eq_ignore_ascii_case(test_name) {
                         debug!("Input '{}' matched display_name: {}", &test_name, &display_name);
                         return true;
                     }
@@ -106,11 +108,47 @@ pub fn slack_user_exists(test_name: &str, slack_client: &SlackAPIClient) -> bool
             return true
         },
     }
-    return false;
+    false
+}
+
+#[cfg(not(test))]
+// Messages a Slack user, matched the same way as slack_user_exists, or a channel
+pub fn slack_send_message(recipient: &str, text: &str, slack_client: &SlackAPIClient) -> bool {
+    debug!("slack_send_message(recipient: {})", &recipient);
+    let users = slack_api::users::list(
+        &slack_client.client,
+        &slack_client.token,
+        &slack_api::users::ListRequest::default(),
+    );
+    // users are messaged by id, which opens a direct message with them
+    let user_id = users.ok().and_then(|users| users.members).and_then(|members| {
+        members.into_iter().find_map(|u| {
+            if u.is_bot.unwrap_or(false) || u.deleted.unwrap_or(false) {
+                return None;
+            }
+            let display_name = u.profile.as_ref().and_then(|profile| profile.display_name.clone());
+            let matched = u.name.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(recipient))
+                || display_name.is_some_and(|name| name.eq_ignore_ascii_case(recipient));
+            if matched { u.id } else { None }
+        })
+    });
+    let channel = user_id.unwrap_or_else(|| recipient.trim_start_matches('#').to_string());
+    let request = slack_api::chat::PostMessageRequest {
+        channel: &channel,
+        text,
+        ..Default::default()
+    };
+    match slack_api::chat::post_message(&slack_client.client, &slack_client.token, &request) {
+        Ok(_) => true,
+        Err(error) => {
+            warn!("Error occured while messaging '{}': {:?}", &recipient, error);
+            false
+        }
+    }
 }
 
 #[cfg(test)]
-pub fn slack_client_init() -> SlackAPIClient {
+pub fn slack_client_init(_config: &types::Settings) -> SlackAPIClient {
     SlackAPIClient {
         token: "new token".into(),
         client: slack_api::default_client().unwrap(),
@@ -119,16 +157,23 @@ pub fn slack_client_init() -> SlackAPIClient {
 
 #[cfg(test)]
 pub fn slack_channel_exists(test_name: &str, _slack_client: &SlackAPIClient) -> bool {
-    if test_name.eq("slack_channel") {
-        return true
-    }
-    return false
+    test_name.eq("slack_channel")
 }
 
 #[cfg(test)]
 pub fn slack_user_exists(test_name: &str, _slack_client: &SlackAPIClient) -> bool {
-    if test_name.eq("slack_user") {
-        return true
-    }
-    return false
+    test_name.eq("slack_user")
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Messages "sent" by the test build, as (recipient, text)
+    pub static SENT_MESSAGES: std::cell::RefCell<Vec<(String, String)>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(test)]
+pub fn slack_send_message(recipient: &str, text: &str, _slack_client: &SlackAPIClient) -> bool {
+    SENT_MESSAGES.with(|sent| sent.borrow_mut().push((recipient.to_string(), text.to_string())));
+    true
 }
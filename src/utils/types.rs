@@ -1,5 +1,163 @@
 //reexport Timestamp, so other modules don't need to use stderrlog
 pub use stderrlog::Timestamp;
+use crate::models::UserRole;
+
+///Where device owners and custom owner recipients are looked up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryBackend {
+    Slack,
+    Ldap,
+}
+
+///A named set of devices reserved together, as pool names and how many from each
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleDefinition {
+    pub name: String,
+    pub pools: Vec<(String, usize)>,
+}
+
+///Branding for the web ui, in every page's context as `theme`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Theme {
+    pub site_title: String,
+    ///Image shown beside the site title
+    pub logo_url: Option<String>,
+    ///Css colour for buttons, links and the header rule
+    pub accent_color: Option<String>,
+    pub footer_links: Vec<FooterLink>,
+    ///Announcement shown at the top of every page
+    pub banner: Option<String>,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            site_title: "Device Checkout".to_string(),
+            logo_url: None,
+            accent_color: None,
+            footer_links: vec![],
+            banner: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FooterLink {
+    pub label: String,
+    pub url: String,
+}
+
+///Where the client subcommands find the server, and how they print
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSettings {
+    pub url: String,
+    pub token: Option<String>,
+    pub json: bool,
+}
+
+///A request for a running server's api, see `client::run`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientCommand {
+    List {
+        pool_id: Option<i32>,
+    },
+    Claim {
+        pool_id: i32,
+        count: usize,
+        bundle: Option<String>,
+        owner: Option<String>,
+        comments: Option<String>,
+    },
+    Return {
+        reservation_id: i32,
+    },
+    Mine,
+    Pools,
+    ///Claims a device from the pool as soon as one is free
+    Wait {
+        pool_id: i32,
+        owner: Option<String>,
+        comments: Option<String>,
+        timeout_secs: u64,
+        interval_secs: u64,
+    },
+}
+
+///A change made straight to the database, see `admin::run`. Pools are given
+///by id or name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    DeviceAdd {
+        name: String,
+        url: String,
+        pool: String,
+    },
+    DeviceEdit {
+        name: String,
+        new_name: Option<String>,
+        url: Option<String>,
+    },
+    DeviceDelete {
+        name: String,
+    },
+    DeviceMove {
+        name: String,
+        pool: String,
+    },
+    PoolAdd {
+        name: String,
+        description: Option<String>,
+        max_reservations_per_owner: Option<i32>,
+    },
+    PoolDelete {
+        pool: String,
+    },
+    OwnerAdd {
+        name: String,
+        recipient: String,
+        description: Option<String>,
+        unlimited_reservations: bool,
+    },
+    ///Adds an account, with no password if none is given, for single sign-on
+    UserAdd {
+        name: String,
+        role: UserRole,
+        password: Option<String>,
+    },
+    ///Force releases every reserved device in the pool
+    ReleaseAll {
+        pool: String,
+        reason: String,
+    },
+    Migrate,
+    Check,
+    ///Writes the inventory to `output`, or stdout
+    Export {
+        format: Option<String>,
+        output: Option<String>,
+    },
+    Import {
+        input: String,
+        format: Option<String>,
+        dry_run: bool,
+    },
+    ///Makes the database match an inventory file
+    Sync {
+        input: String,
+        format: Option<String>,
+        prune: bool,
+        dry_run: bool,
+    },
+}
+
+///What the binary was asked to do, it serves unless given a subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Command {
+    #[default]
+    Serve,
+    Client(ClientSettings, ClientCommand),
+    Admin(AdminCommand),
+}
 
 #[derive(Debug, Clone)]
 pub struct Settings {
@@ -8,8 +166,55 @@ pub struct Settings {
     pub timestamp: Timestamp,
     pub module_path: Option<String>,
     pub template_dir: Option<String>,
+    pub theme: Theme,
     pub database_url: String,
+    ///Interface to listen on
+    pub address: std::net::IpAddr,
     pub port: u16,
+    ///PEM certificate chain and private key to serve HTTPS with
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    ///Where the site is mounted behind a reverse proxy, e.g. `/checkout`, or
+    ///empty at the root. Never ends in `/`.
+    pub base_path: String,
+    ///Base64 or hex key cookies are signed with, otherwise the one stored in
+    ///the database is used
+    pub secret_key: Option<String>,
+    ///Argon2 memory in KiB and iterations for new password hashes. Not on the
+    ///command line, it's only lowered by the tests, which log in a lot
+    pub password_hash_cost: (u32, u32),
+    pub pool_size: u32,
+    pub busy_timeout_ms: u32,
+    pub directory_backends: Vec<DirectoryBackend>,
+    pub slack_api_token: Option<String>,
+    pub ldap_url: Option<String>,
+    pub ldap_bind_dn: Option<String>,
+    pub ldap_bind_password: Option<String>,
+    pub ldap_base_dn: Option<String>,
+    pub ldap_search_filter: String,
+    ///Seconds to wait for the LDAP server to connect, and then for each answer
+    pub ldap_timeout_secs: u64,
+    ///Whether owners are let through when the LDAP server can't be reached
+    pub ldap_accept_unreachable: bool,
+    ///Whether accounts can only be made by admins, or single sign-on
+    pub disable_registration: bool,
+    pub oidc_issuer: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_redirect_url: Option<String>,
+    pub oidc_scopes: String,
+    pub oidc_username_claim: String,
+    pub oidc_groups_claim: String,
+    ///Groups in the groups claim and the role they give, checked on every login
+    pub oidc_group_roles: Vec<(String, UserRole)>,
+    pub bundles: Vec<BundleDefinition>,
+    ///How many devices one owner may have at once, across all pools
+    pub max_reservations_per_owner: Option<usize>,
+    ///Synced into the database at startup
+    pub inventory_file: Option<String>,
+    ///Whether that sync removes pools and devices that aren't in the file
+    pub prune_inventory: bool,
+    pub command: Command,
 }
 
 impl Settings {
@@ -28,8 +233,40 @@ impl Default for Settings {
             timestamp: Timestamp::Off,
             module_path: None,
             template_dir: None,
+            theme: Theme::default(),
             database_url: "devices.db".to_string(),
+            address: std::net::Ipv4Addr::LOCALHOST.into(),
             port: 8000,
+            tls_cert: None,
+            tls_key: None,
+            base_path: String::new(),
+            secret_key: None,
+            password_hash_cost: (argon2::Params::DEFAULT_M_COST, argon2::Params::DEFAULT_T_COST),
+            pool_size: 8,
+            busy_timeout_ms: 5000,
+            directory_backends: vec![DirectoryBackend::Slack],
+            slack_api_token: None,
+            ldap_url: None,
+            ldap_bind_dn: None,
+            ldap_bind_password: None,
+            ldap_base_dn: None,
+            ldap_search_filter: "(uid={username})".to_string(),
+            ldap_timeout_secs: 5,
+            ldap_accept_unreachable: true,
+            disable_registration: false,
+            oidc_issuer: None,
+            oidc_client_id: None,
+            oidc_client_secret: None,
+            oidc_redirect_url: None,
+            oidc_scopes: "openid profile email".to_string(),
+            oidc_username_claim: "preferred_username".to_string(),
+            oidc_groups_claim: "groups".to_string(),
+            oidc_group_roles: vec![],
+            bundles: vec![],
+            max_reservations_per_owner: None,
+            inventory_file: None,
+            prune_inventory: false,
+            command: Command::Serve,
         }
     }
 }
@@ -0,0 +1,53 @@
+use crate::database;
+use crate::utils;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+/// Everything the validation rules that look beyond the form itself need:
+/// the server settings and a database connection, normally those of the
+/// request being validated.
+pub struct ValidationContext<'a> {
+    pub config: &'a utils::types::Settings,
+    pub database: &'a database::DbConn,
+}
+
+impl<'a> ValidationContext<'a> {
+    pub fn new(config: &'a utils::types::Settings, database: &'a database::DbConn) -> Self {
+        ValidationContext { config, database }
+    }
+}
+
+/// Validation for forms whose rules depend on the database or the directory
+/// backends, on top of the plain field rules from `Validate`.
+pub trait ValidateWithContext: Validate {
+    /// The context dependent rules, only run once the field rules pass.
+    fn validate_context(&self, context: &ValidationContext<'_>) -> Result<(), ValidationError>;
+
+    fn validate_with_context(
+        &self,
+        context: &ValidationContext<'_>,
+    ) -> Result<(), ValidationErrors> {
+        self.validate()?;
+        self.validate_context(context).map_err(|e| {
+            let mut errors = ValidationErrors::new();
+            errors.add("__all__", e);
+            errors
+        })
+    }
+}
+
+pub type ValidationErrorsInner =
+    std::collections::HashMap<&'static str, Vec<ValidationError>>;
+
+/// The message to show for a failed validation, from whichever rule has one.
+pub fn find_first_validation_message(
+    errors: &ValidationErrorsInner,
+) -> Option<&std::borrow::Cow<'static, str>> {
+    for es in errors {
+        for e in es.1 {
+            if let Some(ref e) = e.message {
+                return Some(e);
+            }
+        }
+    }
+    None
+}
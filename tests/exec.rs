@@ -1,5 +1,3 @@
-use assert_cli;
-
 //kcov doesn't play nice with assert_cli() see
 //https://github.com/assert-rs/assert_cli/issues/101
 use std::env;
@@ -17,3 +15,25 @@ fn test_help() {
         .contains("USAGE")
         .unwrap();
 }
+
+#[test]
+fn test_client_help() {
+    //the client subcommands have their own help
+    let bin: &str = &format!("{}/target/debug/device-checkout", get_cwd());
+    assert_cli::Assert::command(&[bin])
+        .with_args(&["claim", "-h"])
+        .stdout()
+        .contains("--token")
+        .unwrap();
+}
+
+#[test]
+fn test_admin_help() {
+    //the admin subcommands are listed under 'admin'
+    let bin: &str = &format!("{}/target/debug/device-checkout", get_cwd());
+    assert_cli::Assert::command(&[bin])
+        .with_args(&["admin", "-h"])
+        .stdout()
+        .contains("release-all")
+        .unwrap();
+}
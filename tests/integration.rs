@@ -1,19 +1,127 @@
 use rocket::local::blocking::{Client, LocalResponse};
-use tempfile;
-use victoria_dom;
 use device_checkout::*;
 
-#[test]
-fn test_api_get_device() {
+//Each test gets a database of its own, a sqlite tempfile, or when built with
+//the postgres feature, a scratch database on DEVICE_CHECKOUT_TEST_POSTGRES_URL
+#[cfg(feature = "sqlite")]
+struct TestDatabase {
+    _file: tempfile::NamedTempFile,
+}
+
+#[cfg(feature = "sqlite")]
+fn test_settings() -> (TestDatabase, utils::types::Settings) {
     let file = tempfile::NamedTempFile::new().expect("creating tempfile");
     let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    config.database_url = file.path().to_string_lossy().to_string();
+    //argon2's least, or every login takes the better part of a second
+    config.password_hash_cost = (8, 1);
+    (TestDatabase { _file: file }, config)
+}
+
+#[cfg(feature = "postgres")]
+struct TestDatabase {
+    server_url: String,
+    name: String,
+}
+
+#[cfg(feature = "postgres")]
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        use diesel::connection::SimpleConnection;
+        use diesel::Connection;
+        if let Ok(connection) = diesel::pg::PgConnection::establish(&self.server_url) {
+            let _ = connection.batch_execute(&format!("drop database if exists {} with (force)", self.name));
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn test_settings() -> (TestDatabase, utils::types::Settings) {
+    use diesel::connection::SimpleConnection;
+    use diesel::Connection;
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    let server_url = std::env::var("DEVICE_CHECKOUT_TEST_POSTGRES_URL")
+        .expect("DEVICE_CHECKOUT_TEST_POSTGRES_URL, e.g. postgres://postgres@localhost/postgres");
+    let name = format!(
+        "device_checkout_test_{}_{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    );
+    diesel::pg::PgConnection::establish(&server_url)
+        .expect("connecting to postgres")
+        .batch_execute(&format!("create database {}", name))
+        .expect("creating test database");
+
+    let mut config = utils::types::Settings::new();
+    config.database_url = format!("{}/{}", &server_url[..server_url.rfind('/').unwrap()], name);
+    config.password_hash_cost = (8, 1);
+    (TestDatabase { server_url, name }, config)
+}
+
+
+//Register and log in a user on the client, which keeps the session cookie
+//Add the admin, tester, as 'admin user add' would, and log in as them
+fn login(client: &Client) {
+    let config = client.rocket().state::<utils::types::Settings>().unwrap();
+    let add_admin = utils::types::AdminCommand::UserAdd {
+        name: "tester".into(),
+        role: models::UserRole::Admin,
+        password: Some("password123".into()),
+    };
+    admin::run(config, &add_admin).expect("adding admin");
+    let response = post_form(client, "/login", "username=tester&password=password123");
+    assert_eq!(response.status(), rocket::http::Status::SeeOther);
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+}
+
+//Create a personal api token for the logged in user, as an Authorization header
+fn api_token(client: &Client) -> rocket::http::Header<'static> {
+    let body = post_form(client, "/settings/tokens", "name=test")
+        .into_string()
+        .unwrap();
+    let token = victoria_dom::DOM::new(&body)
+        .at("#new_api_token")
+        .expect("failed to find new api token")
+        .text_all();
+    rocket::http::Header::new("Authorization", format!("Bearer {}", token))
+}
+
+//The session's CSRF token, as rendered into its forms
+fn csrf_token(client: &Client) -> String {
+    let body = client.get("/login").dispatch().into_string().unwrap();
+    victoria_dom::DOM::new(&body)
+        .at(r#"input[name="csrf_token"]"#)
+        .and_then(|input| input.attr("value").map(|value| value.to_string()))
+        .expect("failed to find csrf token")
+}
+
+//Post a form as the browser would, with the session's CSRF token
+fn post_form<'c>(client: &'c Client, uri: &'static str, body: &str) -> LocalResponse<'c> {
+    //before the request is made, as it takes the client's cookies when it is
+    let csrf_token = csrf_token(client);
+    client
+        .post(uri)
+        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
+        .body(format!("{}&csrf_token={}", body, csrf_token))
+        .dispatch()
+}
+
+fn switch_user(client: &Client, credentials: &str) {
+    post_form(client, "/logout", "");
+    let response = post_form(client, "/login", credentials);
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+}
+
+#[test]
+fn test_api_get_device() {
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    let mut response = client.get("/api/devices/unit1").dispatch();
+    let response = client.get("/api/devices/unit1").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -25,15 +133,13 @@ fn test_api_get_device() {
 
 #[test]
 fn test_api_get_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    let mut response = client.get("/api/devices").dispatch();
+    let response = client.get("/api/devices").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -43,26 +149,19 @@ fn test_api_get_devices() {
 
 #[test]
 fn test_api_get_pools() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    client.post("/addPools")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"pool_name=Custom1&description=test+description"#)
-    .dispatch();
+    post_form(&client, "/addPools", r#"pool_name=Custom1&description=test+description"#);
 
-    client.post("/addPools")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"pool_name=Custom2&description=test+description+2"#)
-    .dispatch();
+    post_form(&client, "/addPools", r#"pool_name=Custom2&description=test+description+2"#);
 
-    let mut response = client.get("/api/pools").dispatch();
+    let response = client.get("/api/pools").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -76,21 +175,17 @@ fn test_api_get_pools() {
 
 #[test]
 fn test_api_get_custom_owner() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     // Add custom owner record
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    client.post("/addCustomOwners")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"custom_owner_name=Custom1&recipient=SlackUser&description=custom%20owner%20mapping%201"#)
-    .dispatch();
+    login(&client);
+    post_form(&client, "/addCustomOwners", r#"custom_owner_name=Custom1&recipient=SlackUser&description=custom%20owner%20mapping%201"#);
 
-    let mut response = client.get("/api/custom_owners/custom1").dispatch();
+    let response = client.get("/api/custom_owners/custom1").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -100,7 +195,7 @@ fn test_api_get_custom_owner() {
     assert_eq!(v["description"], "custom owner mapping 1");
 
     // assert request is not case-sensitive
-    let mut response = client.get("/api/custom_owners/CUSTOM1").dispatch();
+    let response = client.get("/api/custom_owners/CUSTOM1").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -115,31 +210,21 @@ fn test_api_get_custom_owner() {
 
 #[test]
 fn test_api_get_custom_owners() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     // Add custom owner records
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    client.post("/addCustomOwners")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"custom_owner_name=Custom1&recipient=SlackUser&description=custom%20owner%20mapping%201"#)
-    .dispatch();
+    login(&client);
+    post_form(&client, "/addCustomOwners", r#"custom_owner_name=Custom1&recipient=SlackUser&description=custom%20owner%20mapping%201"#);
 
-    client.post("/addCustomOwners")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"custom_owner_name=Custom2&recipient=SlackChannel&description=custom%20owner%20mapping%202"#)
-    .dispatch();
+    post_form(&client, "/addCustomOwners", r#"custom_owner_name=Custom2&recipient=SlackChannel&description=custom%20owner%20mapping%202"#);
 
-    client.post("/addCustomOwners")
-    .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-    .body(r#"custom_owner_name=Custom3&recipient=None&description="#)
-    .dispatch();
+    post_form(&client, "/addCustomOwners", r#"custom_owner_name=Custom3&recipient=None&description="#);
 
-    let mut response = client.get("/api/custom_owners").dispatch();
+    let response = client.get("/api/custom_owners").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let v: serde_json::Value = serde_json::from_str(&body).unwrap();
@@ -159,47 +244,44 @@ fn test_api_get_custom_owners() {
 
 #[test]
 fn test_api_delete_reservation() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = api_token(&client);
 
     /* TODO: Change this when the API for making reservations is ready. */
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#);
     let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
 
-    let response = client.delete("/api/reservations/1").dispatch();
+    let response = client.delete("/api/reservations/1").header(token.clone()).dispatch();
     assert_eq!(response.status(), rocket::http::Status::NoContent);
 
     /* Once a reservation has ended, you can't end it again. */
-    let response = client.delete("/api/reservations/1").dispatch();
+    let response = client.delete("/api/reservations/1").header(token.clone()).dispatch();
     assert_eq!(response.status(), rocket::http::Status::BadRequest);
 
-    let response = client.delete("/api/reservations/9000").dispatch();
+    let response = client.delete("/api/reservations/9000").header(token.clone()).dispatch();
     assert_eq!(response.status(), rocket::http::Status::NotFound);
 }
 
 #[test]
 fn test_api_post_reservations() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = api_token(&client);
 
-    let mut response = client
+    let response = client
         .post("/api/reservations")
         .header(rocket::http::ContentType::JSON)
+        .header(token.clone())
         .body(r#"{"device_owner":"Barry","comments":"test reservation","device":{"pool_id":1}}"#)
         .dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
@@ -212,22 +294,282 @@ fn test_api_post_reservations() {
     let response = client
         .post("/api/reservations")
         .header(rocket::http::ContentType::JSON)
+        .header(token.clone())
         .body(r#"{"device_owner":"Barry","comments":"pool with no devices","device":{"pool_id":100}}"#)
         .dispatch();
     assert_eq!(response.status(), rocket::http::Status::NotFound);
+
+    //the owner defaults to the token's user, who is recorded as making the change
+    let response = client
+        .post("/api/reservations")
+        .header(rocket::http::ContentType::JSON)
+        .header(token.clone())
+        .body(r#"{"device":{"pool_id":1}}"#)
+        .dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(v["device_owner"], "tester");
+    assert_eq!(v["device"]["updated_by"], "tester");
+
+    //the token's user can list what they hold, shared or not
+    let response = client.get("/api/reservations").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Unauthorized);
+    let response = client.get("/api/reservations").header(token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(v.as_array().unwrap().len(), 1);
+    assert_eq!(v[0]["device_owner"], "tester");
+    assert_eq!(v[0]["devices"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_api_reservation_owners() {
+    let (_database, mut config) = test_settings();
+    //no directory, so only accounts and custom owners are valid owners
+    config.directory_backends = vec![];
+
+    database::run_migrations(&config).expect("running migrations");
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let admin_token = api_token(&client);
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+    let user_token = api_token(&client);
+    let reserve = |token: &rocket::http::Header<'static>, body: &'static str| {
+        let response = client
+            .post("/api/reservations")
+            .header(rocket::http::ContentType::JSON)
+            .header(token.clone())
+            .body(body)
+            .dispatch();
+        let status = response.status();
+        let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        (status, v)
+    };
+
+    //users can only reserve for themselves
+    let (status, v) = reserve(&user_token, r#"{"device_owner":"tester","device":{"pool_id":1}}"#);
+    assert_eq!(status, rocket::http::Status::Forbidden);
+    assert_eq!(v["error"]["message"], "Only admins can reserve devices for someone else");
+    let (status, v) = reserve(&user_token, r#"{"device":{"pool_id":1}}"#);
+    assert_eq!(status, rocket::http::Status::Ok);
+    assert_eq!(v["device_owner"], "bob");
+    let response = client.delete(format!("/api/reservations/{}", v["id"])).header(user_token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::NoContent);
+
+    //admins can name anyone, as long as they're someone the form would take
+    let (status, v) = reserve(&admin_token, r#"{"device_owner":"made up","device":{"pool_id":1}}"#);
+    assert_eq!(status, rocket::http::Status::UnprocessableEntity);
+    assert!(v["error"]["message"].as_str().unwrap().contains("'made up'"));
+    let (status, v) = reserve(&admin_token, r#"{"device_owner":"bob","device":{"pool_id":1}}"#);
+    assert_eq!(status, rocket::http::Status::Ok);
+    assert_eq!(v["device"]["updated_by"], "tester");
+    let bobs_id = v["id"].as_i64().unwrap();
+
+    //only owners end a reservation, admins release it for them
+    let (status, v) = reserve(&admin_token, r#"{"device":{"pool_id":1}}"#);
+    assert_eq!(status, rocket::http::Status::Ok);
+    let testers_id = v["id"].as_i64().unwrap();
+    let response = client.delete(format!("/api/reservations/{}", testers_id)).header(user_token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Forbidden);
+    let response = client.delete(format!("/api/reservations/{}", bobs_id)).header(admin_token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::NoContent);
+    let body = client
+        .get(format!("/api/audit?entity_type=Device&entity_id={}", bobs_id))
+        .header(admin_token.clone())
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let entries: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0]["action"], "ForceRelease");
+    assert_eq!(entries[0]["reason"], "released through the api");
+}
+
+#[test]
+fn test_api_post_multi_device_reservations() {
+    let (_database, mut config) = test_settings();
+    config.bundles = vec![utils::types::BundleDefinition {
+        name: "rig".to_string(),
+        pools: vec![("Default Pool".to_string(), 1), ("Lab2".to_string(), 1)],
+    }];
+
+    database::run_migrations(&config).expect("running migrations");
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = api_token(&client);
+    post_form(&client, "/addPools", "pool_name=Lab2&description=");
+    post_form(&client, "/addDevices", "device_name=unit3&device_url=http%3A%2F%2Funit3&pool_id=2");
+    let reserve = |body: &'static str| {
+        client
+            .post("/api/reservations")
+            .header(rocket::http::ContentType::JSON)
+            .header(token.clone())
+            .body(body)
+            .dispatch()
+    };
+    let reserved = || {
+        let body = client.get("/api/devices").dispatch().into_string().unwrap();
+        let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+        v.as_array()
+            .unwrap()
+            .iter()
+            .filter(|device| device["reservation_status"] == "Reserved")
+            .count()
+    };
+
+    //asking for more than a pool has reserves nothing
+    let response = reserve(r#"{"device_owner":"Barry","devices":[{"pool_id":2},{"pool_id":1,"count":3}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::NotFound);
+    assert_eq!(reserved(), 0);
+
+    let response = reserve(r#"{"device_owner":"Barry","devices":[{"pool_id":2},{"pool_id":1,"count":2}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(v["devices"].as_array().unwrap().len(), 3);
+    assert_eq!(v["device"], v["devices"][0]);
+    assert_eq!(reserved(), 3);
+
+    //the whole reservation is released together
+    let id = v["id"].as_i64().unwrap();
+    let response = client.delete(format!("/api/reservations/{}", id)).header(token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::NoContent);
+    assert_eq!(reserved(), 0);
+
+    let response = reserve(r#"{"device_owner":"Barry","bundle":"rig"}"#);
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let pools: Vec<_> = v["devices"].as_array().unwrap().iter().map(|device| device["pool_id"].as_i64()).collect();
+    assert_eq!(pools, vec![Some(1), Some(2)]);
+
+    //the rig's only Lab2 device is taken
+    let response = reserve(r#"{"device_owner":"Barry","bundle":"rig"}"#);
+    assert_eq!(response.status(), rocket::http::Status::NotFound);
+    assert_eq!(reserved(), 2);
+
+    let response = reserve(r#"{"device_owner":"Barry","bundle":"nonesuch"}"#);
+    assert_eq!(response.status(), rocket::http::Status::NotFound);
+    let response = reserve(r#"{"device_owner":"Barry"}"#);
+    assert_eq!(response.status(), rocket::http::Status::BadRequest);
+    let response = reserve(r#"{"device_owner":"Barry","bundle":"rig","devices":[{"pool_id":1}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::BadRequest);
+    let response = reserve(r#"{"device_owner":"Barry","devices":[{"pool_id":1,"count":0}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::BadRequest);
+}
+
+#[test]
+fn test_reservation_quotas() {
+    let (_database, mut config) = test_settings();
+    config.max_reservations_per_owner = Some(2);
+
+    database::run_migrations(&config).expect("running migrations");
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = api_token(&client);
+    post_form(&client, "/addPools", "pool_name=Lab2&description=&max_reservations_per_owner=1");
+    post_form(&client, "/addDevices", "device_name=unit3&device_url=http%3A%2F%2Funit3&pool_id=2");
+    post_form(&client, "/addDevices", "device_name=unit4&device_url=http%3A%2F%2Funit4&pool_id=2");
+    post_form(&client, "/addCustomOwners", "custom_owner_name=ci&recipient=none&description=&unlimited_reservations=on");
+    let reserve = |body: &'static str| {
+        client
+            .post("/api/reservations")
+            .header(rocket::http::ContentType::JSON)
+            .header(token.clone())
+            .body(body)
+            .dispatch()
+    };
+
+    //one from the small pool is fine, a second isn't
+    let response = post_form(&client, "/devices", "id=3&device_owner=tester&comments=&reservation_status=Available");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    let response = post_form(&client, "/devices", "id=4&device_owner=tester&comments=&reservation_status=Available");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let error = victoria_dom::DOM::new(&body).at("#error_message").expect("failed to find error").text_all();
+    assert!(error.contains("from Lab2"), "{}", error);
+
+    //nor more than two overall
+    let response = reserve(r#"{"devices":[{"pool_id":1,"count":2}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::UnprocessableEntity);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(v["error"]["code"], 422);
+    assert!(v["error"]["message"].as_str().unwrap().contains("tester already has 1 devices reserved"));
+    let response = reserve(r#"{"device":{"pool_id":1}}"#);
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+
+    //except for custom owners an admin has let off
+    let response = reserve(r#"{"device_owner":"ci","devices":[{"pool_id":1},{"pool_id":2}]}"#);
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+}
+
+#[test]
+fn test_api_tokens() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+
+    let reserve = |token: Option<rocket::http::Header<'static>>| {
+        let mut request = client
+            .post("/api/reservations")
+            .header(rocket::http::ContentType::JSON)
+            .body(r#"{"device_owner":"Barry","device":{"pool_id":1}}"#);
+        if let Some(token) = token {
+            request = request.header(token);
+        }
+        request.dispatch().status()
+    };
+
+    let response = client
+        .post("/api/reservations")
+        .header(rocket::http::ContentType::JSON)
+        .body(r#"{"device_owner":"Barry","device":{"pool_id":1}}"#)
+        .dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Unauthorized);
+    let v: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(v["error"]["code"], 401);
+    assert_eq!(
+        reserve(Some(rocket::http::Header::new("Authorization", "Bearer dc_not_a_token"))),
+        rocket::http::Status::Unauthorized
+    );
+
+    login(&client);
+    let token = api_token(&client);
+    assert_eq!(reserve(Some(token.clone())), rocket::http::Status::Ok);
+
+    //tokens are listed, without the token itself, and can be revoked
+    let body = client.get("/settings").dispatch().into_string().unwrap();
+    let token_value = token.value().trim_start_matches("Bearer ").to_string();
+    assert!(!body.contains(&token_value));
+    let dom = victoria_dom::DOM::new(&body);
+    let _ = dom.at(r#"form[name="revoke-1"]"#).expect("failed to find token");
+    post_form(&client, "/settings/tokens/delete", "id=1");
+    assert_eq!(reserve(Some(token)), rocket::http::Status::Unauthorized);
+
+    //api calls don't need the session cookie
+    post_form(&client, "/logout", "");
+    let token = {
+        switch_user(&client, "username=tester&password=password123");
+        let token = api_token(&client);
+        post_form(&client, "/logout", "");
+        token
+    };
+    assert_eq!(reserve(Some(token)), rocket::http::Status::Ok);
+    assert_eq!(reserve(None), rocket::http::Status::Unauthorized);
 }
 
 #[test]
 fn test_html_get_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    let mut response = client.get("/devices").dispatch();
+    let response = client.get("/devices").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let dom = victoria_dom::DOM::new(&body);
@@ -241,15 +583,14 @@ fn test_html_get_devices() {
 
 #[test]
 fn test_html_get_edit_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
-    let mut response = client.get("/editDevices").dispatch();
+    login(&client);
+    let response = client.get("/editDevices").dispatch();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
     let dom = victoria_dom::DOM::new(&body);
@@ -287,11 +628,8 @@ fn follow_redirect<'a>(
     client: &'a Client,
     response: &LocalResponse<'_>,
 ) -> Option<LocalResponse<'a>> {
-    let cookies = get_cookies(&response);
-    let location = match get_redirect(&response) {
-        Some(l) => l,
-        None => return None,
-    };
+    let cookies = get_cookies(response);
+    let location = get_redirect(response)?;
 
     //manually follow the redirection with a new client
     let mut request = client.get(location);
@@ -305,22 +643,17 @@ fn follow_redirect<'a>(
 
 #[test]
 fn test_html_post_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
 
     let body = response.into_string().unwrap();
@@ -346,22 +679,17 @@ fn test_html_post_devices() {
 
 #[test]
 fn test_html_reserve_without_user() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=&comments=xyzzy&reservation_status=Available"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
 
     let body = response.into_string().unwrap();
@@ -373,24 +701,824 @@ fn test_html_reserve_without_user() {
     assert!(dom.at(r#"#success_message"#).is_none());
 }
 
+#[test]
+fn test_html_login_required() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#);
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    let response = follow_redirect(&client, &response).unwrap();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let body = response.into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    let _ = dom
+        .at(r#"#error_message"#)
+        .expect("failed to find error message");
+
+    //nothing changed
+    let body = client.get("/api/devices/unit1").dispatch().into_string().unwrap();
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(v["reservation_status"], "Available");
+}
+
+#[test]
+fn test_html_csrf() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = csrf_token(&client);
+
+    //every html form post is rejected without the session's token, or with another session's
+    let (_other_database, other_config) = test_settings();
+    let other_client = Client::tracked(create_new_rocket(other_config)).expect("valid rocket instance");
+    let other_token = csrf_token(&other_client);
+    let posts: Vec<String> = routes::html_routes()
+        .iter()
+        .filter(|route| route.method == rocket::http::Method::Post)
+        .map(|route| route.uri.to_string())
+        .collect();
+    assert!(posts.contains(&"/deleteDevices".to_string()));
+    for uri in posts {
+        for body in &["id=1".to_string(), format!("id=1&csrf_token={}", other_token)] {
+            let response = client
+                .post(uri.clone())
+                .header(rocket::http::ContentType(rocket::http::MediaType::Form))
+                .body(body)
+                .dispatch();
+            assert_eq!(response.headers().get_one("Location"), Some("/devices"), "{}", uri);
+            let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+            let error = victoria_dom::DOM::new(&body).at("#error_message").expect("failed to find error").text_all();
+            assert!(error.contains("That form had expired"), "{}", uri);
+        }
+    }
+
+    //nothing changed, and the user is still logged in
+    let body = client.get("/api/devices/unit1").dispatch().into_string().unwrap();
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(v["reservation_status"], "Available");
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#current_user").unwrap().text_all().contains("tester"));
+
+    //and the token is the same for the whole session
+    assert_eq!(csrf_token(&client), token);
+    let response = post_form(&client, "/devices", "id=1&device_owner=tester&comments=&reservation_status=Available");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let body = client.get("/api/devices/unit1").dispatch().into_string().unwrap();
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(v["reservation_status"], "Reserved");
+}
+
+#[test]
+fn test_html_login() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+
+    //the owner is filled in for available devices
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("#current_user").unwrap().text_all().contains("tester"));
+    let _ = dom
+        .at(r#"input[name="device_owner"][value="tester"]"#)
+        .expect("failed to find prefilled owner");
+
+    //local users are valid owners, and the change is recorded against them
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=tester&comments=&reservation_status=Available"#);
+    let response = follow_redirect(&client, &response).unwrap();
+    let body = response.into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at(r#"#error_message"#).is_none());
+    let body = client.get("/api/devices/unit1").dispatch().into_string().unwrap();
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(v["reservation_status"], "Reserved");
+    assert_eq!(v["updated_by"], "tester");
+
+    let response = post_form(&client, "/logout", "");
+    assert_eq!(response.status(), rocket::http::Status::SeeOther);
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(!victoria_dom::DOM::new(&body).at("#current_user").unwrap().text_all().contains("tester"));
+
+    let response = post_form(&client, "/login", r#"username=tester&password=wrong+password"#);
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    let response = post_form(&client, "/register", r#"username=tester&password=another+password"#);
+    assert_eq!(response.headers().get_one("Location"), Some("/register"));
+
+    let response = post_form(&client, "/login", r#"username=tester&password=password123"#);
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#current_user").unwrap().text_all().contains("tester"));
+}
+
+#[test]
+fn test_html_registration_disabled() {
+    let (_database, mut config) = test_settings();
+    config.disable_registration = true;
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config.clone());
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let body = client.get("/login").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"a[href="/register"]"#).is_none());
+    let response = client.get("/register").dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    let response = post_form(&client, "/register", "username=bob&password=password123");
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    let connection = database::establish_connection(&config).expect("opening connection");
+    assert!(database::get_user_by_name(&config, &connection, "bob").unwrap().is_none());
+
+    //accounts added from the command line still log in
+    login(&client);
+}
+
+#[test]
+fn test_html_roles() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    //the admin is made from the command line
+    login(&client);
+    post_form(&client, "/addPools", "pool_name=Lab2&description=");
+    post_form(&client, "/logout", "");
+
+    //everyone who registers is a plain user, who can claim but not edit
+    let response = post_form(&client, "/register", "username=bob&password=password123");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let response = post_form(&client, "/devices", "id=1&device_owner=bob&comments=&reservation_status=Available");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    let response = client.get("/editDevices").dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let response = post_form(&client, "/addDevices", "device_name=unit3&device_url=http%3A%2F%2Funit3&pool_id=1");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+
+    //pool admins manage the devices in their own pools only
+    switch_user(&client, "username=tester&password=password123");
+    let response = post_form(&client, "/editUsers", "id=2&role=PoolAdmin&pool_ids=2");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+
+    switch_user(&client, "username=bob&password=password123");
+    let response = post_form(&client, "/addDevices", "device_name=unit3&device_url=http%3A%2F%2Funit3&pool_id=2");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    let response = post_form(&client, "/addDevices", "device_name=unit4&device_url=http%3A%2F%2Funit4&pool_id=1");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    let response = post_form(&client, "/deleteDevices", "id=2");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    let body = client.get("/editDevices").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at(r#"input[name="device_name"][value="unit3"]"#).is_some());
+    assert!(dom.at(r#"input[name="device_name"][value="unit1"]"#).is_none());
+    let response = post_form(&client, "/addPools", "pool_name=Lab3&description=");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+
+    //viewers can only look
+    switch_user(&client, "username=tester&password=password123");
+    post_form(&client, "/editUsers", "id=2&role=Viewer");
+    switch_user(&client, "username=bob&password=password123");
+    let response = post_form(&client, "/devices", "id=1&device_owner=bob&comments=&reservation_status=Reserved");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let body = client.get("/api/devices/unit1").dispatch().into_string().unwrap();
+    let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(v["reservation_status"], "Reserved");
+
+    //and there's always an admin left
+    switch_user(&client, "username=tester&password=password123");
+    let response = post_form(&client, "/editUsers", "id=1&role=User");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    let response = client.get("/editUsers").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+}
+
+#[test]
+fn test_audit_log() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config.clone());
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+    let token = api_token(&client);
+    let audit = |query: &str| -> Vec<serde_json::Value> {
+        let response = client.get(format!("/api/audit?{}", query)).header(token.clone()).dispatch();
+        assert_eq!(response.status(), rocket::http::Status::Ok);
+        serde_json::from_str(&response.into_string().unwrap()).unwrap()
+    };
+
+    //claims and returns through the page and the api
+    post_form(&client, "/devices", "id=1&device_owner=tester&comments=&reservation_status=Available");
+    post_form(&client, "/devices", "id=1&device_owner=&comments=&reservation_status=Reserved");
+    post_form(&client, "/devices", "id=1&device_owner=tester&comments=&reservation_status=Available");
+    let response = client.delete("/api/reservations/1").header(token.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::NoContent);
+
+    let entries = audit("entity_type=Device&entity_id=1");
+    let actions: Vec<_> = entries.iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, vec!["Return", "Claim", "Return", "Claim"]);
+    let sources: Vec<_> = entries.iter().map(|entry| entry["source"].as_str().unwrap()).collect();
+    assert_eq!(sources, vec!["Api", "Ui", "Ui", "Ui"]);
+    assert!(entries.iter().all(|entry| entry["actor"] == "tester"));
+    assert_eq!(entries[3]["before"]["reservation_status"], "Available");
+    assert_eq!(entries[3]["after"]["reservation_status"], "Reserved");
+    assert_eq!(entries[3]["after"]["device_owner"], "tester");
+
+    //administrative changes, which keep what was deleted
+    post_form(&client, "/addDevices", "device_name=unit3&device_url=http%3A%2F%2Funit3&pool_id=1");
+    post_form(&client, "/deleteDevices", "id=2");
+    let entries = audit("entity_type=Device&entity_id=2");
+    assert_eq!(entries[0]["action"], "Delete");
+    assert_eq!(entries[0]["before"]["device_name"], "unit2");
+    assert!(entries[0]["after"].is_null());
+    let entries = audit("entity_type=User");
+    assert_eq!(entries[0]["action"], "Insert");
+    assert_eq!(entries[0]["after"]["username"], "tester");
+    assert!(entries[0]["after"].get("password_hash").is_none());
+
+    //filtered by who made them
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+    post_form(&client, "/devices", "id=3&device_owner=bob&comments=&reservation_status=Available");
+    let entries = audit("actor=bob");
+    let actions: Vec<_> = entries.iter().map(|entry| (entry["entity_type"].as_str().unwrap(), entry["action"].as_str().unwrap())).collect();
+    assert_eq!(actions, vec![("Device", "Claim"), ("User", "Insert")]);
+    assert_eq!(audit("actor=bob&limit=1").len(), 1);
+
+    //only admins can read it
+    let response = client.get("/audit").dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let bob_token = api_token(&client);
+    let response = client.get("/api/audit").header(bob_token).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Forbidden);
+    switch_user(&client, "username=tester&password=password123");
+    let body = client.get("/audit?actor=bob").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert_eq!(dom.find("#audit_log .action").len(), 3);
+
+    //and nobody can rewrite it
+    {
+        use diesel::RunQueryDsl;
+        let database = database::establish_connection(&config).expect("connecting to database");
+        assert!(diesel::sql_query("update audit_log set actor = 'mallory'").execute(&database).is_err());
+        assert!(diesel::sql_query("delete from audit_log").execute(&database).is_err());
+    }
+}
+
+#[test]
+fn test_html_transfer_and_force_release() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let device = |name: &str| -> serde_json::Value {
+        let body = client.get(format!("/api/devices/{}", name)).dispatch().into_string().unwrap();
+        serde_json::from_str(&body).unwrap()
+    };
+    let flash = |response: &LocalResponse<'_>, selector: &str| {
+        let body = follow_redirect(&client, response).unwrap().into_string().unwrap();
+        assert!(victoria_dom::DOM::new(&body).at(selector).is_some(), "expected {} in {}", selector, body);
+    };
+    login(&client);
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+
+    //owners can hand their device straight on, but can't release it for others
+    post_form(&client, "/devices", "id=1&device_owner=bob&comments=testing&reservation_status=Available");
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at(r#"form[name="transfer-1"]"#).is_some());
+    assert!(dom.at(r#"form[name="transfer-2"]"#).is_none());
+    assert!(dom.at(r#"form[name="release-1"]"#).is_none());
+    let response = post_form(&client, "/transferDevices", "id=1&device_owner=bob&new_owner=tester&comments=testing");
+    flash(&response, "#success_message");
+    assert_eq!(device("unit1")["device_owner"], "tester");
+    assert_eq!(device("unit1")["reservation_status"], "Reserved");
+    assert_eq!(device("unit1")["comments"], "testing");
+    let response = post_form(&client, "/transferDevices", "id=1&device_owner=tester&new_owner=bob");
+    flash(&response, "#error_message");
+    post_form(&client, "/devices", "id=2&device_owner=bob&comments=&reservation_status=Available");
+    let response = post_form(&client, "/releaseDevices", "id=2&device_owner=bob&reason=mine");
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    assert_eq!(device("unit2")["reservation_status"], "Reserved");
+
+    //admins can move or release anyone's, as long as it's still whose they think
+    switch_user(&client, "username=tester&password=password123");
+    let response = post_form(&client, "/transferDevices", "id=1&device_owner=bob&new_owner=bob");
+    flash(&response, "#error_message");
+    let response = post_form(&client, "/releaseDevices", "id=2&device_owner=bob&reason=+");
+    flash(&response, "#error_message");
+    let response = post_form(&client, "/releaseDevices", "id=2&device_owner=bob&reason=lab+rebuild");
+    flash(&response, "#success_message");
+    assert_eq!(device("unit2")["reservation_status"], "Available");
+    assert!(device("unit2")["device_owner"].is_null());
+
+    //and both are audited as what they were
+    let token = api_token(&client);
+    let body = client.get("/api/audit?entity_type=Device").header(token.clone()).dispatch().into_string().unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0]["action"], "ForceRelease");
+    assert_eq!(entries[0]["actor"], "tester");
+    assert_eq!(entries[0]["reason"], "lab rebuild");
+    assert_eq!(entries[0]["before"]["device_owner"], "bob");
+    let transfer = entries.iter().find(|entry| entry["action"] == "Transfer").unwrap();
+    assert_eq!(transfer["actor"], "bob");
+    assert_eq!(transfer["before"]["device_owner"], "bob");
+    assert_eq!(transfer["after"]["device_owner"], "tester");
+
+    //RETURN is only for a device's owners, or a release when a pool admin uses it
+    switch_user(&client, "username=bob&password=password123");
+    post_form(&client, "/devices", "id=2&device_owner=bob&comments=&reservation_status=Available");
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at(r#"form[name="reserve-1"] input[value="RETURN"]"#).is_none());
+    assert!(dom.at(r#"form[name="reserve-2"] input[value="RETURN"]"#).is_some());
+    let response = post_form(&client, "/devices", "id=1&device_owner=&comments=&reservation_status=Reserved");
+    flash(&response, "#error_message");
+    assert_eq!(device("unit1")["reservation_status"], "Reserved");
+    switch_user(&client, "username=tester&password=password123");
+    let response = post_form(&client, "/devices", "id=2&device_owner=&comments=&reservation_status=Reserved");
+    flash(&response, "#success_message");
+    assert_eq!(device("unit2")["reservation_status"], "Available");
+    let body = client.get("/api/audit?entity_type=Device").header(token).dispatch().into_string().unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0]["action"], "ForceRelease");
+    assert_eq!(entries[0]["reason"], "returned from the device list");
+    assert_eq!(entries[0]["before"]["device_owner"], "bob");
+}
+
+#[test]
+fn test_html_shared_reservations() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let device = |name: &str| -> serde_json::Value {
+        let body = client.get(format!("/api/devices/{}", name)).dispatch().into_string().unwrap();
+        serde_json::from_str(&body).unwrap()
+    };
+    login(&client);
+    post_form(&client, "/addCustomOwners", "custom_owner_name=ci&recipient=slack_channel&description=");
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=carol&password=password123");
+
+    //bob claims for the pair of them
+    switch_user(&client, "username=bob&password=password123");
+    post_form(&client, "/devices", "id=1&device_owner=bob%2C+tester&comments=&reservation_status=Available");
+    assert_eq!(device("unit1")["device_owner"], "bob, tester");
+
+    //either can update it, anyone else can't
+    switch_user(&client, "username=carol&password=password123");
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"input[value="SAVE"]"#).is_none());
+    assert!(victoria_dom::DOM::new(&body).at(r#"form[name="reserve-1"] input[value="RETURN"]"#).is_none());
+    let response = post_form(&client, "/updateReservations", "id=1&device_owner=carol&comments=&reservation_status=Reserved");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    let response = post_form(&client, "/devices", "id=1&device_owner=&comments=&reservation_status=Reserved");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    assert_eq!(device("unit1")["reservation_status"], "Reserved");
+    switch_user(&client, "username=tester&password=password123");
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"input[value="SAVE"]"#).is_some());
+    let response = post_form(&client, "/updateReservations", "id=1&device_owner=bob%2C+tester%2C+ci&comments=pairing&reservation_status=Reserved");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    assert_eq!(device("unit1")["device_owner"], "bob, tester, ci");
+    assert_eq!(device("unit1")["reservation_status"], "Reserved");
+    assert_eq!(device("unit1")["comments"], "pairing");
+
+    //a custom owner sharing a reservation still holds it
+    let response = post_form(&client, "/deleteCustomOwners", "id=1");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+
+    //and any of them can give it back
+    switch_user(&client, "username=bob&password=password123");
+    post_form(&client, "/devices", "id=1&device_owner=&comments=&reservation_status=Reserved");
+    assert_eq!(device("unit1")["reservation_status"], "Available");
+    switch_user(&client, "username=tester&password=password123");
+    let response = post_form(&client, "/deleteCustomOwners", "id=1");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+
+    //whoever claimed it decides who shares it, the others can only leave
+    switch_user(&client, "username=bob&password=password123");
+    post_form(&client, "/devices", "id=2&device_owner=bob%2C+carol&comments=&reservation_status=Available");
+    switch_user(&client, "username=carol&password=password123");
+    for owners in &["carol", "carol%2C+bob", "bob%2C+carol%2C+tester"] {
+        let response = post_form(&client, "/updateReservations", &format!("id=2&device_owner={}&comments=&reservation_status=Reserved", owners));
+        let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+        assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    }
+    assert_eq!(device("unit2")["device_owner"], "bob, carol");
+    let response = post_form(&client, "/updateReservations", "id=2&device_owner=bob&comments=&reservation_status=Reserved");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    assert_eq!(device("unit2")["device_owner"], "bob");
+
+    //and dropping them from it is a transfer
+    switch_user(&client, "username=bob&password=password123");
+    let response = post_form(&client, "/updateReservations", "id=2&device_owner=carol&comments=&reservation_status=Reserved");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    assert_eq!(device("unit2")["device_owner"], "carol");
+    switch_user(&client, "username=tester&password=password123");
+    let token = api_token(&client);
+    let body = client.get("/api/audit?entity_type=Device").header(token).dispatch().into_string().unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0]["action"], "Transfer");
+    assert_eq!(entries[0]["actor"], "bob");
+    assert_eq!(entries[0]["before"]["device_owner"], "bob");
+    assert_eq!(entries[0]["after"]["device_owner"], "carol");
+    assert_eq!(entries[1]["action"], "Edit");
+    assert_eq!(entries[1]["actor"], "carol");
+}
+
+#[test]
+fn test_html_reserve_for_someone_else() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let device = |name: &str| -> serde_json::Value {
+        let body = client.get(format!("/api/devices/{}", name)).dispatch().into_string().unwrap();
+        serde_json::from_str(&body).unwrap()
+    };
+    login(&client);
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=carol&password=password123");
+
+    //a member can't claim a device in someone else's name
+    switch_user(&client, "username=bob&password=password123");
+    let response = post_form(&client, "/devices", "id=1&device_owner=carol&comments=&reservation_status=Available");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert_eq!(
+        dom.at("#error_message").expect("error message").text_all().trim(),
+        "Error: Only admins can reserve devices for someone else"
+    );
+    assert_eq!(device("unit1")["reservation_status"], "Available");
+
+    //but can share it with them
+    post_form(&client, "/devices", "id=1&device_owner=carol%2C+bob&comments=&reservation_status=Available");
+    assert_eq!(device("unit1")["device_owner"], "carol, bob");
+
+    //and an admin can reserve for anyone
+    switch_user(&client, "username=tester&password=password123");
+    post_form(&client, "/devices", "id=2&device_owner=carol&comments=&reservation_status=Available");
+    assert_eq!(device("unit2")["device_owner"], "carol");
+}
+
+#[test]
+fn test_html_my_devices() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let device = |name: &str| -> serde_json::Value {
+        let body = client.get(format!("/api/devices/{}", name)).dispatch().into_string().unwrap();
+        serde_json::from_str(&body).unwrap()
+    };
+    login(&client);
+    post_form(&client, "/devices", "id=1&device_owner=tester&comments=&reservation_status=Available");
+    post_form(&client, "/devices", "id=2&device_owner=tester&comments=flashing&reservation_status=Available");
+
+    let body = client.get("/my").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("#device_count").unwrap().text_all().contains("tester holds 2 devices"));
+    assert_eq!(dom.find(r#"input[name="ids"][checked]"#).len(), 2);
+    assert!(dom.at("#reservations").unwrap().text_all().contains("under a minute"));
+    assert!(dom.at("#choose_owner").is_none());
+
+    //anyone can look, but only those logged in can give them back
+    post_form(&client, "/logout", "");
+    let body = client.get("/owners/tester").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("h1").unwrap().text_all().contains("Devices held by tester"));
+    assert!(dom.at("#reservations").unwrap().text_all().contains("flashing"));
+    assert!(dom.at(r#"input[name="ids"]"#).is_none());
+    let response = post_form(&client, "/my/return", "device_owner=tester&ids=1");
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    assert_eq!(device("unit1")["reservation_status"], "Reserved");
+
+    //without a login, /my remembers who it was last asked for
+    let body = client.get("/my").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("#choose_owner").is_some());
+    assert!(dom.at("#device_count").is_none());
+    let body = client.get("/my?owner=tester").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#device_count").is_some());
+    let body = client.get("/my").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("#device_count").unwrap().text_all().contains("tester holds 2 devices"));
+
+    //returning some, then the rest along with one already gone
+    post_form(&client, "/login", "username=tester&password=password123");
+    let response = post_form(&client, "/my/return", "device_owner=tester&ids=1");
+    assert_eq!(response.headers().get_one("Location"), Some("/my"));
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    assert_eq!(device("unit1")["reservation_status"], "Available");
+    assert_eq!(device("unit2")["reservation_status"], "Reserved");
+    let response = post_form(&client, "/my/return", "device_owner=tester&ids=1&ids=2");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    assert!(dom.at("#success_message").unwrap().text_all().contains("Returned 1 of 2 devices"));
+    assert!(dom.at("#device_count").unwrap().text_all().contains("tester holds no devices"));
+    assert_eq!(device("unit2")["reservation_status"], "Available");
+    let response = post_form(&client, "/my/return", "device_owner=tester&ids=2");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+
+    //other people's devices can't be returned, except by releasing them from
+    //a pool you manage
+    post_form(&client, "/devices", "id=2&device_owner=tester&comments=&reservation_status=Available");
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=bob&password=password123");
+    post_form(&client, "/devices", "id=1&device_owner=bob&comments=&reservation_status=Available");
+    let body = client.get("/owners/tester").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"input[name="ids"]"#).is_none());
+    let response = post_form(&client, "/my/return", "device_owner=tester&ids=2");
+    assert_eq!(response.headers().get_one("Location"), Some("/owners/tester"));
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#error_message").is_some());
+    assert_eq!(device("unit2")["reservation_status"], "Reserved");
+
+    switch_user(&client, "username=tester&password=password123");
+    let body = client.get("/owners/bob").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"input[name="ids"][value="1"]"#).is_some());
+    let response = post_form(&client, "/my/return", "device_owner=bob&ids=1");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#success_message").is_some());
+    assert_eq!(device("unit1")["reservation_status"], "Available");
+    let token = api_token(&client);
+    let body = client.get("/api/audit?entity_type=Device").header(token).dispatch().into_string().unwrap();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+    assert_eq!(entries[0]["action"], "ForceRelease");
+    assert_eq!(entries[0]["reason"], "returned from bob's devices");
+    assert_eq!(entries[0]["before"]["device_owner"], "bob");
+}
+
+//A username and the groups it's in
+type MockUser = (String, Vec<String>);
+
+//A stand-in OpenID Connect provider, which logs whoever `user` is straight in
+struct MockIdentityProvider {
+    issuer: String,
+    user: std::sync::Mutex<MockUser>,
+    //authorization codes, and the nonce and user they were issued for
+    codes: std::sync::Mutex<std::collections::HashMap<String, (String, MockUser)>>,
+}
+
+#[rocket::get("/.well-known/openid-configuration")]
+fn mock_discovery(
+    idp: &rocket::State<std::sync::Arc<MockIdentityProvider>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::json!({
+        "issuer": idp.issuer,
+        "authorization_endpoint": format!("{}/authorize", idp.issuer),
+        "token_endpoint": format!("{}/token", idp.issuer),
+        "userinfo_endpoint": format!("{}/userinfo", idp.issuer),
+    }))
+}
+
+#[rocket::get("/authorize?<redirect_uri>&<state>&<nonce>")]
+fn mock_authorize(
+    idp: &rocket::State<std::sync::Arc<MockIdentityProvider>>,
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+) -> rocket::response::Redirect {
+    let mut codes = idp.codes.lock().unwrap();
+    let code = format!("code{}", codes.len());
+    codes.insert(code.clone(), (nonce, idp.user.lock().unwrap().clone()));
+    rocket::response::Redirect::to(format!("{}?code={}&state={}", redirect_uri, code, state))
+}
+
+#[derive(rocket::FromForm)]
+struct MockTokenRequest {
+    grant_type: String,
+    code: String,
+}
+
+#[rocket::post("/token", data = "<request>")]
+fn mock_token(
+    idp: &rocket::State<std::sync::Arc<MockIdentityProvider>>,
+    request: rocket::form::Form<MockTokenRequest>,
+) -> Option<rocket::serde::json::Json<serde_json::Value>> {
+    if request.grant_type != "authorization_code" {
+        return None;
+    }
+    let (nonce, (username, groups)) = idp.codes.lock().unwrap().remove(&request.code)?;
+    //the username comes in the id token, the groups from userinfo
+    let claims = serde_json::json!({
+        "iss": idp.issuer,
+        "sub": username,
+        "aud": "device-checkout",
+        "exp": chrono::Utc::now().timestamp() + 300,
+        "nonce": nonce,
+        "preferred_username": username,
+    });
+    let id_token = format!(
+        "{}.{}.",
+        base64::encode_config(r#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+    );
+    Some(rocket::serde::json::Json(serde_json::json!({
+        "access_token": format!("{} {}", username, groups.join(",")),
+        "token_type": "Bearer",
+        "id_token": id_token,
+    })))
+}
+
+struct MockAccessToken(String);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for MockAccessToken {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, ()> {
+        match request.headers().get_one("Authorization").and_then(|a| a.strip_prefix("Bearer ")) {
+            Some(token) => rocket::outcome::Outcome::Success(MockAccessToken(token.to_string())),
+            None => rocket::outcome::Outcome::Forward(rocket::http::Status::NotFound),
+        }
+    }
+}
+
+#[rocket::get("/userinfo")]
+fn mock_userinfo(access_token: MockAccessToken) -> rocket::serde::json::Json<serde_json::Value> {
+    let (username, groups) = access_token.0.split_once(' ').unwrap();
+    let groups: Vec<&str> = groups.split(',').filter(|group| !group.is_empty()).collect();
+    rocket::serde::json::Json(serde_json::json!({"sub": username, "groups": groups}))
+}
+
+fn start_mock_identity_provider() -> std::sync::Arc<MockIdentityProvider> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("finding a free port")
+        .port();
+    let idp = std::sync::Arc::new(MockIdentityProvider {
+        issuer: format!("http://127.0.0.1:{}", port),
+        user: std::sync::Mutex::new(("nobody".into(), vec![])),
+        codes: std::sync::Mutex::new(std::collections::HashMap::new()),
+    });
+    let rocket_config = rocket::Config {
+        port,
+        address: std::net::Ipv4Addr::LOCALHOST.into(),
+        log_level: rocket::config::LogLevel::Off,
+        shutdown: rocket::config::Shutdown {
+            ctrlc: false,
+            ..Default::default()
+        },
+        ..rocket::Config::debug_default()
+    };
+    let rocket = rocket::custom(&rocket_config)
+        .manage(idp.clone())
+        .mount("/", rocket::routes![mock_discovery, mock_authorize, mock_token, mock_userinfo]);
+    std::thread::spawn(move || {
+        let _ = rocket::execute(rocket.launch());
+    });
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return idp;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("mock identity provider didn't start");
+}
+
+//Log in through the identity provider, returning the callback uri it sent us back to
+fn single_sign_on(client: &Client) -> String {
+    let response = client.get("/login/oidc").dispatch();
+    let authorize = get_redirect(&response).expect("redirect to the identity provider");
+    let response = reqwest::blocking::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(&authorize)
+        .send()
+        .expect("authorizing");
+    let callback = response.headers()["Location"].to_str().unwrap();
+    callback.trim_start_matches("http://checkout.test").to_string()
+}
+
+#[test]
+fn test_html_single_sign_on() {
+    let idp = start_mock_identity_provider();
+    let (_database, mut config) = test_settings();
+    config.oidc_issuer = Some(idp.issuer.clone());
+    config.oidc_client_id = Some("device-checkout".into());
+    config.oidc_redirect_url = Some("http://checkout.test/login/oidc/callback".into());
+    config.oidc_group_roles = vec![("checkout-admins".into(), models::UserRole::Admin)];
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config.clone());
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let body = client.get("/login").dispatch().into_string().unwrap();
+    let _ = victoria_dom::DOM::new(&body)
+        .at("#single_sign_on")
+        .expect("failed to find single sign-on link");
+
+    //groups map to roles
+    *idp.user.lock().unwrap() = ("alice".into(), vec!["staff".into(), "checkout-admins".into()]);
+    let callback = single_sign_on(&client);
+    let response = client.get(callback.clone()).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at("#current_user").unwrap().text_all().contains("alice"));
+    assert_eq!(client.get("/editUsers").dispatch().status(), rocket::http::Status::Ok);
+
+    //the callback can't be replayed
+    post_form(&client, "/logout", "");
+    let response = client.get(callback).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    //nor forged
+    let callback = single_sign_on(&client);
+    let response = client.get(callback.replace("state=", "state=forged")).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    //and the role follows the groups on the next login
+    *idp.user.lock().unwrap() = ("alice".into(), vec!["staff".into()]);
+    let callback = single_sign_on(&client);
+    let response = client.get(callback).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/devices"));
+    let connection = database::establish_connection(&config).expect("opening connection");
+    let alice = database::get_user_by_name(&config, &connection, "alice").unwrap().unwrap();
+    assert_eq!(alice.role, models::UserRole::Viewer);
+    assert_eq!(database::get_users(&config, &connection).unwrap().len(), 1);
+
+    assert_eq!(alice.oidc_issuer.as_deref(), Some(idp.issuer.as_str()));
+    assert_eq!(alice.oidc_subject.as_deref(), Some("alice"));
+
+    //accounts made by single sign-on have no password
+    post_form(&client, "/logout", "");
+    let response = post_form(&client, "/login", "username=alice&password=");
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+
+    //and registering someone's name first doesn't take over their single sign-on
+    post_form(&client, "/register", "username=mallory&password=password123");
+    post_form(&client, "/logout", "");
+    *idp.user.lock().unwrap() = ("mallory".into(), vec!["checkout-admins".into()]);
+    let callback = single_sign_on(&client);
+    let response = client.get(callback).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/login"));
+    let mallory = database::get_user_by_name(&config, &connection, "mallory").unwrap().unwrap();
+    assert_eq!(mallory.role, models::UserRole::User);
+    assert_eq!(mallory.oidc_subject, None);
+}
+
 #[test]
 fn test_html_edit_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/editDevices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_name=testunit&device_url=http://testurl&pool_id=1&save=SAVE"#)
-        .dispatch();
+    let response = post_form(&client, "/editDevices", r#"id=1&device_name=testunit&device_url=http://testurl&pool_id=1&save=SAVE"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
 
@@ -411,42 +1539,32 @@ fn test_html_edit_devices() {
 
 #[test]
 fn test_html_edit_devices_bad_url() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/editDevices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_name=testunit&device_url=notaurl&save=SAVE"#)
-        .dispatch();
+    let response = post_form(&client, "/editDevices", r#"id=1&device_name=testunit&device_url=notaurl&save=SAVE"#);
 
     assert_eq!(response.status(), rocket::http::Status::UnprocessableEntity);
 }
 
 #[test]
 fn test_html_edit_devices_delete() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/deleteDevices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_name=testunit&device_url=testurl&delete=DELETE"#)
-        .dispatch();
+    let response = post_form(&client, "/deleteDevices", r#"id=1&device_name=testunit&device_url=testurl&delete=DELETE"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
 
@@ -462,22 +1580,17 @@ fn test_html_edit_devices_delete() {
 
 #[test]
 fn test_html_add_devices() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/addDevices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"device_name=testunit&device_url=http://testurl&pool_id=1&add=ADD"#)
-        .dispatch();
+    let response = post_form(&client, "/addDevices", r#"device_name=testunit&device_url=http://testurl&pool_id=1&add=ADD"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
 
@@ -493,31 +1606,91 @@ fn test_html_add_devices() {
         .expect("failed to find added device");
 }
 
+#[test]
+fn test_html_import_export_inventory() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
+
+    let response = client.get("/exportInventory?format=csv").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let exported = response.into_string().unwrap();
+    assert!(exported.contains("device,unit2,http://unit2,Default Pool"), "{}", exported);
+
+    let upload = |csv: &str, action: &str| {
+        let data: String = url::form_urlencoded::byte_serialize(csv.as_bytes()).collect();
+        post_form(&client, "/importInventory", &format!("data={}&format=csv&action={}", data, action))
+    };
+    let rack = format!(
+        "{}pool,Phones,,,,,2,\ndevice,phone1,http://phone1,Phones,,,,\n",
+        exported.replace("unit2,http://unit2", "unit2,http://unit2.lab")
+    );
+
+    //previewing changes nothing
+    let response = upload(&rack, "preview");
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    let body = response.into_string().unwrap();
+    let preview = victoria_dom::DOM::new(&body)
+        .at("#import_preview")
+        .expect("failed to find import preview")
+        .text_all();
+    assert!(preview.contains("add pool Phones"), "{}", preview);
+    assert!(preview.contains("add device phone1 to Phones"), "{}", preview);
+    assert!(preview.contains("update device unit2"), "{}", preview);
+    let body = client.get("/editDevices").dispatch().into_string().unwrap();
+    assert!(victoria_dom::DOM::new(&body).at(r#"input[value="phone1"]"#).is_none());
+
+    //one bad row and nothing is imported
+    let response = upload(&format!("{}device,phone2,not a url,Phones,,,,\n", rack), "import");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    let error = dom.at("#error_message").expect("failed to find error").text_all();
+    assert!(error.contains("device phone2: URL was invalid"), "{}", error);
+    assert!(dom.at(r#"input[value="phone1"]"#).is_none());
+
+    let response = upload(&rack, "import");
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    let success = dom.at("#success_message").expect("failed to find success").text_all();
+    assert!(success.contains("imported 3 changes"), "{}", success);
+    let _ = dom
+        .at(r#"input[name="device_url"][value="http://phone1"]"#)
+        .expect("failed to find imported device");
+    let _ = dom
+        .at(r#"input[name="device_url"][value="http://unit2.lab"]"#)
+        .expect("failed to find updated device");
+
+    //only admins can
+    post_form(&client, "/logout", "");
+    post_form(&client, "/register", "username=barry&password=password123");
+    let response = client.get("/exportInventory?format=json").dispatch();
+    let body = follow_redirect(&client, &response).unwrap().into_string().unwrap();
+    let error = victoria_dom::DOM::new(&body).at("#error_message").expect("failed to find error").text_all();
+    assert!(error.contains("You do not have permission"), "{}", error);
+}
+
 #[test]
 fn test_html_add_devices_bad_url() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
-    let response = client
-        .post("/addDevices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"device_name=testunit&device_url=notaurl&add=ADD"#)
-        .dispatch();
+    let response = post_form(&client, "/addDevices", r#"device_name=testunit&device_url=notaurl&add=ADD"#);
 
     assert_eq!(response.status(), rocket::http::Status::UnprocessableEntity);
 }
 
 #[test]
 fn test_get_root() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
@@ -531,21 +1704,16 @@ fn test_get_root() {
 
 #[test]
 fn test_reserve_already_reserved() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
     //reserve unit1
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#);
 
     let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
@@ -557,17 +1725,13 @@ fn test_reserve_already_reserved() {
         .expect("failed to find reservation status");
 
     //reserve unit2
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=Owner2&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner2&comments=xyzzy&reservation_status=Available"#);
 
     let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(&response.status(), &rocket::http::Status::Ok);
     let body = &response.into_string().unwrap();
 
-    let dom = victoria_dom::DOM::new(&body);
+    let dom = victoria_dom::DOM::new(body);
 
     let _ = dom
         .at(r#"#error_message"#)
@@ -584,33 +1748,24 @@ fn test_reserve_already_reserved() {
 
 #[test]
 fn test_returning_clears_fields() {
-    let file = tempfile::NamedTempFile::new().expect("creating tempfile");
-    let mut config = utils::types::Settings::new();
-    config.database_url = file.path().to_string_lossy().to_owned().to_string();
+    let (_database, config) = test_settings();
 
     database::run_migrations(&config).expect("running migrations");
 
     let rocket = create_new_rocket(config);
     let client = Client::tracked(rocket).expect("valid rocket instance");
+    login(&client);
 
     //reserve unit1
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&device_owner=Owner&comments=xyzzy&reservation_status=Available"#);
 
     let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
 
     //return unit1
-    let response = client
-        .post("/devices")
-        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
-        .body(r#"id=1&reservation_status=Reserved"#)
-        .dispatch();
+    let response = post_form(&client, "/devices", r#"id=1&reservation_status=Reserved"#);
 
-    let mut response = follow_redirect(&client, &response).unwrap();
+    let response = follow_redirect(&client, &response).unwrap();
     assert_eq!(response.status(), rocket::http::Status::Ok);
     let body = response.into_string().unwrap();
 
@@ -635,3 +1790,228 @@ fn test_returning_clears_fields() {
         .at(r#"input[name="comments"][form="reserve-1"][value]"#)
         .expect("failed to find empty comments");
 }
+
+#[test]
+#[ignore = "needs an LDAP server, see DEVICE_CHECKOUT_TEST_LDAP_URL in the README"]
+fn test_ldap_user_exists() {
+    let url = std::env::var("DEVICE_CHECKOUT_TEST_LDAP_URL").expect("DEVICE_CHECKOUT_TEST_LDAP_URL");
+    let mut config = utils::types::Settings::new();
+    config.ldap_url = Some(url);
+    config.ldap_bind_dn = std::env::var("DEVICE_CHECKOUT_TEST_LDAP_BIND_DN").ok();
+    config.ldap_base_dn = std::env::var("DEVICE_CHECKOUT_TEST_LDAP_BASE_DN").ok();
+    let user = std::env::var("DEVICE_CHECKOUT_TEST_LDAP_USER").expect("DEVICE_CHECKOUT_TEST_LDAP_USER");
+
+    let client = utils::ldap::ldap_client_init(&config).unwrap();
+    assert!(utils::ldap::ldap_user_exists(&user, &client));
+    assert!(!utils::ldap::ldap_user_exists("no-such-user-xyzzy", &client));
+}
+
+#[test]
+fn test_concurrent_reservations_of_last_device() {
+    let (_database, mut config) = test_settings();
+    config.pool_size = 16;
+
+    database::run_migrations(&config).expect("running migrations");
+    let db_pool = pool::init_pool(&config);
+
+    //leave unit2 as the only available device in the default pool
+    let device_update = models::DeviceUpdate {
+        id: 1,
+        device_owner: Some("Owner".into()),
+        comments: None,
+        reservation_status: models::ReservationStatus::Reserved,
+    };
+    let database = db_pool.get().unwrap();
+    database::update_device(&config, &database, &device_update, models::ReservationStatus::Available, &models::ChangeContext::default())
+        .expect("reserving unit1");
+    drop(database);
+
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(16));
+    let threads: Vec<_> = (0..16)
+        .map(|i| {
+            let config = config.clone();
+            let db_pool = db_pool.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let database = db_pool.get().unwrap();
+                barrier.wait();
+                database::reserve_device_from_pool(&config, &database, 1, Some(format!("owner{}", i)), None, &models::ChangeContext::default())
+                    .expect("reserving from pool")
+            })
+        })
+        .collect();
+    let reserved: Vec<_> = threads
+        .into_iter()
+        .filter_map(|t| t.join().unwrap())
+        .collect();
+
+    assert_eq!(reserved.len(), 1);
+    assert_eq!(reserved[0].device_name, "unit2");
+
+    let database = db_pool.get().unwrap();
+    let unit2 = database::get_device(&config, &database, "unit2").unwrap().unwrap();
+    assert_eq!(unit2.device_owner, reserved[0].device_owner);
+}
+
+#[test]
+fn test_concurrent_reservations_over_quota() {
+    let (_database, mut config) = test_settings();
+    config.pool_size = 16;
+    config.max_reservations_per_owner = Some(1);
+
+    database::run_migrations(&config).expect("running migrations");
+    let db_pool = pool::init_pool(&config);
+
+    //both devices in the default pool are free, but alice may only hold one
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(16));
+    let threads: Vec<_> = (0..16)
+        .map(|_| {
+            let config = config.clone();
+            let db_pool = db_pool.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let database = db_pool.get().unwrap();
+                let owners = vec!["alice".to_string()];
+                let context = validation::ValidationContext::new(&config, &database);
+                barrier.wait();
+                database::reserve_devices_from_pools(
+                    &config,
+                    &database,
+                    &[(1, 1)],
+                    Some("alice".into()),
+                    None,
+                    &models::ChangeContext::default(),
+                    || models::validate_reservation_quota(&owners, &[(1, 1)], None, &context),
+                )
+                .ok()
+                .flatten()
+            })
+        })
+        .collect();
+    let reserved: Vec<_> = threads
+        .into_iter()
+        .filter_map(|t| t.join().unwrap())
+        .collect();
+
+    assert_eq!(reserved.len(), 1);
+    let database = db_pool.get().unwrap();
+    assert_eq!(database::get_devices_by_owner(&config, &database, "alice").unwrap().len(), 1);
+}
+
+#[test]
+fn test_base_path() {
+    let (_database, mut config) = test_settings();
+    config.base_path = "/checkout".to_string();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client.get("/checkout/").dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/checkout/devices"));
+    assert_eq!(client.get("/devices").dispatch().status(), rocket::http::Status::NotFound);
+
+    //every link and form on the page stays under the base path
+    let body = client.get("/checkout/login").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    let links: Vec<String> = dom
+        .find("a")
+        .iter()
+        .filter_map(|link| link.attr("href").map(|x| x.to_string()))
+        .chain(dom.find("form").iter().filter_map(|form| form.attr("action").map(|x| x.to_string())))
+        .filter(|link| link.starts_with('/'))
+        .collect();
+    assert!(links.contains(&"/checkout/register".to_string()));
+    assert!(links.iter().all(|link| link.starts_with("/checkout/")), "{:?}", links);
+
+    let csrf_token = dom
+        .at(r#"input[name="csrf_token"]"#)
+        .and_then(|input| input.attr("value").map(|value| value.to_string()))
+        .expect("failed to find csrf token");
+    let response = client
+        .post("/checkout/register")
+        .header(rocket::http::ContentType(rocket::http::MediaType::Form))
+        .body(format!("username=tester&password=password123&csrf_token={}", csrf_token))
+        .dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/checkout/devices"));
+    let response = client.get("/checkout/settings").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+
+    let response = client.get("/checkout/api/devices/unit1").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+}
+
+#[test]
+fn test_stored_secret_key() {
+    let (_database, mut config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+    let connection = database::establish_connection(&config).unwrap();
+    let key = database::get_or_create_secret_key(&config, &connection).unwrap();
+    assert_eq!(database::get_or_create_secret_key(&config, &connection).unwrap(), key);
+    config.secret_key = Some(key);
+
+    //a session from before a restart is still logged in after it
+    let client = Client::tracked(create_new_rocket(config.clone())).expect("valid rocket instance");
+    login(&client);
+    let session = client.cookies().get("user_id").expect("session cookie").clone();
+    let restarted = Client::tracked(create_new_rocket(config)).expect("valid rocket instance");
+    let response = restarted.get("/settings").cookie(session.clone()).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+
+    //but not with a different key
+    let (_other_database, other_config) = test_settings();
+    database::run_migrations(&other_config).expect("running migrations");
+    let other = Client::tracked(create_new_rocket(other_config)).expect("valid rocket instance");
+    let response = other.get("/settings").cookie(session).dispatch();
+    assert_eq!(response.status(), rocket::http::Status::SeeOther);
+}
+
+#[test]
+fn test_static_files() {
+    let (_database, config) = test_settings();
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client.get("/static/jquery.min.js").dispatch();
+    assert_eq!(response.status(), rocket::http::Status::Ok);
+    assert_eq!(response.content_type(), Some(rocket::http::ContentType::JavaScript));
+    assert_eq!(client.get("/static/missing.js").dispatch().status(), rocket::http::Status::NotFound);
+    assert_ne!(client.get("/static/../Cargo.toml").dispatch().status(), rocket::http::Status::Ok);
+    assert_ne!(client.get("/static/%2e%2e/Cargo.toml").dispatch().status(), rocket::http::Status::Ok);
+
+    //whatever a page loads from /static/ has to be there, the rest comes from a CDN
+    let body = client.get("/devices").dispatch().into_string().unwrap();
+    let dom = victoria_dom::DOM::new(&body);
+    let mut assets: Vec<String> = dom
+        .find("link[rel=stylesheet]")
+        .iter()
+        .filter_map(|link| link.attr("href").map(|href| href.to_string()))
+        .collect();
+    assets.extend(dom.find("script[src]").iter().filter_map(|script| script.attr("src").map(|src| src.to_string())));
+    assert_eq!(assets.len(), 6);
+    for asset in assets.iter().filter(|asset| asset.starts_with("/static/")) {
+        assert_eq!(client.get(asset.clone()).dispatch().status(), rocket::http::Status::Ok, "{}", asset);
+    }
+    assert!(assets.iter().any(|asset| asset == "/static/jquery.min.js"));
+}
+
+#[test]
+fn test_theme() {
+    let (_database, mut config) = test_settings();
+    config.theme.site_title = "Lab 4".to_string();
+    config.theme.banner = Some("Closed Friday".to_string());
+
+    database::run_migrations(&config).expect("running migrations");
+
+    let rocket = create_new_rocket(config);
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    for page in &["/devices", "/login", "/register"] {
+        let body = client.get(*page).dispatch().into_string().unwrap();
+        let dom = victoria_dom::DOM::new(&body);
+        assert_eq!(dom.at("#site_header").expect("header").text_all().trim(), "Lab 4");
+        assert_eq!(dom.at("#banner").expect("banner").text_all(), "Closed Friday");
+    }
+}